@@ -0,0 +1,43 @@
+//! CPU pinning and NUMA node lookup for the runtime thread
+//!
+//! kTLS + io_uring benchmarks are sensitive to the scheduler moving the
+//! runtime between cores (and between NUMA nodes, away from its buffers),
+//! so the thread can be pinned before `tokio_uring::start`.
+
+/// Restrict the calling thread to the given CPUs
+pub fn pin_current_thread(cpus: &[usize]) -> std::io::Result<()> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for &cpu in cpus {
+        if cpu >= libc::CPU_SETSIZE as usize {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("CPU {cpu} out of range"),
+            ));
+        }
+        unsafe { libc::CPU_SET(cpu, &mut set) };
+    }
+
+    let ret = unsafe { libc::sched_setaffinity(0, std::mem::size_of_val(&set), &set) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// NUMA node of the CPU the calling thread is currently running on
+pub fn current_numa_node() -> std::io::Result<u32> {
+    let mut cpu: libc::c_uint = 0;
+    let mut node: libc::c_uint = 0;
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_getcpu,
+            &mut cpu as *mut libc::c_uint,
+            &mut node as *mut libc::c_uint,
+            std::ptr::null_mut::<libc::c_void>(),
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(node)
+}
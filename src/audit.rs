@@ -0,0 +1,675 @@
+//! `audit` subcommand: the syscalls and io_uring opcodes a configuration uses
+//!
+//! Under a seccomp filter, or on a kernel that restricts io_uring (the
+//! `kernel.io_uring_disabled` sysctl, `IORING_REGISTER_RESTRICTIONS`), a
+//! missing syscall or opcode shows up as an `EPERM` or `EINVAL` halfway
+//! through a request. The audit lists everything the client needs for a
+//! given set of options, each with the reason it's needed, so operators can
+//! pre-authorize it:
+//!
+//! ```text
+//! ktls-uring-demo audit --fast-open --buffers             # table
+//! ktls-uring-demo audit --fast-open --buffers --seccomp   # OCI seccomp profile
+//! ktls-uring-demo audit --restrictions                    # io_uring allowlist
+//! ktls-uring-demo audit --check                           # probe this kernel
+//! ```
+//!
+//! `--check` tries the pieces on the running kernel instead, the same way
+//! the client would, and exits non-zero if the client can't run at all.
+//! Missing kTLS or MPTCP only degrade it, since both fall back.
+//!
+//! The lists cover the crate, tokio-uring, tokio's reactor, std and glibc.
+//! Names are x86_64's; where other architectures only have the newer call
+//! (`ppoll`, `epoll_pwait`) both are listed, and seccomp ignores names the
+//! architecture doesn't have.
+
+use std::collections::BTreeMap;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use io_uring::{IoUring, Probe, opcode};
+
+use ktls_uring_demo::buffers::BufferPoolConfig;
+use ktls_uring_demo::{connect, markers};
+
+pub const USAGE: &str = "\
+usage: ktls-uring-demo audit [--no-ktls] [--fast-open] [--mptcp] [--buffers]
+                             [--numa-local] [--huge-pages] [--provided-buffers]
+                             [--io-timeout] [--dscp] [--nodelay] [--websocket]
+                             [--backpressure] [--control] [--pin]
+                             [--perf-markers] [--cassette] [--bench]
+                             [--seccomp | --restrictions | --check]
+
+  --no-ktls       userspace TLS only
+  --fast-open     TCP Fast Open connects
+  --mptcp         Multipath TCP connects
+  --buffers       registered (fixed) read buffers
+  --numa-local    NUMA-bind those buffers
+  --huge-pages    back those buffers with huge pages
+  --provided-buffers
+                  the provided-buffer receive ring
+  --io-timeout    per-operation kTLS timeouts (implies the ring above)
+  --dscp          a traffic class on connections
+  --nodelay       TCP_NODELAY on connections or per request
+  --websocket     WebSocket connections
+  --backpressure  send queue readings and full-buffer alerts
+  --control       the runtime control socket
+  --pin           pin the runtime thread to CPUs
+  --perf-markers  phase markers for perf
+  --cassette      record or replay a cassette
+  --bench         the bench subcommand
+
+  --seccomp       print an OCI seccomp profile allowing the syscalls
+  --restrictions  print the io_uring opcodes and register operations, per
+                  ring, for IORING_REGISTER_RESTRICTIONS
+  --check         probe whether this kernel and process allow them";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Output {
+    Table,
+    Seccomp,
+    Restrictions,
+    Check,
+}
+
+pub struct AuditConfig {
+    ktls: bool,
+    fast_open: bool,
+    mptcp: bool,
+    buffers: Option<BufferPoolConfig>,
+    provided_buffers: bool,
+    io_timeout: bool,
+    traffic_class: bool,
+    nodelay: bool,
+    websocket: bool,
+    backpressure: bool,
+    control: bool,
+    pin: bool,
+    perf_markers: bool,
+    cassette: bool,
+    bench: bool,
+    output: Output,
+}
+
+impl AuditConfig {
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let mut config = Self {
+            ktls: true,
+            fast_open: false,
+            mptcp: false,
+            buffers: None,
+            provided_buffers: false,
+            io_timeout: false,
+            traffic_class: false,
+            nodelay: false,
+            websocket: false,
+            backpressure: false,
+            control: false,
+            pin: false,
+            perf_markers: false,
+            cassette: false,
+            bench: false,
+            output: Output::Table,
+        };
+
+        for arg in args {
+            match arg.as_str() {
+                "--no-ktls" => config.ktls = false,
+                "--fast-open" => config.fast_open = true,
+                "--mptcp" => config.mptcp = true,
+                "--buffers" => {
+                    config.buffers.get_or_insert_with(Default::default);
+                }
+                "--numa-local" => {
+                    config
+                        .buffers
+                        .get_or_insert_with(Default::default)
+                        .numa_local = true;
+                }
+                "--huge-pages" => {
+                    config
+                        .buffers
+                        .get_or_insert_with(Default::default)
+                        .huge_pages = true;
+                }
+                "--provided-buffers" => config.provided_buffers = true,
+                "--io-timeout" => config.io_timeout = true,
+                "--dscp" => config.traffic_class = true,
+                "--nodelay" => config.nodelay = true,
+                "--websocket" => config.websocket = true,
+                "--backpressure" => config.backpressure = true,
+                "--control" => config.control = true,
+                "--pin" => config.pin = true,
+                "--perf-markers" => config.perf_markers = true,
+                "--cassette" => config.cassette = true,
+                "--bench" => config.bench = true,
+                "--seccomp" => config.output = Output::Seccomp,
+                "--restrictions" => config.output = Output::Restrictions,
+                "--check" => config.output = Output::Check,
+                other => return Err(format!("unknown argument: {other}")),
+            }
+        }
+        Ok(config)
+    }
+
+    /// kTLS I/O goes through the provided-buffer ring
+    fn uses_recv_ring(&self) -> bool {
+        self.ktls && (self.provided_buffers || self.io_timeout)
+    }
+}
+
+/// One of the two rings the client may submit to
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Ring {
+    /// tokio-uring's, which drives connects and most reads and writes
+    Runtime,
+    /// The provided-buffer ring ([`RecvRing`](ktls_uring_demo::bufring::RecvRing))
+    Recv,
+}
+
+impl Ring {
+    fn name(self) -> &'static str {
+        match self {
+            Ring::Runtime => "runtime",
+            Ring::Recv => "provided-buffer",
+        }
+    }
+}
+
+/// `io_uring_register(2)` operations, which the io-uring crate doesn't export
+const IORING_REGISTER_BUFFERS: u8 = 0;
+const IORING_REGISTER_EVENTFD: u8 = 4;
+const IORING_REGISTER_PBUF_RING: u8 = 22;
+
+struct Operation {
+    ring: Ring,
+    name: &'static str,
+    code: u8,
+    why: &'static str,
+}
+
+/// What a configuration needs from the kernel
+struct Audit {
+    /// Syscall name to the reasons it's needed
+    syscalls: BTreeMap<&'static str, Vec<&'static str>>,
+    opcodes: Vec<Operation>,
+    registers: Vec<Operation>,
+}
+
+impl Audit {
+    fn new(config: &AuditConfig) -> Self {
+        let mut audit = Self {
+            syscalls: BTreeMap::new(),
+            opcodes: Vec::new(),
+            registers: Vec::new(),
+        };
+
+        // Process start-up and exit, std and the allocator
+        audit.syscalls(
+            &[
+                "brk",
+                "mmap",
+                "munmap",
+                "mremap",
+                "mprotect",
+                "madvise",
+                "rt_sigaction",
+                "rt_sigprocmask",
+                "rt_sigreturn",
+                "sigaltstack",
+                "poll",
+                "ppoll",
+                "futex",
+                "getrandom",
+                "clock_gettime",
+                "write",
+                "exit_group",
+            ],
+            "process runtime (std, glibc, allocator, stdout/stderr)",
+        );
+        // tokio's reactor, which also watches the io_uring fd
+        audit.syscalls(
+            &[
+                "epoll_create1",
+                "epoll_ctl",
+                "epoll_wait",
+                "epoll_pwait",
+                "eventfd2",
+                "read",
+            ],
+            "tokio reactor",
+        );
+        audit.syscalls(
+            &["io_uring_setup", "io_uring_enter", "mmap"],
+            "tokio-uring runtime ring",
+        );
+        // Trust roots, /etc/hosts, resolv.conf, nsswitch.conf
+        audit.syscalls(
+            &[
+                "openat",
+                "read",
+                "close",
+                "fstat",
+                "newfstatat",
+                "statx",
+                "lseek",
+                "getdents64",
+            ],
+            "loading CA certificates and resolver configuration",
+        );
+        audit.syscalls(
+            &[
+                "socket", "connect", "sendto", "sendmmsg", "recvfrom", "ioctl", "close",
+            ],
+            "DNS lookups (glibc resolver)",
+        );
+        audit.syscalls(
+            &[
+                "clone3",
+                "clone",
+                "set_robust_list",
+                "rseq",
+                "sched_getaffinity",
+                "futex",
+                "exit",
+            ],
+            "tokio blocking threads, which run the DNS lookups",
+        );
+        audit.syscalls(&["socket", "close"], "TCP sockets");
+        audit.opcode(
+            Ring::Runtime,
+            "IORING_OP_CONNECT",
+            opcode::Connect::CODE,
+            "connecting",
+        );
+        audit.opcode(
+            Ring::Runtime,
+            "IORING_OP_READ",
+            opcode::Read::CODE,
+            "TLS handshakes and reading responses",
+        );
+        audit.opcode(
+            Ring::Runtime,
+            "IORING_OP_WRITE",
+            opcode::Write::CODE,
+            "TLS handshakes and writing requests",
+        );
+        audit.opcode(
+            Ring::Runtime,
+            "IORING_OP_ASYNC_CANCEL",
+            opcode::AsyncCancel::CODE,
+            "cancelling operations in flight at shutdown",
+        );
+
+        if config.ktls {
+            audit.syscalls(&["setsockopt"], "kTLS (TCP_ULP, SOL_TLS TLS_TX/TLS_RX)");
+            audit.syscalls(
+                &["sendmsg"],
+                "close_notify alerts over kTLS (TLS_SET_RECORD_TYPE)",
+            );
+        }
+        if config.fast_open || config.mptcp {
+            audit.syscalls(
+                &["socket", "connect", "getsockopt"],
+                "non-blocking connect outside the ring (SO_ERROR)",
+            );
+        }
+        if config.fast_open {
+            audit.syscalls(&["setsockopt"], "TCP_FASTOPEN_CONNECT");
+        }
+        if config.mptcp {
+            audit.syscalls(
+                &["socket", "getsockopt"],
+                "MPTCP sockets (IPPROTO_MPTCP, TCP_IS_MPTCP)",
+            );
+        }
+        if let Some(buffers) = &config.buffers {
+            audit.syscalls(&["mmap", "munmap"], "registered buffer memory");
+            audit.syscalls(&["io_uring_register"], "registering the buffers");
+            audit.register(
+                Ring::Runtime,
+                "IORING_REGISTER_BUFFERS",
+                IORING_REGISTER_BUFFERS,
+                "registered buffers",
+            );
+            audit.opcode(
+                Ring::Runtime,
+                "IORING_OP_READ_FIXED",
+                opcode::ReadFixed::CODE,
+                "reading into registered buffers",
+            );
+            if buffers.numa_local {
+                audit.syscalls(&["getcpu"], "finding the runtime thread's NUMA node");
+                audit.syscalls(&["mbind"], "NUMA-binding the buffers");
+            }
+            if buffers.huge_pages {
+                audit.syscalls(&["madvise"], "transparent huge pages (MADV_HUGEPAGE)");
+            }
+        }
+        if config.uses_recv_ring() {
+            audit.syscalls(
+                &["io_uring_setup", "io_uring_enter", "mmap"],
+                "provided-buffer ring",
+            );
+            audit.syscalls(
+                &["io_uring_register", "eventfd2", "read"],
+                "provided-buffer ring completions and buffers",
+            );
+            audit.register(
+                Ring::Recv,
+                "IORING_REGISTER_EVENTFD",
+                IORING_REGISTER_EVENTFD,
+                "completion notifications",
+            );
+            audit.register(
+                Ring::Recv,
+                "IORING_REGISTER_PBUF_RING",
+                IORING_REGISTER_PBUF_RING,
+                "provided buffers",
+            );
+            audit.opcode(
+                Ring::Recv,
+                "IORING_OP_RECV",
+                opcode::Recv::CODE,
+                "kTLS reads",
+            );
+            audit.opcode(
+                Ring::Recv,
+                "IORING_OP_SEND",
+                opcode::Send::CODE,
+                "kTLS writes",
+            );
+            audit.opcode(
+                Ring::Recv,
+                "IORING_OP_ASYNC_CANCEL",
+                opcode::AsyncCancel::CODE,
+                "cancelling dropped operations",
+            );
+            if config.io_timeout {
+                audit.opcode(
+                    Ring::Recv,
+                    "IORING_OP_LINK_TIMEOUT",
+                    opcode::LinkTimeout::CODE,
+                    "per-operation timeouts",
+                );
+            }
+        }
+        if config.traffic_class {
+            audit.syscalls(
+                &["setsockopt"],
+                "traffic class (IP_TOS, IPV6_TCLASS, SO_PRIORITY)",
+            );
+        }
+        if config.nodelay {
+            audit.syscalls(&["setsockopt"], "TCP_NODELAY");
+        }
+        if config.websocket {
+            audit.syscalls(
+                &["sendto", "recvfrom", "fcntl"],
+                "WebSocket I/O on the reactor (MSG_DONTWAIT)",
+            );
+            audit.syscalls(&["shutdown"], "closing the write half");
+        }
+        if config.backpressure {
+            audit.syscalls(
+                &["ioctl", "getsockopt", "poll", "fcntl"],
+                "send queue readings (SIOCOUTQ, SO_SNDBUF, POLLOUT) on a duplicate fd",
+            );
+        }
+        if config.control {
+            audit.syscalls(
+                &["socket", "bind", "listen", "accept4", "unlink"],
+                "runtime control socket (AF_UNIX)",
+            );
+            audit.syscalls(&["recvfrom", "sendto"], "control commands and replies");
+        }
+        if config.pin {
+            audit.syscalls(&["sched_setaffinity"], "pinning the runtime thread");
+        }
+        if config.perf_markers {
+            audit.syscalls(&["openat", "write"], "ftrace trace_marker");
+        }
+        if config.cassette {
+            audit.syscalls(&["openat", "read", "write"], "cassette file");
+        }
+        if config.bench {
+            audit.syscalls(&["getrusage"], "CPU usage");
+        }
+        audit
+    }
+
+    fn syscalls(&mut self, names: &[&'static str], why: &'static str) {
+        for name in names {
+            let reasons = self.syscalls.entry(name).or_default();
+            if !reasons.contains(&why) {
+                reasons.push(why);
+            }
+        }
+    }
+
+    fn opcode(&mut self, ring: Ring, name: &'static str, code: u8, why: &'static str) {
+        self.opcodes.push(Operation {
+            ring,
+            name,
+            code,
+            why,
+        });
+    }
+
+    fn register(&mut self, ring: Ring, name: &'static str, code: u8, why: &'static str) {
+        self.registers.push(Operation {
+            ring,
+            name,
+            code,
+            why,
+        });
+    }
+
+    fn print_table(&self) {
+        println!("syscalls:");
+        for (name, reasons) in &self.syscalls {
+            println!("  {name:<18} {}", reasons.join("; "));
+        }
+        println!("\nio_uring opcodes:");
+        for op in &self.opcodes {
+            println!(
+                "  {:<16} {:<26} {:>3}  {}",
+                op.ring.name(),
+                op.name,
+                op.code,
+                op.why
+            );
+        }
+        if !self.registers.is_empty() {
+            println!("\nio_uring register operations:");
+            for op in &self.registers {
+                println!(
+                    "  {:<16} {:<26} {:>3}  {}",
+                    op.ring.name(),
+                    op.name,
+                    op.code,
+                    op.why
+                );
+            }
+        }
+    }
+
+    /// An OCI runtime seccomp section allowing exactly these syscalls
+    fn seccomp_json(&self) -> String {
+        let arch = if cfg!(target_arch = "aarch64") {
+            "SCMP_ARCH_AARCH64"
+        } else {
+            "SCMP_ARCH_X86_64"
+        };
+        let names: Vec<String> = self.syscalls.keys().map(|n| format!("\"{n}\"")).collect();
+        format!(
+            "{{\n  \"defaultAction\": \"SCMP_ACT_ERRNO\",\n  \"architectures\": [\"{arch}\"],\n  \
+             \"syscalls\": [\n    {{\n      \"names\": [{}],\n      \"action\": \"SCMP_ACT_ALLOW\"\n    \
+             }}\n  ]\n}}",
+            names.join(", ")
+        )
+    }
+
+    fn print_restrictions(&self) {
+        for ring in [Ring::Runtime, Ring::Recv] {
+            let opcodes = self.opcodes.iter().filter(|op| op.ring == ring);
+            let registers = self.registers.iter().filter(|op| op.ring == ring);
+            let mut ops = opcodes.chain(registers).peekable();
+            if ops.peek().is_none() {
+                continue;
+            }
+            println!("# {} ring", ring.name());
+            for op in ops {
+                println!("{} {}", op.name, op.code);
+            }
+        }
+    }
+
+    /// Probe the running kernel; false if the client can't work here
+    fn check(&self, config: &AuditConfig) -> bool {
+        let mut usable = true;
+        let mut report = |ok: bool, fatal: bool, what: &str, detail: String| {
+            let status = match (ok, fatal) {
+                (true, _) => "ok",
+                (false, true) => "FAIL",
+                (false, false) => "degraded",
+            };
+            println!("  {status:<9} {what:<22} {detail}");
+            usable &= ok || !fatal;
+        };
+
+        println!("kernel checks:");
+        if let Ok(mode) = std::fs::read_to_string("/proc/self/status")
+            && let Some(line) = mode.lines().find(|l| l.starts_with("Seccomp:"))
+        {
+            let mode = line["Seccomp:".len()..].trim();
+            let detail = match mode {
+                "0" => "no filter".to_owned(),
+                "2" => "filter active; missing syscalls show up below as EPERM".to_owned(),
+                other => format!("mode {other}"),
+            };
+            report(mode != "1", true, "seccomp", detail);
+        }
+        if let Ok(disabled) = std::fs::read_to_string("/proc/sys/kernel/io_uring_disabled") {
+            let disabled = disabled.trim();
+            let detail = match disabled {
+                "0" => "io_uring enabled".to_owned(),
+                "1" => "io_uring limited to kernel.io_uring_group".to_owned(),
+                other => format!("io_uring disabled ({other})"),
+            };
+            // Group membership is checked by the ring setup below
+            report(disabled != "2", true, "io_uring_disabled", detail);
+        }
+
+        let ring = match IoUring::new(8) {
+            Ok(ring) => {
+                report(true, true, "io_uring_setup", String::new());
+                Some(ring)
+            }
+            Err(e) => {
+                report(false, true, "io_uring_setup", e.to_string());
+                None
+            }
+        };
+        if let Some(ring) = ring {
+            let mut probe = Probe::new();
+            match ring.submitter().register_probe(&mut probe) {
+                Ok(()) => {
+                    let mut missing: Vec<&str> = Vec::new();
+                    for op in &self.opcodes {
+                        if !probe.is_supported(op.code) && !missing.contains(&op.name) {
+                            missing.push(op.name);
+                        }
+                    }
+                    report(
+                        missing.is_empty(),
+                        true,
+                        "io_uring opcodes",
+                        if missing.is_empty() {
+                            format!("{} supported", self.opcodes.len())
+                        } else {
+                            format!("unsupported: {}", missing.join(", "))
+                        },
+                    );
+                }
+                Err(e) => report(
+                    false,
+                    false,
+                    "IORING_REGISTER_PROBE",
+                    format!("{e}; opcodes not checked"),
+                ),
+            }
+        }
+
+        if config.ktls {
+            let (ok, detail) = match tls_ulp() {
+                Ok(()) => (true, "tls ULP available".to_owned()),
+                Err(e) => (
+                    false,
+                    format!("{e}; connections fall back to userspace TLS"),
+                ),
+            };
+            report(ok, false, "kTLS", detail);
+        }
+        if config.mptcp {
+            let ok = connect::mptcp_supported();
+            let detail = if ok {
+                "IPPROTO_MPTCP sockets available"
+            } else {
+                "unavailable; connections fall back to TCP"
+            };
+            report(ok, false, "MPTCP", detail.to_owned());
+        }
+        if config.perf_markers {
+            let (ok, detail) = match markers::enable() {
+                Ok(()) => (true, "trace_marker writable".to_owned()),
+                Err(e) => (false, format!("{e}; markers are skipped")),
+            };
+            report(ok, false, "perf markers", detail);
+        }
+        usable
+    }
+}
+
+/// Whether the kernel attaches the TLS ULP, the first step of kTLS setup
+///
+/// The ULP only attaches to connected sockets, so on an unconnected one a
+/// kernel that has it fails with `ENOTCONN`, and one that doesn't with
+/// `ENOENT`. That loads the module on demand, just as the client would.
+fn tls_ulp() -> std::io::Result<()> {
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    let ulp = b"tls";
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_TCP,
+            libc::TCP_ULP,
+            ulp.as_ptr() as *const libc::c_void,
+            ulp.len() as libc::socklen_t,
+        )
+    };
+    let err = std::io::Error::last_os_error();
+    match (ret, err.raw_os_error()) {
+        (0, _) | (_, Some(libc::ENOTCONN)) => Ok(()),
+        _ => Err(err),
+    }
+}
+
+/// Print the audit in the requested form; returns the exit code
+pub fn run(config: &AuditConfig) -> i32 {
+    let audit = Audit::new(config);
+    match config.output {
+        Output::Table => audit.print_table(),
+        Output::Seccomp => println!("{}", audit.seccomp_json()),
+        Output::Restrictions => audit.print_restrictions(),
+        Output::Check => {
+            if !audit.check(config) {
+                return 1;
+            }
+        }
+    }
+    0
+}
@@ -0,0 +1,92 @@
+//! Alerts for connections whose send buffer stays full
+//!
+//! A peer that reads slowly, or a path that has stopped acknowledging,
+//! shows up on the client as a send buffer that fills and stays full: with
+//! kTLS the writes simply stop completing, with nothing in userspace to
+//! say why. With [`RequestOptions::on_backpressure`](crate::RequestOptions)
+//! set, a watcher samples the connection's [`SendQueue`] while it is in use
+//! (for the request, the session, or the WebSocket's lifetime) and calls
+//! the hook once the buffer has been full for longer than the threshold,
+//! then again each time it fills up anew and stays that way.
+
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, BorrowedFd};
+use std::rc::Rc;
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::connect::{self, SendQueue};
+
+/// A send buffer that has stayed full, as reported to the hook
+#[derive(Clone, Copy, Debug)]
+pub struct Backpressure {
+    pub peer: SocketAddr,
+    /// How long the buffer has been full so far
+    pub full_for: Duration,
+    pub queue: SendQueue,
+}
+
+/// When and how to report [`Backpressure`]
+#[derive(Clone)]
+pub struct BackpressureAlert {
+    /// How long the buffer must stay full before the hook is called
+    pub after: Duration,
+    pub hook: Rc<dyn Fn(Backpressure)>,
+}
+
+impl BackpressureAlert {
+    pub fn new(after: Duration, hook: impl Fn(Backpressure) + 'static) -> Self {
+        Self {
+            after,
+            hook: Rc::new(hook),
+        }
+    }
+
+    /// Start watching the socket behind `fd` until the returned guard drops
+    ///
+    /// The watcher keeps a duplicate of the fd, so it never samples a
+    /// socket that was closed and its number reused.
+    pub fn watch(&self, fd: BorrowedFd<'_>, peer: SocketAddr) -> std::io::Result<Watch> {
+        let fd = fd.try_clone_to_owned()?;
+        let alert = self.clone();
+        // A few samples per threshold, so a report is at most a quarter late
+        let period = (alert.after / 4).max(Duration::from_millis(1));
+        let task = tokio_uring::spawn(async move {
+            let mut full_since: Option<Instant> = None;
+            let mut reported = false;
+            let mut ticks = tokio::time::interval(period);
+            loop {
+                ticks.tick().await;
+                let Ok(queue) = connect::send_queue(fd.as_raw_fd()) else {
+                    return;
+                };
+                if !queue.full {
+                    full_since = None;
+                    reported = false;
+                    continue;
+                }
+                let full_for = full_since.get_or_insert_with(Instant::now).elapsed();
+                if full_for >= alert.after && !reported {
+                    reported = true;
+                    (alert.hook)(Backpressure {
+                        peer,
+                        full_for,
+                        queue,
+                    });
+                }
+            }
+        });
+        Ok(Watch(task))
+    }
+}
+
+/// A connection being watched; dropping it stops the watcher
+pub struct Watch(JoinHandle<()>);
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
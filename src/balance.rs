@@ -0,0 +1,184 @@
+//! Spreading connections across a host's resolved addresses
+//!
+//! Without a balancer every connection goes to the first address DNS
+//! returns. With one, each new connection picks among all of them by the
+//! configured [`Strategy`], skipping addresses that recently failed to
+//! connect or handshake [`BalancePolicy::max_failures`] times in a row until
+//! their ban runs out. When every address is banned, the one whose ban ends
+//! first is used anyway rather than failing the request outright.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::rng::{self, Rng};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    /// Each address in turn, per host
+    RoundRobin,
+    /// Uniformly at random
+    Random,
+    /// The address with the fewest connections in use from this client
+    LeastOutstanding,
+}
+
+#[derive(Clone, Debug)]
+pub struct BalancePolicy {
+    pub strategy: Strategy,
+    /// Consecutive connect or handshake failures that get an address banned
+    pub max_failures: u32,
+    /// How long a banned address is skipped
+    pub ban: Duration,
+}
+
+impl Default for BalancePolicy {
+    fn default() -> Self {
+        Self {
+            strategy: Strategy::RoundRobin,
+            max_failures: 3,
+            ban: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Endpoint {
+    outstanding: u32,
+    failures: u32,
+    banned_until: Option<Instant>,
+}
+
+impl Endpoint {
+    fn banned(&self, now: Instant) -> bool {
+        self.banned_until.is_some_and(|until| until > now)
+    }
+}
+
+#[derive(Default)]
+struct State {
+    endpoints: HashMap<SocketAddr, Endpoint>,
+    /// Round-robin position per host
+    cursors: HashMap<String, usize>,
+}
+
+/// Per-address bookkeeping for one client
+pub struct Balancer {
+    policy: BalancePolicy,
+    state: RefCell<State>,
+}
+
+impl Balancer {
+    pub fn new(policy: BalancePolicy) -> Self {
+        Self {
+            policy,
+            state: RefCell::new(State::default()),
+        }
+    }
+
+    /// Choose one of `host`'s `addrs` for a new connection, drawing from
+    /// `rng` for a random pick
+    pub fn pick(&self, host: &str, addrs: &[SocketAddr], rng: &dyn Rng) -> Lease<'_> {
+        let now = Instant::now();
+        let mut state = self.state.borrow_mut();
+        let State { endpoints, cursors } = &mut *state;
+
+        let mut candidates: Vec<SocketAddr> = addrs
+            .iter()
+            .copied()
+            .filter(|addr| !endpoints.get(addr).is_some_and(|e| e.banned(now)))
+            .collect();
+        if candidates.is_empty() {
+            let soonest = addrs
+                .iter()
+                .min_by_key(|addr| endpoints.get(addr).and_then(|e| e.banned_until));
+            candidates.extend(soonest);
+        }
+
+        let addr = match self.policy.strategy {
+            Strategy::RoundRobin => {
+                let cursor = cursors.entry(host.to_owned()).or_default();
+                let addr = candidates[*cursor % candidates.len()];
+                *cursor = cursor.wrapping_add(1);
+                addr
+            }
+            Strategy::Random => candidates[random_index(candidates.len(), rng)],
+            Strategy::LeastOutstanding => *candidates
+                .iter()
+                .min_by_key(|addr| endpoints.get(addr).map_or(0, |e| e.outstanding))
+                .expect("at least one address"),
+        };
+        endpoints.entry(addr).or_default().outstanding += 1;
+        Lease {
+            balancer: Some(self),
+            addr,
+            connected: false,
+        }
+    }
+}
+
+/// The address chosen for one connection, held for as long as the
+/// connection is in use
+///
+/// Dropping a lease that was never marked [`connected`](Self::connected)
+/// counts as a failure against its address.
+pub struct Lease<'b> {
+    balancer: Option<&'b Balancer>,
+    pub addr: SocketAddr,
+    connected: bool,
+}
+
+impl Lease<'_> {
+    /// A lease on `addr` that nothing keeps track of
+    pub fn unbalanced(addr: SocketAddr) -> Self {
+        Self {
+            balancer: None,
+            addr,
+            connected: false,
+        }
+    }
+
+    /// Record that the connection and its handshake succeeded
+    pub fn connected(&mut self) {
+        self.connected = true;
+        if let Some(balancer) = self.balancer {
+            let mut state = balancer.state.borrow_mut();
+            if let Some(endpoint) = state.endpoints.get_mut(&self.addr) {
+                endpoint.failures = 0;
+            }
+        }
+    }
+}
+
+impl Drop for Lease<'_> {
+    fn drop(&mut self) {
+        let Some(balancer) = self.balancer else {
+            return;
+        };
+        let mut state = balancer.state.borrow_mut();
+        let Some(endpoint) = state.endpoints.get_mut(&self.addr) else {
+            return;
+        };
+        endpoint.outstanding = endpoint.outstanding.saturating_sub(1);
+        if self.connected {
+            return;
+        }
+        endpoint.failures += 1;
+        if endpoint.failures >= balancer.policy.max_failures {
+            eprintln!(
+                "{} failed {} times in a row, avoiding it for {:?}",
+                self.addr, endpoint.failures, balancer.policy.ban
+            );
+            endpoint.failures = 0;
+            endpoint.banned_until = Some(Instant::now() + balancer.policy.ban);
+        }
+    }
+}
+
+/// An index below `len`, from `rng`
+fn random_index(len: usize, rng: &dyn Rng) -> usize {
+    // Falls back to the first address if the RNG fails
+    let n = rng::bytes(rng).map_or(0, u64::from_ne_bytes);
+    (n % len as u64) as usize
+}
@@ -0,0 +1,488 @@
+//! `bench` subcommand: kTLS vs userspace TLS throughput and latency
+//!
+//! Runs the same upload, download or WebSocket echo workload twice, once
+//! through the kTLS path and once with kTLS disabled, and prints latency
+//! percentiles, throughput, process CPU usage, per-request syscall counts
+//! and how many connections actually ended up on kTLS side by side, as a
+//! table or, with `--json`, as one JSON object for scripts to compare runs.
+//! Every HTTP request opens its own connection, so latencies include connect
+//! and handshake. The echo workload opens one WebSocket per mode and times
+//! each message's round trip over it, the upgrade left out.
+//!
+//! `--perf-markers` marks each request's phases for perf; see
+//! [`markers`](ktls_uring_demo::markers).
+
+use std::time::{Duration, Instant};
+
+use ktls_uring_demo::balance::{BalancePolicy, Strategy};
+use ktls_uring_demo::buffers::BufferPoolConfig;
+use ktls_uring_demo::http::Body;
+use ktls_uring_demo::markers;
+use ktls_uring_demo::qos::TrafficClass;
+use ktls_uring_demo::stats::{self, SyscallCounts};
+use ktls_uring_demo::websocket::{Message, WssClient};
+use ktls_uring_demo::{HttpsClient, RequestOptions};
+
+pub const USAGE: &str = "\
+usage: ktls-uring-demo bench [--download | --upload | --echo] [--host HOST] [--path PATH]
+                             [--size BYTES] [--requests N] [--pin CPUS] [--buffers]
+                             [--numa-local] [--huge-pages] [--provided-buffers]
+                             [--dscp CODEPOINT] [--gzip] [--balance STRATEGY]
+                             [--json] [--perf-markers]
+
+  --download      GET PATH and measure the response body (default)
+  --upload        POST a JSON body of roughly BYTES to PATH
+  --echo          send binary WebSocket messages of BYTES to PATH and time
+                  each one's echo
+  --host HOST     target host, port 443 (default: httpbin.org, or
+                  echo.websocket.org for --echo)
+  --path PATH     default: /bytes/BYTES for downloads, /post for uploads,
+                  / for echo
+  --size BYTES    payload size per request or message (default: 65536)
+  --requests N    requests or messages per mode (default: 20)
+  --pin CPUS      pin the runtime thread to a comma-separated CPU list
+  --buffers       read kTLS responses into registered (fixed) buffers
+  --numa-local    allocate those buffers on the runtime thread's NUMA node
+  --huge-pages    back those buffers with 2 MiB pages
+  --provided-buffers
+                  let the kernel pick receive buffers from a registered ring
+  --dscp CODEPOINT
+                  mark both modes' connections with a DSCP value (0-63)
+  --gzip          gzip upload bodies (Content-Encoding: gzip)
+  --balance STRATEGY
+                  spread connections over all of HOST's addresses:
+                  round-robin, random or least-outstanding
+  --json          print the results as JSON on stdout
+  --perf-markers  write phase markers to ftrace's trace_marker for perf
+                  (needs tracefs write access, usually root)";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Workload {
+    Download,
+    Upload,
+    Echo,
+}
+
+pub struct BenchConfig {
+    workload: Workload,
+    host: Option<String>,
+    path: Option<String>,
+    size: usize,
+    requests: usize,
+    /// CPUs to pin the runtime thread to before it starts
+    pub pin: Vec<usize>,
+    buffers: Option<BufferPoolConfig>,
+    provided_buffers: bool,
+    traffic_class: Option<TrafficClass>,
+    gzip: bool,
+    balance: Option<Strategy>,
+    json: bool,
+    perf_markers: bool,
+}
+
+impl BenchConfig {
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let mut config = Self {
+            workload: Workload::Download,
+            host: None,
+            path: None,
+            size: 64 * 1024,
+            requests: 20,
+            pin: Vec::new(),
+            buffers: None,
+            provided_buffers: false,
+            traffic_class: None,
+            gzip: false,
+            balance: None,
+            json: false,
+            perf_markers: false,
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .cloned()
+                    .ok_or_else(|| format!("{arg} needs a value"))
+            };
+            match arg.as_str() {
+                "--download" => config.workload = Workload::Download,
+                "--upload" => config.workload = Workload::Upload,
+                "--echo" => config.workload = Workload::Echo,
+                "--host" => config.host = Some(value()?),
+                "--path" => config.path = Some(value()?),
+                "--size" => config.size = parse_number(arg, &value()?)?,
+                "--requests" => config.requests = parse_number(arg, &value()?)?.max(1),
+                "--pin" => {
+                    config.pin = value()?
+                        .split(',')
+                        .map(|cpu| parse_number(arg, cpu.trim()))
+                        .collect::<Result<_, _>>()?;
+                }
+                "--buffers" => {
+                    config.buffers.get_or_insert_with(Default::default);
+                }
+                "--numa-local" => {
+                    config
+                        .buffers
+                        .get_or_insert_with(Default::default)
+                        .numa_local = true;
+                }
+                "--provided-buffers" => config.provided_buffers = true,
+                "--gzip" => config.gzip = true,
+                "--json" => config.json = true,
+                "--perf-markers" => config.perf_markers = true,
+                "--dscp" => {
+                    let dscp = parse_number(arg, &value()?)?;
+                    let dscp = u8::try_from(dscp)
+                        .ok()
+                        .filter(|&d| d <= 63)
+                        .ok_or_else(|| format!("{arg} must be between 0 and 63"))?;
+                    config.traffic_class = Some(TrafficClass {
+                        dscp: Some(dscp),
+                        ..Default::default()
+                    });
+                }
+                "--balance" => {
+                    config.balance = Some(match value()?.as_str() {
+                        "round-robin" => Strategy::RoundRobin,
+                        "random" => Strategy::Random,
+                        "least-outstanding" => Strategy::LeastOutstanding,
+                        other => return Err(format!("unknown {arg} strategy: {other}")),
+                    });
+                }
+                "--huge-pages" => {
+                    config
+                        .buffers
+                        .get_or_insert_with(Default::default)
+                        .huge_pages = true;
+                }
+                other => return Err(format!("unknown argument: {other}")),
+            }
+        }
+        Ok(config)
+    }
+
+    fn host(&self) -> &str {
+        match (&self.host, self.workload) {
+            (Some(host), _) => host,
+            (None, Workload::Echo) => "echo.websocket.org",
+            (None, _) => "httpbin.org",
+        }
+    }
+
+    fn path(&self) -> String {
+        match (&self.path, self.workload) {
+            (Some(path), _) => path.clone(),
+            (None, Workload::Download) => format!("/bytes/{}", self.size),
+            (None, Workload::Upload) => "/post".to_owned(),
+            (None, Workload::Echo) => "/".to_owned(),
+        }
+    }
+}
+
+fn parse_number(arg: &str, value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{arg} expects a number, got {value:?}"))
+}
+
+struct ModeResult {
+    label: &'static str,
+    /// Sorted ascending
+    latencies: Vec<Duration>,
+    bytes: u64,
+    wall: Duration,
+    cpu: Duration,
+    syscalls: SyscallCounts,
+    /// Connections set up with kTLS, and ones that fell back to userspace TLS
+    ktls: usize,
+    fallback: usize,
+}
+
+impl ModeResult {
+    fn per_request(&self, count: u64) -> f64 {
+        count as f64 / self.latencies.len() as f64
+    }
+
+    fn percentile(&self, p: f64) -> Duration {
+        let idx = ((self.latencies.len() - 1) as f64 * p).round() as usize;
+        self.latencies[idx]
+    }
+
+    fn throughput(&self) -> f64 {
+        self.bytes as f64 / self.wall.as_secs_f64() / 1e6
+    }
+
+    fn cpu_percent(&self) -> f64 {
+        self.cpu.as_secs_f64() / self.wall.as_secs_f64() * 100.0
+    }
+
+    fn to_json(&self) -> String {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        let mean = self.latencies.iter().sum::<Duration>() / self.latencies.len() as u32;
+        format!(
+            "{{\"mode\":{},\"requests\":{},\"bytes\":{},\"wall_s\":{:.6},\
+             \"latency_ms\":{{\"min\":{:.3},\"mean\":{:.3},\"p50\":{:.3},\"p90\":{:.3},\
+             \"p99\":{:.3},\"max\":{:.3}}},\
+             \"throughput_mb_s\":{:.3},\"cpu_percent\":{:.1},\
+             \"syscalls\":{{\"uring_submissions\":{},\"uring_completions\":{},\"direct\":{},\
+             \"uring_per_request\":{:.2},\"direct_per_request\":{:.2}}},\
+             \"connections\":{{\"ktls\":{},\"fallback\":{}}}}}",
+            json_string(self.label),
+            self.latencies.len(),
+            self.bytes,
+            self.wall.as_secs_f64(),
+            ms(self.latencies[0]),
+            ms(mean),
+            ms(self.percentile(0.50)),
+            ms(self.percentile(0.90)),
+            ms(self.percentile(0.99)),
+            ms(self.latencies[self.latencies.len() - 1]),
+            self.throughput(),
+            self.cpu_percent(),
+            self.syscalls.uring_submissions,
+            self.syscalls.uring_completions,
+            self.syscalls.syscalls,
+            self.per_request(self.syscalls.uring_submissions),
+            self.per_request(self.syscalls.syscalls),
+            self.ktls,
+            self.fallback,
+        )
+    }
+}
+
+/// `s` as a JSON string literal
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c < ' ' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+pub async fn run(config: &BenchConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let mut ktls = HttpsClient::new().with_verbose(false);
+    if let Some(buffers) = &config.buffers {
+        ktls = ktls.with_buffer_pool(buffers)?;
+        if let Some(pool) = ktls.buffer_pool() {
+            eprintln!("registered buffers: {:?} pages", pool.backing());
+        }
+    }
+    if config.provided_buffers {
+        ktls = ktls.with_provided_buffers(64, 16 * 1024)?;
+    }
+    let mut userspace = HttpsClient::new().with_verbose(false).with_ktls(false);
+    if config.gzip {
+        ktls = ktls.with_request_compression(0);
+        userspace = userspace.with_request_compression(0);
+    }
+    if let Some(class) = config.traffic_class {
+        ktls = ktls.with_traffic_class(class);
+        userspace = userspace.with_traffic_class(class);
+    }
+    if let Some(strategy) = config.balance {
+        let policy = BalancePolicy {
+            strategy,
+            ..Default::default()
+        };
+        ktls = ktls.with_load_balancing(policy.clone());
+        userspace = userspace.with_load_balancing(policy);
+    }
+    if config.perf_markers {
+        markers::enable().map_err(|e| format!("can't open trace_marker: {e}"))?;
+    }
+    stats::set_enabled(true);
+
+    let results = [
+        run_mode("kTLS", &ktls, config).await?,
+        run_mode("userspace", &userspace, config).await?,
+    ];
+
+    let workload = match config.workload {
+        Workload::Download => "download",
+        Workload::Upload => "upload",
+        Workload::Echo => "echo",
+    };
+    if config.json {
+        let modes: Vec<String> = results.iter().map(ModeResult::to_json).collect();
+        println!(
+            "{{\"workload\":{},\"host\":{},\"path\":{},\"size\":{},\"requests\":{},\"modes\":[{}]}}",
+            json_string(workload),
+            json_string(config.host()),
+            json_string(&config.path()),
+            config.size,
+            config.requests,
+            modes.join(",")
+        );
+        return Ok(());
+    }
+    println!(
+        "\n{workload} {} bytes x {} requests against {}{}\n",
+        config.size,
+        config.requests,
+        config.host(),
+        config.path()
+    );
+    println!(
+        "{:<10} {:>9} {:>9} {:>9} {:>10} {:>7} {:>10} {:>10} {:>9}",
+        "mode", "p50 ms", "p90 ms", "p99 ms", "MB/s", "cpu %", "uring/req", "sys/req", "kTLS/fb"
+    );
+    for r in &results {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        println!(
+            "{:<10} {:>9.2} {:>9.2} {:>9.2} {:>10.2} {:>7.1} {:>10.1} {:>10.1} {:>9}",
+            r.label,
+            ms(r.percentile(0.50)),
+            ms(r.percentile(0.90)),
+            ms(r.percentile(0.99)),
+            r.throughput(),
+            r.cpu_percent(),
+            r.per_request(r.syscalls.uring_submissions),
+            r.per_request(r.syscalls.syscalls),
+            format!("{}/{}", r.ktls, r.fallback),
+        );
+    }
+    Ok(())
+}
+
+async fn run_mode(
+    label: &'static str,
+    client: &HttpsClient,
+    config: &BenchConfig,
+) -> Result<ModeResult, Box<dyn std::error::Error>> {
+    if config.workload == Workload::Echo {
+        return run_echo(label, client, config).await;
+    }
+    let host = config.host();
+    let path = config.path();
+    let payload = format!(r#"{{"data":"{}"}}"#, "x".repeat(config.size));
+    let options = RequestOptions {
+        bypass_cache: true,
+        ..Default::default()
+    };
+
+    let mut latencies = Vec::with_capacity(config.requests);
+    let mut bytes = 0u64;
+    let (mut ktls, mut fallback) = (0, 0);
+    let cpu_start = cpu_time();
+    let syscalls_start = stats::snapshot();
+    let start = Instant::now();
+
+    for _ in 0..config.requests {
+        let t = Instant::now();
+        let response = match config.workload {
+            Workload::Download => {
+                let r = client
+                    .request("GET", host, &path, Body::Empty, &options)
+                    .await?;
+                bytes += r.body.len() as u64;
+                r
+            }
+            Workload::Upload => {
+                let r = client
+                    .request("POST", host, &path, Body::Json(&payload), &options)
+                    .await?;
+                bytes += payload.len() as u64;
+                r
+            }
+            Workload::Echo => unreachable!("echo runs over a WebSocket"),
+        };
+        latencies.push(t.elapsed());
+        match response.connection {
+            Some(connection) if connection.ktls => ktls += 1,
+            _ => fallback += 1,
+        }
+
+        if !(200..300).contains(&response.status) {
+            return Err(format!("{label}: server answered {}", response.status).into());
+        }
+    }
+
+    let wall = start.elapsed();
+    let cpu = cpu_time().saturating_sub(cpu_start);
+    let syscalls = stats::snapshot() - syscalls_start;
+    latencies.sort();
+
+    Ok(ModeResult {
+        label,
+        latencies,
+        bytes,
+        wall,
+        cpu,
+        syscalls,
+        ktls,
+        fallback,
+    })
+}
+
+/// The echo workload: `requests` binary messages of `size` bytes over one
+/// WebSocket, each sent once the last one's echo is back
+///
+/// Anything else the server sends, such as a greeting, is skipped.
+async fn run_echo(
+    label: &'static str,
+    client: &HttpsClient,
+    config: &BenchConfig,
+) -> Result<ModeResult, Box<dyn std::error::Error>> {
+    let options = RequestOptions::default();
+    let mut ws = WssClient::connect(client, config.host(), &config.path(), &options)
+        .await?
+        .with_auto_pong(true);
+    let ktls = usize::from(ws.connection().ktls);
+    let payload = vec![b'x'; config.size];
+
+    let mut latencies = Vec::with_capacity(config.requests);
+    let cpu_start = cpu_time();
+    let syscalls_start = stats::snapshot();
+    let start = Instant::now();
+
+    for _ in 0..config.requests {
+        let t = Instant::now();
+        ws.send(Message::Binary(payload.clone())).await?;
+        loop {
+            match ws.receive().await? {
+                Message::Binary(echo) if echo == payload => break,
+                Message::Close(_) => return Err(format!("{label}: server closed").into()),
+                _ => {}
+            }
+        }
+        latencies.push(t.elapsed());
+    }
+
+    let wall = start.elapsed();
+    let cpu = cpu_time().saturating_sub(cpu_start);
+    let syscalls = stats::snapshot() - syscalls_start;
+    latencies.sort();
+    ws.close(1000, "bench finished").await?;
+
+    Ok(ModeResult {
+        label,
+        bytes: (config.size * latencies.len()) as u64,
+        latencies,
+        wall,
+        cpu,
+        syscalls,
+        ktls,
+        fallback: 1 - ktls,
+    })
+}
+
+/// User + system CPU time consumed by this process so far
+fn cpu_time() -> Duration {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    let ret = unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+    if ret < 0 {
+        return Duration::ZERO;
+    }
+
+    let tv = |t: libc::timeval| Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000);
+    tv(usage.ru_utime) + tv(usage.ru_stime)
+}
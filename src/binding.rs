@@ -0,0 +1,200 @@
+//! TLS channel binding: `tls-exporter` (RFC 9266) and
+//! `tls-server-end-point` (RFC 5929)
+//!
+//! Authentication that runs inside the TLS connection, such as SCRAM with
+//! `-PLUS` mechanisms in PostgreSQL or LDAP SASL binds, can tie itself to
+//! that connection so credentials exchanged over one can't be relayed over
+//! another. `tls-exporter` binding data comes from the TLS exporter, and so
+//! has to be asked for before the handshake: put [`ChannelBinding::exporter`]
+//! in the options' exporters, then read the binding from the session or
+//! WebSocket with [`ChannelBinding::from_material`].
+//!
+//! RFC 9266 defines `tls-exporter` for TLS 1.3. Over TLS 1.2 the exporter
+//! is only unique to the connection with the extended master secret,
+//! which rustls negotiates whenever the server offers it.
+//!
+//! PostgreSQL only binds to `tls-server-end-point`, a hash of the server's
+//! certificate, which [`ChannelBinding::server_end_point`] computes from
+//! the certificate kept with the keying material. It proves less: the same
+//! certificate may serve other connections.
+
+use aws_lc_rs::digest;
+
+use crate::handshake::{Exporter, KeyingMaterial};
+use crate::wsproto::base64;
+
+/// The exporter label RFC 9266 registers
+const LABEL: &[u8] = b"EXPORTER-Channel-Binding";
+/// Bytes of binding data RFC 9266 asks for
+const LEN: usize = 32;
+
+/// The kinds of channel binding data
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BindingType {
+    /// From the TLS exporter
+    TlsExporter,
+    /// A hash of the server's certificate
+    TlsServerEndPoint,
+}
+
+impl BindingType {
+    /// The type's name, as SASL mechanisms negotiate it
+    pub fn name(self) -> &'static str {
+        match self {
+            BindingType::TlsExporter => "tls-exporter",
+            BindingType::TlsServerEndPoint => "tls-server-end-point",
+        }
+    }
+}
+
+/// Channel binding data for one connection
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelBinding {
+    kind: BindingType,
+    data: Vec<u8>,
+}
+
+impl ChannelBinding {
+    /// What to ask the handshake for
+    pub fn exporter() -> Exporter {
+        Exporter {
+            label: LABEL.to_vec(),
+            context: None,
+            len: LEN,
+        }
+    }
+
+    /// The `tls-exporter` binding among material exported for
+    /// [`exporter`](Self::exporter); `None` if it wasn't asked for
+    pub fn from_material(material: &KeyingMaterial) -> Option<Self> {
+        material.get(&Self::exporter()).map(|data| Self {
+            kind: BindingType::TlsExporter,
+            data: data.to_vec(),
+        })
+    }
+
+    /// The `tls-server-end-point` binding for the server's certificate;
+    /// `None` if it presented none
+    ///
+    /// The certificate is hashed with its signature's hash, or SHA-256
+    /// where that is weaker or has no name this knows.
+    pub fn server_end_point(material: &KeyingMaterial) -> Option<Self> {
+        let cert = material.server_certificate()?;
+        let algorithm = match signature_algorithm(cert) {
+            Some(SHA384_WITH_RSA | ECDSA_WITH_SHA384) => &digest::SHA384,
+            Some(SHA512_WITH_RSA | ECDSA_WITH_SHA512) => &digest::SHA512,
+            _ => &digest::SHA256,
+        };
+        Some(Self {
+            kind: BindingType::TlsServerEndPoint,
+            data: digest::digest(algorithm, cert).as_ref().to_vec(),
+        })
+    }
+
+    /// The raw binding data
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The GS2 header a SCRAM client sends when it binds to the channel
+    /// (RFC 5802 section 7), authorizing as `authzid` if given
+    pub fn gs2_header(&self, authzid: Option<&str>) -> String {
+        let name = self.kind.name();
+        match authzid {
+            Some(authzid) => format!("p={name},a={},", saslname(authzid)),
+            None => format!("p={name},,"),
+        }
+    }
+
+    /// The value of SCRAM's `c=` attribute in the client's final message:
+    /// the GS2 header and the binding data, base64 encoded
+    pub fn scram_attribute(&self, authzid: Option<&str>) -> String {
+        let mut input = self.gs2_header(authzid).into_bytes();
+        input.extend_from_slice(&self.data);
+        base64(&input)
+    }
+}
+
+/// `name` with `=` and `,` escaped, as SCRAM's saslname requires
+fn saslname(name: &str) -> String {
+    name.replace('=', "=3D").replace(',', "=2C")
+}
+
+/// DER of the signature algorithms' OIDs that hash with more than SHA-256
+const SHA384_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0c];
+const SHA512_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0d];
+const ECDSA_WITH_SHA384: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03];
+const ECDSA_WITH_SHA512: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x04];
+
+/// The OID of the algorithm `cert` was signed with: the first field of
+/// the certificate's second element, past the signed TBSCertificate
+fn signature_algorithm(cert: &[u8]) -> Option<&[u8]> {
+    const SEQUENCE: u8 = 0x30;
+    const OID: u8 = 0x06;
+    let (SEQUENCE, cert, _) = der_element(cert)? else {
+        return None;
+    };
+    let (SEQUENCE, _, rest) = der_element(cert)? else {
+        return None;
+    };
+    let (SEQUENCE, algorithm, _) = der_element(rest)? else {
+        return None;
+    };
+    match der_element(algorithm)? {
+        (OID, oid, _) => Some(oid),
+        _ => None,
+    }
+}
+
+/// The tag and contents of the DER element `input` starts with, and what
+/// follows it
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&first, input) = input.split_first()?;
+    let (len, input) = match first {
+        0..=0x7f => (usize::from(first), input),
+        0x81..=0x84 => {
+            let (len, input) = input.split_at_checked(usize::from(first & 0x7f))?;
+            let len = len.iter().fold(0, |n, &b| n << 8 | usize::from(b));
+            (len, input)
+        }
+        _ => return None,
+    };
+    let (contents, rest) = input.split_at_checked(len)?;
+    Some((tag, contents, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scram_attribute_covers_header_and_data() {
+        let binding = ChannelBinding {
+            kind: BindingType::TlsExporter,
+            data: vec![0u8; LEN],
+        };
+        assert_eq!(binding.gs2_header(None), "p=tls-exporter,,");
+        assert_eq!(
+            binding.gs2_header(Some("a=b,c")),
+            "p=tls-exporter,a=a=3Db=2Cc,"
+        );
+
+        let mut expected = b"p=tls-exporter,,".to_vec();
+        expected.extend_from_slice(&[0u8; LEN]);
+        assert_eq!(binding.scram_attribute(None), base64(&expected));
+    }
+
+    #[test]
+    fn finds_the_signature_algorithm() {
+        // Certificate { TBSCertificate, AlgorithmIdentifier, BIT STRING },
+        // with the TBSCertificate's contents elided
+        let mut cert = vec![
+            0x30, 0x81, 0x17, 0x30, 0x02, 0x05, 0x00, 0x30, 0x0c, 0x06, 0x08,
+        ];
+        cert.extend_from_slice(ECDSA_WITH_SHA384);
+        cert.extend_from_slice(&[0x05, 0x00, 0x03, 0x03, 0x00, 0x01, 0x02]);
+        assert_eq!(signature_algorithm(&cert), Some(ECDSA_WITH_SHA384));
+        assert_eq!(signature_algorithm(&cert[..12]), None);
+    }
+}
@@ -0,0 +1,189 @@
+//! Failing fast on hosts that keep failing
+//!
+//! Each host has a circuit. It starts closed and lets every request through.
+//! After [`BreakerPolicy::failure_threshold`] consecutive failures it opens:
+//! requests to the host then fail at once with [`CircuitOpen`] instead of
+//! each spending a connect and a handshake on the runtime thread. Once the
+//! cooldown has passed the circuit half-opens and lets a single trial
+//! request through. A trial that succeeds closes the circuit; one that fails
+//! opens it for another cooldown.
+//!
+//! Failures are connects and handshakes that fail or run out of time, and
+//! 5xx responses. A request that fails later on, or is cancelled, counts
+//! neither way.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug)]
+pub struct BreakerPolicy {
+    /// Consecutive failures that open a host's circuit
+    pub failure_threshold: u32,
+    /// How long an open circuit fails requests before letting a trial through
+    pub cooldown: Duration,
+}
+
+impl Default for BreakerPolicy {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+enum Circuit {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// The cooldown is over; `trial` while the one request let through is
+    /// still in flight
+    HalfOpen {
+        trial: bool,
+    },
+}
+
+/// A host's circuit is open
+#[derive(Debug)]
+pub struct CircuitOpen {
+    pub host: String,
+    /// Until the circuit half-opens; zero while a trial request is in flight
+    pub retry_in: Duration,
+}
+
+impl std::fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.retry_in.is_zero() {
+            write!(
+                f,
+                "Circuit for {} is open, a trial request is in flight",
+                self.host
+            )
+        } else {
+            write!(
+                f,
+                "Circuit for {} is open for another {:.1}s",
+                self.host,
+                self.retry_in.as_secs_f64()
+            )
+        }
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+/// Per-host circuits for one client
+pub struct CircuitBreaker {
+    policy: BreakerPolicy,
+    circuits: RefCell<HashMap<String, Circuit>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(policy: BreakerPolicy) -> Self {
+        Self {
+            policy,
+            circuits: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Let a request to `host` through, unless its circuit is open
+    pub fn admit(&self, host: &str) -> Result<Admission<'_>, CircuitOpen> {
+        let now = Instant::now();
+        let mut circuits = self.circuits.borrow_mut();
+        let circuit = circuits
+            .entry(host.to_owned())
+            .or_insert(Circuit::Closed { failures: 0 });
+
+        let trial = match *circuit {
+            Circuit::Closed { .. } => false,
+            Circuit::Open { until } if until > now => {
+                return Err(CircuitOpen {
+                    host: host.to_owned(),
+                    retry_in: until - now,
+                });
+            }
+            Circuit::Open { .. } | Circuit::HalfOpen { trial: false } => {
+                *circuit = Circuit::HalfOpen { trial: true };
+                true
+            }
+            Circuit::HalfOpen { trial: true } => {
+                return Err(CircuitOpen {
+                    host: host.to_owned(),
+                    retry_in: Duration::ZERO,
+                });
+            }
+        };
+        Ok(Admission {
+            breaker: self,
+            host: host.to_owned(),
+            trial,
+            outcome: None,
+        })
+    }
+
+    fn settle(&self, host: &str, trial: bool, outcome: Option<bool>) {
+        let mut circuits = self.circuits.borrow_mut();
+        let Some(circuit) = circuits.get_mut(host) else {
+            return;
+        };
+        match (outcome, &mut *circuit) {
+            (None, Circuit::HalfOpen { trial: in_flight }) if trial => *in_flight = false,
+            (None, _) => {}
+            (Some(true), _) => *circuit = Circuit::Closed { failures: 0 },
+            (Some(false), Circuit::Closed { failures }) if !trial => {
+                *failures += 1;
+                if *failures >= self.policy.failure_threshold {
+                    eprintln!(
+                        "{host} failed {failures} times in a row, failing its requests for {:?}",
+                        self.policy.cooldown
+                    );
+                    *circuit = Circuit::Open {
+                        until: Instant::now() + self.policy.cooldown,
+                    };
+                }
+            }
+            (Some(false), Circuit::HalfOpen { .. }) if trial => {
+                eprintln!(
+                    "Trial request to {host} failed, failing its requests for another {:?}",
+                    self.policy.cooldown
+                );
+                *circuit = Circuit::Open {
+                    until: Instant::now() + self.policy.cooldown,
+                };
+            }
+            // A request let through before the circuit opened
+            (Some(false), _) => {}
+        }
+    }
+}
+
+/// A request let through a circuit, to be reported as a success or failure
+///
+/// Dropping it unreported counts neither way, and lets another trial
+/// through if it was one.
+pub struct Admission<'b> {
+    breaker: &'b CircuitBreaker,
+    host: String,
+    trial: bool,
+    outcome: Option<bool>,
+}
+
+impl Admission<'_> {
+    pub fn succeeded(mut self) {
+        self.outcome = Some(true);
+    }
+
+    pub fn failed(mut self) {
+        self.outcome = Some(false);
+    }
+}
+
+impl Drop for Admission<'_> {
+    fn drop(&mut self) {
+        self.breaker.settle(&self.host, self.trial, self.outcome);
+    }
+}
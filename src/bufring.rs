@@ -0,0 +1,442 @@
+//! Provided-buffer ring receive path (`IORING_REGISTER_PBUF_RING`)
+//!
+//! Instead of handing the kernel a buffer with every read, a ring of buffers
+//! is registered once and each RECV is submitted with `IOSQE_BUFFER_SELECT`:
+//! the kernel picks a free buffer when data arrives and reports its id in
+//! the completion. A buffer goes back on the ring as soon as its bytes have
+//! been copied out, so many reads can be in flight against a fixed amount
+//! of memory.
+//!
+//! tokio-uring 0.5 exposes neither buffer rings nor raw SQE submission, so
+//! these reads run on a small dedicated ring. Its completions are signalled
+//! through an eventfd that the tokio reactor polls, which keeps everything
+//! on the runtime thread without blocking it.
+//!
+//! Owning the SQEs also allows per-operation timeouts: an operation can be
+//! linked to an `IORING_OP_LINK_TIMEOUT`, so a hung read or write is
+//! cancelled by the kernel rather than abandoned, and completes with
+//! `-ECANCELED`. A future dropped before its completion (for example by a
+//! request context firing) submits an `IORING_OP_ASYNC_CANCEL`; any buffer
+//! the operation uses stays with the ring until its completion arrives.
+//!
+//! The ring also runs READVs that split a response between a small head
+//! buffer and the body's own, see [`RecvRing::recv_split`].
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::Duration;
+
+use io_uring::{IoUring, cqueue, opcode, squeue, types};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::Notify;
+
+use crate::buffers::Region;
+use crate::stats;
+
+const BUFFER_GROUP: u16 = 0;
+/// `user_data` of linked timeouts and cancellations, whose completions are ignored
+const INTERNAL: u64 = u64::MAX;
+
+/// Memory an operation hands the kernel, held until its completion
+enum InFlight {
+    Send(Vec<u8>),
+    Readv {
+        head: Vec<u8>,
+        body: Vec<u8>,
+        _iovecs: Box<[libc::iovec; 2]>,
+    },
+}
+
+pub struct RecvRing {
+    ring: RefCell<IoUring>,
+    eventfd: AsyncFd<OwnedFd>,
+    /// `BufRingEntry` array shared with the kernel
+    entries: Region,
+    buffers: Region,
+    count: u16,
+    size: usize,
+    tail: Cell<u16>,
+    next_token: Cell<u64>,
+    /// Tokens whose futures are still waiting for their completion
+    waiting: RefCell<HashSet<u64>>,
+    completed: RefCell<HashMap<u64, (i32, u32)>>,
+    /// Buffers the kernel may still be using, by token
+    in_flight: RefCell<HashMap<u64, InFlight>>,
+    /// Woken whenever completions are moved into `completed`
+    reaped: Notify,
+}
+
+impl RecvRing {
+    /// Register `count` buffers of `size` bytes; `count` must be a power of two
+    pub fn new(count: u16, size: usize) -> std::io::Result<Self> {
+        if !count.is_power_of_two() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "provided buffer count must be a power of two",
+            ));
+        }
+
+        let ring = IoUring::new(u32::from(count).max(8))?;
+
+        let efd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if efd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let efd = unsafe { OwnedFd::from_raw_fd(efd) };
+        ring.submitter().register_eventfd(efd.as_raw_fd())?;
+
+        let entries = Region::map(count as usize * std::mem::size_of::<types::BufRingEntry>())?;
+        let buffers = Region::map(count as usize * size)?;
+        unsafe {
+            ring.submitter()
+                .register_buf_ring(entries.as_ptr() as u64, count, BUFFER_GROUP)?;
+        }
+
+        let recv_ring = Self {
+            ring: RefCell::new(ring),
+            eventfd: AsyncFd::new(efd)?,
+            entries,
+            buffers,
+            count,
+            size,
+            tail: Cell::new(0),
+            next_token: Cell::new(0),
+            waiting: RefCell::new(HashSet::new()),
+            completed: RefCell::new(HashMap::new()),
+            in_flight: RefCell::new(HashMap::new()),
+            reaped: Notify::new(),
+        };
+        for bid in 0..count {
+            recv_ring.recycle(bid);
+        }
+        Ok(recv_ring)
+    }
+
+    /// One RECV on `fd` into a kernel-selected buffer, appended to `out`,
+    /// cancelled by the kernel if it takes longer than `timeout`
+    pub async fn recv(
+        &self,
+        fd: RawFd,
+        out: &mut Vec<u8>,
+        timeout: Option<Duration>,
+    ) -> std::io::Result<usize> {
+        loop {
+            let token = self.next_token();
+            let sqe = opcode::Recv::new(types::Fd(fd), std::ptr::null_mut(), self.size as u32)
+                .buf_group(BUFFER_GROUP)
+                .build()
+                .flags(squeue::Flags::BUFFER_SELECT);
+            let (res, flags) = self.submit_and_wait(token, sqe, timeout).await?;
+
+            if res == -libc::ENOBUFS {
+                // Every buffer is held by another in-flight read; let them drain
+                tokio::task::yield_now().await;
+                continue;
+            }
+            if op_result(res, timeout)? == 0 {
+                return Ok(0);
+            }
+
+            let bid = cqueue::buffer_select(flags).ok_or_else(|| {
+                std::io::Error::other("completion did not carry a provided buffer")
+            })?;
+            let n = res as usize;
+            let data = unsafe {
+                std::slice::from_raw_parts(self.buffers.as_ptr().add(bid as usize * self.size), n)
+            };
+            out.extend_from_slice(data);
+            self.recycle(bid);
+            return Ok(n);
+        }
+    }
+
+    /// One READV on `fd`: up to `head_room` bytes appended to `head`, then up
+    /// to `body_room` more to `body`, cancelled by the kernel if it takes
+    /// longer than `timeout`
+    ///
+    /// Both buffers are with the ring while the READV is outstanding; if
+    /// this future is dropped first, they stay there until it completes and
+    /// the caller's are left empty.
+    pub async fn recv_split(
+        &self,
+        fd: RawFd,
+        head: &mut Vec<u8>,
+        head_room: usize,
+        body: &mut Vec<u8>,
+        body_room: usize,
+        timeout: Option<Duration>,
+    ) -> std::io::Result<usize> {
+        let (mut head_buf, mut body_buf) = (std::mem::take(head), std::mem::take(body));
+        head_buf.reserve(head_room);
+        body_buf.reserve(body_room);
+        let iovecs = Box::new([
+            libc::iovec {
+                iov_base: unsafe { head_buf.as_mut_ptr().add(head_buf.len()) }.cast(),
+                iov_len: head_room,
+            },
+            libc::iovec {
+                iov_base: unsafe { body_buf.as_mut_ptr().add(body_buf.len()) }.cast(),
+                iov_len: body_room,
+            },
+        ]);
+        let token = self.next_token();
+        let sqe = opcode::Readv::new(types::Fd(fd), iovecs.as_ptr(), 2).build();
+        self.in_flight.borrow_mut().insert(
+            token,
+            InFlight::Readv {
+                head: head_buf,
+                body: body_buf,
+                _iovecs: iovecs,
+            },
+        );
+
+        let (res, _) = self.submit_and_wait(token, sqe, timeout).await?;
+        let Some(InFlight::Readv {
+            head: mut head_buf,
+            body: mut body_buf,
+            ..
+        }) = self.in_flight.borrow_mut().remove(&token)
+        else {
+            unreachable!("read buffers released before their completion");
+        };
+        let result = op_result(res, timeout);
+        if let Ok(n) = result {
+            let to_head = n.min(head_room);
+            // SAFETY: the kernel filled the iovecs in order, `n` bytes in
+            // all, within the capacity reserved above
+            unsafe {
+                head_buf.set_len(head_buf.len() + to_head);
+                body_buf.set_len(body_buf.len() + n - to_head);
+            }
+        }
+        (*head, *body) = (head_buf, body_buf);
+        result
+    }
+
+    /// SEND all of `data` on `fd`, each SEND cancelled by the kernel if it
+    /// takes longer than `timeout`; short and interrupted SENDs are resumed
+    ///
+    /// `data` is parked in the ring while a SEND is outstanding, so dropping
+    /// this future never frees memory the kernel is still reading.
+    pub async fn send(
+        &self,
+        fd: RawFd,
+        mut data: Vec<u8>,
+        timeout: Option<Duration>,
+    ) -> std::io::Result<()> {
+        let mut sent = 0;
+        while sent < data.len() {
+            let token = self.next_token();
+            let sqe = opcode::Send::new(
+                types::Fd(fd),
+                unsafe { data.as_ptr().add(sent) },
+                (data.len() - sent) as u32,
+            )
+            .flags(libc::MSG_NOSIGNAL)
+            .build();
+            self.in_flight
+                .borrow_mut()
+                .insert(token, InFlight::Send(data));
+
+            let (res, _) = self.submit_and_wait(token, sqe, timeout).await?;
+            let Some(InFlight::Send(sent_data)) = self.in_flight.borrow_mut().remove(&token) else {
+                unreachable!("send buffer released before its completion");
+            };
+            data = sent_data;
+
+            match op_result(res, timeout) {
+                Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+                Ok(n) => sent += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn next_token(&self) -> u64 {
+        let token = self.next_token.get();
+        self.next_token.set(token.wrapping_add(1));
+        token
+    }
+
+    async fn submit_and_wait(
+        &self,
+        token: u64,
+        sqe: squeue::Entry,
+        timeout: Option<Duration>,
+    ) -> std::io::Result<(i32, u32)> {
+        let sqe = sqe.user_data(token);
+        {
+            let mut ring = self.ring.borrow_mut();
+            match timeout {
+                Some(timeout) => {
+                    // The kernel copies the timespec while the SQE is prepared,
+                    // i.e. during the submit below
+                    let timespec = types::Timespec::from(timeout);
+                    let linked = [
+                        sqe.flags(squeue::Flags::IO_LINK),
+                        opcode::LinkTimeout::new(&timespec)
+                            .build()
+                            .user_data(INTERNAL),
+                    ];
+                    while unsafe { ring.submission().push_multiple(&linked) }.is_err() {
+                        ring.submit()?;
+                    }
+                    ring.submit()?;
+                }
+                None => {
+                    while unsafe { ring.submission().push(&sqe) }.is_err() {
+                        ring.submit()?;
+                    }
+                    ring.submit()?;
+                }
+            }
+        }
+        stats::uring_submitted();
+
+        let mut guard = WaitGuard {
+            ring: self,
+            token,
+            finished: false,
+        };
+        self.waiting.borrow_mut().insert(token);
+
+        loop {
+            let notified = self.reaped.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            self.reap();
+            let result = self.completed.borrow_mut().remove(&token);
+            if let Some(result) = result {
+                stats::uring_completed();
+                guard.finished = true;
+                drop(guard);
+                return Ok(result);
+            }
+
+            tokio::select! {
+                ready = self.eventfd.readable() => self.drain_eventfd(ready?),
+                _ = notified => {}
+            }
+        }
+    }
+
+    /// Reset the eventfd counter; readiness is only cleared once it reads empty
+    fn drain_eventfd(&self, mut ready: tokio::io::unix::AsyncFdReadyGuard<'_, OwnedFd>) {
+        let mut counter = [0u8; 8];
+        while let Ok(Ok(_)) = ready.try_io(|fd| {
+            let n = unsafe {
+                libc::read(
+                    fd.as_raw_fd(),
+                    counter.as_mut_ptr() as *mut libc::c_void,
+                    counter.len(),
+                )
+            };
+            if n < 0 {
+                Err(std::io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        }) {}
+    }
+
+    /// Move every available completion into `completed`
+    fn reap(&self) {
+        let mut reaped = false;
+        {
+            let mut ring = self.ring.borrow_mut();
+            for cqe in ring.completion() {
+                let token = cqe.user_data();
+                if self.waiting.borrow().contains(&token) {
+                    self.completed
+                        .borrow_mut()
+                        .insert(token, (cqe.result(), cqe.flags()));
+                    reaped = true;
+                } else {
+                    // The waiting future was dropped; release its buffers
+                    self.in_flight.borrow_mut().remove(&token);
+                    if let Some(bid) = cqueue::buffer_select(cqe.flags()) {
+                        self.recycle(bid);
+                    }
+                }
+            }
+        }
+        if reaped {
+            self.reaped.notify_waiters();
+        }
+    }
+
+    /// Put buffer `bid` back on the ring for the kernel to select
+    fn recycle(&self, bid: u16) {
+        let base = self.entries.as_ptr() as *mut types::BufRingEntry;
+        let tail = self.tail.get();
+        unsafe {
+            let entry = &mut *base.add((tail & (self.count - 1)) as usize);
+            entry.set_addr(self.buffers.as_ptr().add(bid as usize * self.size) as u64);
+            entry.set_len(self.size as u32);
+            entry.set_bid(bid);
+
+            let tail_ptr = types::BufRingEntry::tail(base) as *const AtomicU16;
+            (*tail_ptr).store(tail.wrapping_add(1), Ordering::Release);
+        }
+        self.tail.set(tail.wrapping_add(1));
+    }
+}
+
+/// Map a completion result to the bytes transferred; a linked timeout
+/// firing shows up as the operation being cancelled
+fn op_result(res: i32, timeout: Option<Duration>) -> std::io::Result<usize> {
+    if res == -libc::ECANCELED && timeout.is_some() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "io_uring operation timed out",
+        ));
+    }
+    if res < 0 {
+        return Err(std::io::Error::from_raw_os_error(-res));
+    }
+    Ok(res as usize)
+}
+
+/// Forgets a token when its future completes or is dropped, so a late
+/// completion for it releases its buffers instead of leaking them, and asks
+/// the kernel to cancel the operation if it is still outstanding
+struct WaitGuard<'a> {
+    ring: &'a RecvRing,
+    token: u64,
+    finished: bool,
+}
+
+impl Drop for WaitGuard<'_> {
+    fn drop(&mut self) {
+        self.ring.waiting.borrow_mut().remove(&self.token);
+        if self.finished {
+            return;
+        }
+
+        let unclaimed = self.ring.completed.borrow_mut().remove(&self.token);
+        match unclaimed {
+            Some((_, flags)) => {
+                self.ring.in_flight.borrow_mut().remove(&self.token);
+                if let Some(bid) = cqueue::buffer_select(flags) {
+                    self.ring.recycle(bid);
+                }
+            }
+            None => {
+                // Best effort: if this fails the operation still completes
+                // eventually and `reap` releases it then
+                let cancel = opcode::AsyncCancel::new(self.token)
+                    .build()
+                    .user_data(INTERNAL);
+                let mut ring = self.ring.ring.borrow_mut();
+                if unsafe { ring.submission().push(&cancel) }.is_ok() {
+                    let _ = ring.submit();
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,290 @@
+//! In-memory HTTP response cache (RFC 9111 subset)
+//!
+//! Stores successful GET responses keyed by host and path. Honors the
+//! `no-store`, `no-cache` and `max-age` Cache-Control directives, `Expires`
+//! when there is no `max-age`, and the `Age` header, and keeps
+//! `ETag`/`Last-Modified` validators so stale entries can be revalidated
+//! with a conditional request instead of refetched.
+//!
+//! Entries aren't keyed on request headers, so a response carrying `Vary`
+//! isn't stored at all rather than served for a request it doesn't match.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::clock::Clock;
+use crate::date;
+use crate::http::{Response, Validators};
+
+/// Outcome of a cache lookup
+pub enum Lookup {
+    /// Entry is fresh and can be returned without contacting the server
+    Fresh(Box<Response>),
+    /// Entry is stale; send these validators to revalidate it
+    Stale(Validators),
+    Miss,
+}
+
+struct CacheEntry {
+    response: Response,
+    stored_at: Instant,
+    /// Remaining freshness lifetime at the time the entry was stored
+    ttl: Option<Duration>,
+    no_cache: bool,
+}
+
+impl CacheEntry {
+    fn is_fresh(&self, now: Instant) -> bool {
+        !self.no_cache
+            && self
+                .ttl
+                .is_some_and(|ttl| now.duration_since(self.stored_at) < ttl)
+    }
+}
+
+/// Cache-Control directives relevant to a private client cache
+#[derive(Default)]
+struct Directives {
+    no_store: bool,
+    no_cache: bool,
+    max_age: Option<u64>,
+}
+
+fn directives(response: &Response) -> Directives {
+    let mut d = Directives::default();
+    // Repeated Cache-Control lines combine into one list (RFC 9110 §5.3)
+    for directive in response
+        .headers
+        .get_all("Cache-Control")
+        .flat_map(|value| value.split(','))
+    {
+        let (name, arg) = match directive.trim().split_once('=') {
+            Some((name, arg)) => (name, Some(arg.trim_matches('"'))),
+            None => (directive.trim(), None),
+        };
+        match name.to_ascii_lowercase().as_str() {
+            "no-store" => d.no_store = true,
+            "no-cache" => d.no_cache = true,
+            "max-age" => d.max_age = arg.and_then(|a| a.parse().ok()),
+            _ => {}
+        }
+    }
+    d
+}
+
+/// Freshness lifetime minus the age the response already had when received
+///
+/// `max-age` takes precedence over `Expires`, which is measured from the
+/// response's `Date` (RFC 9111 §4.2.1), or from `now` without one.
+fn ttl(response: &Response, max_age: Option<u64>, now: SystemTime) -> Option<Duration> {
+    let age = response
+        .header("Age")
+        .and_then(|a| a.parse::<u64>().ok())
+        .unwrap_or(0);
+    let lifetime = match max_age {
+        Some(max_age) => max_age,
+        None => {
+            let expires = date::parse(response.header("Expires")?)?;
+            let date = response.header("Date").and_then(date::parse).unwrap_or(now);
+            expires.duration_since(date).map_or(0, |d| d.as_secs())
+        }
+    };
+    Some(Duration::from_secs(lifetime.saturating_sub(age)))
+}
+
+pub struct ResponseCache {
+    max_entries: usize,
+    entries: HashMap<String, CacheEntry>,
+    /// Least recently used key at the front
+    order: VecDeque<String>,
+}
+
+impl ResponseCache {
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            max_entries,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub fn key(host: &str, path: &str) -> String {
+        format!("{host}{path}")
+    }
+
+    pub fn lookup(&mut self, key: &str, clock: &dyn Clock) -> Lookup {
+        let Some(entry) = self.entries.get(key) else {
+            return Lookup::Miss;
+        };
+
+        let lookup = if entry.is_fresh(clock.now()) {
+            Lookup::Fresh(Box::new(entry.response.clone()))
+        } else {
+            let validators = Validators::from_response(&entry.response);
+            if validators.is_empty() {
+                Lookup::Miss
+            } else {
+                Lookup::Stale(validators)
+            }
+        };
+        self.touch(key);
+        lookup
+    }
+
+    /// Store a response if it is cacheable, replacing any previous entry
+    pub fn store(&mut self, key: String, response: &Response, clock: &dyn Clock) {
+        let d = directives(response);
+        if d.no_store || response.header("Vary").is_some() {
+            self.invalidate(&key);
+            return;
+        }
+
+        let ttl = ttl(response, d.max_age, clock.wall());
+        if response.status != 200
+            || (ttl.is_none() && Validators::from_response(response).is_empty())
+        {
+            return;
+        }
+
+        let entry = CacheEntry {
+            response: response.clone(),
+            stored_at: clock.now(),
+            ttl,
+            no_cache: d.no_cache,
+        };
+        if self.entries.insert(key.clone(), entry).is_some() {
+            self.touch(&key);
+        } else {
+            self.order.push_back(key);
+        }
+
+        while self.entries.len() > self.max_entries {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+
+    /// Apply a 304 Not Modified to the stored entry and return the refreshed response
+    pub fn revalidate(
+        &mut self,
+        key: &str,
+        not_modified: &Response,
+        clock: &dyn Clock,
+    ) -> Option<Response> {
+        let entry = self.entries.get_mut(key)?;
+
+        // RFC 9111 §4.3.4: headers in the 304 replace the stored ones
+        let updates = || {
+            not_modified
+                .headers
+                .iter()
+                .filter(|(name, _)| !name.eq_ignore_ascii_case("Content-Length"))
+        };
+        for (name, _) in updates() {
+            entry.response.headers.remove(name);
+        }
+        entry.response.headers.extend(updates());
+
+        let d = directives(&entry.response);
+        entry.stored_at = clock.now();
+        entry.ttl = ttl(&entry.response, d.max_age, clock.wall());
+        entry.no_cache = d.no_cache;
+
+        let response = entry.response.clone();
+        self.touch(key);
+        Some(response)
+    }
+
+    pub fn invalidate(&mut self, key: &str) {
+        if self.entries.remove(key).is_some() {
+            self.order.retain(|k| k != key);
+        }
+    }
+
+    fn touch(&mut self, key: &str) {
+        if let Some(pos) = self.order.iter().position(|k| k == key) {
+            let key = self.order.remove(pos).unwrap();
+            self.order.push_back(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn response(cache_control: &str) -> Response {
+        let raw = format!(
+            "HTTP/1.1 200 OK\r\nCache-Control: {cache_control}\r\nETag: \"v1\"\r\n\
+             Content-Length: 2\r\n\r\nhi"
+        );
+        Response::parse(raw.as_bytes()).unwrap()
+    }
+
+    /// Sun, 06 Nov 1994 08:49:37 GMT
+    fn clock() -> ManualClock {
+        ManualClock::at(SystemTime::UNIX_EPOCH + Duration::from_secs(784_111_777))
+    }
+
+    #[test]
+    fn entries_go_stale_as_the_clock_advances() {
+        let clock = clock();
+        let mut cache = ResponseCache::new(4);
+        cache.store("a/".to_owned(), &response("max-age=60"), &clock);
+
+        clock.advance(Duration::from_secs(59));
+        assert!(matches!(cache.lookup("a/", &clock), Lookup::Fresh(_)));
+        clock.advance(Duration::from_secs(1));
+        assert!(matches!(cache.lookup("a/", &clock), Lookup::Stale(_)));
+    }
+
+    #[test]
+    fn revalidating_keeps_the_stored_expires() {
+        let clock = clock();
+        let mut response = response("public");
+        response
+            .headers
+            .append("Date", "Sun, 06 Nov 1994 08:49:37 GMT");
+        response
+            .headers
+            .append("Expires", "Sun, 06 Nov 1994 08:50:37 GMT");
+        let mut cache = ResponseCache::new(4);
+        cache.store("a/".to_owned(), &response, &clock);
+
+        let not_modified =
+            Response::parse(b"HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\n\r\n").unwrap();
+        clock.advance(Duration::from_secs(30));
+        assert!(cache.revalidate("a/", &not_modified, &clock).is_some());
+        assert!(matches!(cache.lookup("a/", &clock), Lookup::Fresh(_)));
+    }
+
+    #[test]
+    fn expires_without_date_counts_from_the_clock() {
+        let clock = clock();
+        let mut response = response("public");
+        response
+            .headers
+            .append("Expires", "Sun, 06 Nov 1994 08:50:07 GMT");
+        let mut cache = ResponseCache::new(4);
+        cache.store("a/".to_owned(), &response, &clock);
+
+        assert!(matches!(cache.lookup("a/", &clock), Lookup::Fresh(_)));
+        clock.advance(Duration::from_secs(30));
+        assert!(matches!(cache.lookup("a/", &clock), Lookup::Stale(_)));
+    }
+
+    #[test]
+    fn responses_that_vary_are_not_stored() {
+        let clock = clock();
+        let mut cache = ResponseCache::new(4);
+        cache.store("a/".to_owned(), &response("max-age=60"), &clock);
+
+        let mut varies = response("max-age=60");
+        varies.headers.append("Vary", "Accept-Encoding");
+        cache.store("a/".to_owned(), &varies, &clock);
+        assert!(matches!(cache.lookup("a/", &clock), Lookup::Miss));
+    }
+}
@@ -0,0 +1,177 @@
+//! Recording responses to disk and replaying them without a network
+//!
+//! A cassette in record mode appends every response the client receives to
+//! a file, raw as it came off the connection, under its request's method,
+//! host and path. A cassette in replay mode loads such a file and answers
+//! requests from it in the order they were recorded, so a request made
+//! twice gets the two recorded responses in turn. Replay never connects:
+//! requests that weren't recorded, and sessions and WebSockets, which
+//! need a live connection, fail instead.
+//!
+//! The file is a header line followed by one entry per response:
+//!
+//! ```text
+//! ktls-uring-demo cassette 1
+//! GET httpbin.org /get 312
+//! <312 bytes of raw response>
+//! ```
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const HEADER: &str = "ktls-uring-demo cassette 1\n";
+
+#[derive(Debug)]
+pub enum CassetteError {
+    Io(std::io::Error),
+    /// The file isn't a cassette, or is cut short
+    Corrupt {
+        path: PathBuf,
+        reason: String,
+    },
+    /// Replay has no (more) responses for this request
+    NotRecorded {
+        method: String,
+        host: String,
+        path: String,
+    },
+    /// Replay was asked for a live connection
+    Offline {
+        host: String,
+    },
+}
+
+impl std::fmt::Display for CassetteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CassetteError::Io(e) => write!(f, "Cassette I/O failed: {e}"),
+            CassetteError::Corrupt { path, reason } => {
+                write!(f, "Corrupt cassette {}: {reason}", path.display())
+            }
+            CassetteError::NotRecorded { method, host, path } => {
+                write!(f, "No recorded response left for {method} {host}{path}")
+            }
+            CassetteError::Offline { host } => {
+                write!(f, "Replaying a cassette, not connecting to {host}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CassetteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CassetteError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for CassetteError {
+    fn from(e: std::io::Error) -> Self {
+        CassetteError::Io(e)
+    }
+}
+
+type Key = (String, String, String);
+
+enum Mode {
+    Record(RefCell<File>),
+    Replay(RefCell<HashMap<Key, VecDeque<Vec<u8>>>>),
+}
+
+pub struct Cassette {
+    mode: Mode,
+}
+
+impl Cassette {
+    /// Record into `path`, replacing whatever it held
+    pub fn record(path: impl AsRef<Path>) -> Result<Self, CassetteError> {
+        let mut file = File::create(path)?;
+        file.write_all(HEADER.as_bytes())?;
+        Ok(Self {
+            mode: Mode::Record(RefCell::new(file)),
+        })
+    }
+
+    /// Replay the responses recorded in `path`
+    pub fn replay(path: impl AsRef<Path>) -> Result<Self, CassetteError> {
+        let path = path.as_ref();
+        let data = std::fs::read(path)?;
+        let corrupt = |reason: &str| CassetteError::Corrupt {
+            path: path.to_owned(),
+            reason: reason.to_owned(),
+        };
+
+        let mut rest = data
+            .strip_prefix(HEADER.as_bytes())
+            .ok_or_else(|| corrupt("missing header"))?;
+        let mut recorded: HashMap<Key, VecDeque<Vec<u8>>> = HashMap::new();
+        while !rest.is_empty() {
+            let end = rest
+                .iter()
+                .position(|&b| b == b'\n')
+                .ok_or_else(|| corrupt("unterminated entry line"))?;
+            let line = std::str::from_utf8(&rest[..end]).map_err(|_| corrupt("entry line"))?;
+            // The path is last but for the length, so it may hold spaces
+            let Some((key, len)) = line.rsplit_once(' ') else {
+                return Err(corrupt(&format!("entry line {line:?}")));
+            };
+            let [method, host, path] = key.splitn(3, ' ').collect::<Vec<_>>()[..] else {
+                return Err(corrupt(&format!("entry line {line:?}")));
+            };
+            let len: usize = len
+                .parse()
+                .map_err(|_| corrupt(&format!("entry length {len:?}")))?;
+            let raw = rest
+                .get(end + 1..end + 1 + len)
+                .ok_or_else(|| corrupt("response cut short"))?;
+            rest = rest[end + 1 + len..]
+                .strip_prefix(b"\n")
+                .ok_or_else(|| corrupt("response cut short"))?;
+            recorded
+                .entry((method.to_owned(), host.to_owned(), path.to_owned()))
+                .or_default()
+                .push_back(raw.to_vec());
+        }
+        Ok(Self {
+            mode: Mode::Replay(RefCell::new(recorded)),
+        })
+    }
+
+    pub fn is_replay(&self) -> bool {
+        matches!(self.mode, Mode::Replay(_))
+    }
+
+    /// Append `raw`, the response to `method host path`, when recording
+    pub fn save(&self, method: &str, host: &str, path: &str, raw: &[u8]) -> std::io::Result<()> {
+        let Mode::Record(file) = &self.mode else {
+            return Ok(());
+        };
+        let mut entry = format!("{method} {host} {path} {}\n", raw.len()).into_bytes();
+        entry.extend_from_slice(raw);
+        entry.push(b'\n');
+        file.borrow_mut().write_all(&entry)
+    }
+
+    /// The next recorded response to `method host path`, when replaying
+    pub fn take(&self, method: &str, host: &str, path: &str) -> Result<Vec<u8>, CassetteError> {
+        let not_recorded = || CassetteError::NotRecorded {
+            method: method.to_owned(),
+            host: host.to_owned(),
+            path: path.to_owned(),
+        };
+        let Mode::Replay(recorded) = &self.mode else {
+            return Err(not_recorded());
+        };
+        let key = (method.to_owned(), host.to_owned(), path.to_owned());
+        recorded
+            .borrow_mut()
+            .get_mut(&key)
+            .and_then(VecDeque::pop_front)
+            .ok_or_else(not_recorded)
+    }
+}
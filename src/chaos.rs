@@ -0,0 +1,316 @@
+//! `chaos` subcommand: malformed WebSocket frames against a server
+//!
+//! Opens one WebSocket per case, over kTLS where it can be set up, sends a
+//! frame RFC 6455 says the server must reject (reserved bits set, an
+//! oversized or fragmented control frame, text that isn't UTF-8 and so
+//! on) and reports what the server did about it. A server passes a case
+//! by closing with the status code the RFC gives for it, or at least by
+//! dropping the connection; it fails by carrying on as if nothing had
+//! happened. The `split-header` case instead sends a valid frame a byte
+//! per write, which the server has to put back together and must not
+//! close over.
+//!
+//! Exits with 1 if the server failed any case, so it can gate CI.
+
+use std::path::Path;
+use std::time::Duration;
+
+use ktls_uring_demo::connect::Endpoint;
+use ktls_uring_demo::rng;
+use ktls_uring_demo::websocket::WssClient;
+use ktls_uring_demo::wsproto::{Message, OP_CLOSE, OP_CONTINUATION, OP_PING, OP_TEXT, WsError};
+use ktls_uring_demo::{HttpsClient, RequestOptions};
+
+pub const USAGE: &str = "\
+usage: ktls-uring-demo chaos [--host HOST] [--port PORT] [--path PATH]
+                             [--ca-file PATH] [--case NAME]... [--wait MS]
+
+  --host HOST     server to test (default: echo.websocket.org)
+  --port PORT     connect to PORT instead of 443
+  --path PATH     WebSocket path (default: /)
+  --ca-file PATH  trust this PEM bundle instead of the platform's roots
+  --case NAME     run only this case; may be repeated (default: all)
+  --wait MS       how long to wait for the server's reaction (default: 2000)
+
+cases:
+  rsv-bits             text frame with RSV1-3 set, no extension negotiated
+  oversized-ping       ping with a 126-byte payload
+  fragmented-ping      ping without FIN
+  reserved-opcode      frame with opcode 0x3
+  orphan-continuation  continuation frame with no message to continue
+  unmasked             text frame without a mask
+  invalid-utf8         text frame that isn't UTF-8
+  invalid-close-code   close frame with status code 999
+  split-header         valid text frame written one byte at a time";
+
+/// 1002, for frames that break the protocol
+const PROTOCOL_ERROR: u16 = 1002;
+/// 1007, for a text payload that isn't UTF-8
+const INVALID_PAYLOAD: u16 = 1007;
+
+/// How a conforming server reacts to a case
+#[derive(Clone, Copy)]
+enum Expect {
+    /// Fails the connection, preferably with a Close carrying this code
+    Close(u16),
+    /// Keeps the connection open
+    Open,
+}
+
+struct Case {
+    name: &'static str,
+    expect: Expect,
+    /// The frames to send, each as separate writes
+    writes: fn(mask: [u8; 4]) -> Vec<Vec<u8>>,
+}
+
+const CASES: [Case; 9] = [
+    Case {
+        name: "rsv-bits",
+        expect: Expect::Close(PROTOCOL_ERROR),
+        writes: |mask| vec![frame(0x80 | 0x70 | OP_TEXT, b"chaos", Some(mask))],
+    },
+    Case {
+        name: "oversized-ping",
+        expect: Expect::Close(PROTOCOL_ERROR),
+        writes: |mask| vec![frame(0x80 | OP_PING, &[b'p'; 126], Some(mask))],
+    },
+    Case {
+        name: "fragmented-ping",
+        expect: Expect::Close(PROTOCOL_ERROR),
+        writes: |mask| vec![frame(OP_PING, b"chaos", Some(mask))],
+    },
+    Case {
+        name: "reserved-opcode",
+        expect: Expect::Close(PROTOCOL_ERROR),
+        writes: |mask| vec![frame(0x80 | 0x3, b"chaos", Some(mask))],
+    },
+    Case {
+        name: "orphan-continuation",
+        expect: Expect::Close(PROTOCOL_ERROR),
+        writes: |mask| vec![frame(0x80 | OP_CONTINUATION, b"chaos", Some(mask))],
+    },
+    Case {
+        name: "unmasked",
+        expect: Expect::Close(PROTOCOL_ERROR),
+        writes: |_| vec![frame(0x80 | OP_TEXT, b"chaos", None)],
+    },
+    Case {
+        name: "invalid-utf8",
+        expect: Expect::Close(INVALID_PAYLOAD),
+        writes: |mask| vec![frame(0x80 | OP_TEXT, b"cha\xff\xfeos", Some(mask))],
+    },
+    Case {
+        name: "invalid-close-code",
+        expect: Expect::Close(PROTOCOL_ERROR),
+        writes: |mask| vec![frame(0x80 | OP_CLOSE, &999u16.to_be_bytes(), Some(mask))],
+    },
+    Case {
+        name: "split-header",
+        expect: Expect::Open,
+        writes: |mask| {
+            frame(0x80 | OP_TEXT, b"chaos", Some(mask))
+                .into_iter()
+                .map(|byte| vec![byte])
+                .collect()
+        },
+    },
+];
+
+pub struct ChaosConfig {
+    host: String,
+    port: Option<u16>,
+    path: String,
+    ca_file: Option<String>,
+    /// Names of the cases to run; empty for all
+    cases: Vec<String>,
+    wait: Duration,
+}
+
+impl ChaosConfig {
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let mut config = Self {
+            host: "echo.websocket.org".to_owned(),
+            port: None,
+            path: "/".to_owned(),
+            ca_file: None,
+            cases: Vec::new(),
+            wait: Duration::from_secs(2),
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .cloned()
+                    .ok_or_else(|| format!("{arg} needs a value"))
+            };
+            match arg.as_str() {
+                "--host" => config.host = value()?,
+                "--port" => {
+                    let port = value()?;
+                    config.port = Some(
+                        port.parse()
+                            .map_err(|_| format!("{arg} expects a port, got {port:?}"))?,
+                    );
+                }
+                "--path" => config.path = value()?,
+                "--ca-file" => config.ca_file = Some(value()?),
+                "--case" => {
+                    let name = value()?;
+                    if !CASES.iter().any(|case| case.name == name) {
+                        return Err(format!("unknown case {name:?}"));
+                    }
+                    config.cases.push(name);
+                }
+                "--wait" => {
+                    let ms = value()?;
+                    let ms = ms
+                        .parse()
+                        .map_err(|_| format!("{arg} expects a number, got {ms:?}"))?;
+                    config.wait = Duration::from_millis(ms);
+                }
+                _ => return Err(format!("unknown option {arg:?}")),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// What the server did after a case's frames
+enum Outcome {
+    /// Sent a Close, with its status code if the payload had one
+    Closed(Option<u16>),
+    /// Closed the connection without a Close frame
+    Dropped,
+    /// Sent something other than a Close
+    Replied(Message),
+    /// Sent a frame this client couldn't make sense of
+    Invalid(&'static str),
+    /// Nothing within the wait
+    Silent,
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Closed(Some(code)) => write!(f, "closed with {code}"),
+            Outcome::Closed(None) => write!(f, "closed without a code"),
+            Outcome::Dropped => write!(f, "dropped the connection"),
+            Outcome::Replied(Message::Text(text)) => write!(f, "replied with text {text:?}"),
+            Outcome::Replied(message) => write!(
+                f,
+                "replied with opcode {:#x}, {} bytes",
+                message.opcode(),
+                message.payload().len()
+            ),
+            Outcome::Invalid(what) => write!(f, "sent an invalid frame: {what}"),
+            Outcome::Silent => write!(f, "no reaction"),
+        }
+    }
+}
+
+impl Outcome {
+    /// Whether a conforming server could have reacted this way; a
+    /// connection failed without the expected Close still passes, as the
+    /// RFC only recommends sending one
+    fn passes(&self, expect: Expect) -> bool {
+        match (expect, self) {
+            (Expect::Close(code), Outcome::Closed(got)) => *got == Some(code),
+            (Expect::Close(_), Outcome::Dropped) => true,
+            (Expect::Close(_), _) => false,
+            (Expect::Open, Outcome::Replied(_) | Outcome::Silent) => true,
+            (Expect::Open, _) => false,
+        }
+    }
+}
+
+/// Run the selected cases and print a line for each; `Ok(false)` if the
+/// server failed any of them
+pub async fn run(config: &ChaosConfig) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut client = HttpsClient::new().with_verbose(false);
+    if let Some(path) = &config.ca_file {
+        client = client.with_ca_file(Path::new(path))?;
+    }
+    let options = RequestOptions {
+        endpoints: config
+            .port
+            .map(|port| vec![Endpoint::new(config.host.as_str(), port)])
+            .unwrap_or_default(),
+        ..Default::default()
+    };
+
+    let mut passed = true;
+    for case in CASES
+        .iter()
+        .filter(|case| config.cases.is_empty() || config.cases.iter().any(|c| c == case.name))
+    {
+        let mut ws = WssClient::connect(&client, &config.host, &config.path, &options).await?;
+        let transport = if ws.connection().ktls {
+            "kTLS"
+        } else {
+            "userspace TLS"
+        };
+        for write in (case.writes)(rng::bytes(client.rng())?) {
+            // A server that gave up partway through may already be gone
+            if ws.send_raw(write).await.is_err() {
+                break;
+            }
+        }
+        let outcome = react(&mut ws, config.wait).await;
+        let pass = outcome.passes(case.expect);
+        passed &= pass;
+        println!(
+            "{:<20} {:<4} {outcome} ({transport})",
+            case.name,
+            if pass { "ok" } else { "FAIL" }
+        );
+    }
+    Ok(passed)
+}
+
+/// The server's reaction within `wait`, past any pings and pongs
+async fn react(ws: &mut WssClient, wait: Duration) -> Outcome {
+    let deadline = tokio::time::Instant::now() + wait;
+    loop {
+        let left = deadline.saturating_duration_since(tokio::time::Instant::now());
+        return match ws.receive_timeout(left).await {
+            Ok(Some(Message::Ping(_) | Message::Pong(_))) => continue,
+            Ok(Some(Message::Close(payload))) => Outcome::Closed(
+                payload
+                    .get(..2)
+                    .map(|code| u16::from_be_bytes([code[0], code[1]])),
+            ),
+            Ok(Some(message)) => Outcome::Replied(message),
+            Ok(None) => Outcome::Silent,
+            Err(WsError::Protocol(what)) => Outcome::Invalid(what),
+            Err(_) => Outcome::Dropped,
+        };
+    }
+}
+
+/// One frame, header and all, masked with `mask` unless it's `None`;
+/// `first` is the header's first byte: FIN, RSV1-3 and the opcode
+fn frame(first: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+    let mut frame = vec![first];
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        len @ 0..=125 => frame.push(mask_bit | len as u8),
+        len @ 126..=0xffff => {
+            frame.push(mask_bit | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(mask_bit | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    match mask {
+        Some(mask) => {
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+        }
+        None => frame.extend_from_slice(payload),
+    }
+    frame
+}
@@ -0,0 +1,2076 @@
+//! [`HttpsClient`] and the options a request can override: io_uring and
+//! kTLS all the way down, so Linux only
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc;
+use tokio_uring::net::TcpStream;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{ClientConfig, ClientConnection};
+
+use crate::backpressure::BackpressureAlert;
+use crate::balance::{BalancePolicy, Balancer, Lease};
+use crate::breaker::{BreakerPolicy, CircuitBreaker};
+use crate::buffers::{BufferPool, BufferPoolConfig};
+use crate::bufring::RecvRing;
+use crate::cache::{Lookup, ResponseCache};
+use crate::cassette::{Cassette, CassetteError};
+use crate::clock::{Clock, SystemClock};
+use crate::compat::PollStream;
+use crate::connect::{ConnectionInfo, Endpoint, EndpointsExhausted};
+use crate::context::{Context, ContextError};
+use crate::control::{Controls, KtlsPolicy};
+use crate::handshake::Exporter;
+use crate::headers::HeaderMap;
+use crate::http::{
+    Body, ClosePolicy, Conditional, EarlyHintsHook, HttpError, Redirect, Request, Response, Timing,
+    Trailers, Validators,
+};
+use crate::introspect::{DebugState, Registry, Use};
+use crate::limit::{ConnectionLimit, Priority, Slot};
+use crate::pool::{Idle, Pool, PoolPolicy, Reused};
+use crate::qos::TrafficClass;
+use crate::resolver::{Resolver, ResolverPolicy};
+use crate::retry::{Rejected, RetryPolicy, Verdict, Verifier};
+use crate::rng::{Rng, SystemRng};
+use crate::session::{Established, RecyclePolicy, Session, Transport};
+use crate::standby::{Standby, StandbyPolicy, Warm};
+use crate::streaming::StreamedResponse;
+use crate::tls::UringTlsStream;
+use crate::websocket::{Credentials, WssClient};
+use crate::wirelog::WireLog;
+use crate::wsproto::redirect_target;
+use crate::{connect, fd, handshake, http, ktls, markers, session, stats};
+
+/// Client TLS settings trusting `root_store`
+fn tls_config(root_store: rustls::RootCertStore) -> Arc<ClientConfig> {
+    let mut config = ClientConfig::builder()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+
+    // Enable secret extraction for kTLS
+    config.enable_secret_extraction = true;
+    Arc::new(config)
+}
+
+/// Bytes transferred so far and the expected total, when known
+#[derive(Clone, Copy, Debug)]
+pub struct Progress {
+    pub transferred: u64,
+    pub total: Option<u64>,
+}
+
+/// Per-request behavior overrides
+#[derive(Default)]
+pub struct RequestOptions {
+    /// Neither read from nor write to the response cache
+    pub bypass_cache: bool,
+    /// Called after each write with request bytes sent (head included);
+    /// the total is unknown for channel bodies
+    pub on_upload: Option<Box<dyn Fn(Progress)>>,
+    /// Called after each read with response bytes received (head included);
+    /// the total is known once the head carried a Content-Length
+    pub on_download: Option<Box<dyn Fn(Progress)>>,
+    /// Overrides the client's traffic class for this request's connection
+    pub traffic_class: Option<TrafficClass>,
+    /// Where the request queues for a connection when the client's
+    /// [`connection limit`](HttpsClient::with_connection_limit) is reached
+    pub priority: Priority,
+    /// Overrides the client's `TCP_NODELAY` setting for this request's
+    /// connection; on a session, for this request only
+    pub nodelay: Option<bool>,
+    /// Deadline and cancellation for every stage of the request, retries included
+    pub context: Context,
+    /// Where to connect instead of the request's host on port 443, tried in
+    /// order until one connects and completes the handshake; TLS still
+    /// verifies the request's host
+    pub endpoints: Vec<Endpoint>,
+    /// Called when the connection's send buffer stays full, while the
+    /// request, or the session or WebSocket opened with these options, uses it
+    pub on_backpressure: Option<BackpressureAlert>,
+    /// What to make of the connection closing without close_notify; by
+    /// default strict for framed bodies, lenient for close-delimited ones
+    pub close_policy: Option<ClosePolicy>,
+    /// Keying material a session or WebSocket opened with these options
+    /// derives from the TLS exporter, before the keys go to the kernel
+    pub exporters: Vec<Exporter>,
+    /// Called with the headers of each 103 Early Hints response, its `Link`
+    /// headers among them, as it arrives ahead of the final response
+    pub on_early_hints: Option<Box<EarlyHintsHook>>,
+    /// Write the request at once, on its own, rather than packed with the
+    /// requests around it in a [`Session::pipeline`]; for latency-critical
+    /// requests
+    pub no_coalescing: bool,
+}
+
+/// Whether a request over a reused connection got nothing back because the
+/// server had already closed it, so it can be sent again over a new one
+fn closed_while_idle(received: &Result<Received, Box<dyn std::error::Error>>) -> bool {
+    match received {
+        Ok(received) => received.raw.is_empty(),
+        Err(e) => e.downcast_ref::<std::io::Error>().is_some_and(|e| {
+            session::closed_without_notify(e)
+                || matches!(
+                    e.kind(),
+                    ErrorKind::BrokenPipe
+                        | ErrorKind::ConnectionReset
+                        | ErrorKind::ConnectionAborted
+                )
+        }),
+    }
+}
+
+/// A response as read off its connection
+struct Received {
+    raw: Vec<u8>,
+    /// The rest of the body, when it was read apart from `raw`
+    rest: Vec<u8>,
+    /// The connection closed without close_notify
+    unclean_close: bool,
+    /// The response ended where its framing says, with nothing after it
+    complete: bool,
+    /// From starting to write the request to the first byte read back
+    ttfb: Duration,
+}
+
+/// Feeds the upload hook with the running count of request bytes written
+struct UploadProgress<'a> {
+    hook: Option<&'a dyn Fn(Progress)>,
+    sent: u64,
+    total: Option<u64>,
+}
+
+impl<'a> UploadProgress<'a> {
+    fn new(request: &[u8], body: &Body<'_>, options: &'a RequestOptions) -> Self {
+        let total = match body {
+            Body::Channel(..) | Body::GzipChannel(..) => None,
+            Body::Empty | Body::Json(_) | Body::Gzip { .. } => Some(request.len() as u64),
+        };
+        Self {
+            hook: options.on_upload.as_deref(),
+            sent: 0,
+            total,
+        }
+    }
+
+    fn sent(&mut self, n: usize) {
+        self.sent += n as u64;
+        if let Some(hook) = self.hook {
+            hook(Progress {
+                transferred: self.sent,
+                total: self.total,
+            });
+        }
+    }
+}
+
+/// Feeds the download hook, working out the total once the head is complete
+struct DownloadProgress<'a> {
+    hook: Option<&'a dyn Fn(Progress)>,
+    head_parsed: bool,
+    total: Option<u64>,
+}
+
+impl<'a> DownloadProgress<'a> {
+    fn new(options: &'a RequestOptions) -> Self {
+        Self {
+            hook: options.on_download.as_deref(),
+            head_parsed: false,
+            total: None,
+        }
+    }
+
+    fn update(&mut self, response: &[u8]) {
+        self.update_split(response, response.len());
+    }
+
+    /// [`update`](Self::update) for a response read into more than one
+    /// buffer, `head` being the one it starts in
+    fn update_split(&mut self, head: &[u8], transferred: usize) {
+        let Some(hook) = self.hook else {
+            return;
+        };
+        if !self.head_parsed
+            && let Some(total) = http::expected_len(head)
+        {
+            self.head_parsed = true;
+            self.total = total.map(|t| t as u64);
+        }
+        hook(Progress {
+            transferred: transferred as u64,
+            total: self.total,
+        });
+    }
+}
+
+/// Feeds the early hints hook each 103 response at the start of what has
+/// been read, once
+pub(crate) struct EarlyHints<'a> {
+    hook: Option<&'a EarlyHintsHook>,
+    /// Bytes of interim responses already looked at
+    seen: usize,
+}
+
+impl<'a> EarlyHints<'a> {
+    pub(crate) fn new(options: &'a RequestOptions) -> Self {
+        Self {
+            hook: options.on_early_hints.as_deref(),
+            seen: 0,
+        }
+    }
+
+    pub(crate) fn update(&mut self, response: &[u8]) {
+        let Some(hook) = self.hook else {
+            return;
+        };
+        while let Some(interim) = http::interim(&response[self.seen..]) {
+            self.seen += interim.len;
+            if interim.status == 103 {
+                hook(&interim.headers);
+            }
+        }
+    }
+}
+
+/// Yield point for read loops, so one long download can't monopolize the
+/// single-threaded runtime
+struct ReadQuantum {
+    /// Bytes a loop may read between yields; 0 never yields
+    quantum: usize,
+    since_yield: usize,
+}
+
+impl ReadQuantum {
+    fn new(quantum: usize) -> Self {
+        Self {
+            quantum,
+            since_yield: 0,
+        }
+    }
+
+    /// Account for `n` bytes read, yielding to other tasks once the quantum is used up
+    async fn consumed(&mut self, n: usize) {
+        self.since_yield += n;
+        if self.quantum > 0 && self.since_yield >= self.quantum {
+            self.since_yield = 0;
+            tokio::task::yield_now().await;
+        }
+    }
+}
+
+pub struct HttpsClient {
+    tls_config: Arc<ClientConfig>,
+    cache: Option<RefCell<ResponseCache>>,
+    /// kTLS policy and verbosity, which may change while the client runs
+    pub(crate) controls: Rc<Controls>,
+    /// Registered buffers for kTLS reads; plain heap buffers when unset
+    buffers: Option<BufferPool>,
+    /// Kernel-selected buffers for kTLS reads; takes precedence over `buffers`
+    recv_ring: Option<RecvRing>,
+    /// Response bytes a request may read before yielding to other tasks
+    read_quantum: usize,
+    /// Kernel-enforced limit on each kTLS read and write
+    io_timeout: Option<Duration>,
+    /// Read kTLS response heads into a buffer of this many bytes, and
+    /// bodies straight into their own
+    head_buffer: Option<usize>,
+    /// DSCP / priority marks for every connection, unless a request overrides them
+    traffic_class: Option<TrafficClass>,
+    /// `TCP_NODELAY` for every connection, unless a request overrides it;
+    /// the kernel's default (Nagle on) when unset
+    pub(crate) nodelay: Option<bool>,
+    /// Send the ClientHello in the SYN with TCP Fast Open
+    fast_open: bool,
+    /// Offer multipath TCP when connecting
+    mptcp: bool,
+    /// Retry 429/503 responses; `None` returns them as is
+    retry: Option<RetryPolicy>,
+    /// Gzip request bodies of at least this many bytes
+    pub(crate) compress_requests: Option<usize>,
+    /// Request bytes a session pipeline packs into one write; 0 writes
+    /// each request on its own
+    pub(crate) coalesce_writes: usize,
+    /// Judges every response before it is returned
+    verifier: Option<Box<Verifier>>,
+    /// Spreads connections over all of a host's addresses; the first one
+    /// is used when unset
+    balancer: Option<Balancer>,
+    /// Fails requests to hosts that keep failing; unset, every request is
+    /// attempted
+    breaker: Option<CircuitBreaker>,
+    /// Looks up hosts off the runtime thread, a bounded number at a time
+    resolver: Resolver,
+    /// Connections handshaken ahead of requests, for hosts kept warm
+    standby: Standby,
+    /// Connections kept open between requests
+    pool: Pool,
+    /// When sessions and pooled connections move on to a new connection
+    pub(crate) recycle: RecyclePolicy,
+    /// Caps open connections; unset, every request connects at once
+    connection_limit: Option<ConnectionLimit>,
+    /// Dumps each request and response to stderr
+    pub(crate) wire_log: Option<WireLog>,
+    /// Records responses to a file, or answers requests from one
+    cassette: Option<Cassette>,
+    /// Parse responses with [`Response::parse_strict`]
+    pub(crate) strict_parsing: bool,
+    /// Time for cache freshness and WebSocket heartbeats
+    pub(crate) clock: Rc<dyn Clock>,
+    /// Randomness for WebSocket keys and masks, retry jitter and random
+    /// address picks
+    pub(crate) rng: Rc<dyn Rng>,
+    /// `Sec-WebSocket-Version` offered when upgrading
+    pub(crate) websocket_version: u8,
+    /// Redirects a request may follow
+    max_redirects: usize,
+    /// Redirects a WebSocket upgrade may follow
+    pub(crate) websocket_redirects: usize,
+    /// Answers a 401 to a WebSocket upgrade
+    pub(crate) websocket_credentials: Option<Box<Credentials>>,
+    /// Every connection open for a request, session, WebSocket or stream
+    pub(crate) registry: Registry,
+}
+
+impl Default for HttpsClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HttpsClient {
+    pub fn new() -> Self {
+        let mut root_store = rustls::RootCertStore::empty();
+
+        for cert in rustls_native_certs::load_native_certs().expect("failed to load native certs") {
+            let _ = root_store.add(cert);
+        }
+
+        Self {
+            tls_config: tls_config(root_store),
+            cache: None,
+            controls: Rc::new(Controls::default()),
+            buffers: None,
+            recv_ring: None,
+            read_quantum: 256 * 1024,
+            io_timeout: None,
+            head_buffer: None,
+            traffic_class: None,
+            nodelay: None,
+            fast_open: false,
+            mptcp: false,
+            retry: None,
+            compress_requests: None,
+            coalesce_writes: 0,
+            verifier: None,
+            balancer: None,
+            breaker: None,
+            resolver: Resolver::new(ResolverPolicy::default()),
+            standby: Standby::new(StandbyPolicy::default()),
+            pool: Pool::new(PoolPolicy::default()),
+            recycle: RecyclePolicy::default(),
+            connection_limit: None,
+            wire_log: None,
+            cassette: None,
+            strict_parsing: false,
+            clock: Rc::new(SystemClock::default()),
+            rng: Rc::new(SystemRng),
+            websocket_version: 13,
+            max_redirects: 0,
+            websocket_redirects: 0,
+            websocket_credentials: None,
+            registry: Registry::default(),
+        }
+    }
+
+    /// Trust the CA certificates in the PEM file at `path` instead of the
+    /// platform's roots, e.g. for an internal PKI
+    pub fn with_ca_file(mut self, path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut root_store = rustls::RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(path)? {
+            root_store.add(cert?)?;
+        }
+        self.tls_config = tls_config(root_store);
+        Ok(self)
+    }
+
+    /// Read kTLS responses into a pool of registered buffers
+    ///
+    /// Must be called inside the tokio-uring runtime, since the buffers are
+    /// registered with its ring.
+    pub fn with_buffer_pool(mut self, config: &BufferPoolConfig) -> std::io::Result<Self> {
+        self.buffers = Some(BufferPool::new(config)?);
+        Ok(self)
+    }
+
+    /// Read kTLS responses through a provided-buffer ring of `count` x `size` bytes
+    ///
+    /// `count` must be a power of two. Must be called inside the tokio-uring
+    /// runtime, whose reactor polls the ring's completion eventfd.
+    pub fn with_provided_buffers(mut self, count: u16, size: usize) -> std::io::Result<Self> {
+        self.recv_ring = Some(RecvRing::new(count, size)?);
+        Ok(self)
+    }
+
+    /// Cancel any single kTLS read or write that takes longer than `timeout`
+    ///
+    /// Each operation is linked to an `IORING_OP_LINK_TIMEOUT`, so a hung read
+    /// is cancelled in the kernel and its buffer handed back, rather than
+    /// left pending behind a dropped future. tokio-uring can't link SQEs, so
+    /// this moves kTLS I/O onto the provided-buffer ring, creating one of
+    /// 64 x 16 KiB unless [`with_provided_buffers`](Self::with_provided_buffers)
+    /// already did. Must be called inside the tokio-uring runtime.
+    pub fn with_io_timeout(mut self, timeout: Duration) -> std::io::Result<Self> {
+        if self.recv_ring.is_none() {
+            self.recv_ring = Some(RecvRing::new(64, 16 * 1024)?);
+        }
+        self.io_timeout = Some(timeout);
+        Ok(self)
+    }
+
+    /// Read kTLS responses with one READV per read: the head into a buffer
+    /// of `head_size` bytes, the body into a buffer of its own, growing by
+    /// at most 16 KiB a read
+    ///
+    /// The body buffer becomes the response's body without being copied,
+    /// apart from whatever of it the first read put in the head buffer, so
+    /// this pays off for responses that are mostly body. Heads longer than
+    /// `head_size` still work, growing the head buffer. READV runs on the
+    /// provided-buffer ring, so this creates one of 64 x 16 KiB unless one
+    /// exists. Must be called inside the tokio-uring runtime.
+    pub fn with_split_reads(mut self, head_size: usize) -> std::io::Result<Self> {
+        if self.recv_ring.is_none() {
+            self.recv_ring = Some(RecvRing::new(64, 16 * 1024)?);
+        }
+        self.head_buffer = Some(head_size.max(1));
+        Ok(self)
+    }
+
+    pub fn buffer_pool(&self) -> Option<&BufferPool> {
+        self.buffers.as_ref()
+    }
+
+    /// Disable kTLS to force the userspace TLS path (e.g. for comparisons)
+    pub fn with_ktls(self, enabled: bool) -> Self {
+        self.controls.set_ktls(if enabled {
+            KtlsPolicy::Prefer
+        } else {
+            KtlsPolicy::Off
+        });
+        self
+    }
+
+    /// The settings that can be changed while the client runs, e.g. from a
+    /// [`control::serve`](crate::control::serve) socket
+    pub fn controls(&self) -> Rc<Controls> {
+        self.controls.clone()
+    }
+
+    /// The connections open right now: who to, over what, for how long and
+    /// how much has gone through them, e.g. for a health or debug endpoint
+    pub fn debug_state(&self) -> DebugState {
+        self.registry.snapshot()
+    }
+
+    /// The client's connections, for reporting them elsewhere, e.g. on a
+    /// [`control::serve`](crate::control::serve) socket
+    pub fn registry(&self) -> Registry {
+        self.registry.clone()
+    }
+
+    /// Yield to other tasks after every `bytes` of response read (0 disables)
+    ///
+    /// Smaller quanta keep short requests responsive next to bulk downloads on
+    /// the same runtime, at the cost of more scheduler round trips. This matters
+    /// most for the userspace fallback, which hands out plaintext rustls has
+    /// already decrypted without waiting on the socket.
+    pub fn with_read_quantum(mut self, bytes: usize) -> Self {
+        self.read_quantum = bytes;
+        self
+    }
+
+    /// Mark every connection with `class` for network QoS
+    pub fn with_traffic_class(mut self, class: TrafficClass) -> Self {
+        self.traffic_class = Some(class);
+        self
+    }
+
+    /// Set `TCP_NODELAY` on every connection: `true` sends small writes at
+    /// once, `false` leaves Nagle's algorithm to coalesce them
+    pub fn with_nodelay(mut self, enabled: bool) -> Self {
+        self.nodelay = Some(enabled);
+        self
+    }
+
+    /// Connect with TCP Fast Open, falling back to a plain connect on kernels
+    /// without client support
+    ///
+    /// Saves a round trip once a server has handed out a Fast Open cookie, and
+    /// needs bit 0 of `net.ipv4.tcp_fastopen` set (the default). TFO connects
+    /// are nonblocking `connect(2)` calls rather than io_uring operations.
+    pub fn with_fast_open(mut self, enabled: bool) -> Self {
+        self.fast_open = enabled;
+        self
+    }
+
+    /// Open connections as MPTCP sockets where the kernel supports them
+    ///
+    /// Servers without MPTCP support transparently get plain TCP; each
+    /// response's [`ConnectionInfo`] records which one was negotiated.
+    pub fn with_mptcp(mut self, enabled: bool) -> Self {
+        self.mptcp = enabled;
+        self
+    }
+
+    /// Retry throttled requests, waiting as long as `Retry-After` asks
+    pub fn with_retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+
+    /// Gzip JSON request bodies of at least `min_size` bytes, and every
+    /// streamed body, sending them with `Content-Encoding: gzip`
+    ///
+    /// Only useful against servers that accept compressed requests, which
+    /// many don't; ingest endpoints usually do.
+    pub fn with_request_compression(mut self, min_size: usize) -> Self {
+        self.compress_requests = Some(min_size);
+        self
+    }
+
+    /// Pack the requests of a [`Session::pipeline`] into writes of up to
+    /// `max_bytes`, so several small requests cost one write and, under
+    /// kTLS, one TLS record instead of one each
+    ///
+    /// A request bigger than `max_bytes` still goes in a write of its own,
+    /// as does one whose options set
+    /// [`no_coalescing`](RequestOptions::no_coalescing).
+    pub fn with_write_coalescing(mut self, max_bytes: usize) -> Self {
+        self.coalesce_writes = max_bytes;
+        self
+    }
+
+    /// Inspect every response and accept it, retry the request, or fail it
+    ///
+    /// Retries follow the retry policy's attempt limit and backoff; without a
+    /// policy, or once the attempts are used up, a retry verdict fails too.
+    pub fn with_verifier(mut self, verifier: impl Fn(&Response) -> Verdict + 'static) -> Self {
+        self.verifier = Some(Box::new(verifier));
+        self
+    }
+
+    /// Spread new connections over every address a host resolves to, and
+    /// steer clear of addresses that keep failing to connect or handshake
+    pub fn with_load_balancing(mut self, policy: BalancePolicy) -> Self {
+        self.balancer = Some(Balancer::new(policy));
+        self
+    }
+
+    /// Fail requests to a host at once after `policy.failure_threshold`
+    /// consecutive connect, handshake or 5xx failures, until its cooldown
+    /// has passed and a trial request gets through
+    pub fn with_circuit_breaker(mut self, policy: BreakerPolicy) -> Self {
+        self.breaker = Some(CircuitBreaker::new(policy));
+        self
+    }
+
+    /// Bound outstanding DNS lookups and how long each may take, and
+    /// remember failed ones for a while, per `policy`
+    pub fn with_resolver(mut self, policy: ResolverPolicy) -> Self {
+        self.resolver = Resolver::new(policy);
+        self
+    }
+
+    /// Retire a session's or pooled connection, with `close_notify`, once
+    /// it has carried `policy.max_requests` requests or been open for
+    /// `policy.max_age`, and go on over a new one
+    pub fn with_recycling(mut self, policy: RecyclePolicy) -> Self {
+        self.recycle = policy;
+        self
+    }
+
+    /// Keep as many idle connections per host, for as long, as `policy`
+    /// says, for later requests to reuse; see [`pool`](crate::pool)
+    pub fn with_pool(mut self, policy: PoolPolicy) -> Self {
+        self.pool = Pool::new(policy);
+        self
+    }
+
+    /// Keep as many standby connections per host, and replace them as
+    /// often, as `policy` says; see [`keep_warm`](Self::keep_warm)
+    pub fn with_standby(mut self, policy: StandbyPolicy) -> Self {
+        self.standby = Standby::new(policy);
+        self
+    }
+
+    /// Keep at most `max` connections open for requests, sessions and
+    /// WebSocket upgrades; the rest wait, in order of
+    /// [`RequestOptions::priority`]
+    pub fn with_connection_limit(mut self, max: usize) -> Self {
+        self.connection_limit = Some(ConnectionLimit::new(max));
+        self
+    }
+
+    /// Follow up to `max` redirects, each to a fresh connection with its own
+    /// handshake and kTLS setup; the final response lists them in
+    /// [`Response::redirects`]
+    ///
+    /// Only `https://` locations on port 443, or paths on the same host,
+    /// are followed; past the limit, the redirect itself is returned. A 307
+    /// or 308 is only followed when the body can be sent again.
+    pub fn with_redirects(mut self, max: usize) -> Self {
+        self.max_redirects = max;
+        self
+    }
+
+    /// Offer `version` rather than 13 when upgrading to a WebSocket
+    ///
+    /// Only for testing how servers handle other versions: this client
+    /// speaks RFC 6455 whatever it offers.
+    pub fn with_websocket_version(mut self, version: u8) -> Self {
+        self.websocket_version = version;
+        self
+    }
+
+    /// Follow up to `max` redirects in answer to a WebSocket upgrade, each
+    /// to a fresh connection with its own handshake and kTLS setup
+    ///
+    /// Only `wss://` and `https://` locations on port 443, or paths on the
+    /// same host, are followed.
+    pub fn with_websocket_redirects(mut self, max: usize) -> Self {
+        self.websocket_redirects = max;
+        self
+    }
+
+    /// Retry a WebSocket upgrade answered with 401 once, with the
+    /// `Authorization` value `credentials` returns for the host and its
+    /// challenge
+    pub fn with_websocket_credentials(
+        mut self,
+        credentials: impl Fn(&str, &Response) -> Option<String> + 'static,
+    ) -> Self {
+        self.websocket_credentials = Some(Box::new(credentials));
+        self
+    }
+
+    /// Print every request and response as sent and received to stderr,
+    /// credentials redacted, for debugging traffic tcpdump only sees
+    /// encrypted
+    pub fn with_wire_log(mut self, log: WireLog) -> Self {
+        self.wire_log = Some(log);
+        self
+    }
+
+    /// Record every response to a cassette, or with a replaying cassette
+    /// answer requests from it and never touch the network
+    pub fn with_cassette(mut self, cassette: Cassette) -> Self {
+        self.cassette = Some(cassette);
+        self
+    }
+
+    /// Reject responses with folded or control-character headers, or with
+    /// ambiguous framing, as [`HttpError::Protocol`] instead of reading
+    /// them the way most clients would
+    ///
+    /// For talking to upstreams through proxies that might frame the
+    /// response differently than this client does.
+    pub fn with_strict_parsing(mut self, strict: bool) -> Self {
+        self.strict_parsing = strict;
+        self
+    }
+
+    /// Read the time from `clock` rather than the system's clocks
+    ///
+    /// Applies to the response cache and to the WebSockets this client
+    /// opens.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Rc::new(clock);
+        self
+    }
+
+    /// Draw randomness from `rng` rather than the system's RNG
+    ///
+    /// With a [`SeededRng`](crate::rng::SeededRng), WebSocket handshakes and frames, retry jitter
+    /// and random address picks come out the same on every run.
+    pub fn with_rng(mut self, rng: impl Rng + 'static) -> Self {
+        self.rng = Rc::new(rng);
+        self
+    }
+
+    /// Where the client draws its randomness, for protocols run over its
+    /// connections that need some of their own
+    pub fn rng(&self) -> &dyn Rng {
+        &*self.rng
+    }
+
+    /// Parse a raw response, strictly if the client was built to
+    fn parse_response(&self, raw: &[u8]) -> Result<Response, HttpError> {
+        if self.strict_parsing {
+            Response::parse_strict(raw)
+        } else {
+            Response::parse(raw)
+        }
+    }
+
+    /// [`parse_response`](Self::parse_response) for the response to a
+    /// `method` request; one to HEAD has no body to check
+    pub(crate) fn parse_response_to(
+        &self,
+        method: &str,
+        raw: &[u8],
+    ) -> Result<Response, HttpError> {
+        if method == "HEAD" {
+            Ok(Response::parse_head(raw, self.strict_parsing)?.0)
+        } else {
+            self.parse_response(raw)
+        }
+    }
+
+    /// Decide whether `raw`, the response so far from `host`, stands after
+    /// its connection closed without close_notify, by the request's
+    /// [`ClosePolicy`] or the default for the response; returns the policy
+    /// for the response to record
+    pub(crate) fn judge_close(
+        &self,
+        host: &str,
+        raw: &[u8],
+        options: &RequestOptions,
+    ) -> Result<ClosePolicy, HttpError> {
+        let policy = options
+            .close_policy
+            .unwrap_or_else(|| ClosePolicy::default_for(raw));
+        policy.apply(host, raw)?;
+        Ok(policy)
+    }
+
+    /// [`parse_response`](Self::parse_response) for a response read as a
+    /// head and the rest of its body, see [`Response::parse_split`]
+    fn parse_split_response(&self, raw: &[u8], rest: Vec<u8>) -> Result<Response, HttpError> {
+        Response::parse_split(raw, rest, self.strict_parsing)
+    }
+
+    pub fn with_verbose(self, verbose: bool) -> Self {
+        self.controls.set_verbose(verbose);
+        self
+    }
+
+    /// Enable the in-memory response cache, holding at most `max_entries` responses
+    pub fn with_cache(mut self, max_entries: usize) -> Self {
+        self.cache = Some(RefCell::new(ResponseCache::new(max_entries)));
+        self
+    }
+
+    pub async fn request(
+        &self,
+        method: &str,
+        host: &str,
+        path: &str,
+        body: Body<'_>,
+        options: &RequestOptions,
+    ) -> Result<Response, Box<dyn std::error::Error>> {
+        self.send(Request::new(method, host, path).with_body(body), options)
+            .await
+    }
+
+    /// Send `request` over `stream`, already connected to its host's server,
+    /// e.g. by a custom dialer or a transparent proxy
+    ///
+    /// Only the TLS handshake (with kTLS where it can be set up) and the
+    /// request itself happen here. With just the one connection there are
+    /// no retries, and a redirect is returned rather than followed; the
+    /// response cache isn't consulted either.
+    pub async fn request_on(
+        &self,
+        stream: std::net::TcpStream,
+        request: Request<'_>,
+        options: &RequestOptions,
+    ) -> Result<Response, Box<dyn std::error::Error>> {
+        self.attempt_on(Some(stream), request, options).await
+    }
+
+    /// Connect to `host` once and send requests over that one connection
+    /// through the returned [`Session`]
+    pub async fn open_session(
+        &self,
+        host: &str,
+    ) -> Result<Session<'_>, Box<dyn std::error::Error>> {
+        Session::open(self, host, &RequestOptions::default()).await
+    }
+
+    /// Connect to `host` for a caller that does its own HTTP, e.g. hyper,
+    /// through tokio's I/O traits
+    pub async fn open_stream(&self, host: &str) -> Result<PollStream, Box<dyn std::error::Error>> {
+        let session = Session::open(self, host, &RequestOptions::default()).await?;
+        let (transport, buffered, registration) = session.into_transport();
+        registration.set_use(Use::Stream);
+        Ok(PollStream::new(transport, buffered, registration)?)
+    }
+
+    /// Connect to `host` and upgrade `path` to a WebSocket
+    pub async fn open_websocket(
+        &self,
+        host: &str,
+        path: &str,
+    ) -> Result<WssClient, Box<dyn std::error::Error>> {
+        WssClient::connect(self, host, path, &RequestOptions::default()).await
+    }
+
+    /// Send a prepared request, consulting the response cache for GETs
+    pub async fn send(
+        &self,
+        mut request: Request<'_>,
+        options: &RequestOptions,
+    ) -> Result<Response, Box<dyn std::error::Error>> {
+        let cache = match &self.cache {
+            Some(cache) if !options.bypass_cache => cache,
+            _ => return self.https_request(request, options).await,
+        };
+        let key = ResponseCache::key(&request.host, &request.path);
+
+        if request.method != "GET" {
+            let unsafe_method = request.method != "HEAD";
+            let response = self.https_request(request, options).await?;
+            // Unsafe methods invalidate the cached representation (RFC 9111 §4.4)
+            if unsafe_method && (200..400).contains(&response.status) {
+                cache.borrow_mut().invalidate(&key);
+            }
+            return Ok(response);
+        }
+
+        match cache.borrow_mut().lookup(&key, &*self.clock) {
+            Lookup::Fresh(response) => return Ok(*response),
+            Lookup::Stale(validators) => request.headers.extend(&validators.headers()),
+            Lookup::Miss => {}
+        }
+
+        let response = self.https_request(request, options).await?;
+
+        let mut cache = cache.borrow_mut();
+        if response.status == 304 {
+            if let Some(cached) = cache.revalidate(&key, &response, &*self.clock) {
+                return Ok(cached);
+            }
+        } else {
+            cache.store(key, &response, &*self.clock);
+        }
+        Ok(response)
+    }
+
+    /// Run `requests` concurrently, returning their results in the same order
+    ///
+    /// All requests are polled in one pass, so their connects are queued on the
+    /// ring together and go to the kernel in a single `io_uring_enter`; later
+    /// reads and writes batch the same way whenever several are ready at once,
+    /// handshakes' included.
+    pub async fn batch(
+        &self,
+        requests: Vec<Request<'_>>,
+    ) -> Vec<Result<Response, Box<dyn std::error::Error>>> {
+        let mut pending: Vec<_> = requests
+            .into_iter()
+            .map(|request| {
+                Box::pin(async move { self.send(request, &RequestOptions::default()).await })
+            })
+            .collect();
+        let mut results: Vec<_> = pending.iter().map(|_| None).collect();
+
+        std::future::poll_fn(|cx| {
+            let mut done = true;
+            for (slot, request) in results.iter_mut().zip(&mut pending) {
+                if slot.is_none() {
+                    match request.as_mut().poll(cx) {
+                        Poll::Ready(result) => *slot = Some(result),
+                        Poll::Pending => done = false,
+                    }
+                }
+            }
+            if done { Poll::Ready(()) } else { Poll::Pending }
+        })
+        .await;
+
+        results.into_iter().map(Option::unwrap).collect()
+    }
+
+    /// Keep [`StandbyPolicy::ready`] connections to `host` on standby for
+    /// requests to take, connecting a replacement whenever one is taken or
+    /// grows too old, until `ctx` is cancelled or its deadline passes
+    ///
+    /// Run it alongside the requests it serves, in a `join!` say. A failed
+    /// connect or handshake is tried again after a pause that doubles while
+    /// they keep failing, up to the policy's `max_age`.
+    pub async fn keep_warm(&self, host: &str, ctx: &Context) {
+        let options = RequestOptions {
+            context: ctx.clone(),
+            ..Default::default()
+        };
+        let mut pause = Duration::from_secs(1);
+        loop {
+            let (missing, expires) = self.standby.shortfall(host, &*self.clock);
+            if missing == 0 {
+                let expires_in = expires.map(|at| at.saturating_duration_since(self.clock.now()));
+                let wait = async {
+                    match expires_in {
+                        Some(expires_in) => tokio::select! {
+                            _ = tokio::time::sleep(expires_in) => {}
+                            _ = self.standby.taken() => {}
+                        },
+                        None => self.standby.taken().await,
+                    }
+                };
+                if ctx.run("standby", wait).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+
+            match self.warm_up(host, &options).await {
+                Ok(warm) => {
+                    self.standby.add(host, warm);
+                    pause = Duration::from_secs(1);
+                }
+                Err(e) if e.is::<ContextError>() => return,
+                Err(e) => {
+                    if self.controls.verbose() {
+                        println!(
+                            "Standby connection to {host} failed ({e}), retrying in {pause:?}"
+                        );
+                    }
+                    if ctx.run("standby", tokio::time::sleep(pause)).await.is_err() {
+                        return;
+                    }
+                    pause = (pause * 2).min(self.standby.policy().max_age);
+                }
+            }
+        }
+    }
+
+    /// A new connection to `host` for [`keep_warm`](Self::keep_warm)
+    async fn warm_up(
+        &self,
+        host: &str,
+        options: &RequestOptions,
+    ) -> Result<Warm, Box<dyn std::error::Error>> {
+        // Counted by the balancer again once a request takes it
+        let Established {
+            transport,
+            connection,
+            keying_material,
+            ..
+        } = self.establish_any(host, options).await?;
+        let registration =
+            self.registry
+                .register(host, &connection, transport.fd(), Use::Standby)?;
+        Ok(Warm {
+            transport,
+            connection,
+            keying_material,
+            registration,
+            opened: self.clock.now(),
+        })
+    }
+
+    /// GET that only transfers the body if it changed since `validators` were taken
+    ///
+    /// Bypasses the response cache; the caller owns the validators, typically
+    /// refreshed with [`Validators::from_response`] after each `Modified` result.
+    pub async fn get_conditional(
+        &self,
+        host: &str,
+        path: &str,
+        validators: &Validators,
+    ) -> Result<Conditional, Box<dyn std::error::Error>> {
+        let mut request = Request::new("GET", host, path);
+        request.headers = validators.headers();
+        let response = self
+            .https_request(request, &RequestOptions::default())
+            .await?;
+
+        if response.status == 304 {
+            Ok(Conditional::NotModified)
+        } else {
+            Ok(Conditional::Modified(Box::new(response)))
+        }
+    }
+
+    /// Send `request`, following redirects as far as
+    /// [`with_redirects`](Self::with_redirects) allows, each over a new
+    /// connection, and recording them on the final response
+    async fn https_request(
+        &self,
+        mut request: Request<'_>,
+        options: &RequestOptions,
+    ) -> Result<Response, Box<dyn std::error::Error>> {
+        let mut redirects = Vec::new();
+        loop {
+            let follow = redirects.len() < self.max_redirects;
+            let from = format!("https://{}{}", request.host, request.path);
+            let (method, host) = (request.method.clone(), request.host.clone());
+            let mut headers = request.headers.clone();
+            let replay = if follow { request.try_clone() } else { None };
+
+            let started = Instant::now();
+            let mut response = self.exchange(request, options).await?;
+            let target = match response.status {
+                301 | 302 | 303 | 307 | 308 if follow => response
+                    .header("Location")
+                    .filter(|location| !location.starts_with("wss://"))
+                    .and_then(|location| redirect_target(location, &host)),
+                _ => None,
+            };
+            // 307 and 308 resend the request as it was; the others turn it
+            // into a bodiless GET (RFC 9110 §15.4)
+            let next = target.and_then(|(to_host, to_path)| match response.status {
+                307 | 308 => replay.map(|mut replay| {
+                    (replay.host, replay.path) = (to_host, to_path);
+                    replay
+                }),
+                _ => {
+                    let method = if method == "HEAD" { "HEAD" } else { "GET" };
+                    Some(Request::new(method, &to_host, &to_path))
+                }
+            });
+            let Some(mut next) = next else {
+                response.redirects = redirects;
+                return Ok(response);
+            };
+
+            let to = format!("https://{}{}", next.host, next.path);
+            if self.controls.verbose() {
+                println!("{} redirected {from} to {to}", response.status);
+            }
+            redirects.push(Redirect {
+                from,
+                to,
+                status: response.status,
+                elapsed: started.elapsed(),
+            });
+            if next.host != host {
+                // Credentials were for the old host
+                headers.remove("Authorization");
+                headers.remove("Cookie");
+            }
+            next.headers = headers;
+            request = next;
+        }
+    }
+
+    /// Send `request`, retrying throttled responses under the retry policy
+    async fn exchange(
+        &self,
+        mut request: Request<'_>,
+        options: &RequestOptions,
+    ) -> Result<Response, Box<dyn std::error::Error>> {
+        let mut attempt = 1;
+        loop {
+            let replay = self.retry.as_ref().and_then(|_| request.try_clone());
+            let response = self.attempt(request, options).await?;
+
+            let verdict = self
+                .verifier
+                .as_ref()
+                .map_or(Verdict::Accept, |verify| verify(&response));
+            let delay = match verdict {
+                Verdict::Accept => self
+                    .retry
+                    .as_ref()
+                    .and_then(|policy| policy.delay(&response, attempt, &*self.rng)),
+                Verdict::Retry => {
+                    let backoff = self
+                        .retry
+                        .as_ref()
+                        .and_then(|p| p.backoff(attempt, &*self.rng));
+                    if backoff.is_none() || replay.is_none() {
+                        return Err(Rejected {
+                            status: response.status,
+                            reason: "verifier asked for a retry, but none is left".to_owned(),
+                        }
+                        .into());
+                    }
+                    backoff
+                }
+                Verdict::Fail(reason) => {
+                    return Err(Rejected {
+                        status: response.status,
+                        reason,
+                    }
+                    .into());
+                }
+            };
+            match (delay, replay) {
+                (Some(delay), Some(next)) => {
+                    if self.controls.verbose() {
+                        println!(
+                            "Retrying {} response in {:.1}s",
+                            response.status,
+                            delay.as_secs_f64()
+                        );
+                    }
+                    options
+                        .context
+                        .run("retry wait", tokio::time::sleep(delay))
+                        .await?;
+                    request = next;
+                    attempt += 1;
+                }
+                _ => return Ok(response),
+            }
+        }
+    }
+
+    /// One connection carrying one request
+    async fn attempt(
+        &self,
+        request: Request<'_>,
+        options: &RequestOptions,
+    ) -> Result<Response, Box<dyn std::error::Error>> {
+        self.attempt_on(None, request, options).await
+    }
+
+    /// [`attempt`](Self::attempt), over `stream` if given rather than a
+    /// connection of the client's own
+    ///
+    /// Without `stream`, the request may go over a connection from the
+    /// [`pool`], and leave its own there once the response is in.
+    async fn attempt_on(
+        &self,
+        mut stream: Option<std::net::TcpStream>,
+        mut request: Request<'_>,
+        options: &RequestOptions,
+    ) -> Result<Response, Box<dyn std::error::Error>> {
+        let started = Instant::now();
+        let pooled = stream.is_none()
+            && self.pool.policy().max_idle > 0
+            && request.method != "HEAD"
+            && options.endpoints.is_empty()
+            && options.exporters.is_empty()
+            && options.traffic_class.is_none();
+        let mut headers = HeaderMap::new();
+        headers.append("User-Agent", "ktls-uring-demo/0.1");
+        headers.append("Accept-Encoding", "gzip, deflate");
+        headers.extend(&request.headers);
+        if !pooled {
+            headers.append("Connection", "close");
+        }
+        request.headers = headers;
+        if let Some(trace) = options.context.trace() {
+            trace.child()?.inject(&mut request.headers);
+        }
+        if let Some(min_size) = self.compress_requests {
+            request.gzip_body(min_size);
+        }
+        let encoded = request.encode();
+        if let Some(log) = &self.wire_log {
+            log.sent(&request.host, &encoded);
+        }
+        if let Some(cassette) = &self.cassette
+            && cassette.is_replay()
+        {
+            let raw = cassette.take(&request.method, &request.host, &request.path)?;
+            return Ok(self.parse_response(&raw)?);
+        }
+
+        // A pooled connection is only worth trying when the request can be
+        // sent again over a new one, should the server have closed it
+        let mut reuse = pooled && request.body.replayable();
+        loop {
+            let (established, reused) = match stream.take() {
+                Some(stream) => (
+                    self.establish_on(stream, &request.host, options).await?,
+                    None,
+                ),
+                None => {
+                    self.establish_reusing(&request.host, options, reuse)
+                        .await?
+                }
+            };
+            let replay = reused.and_then(|_| request.try_clone());
+            let Request {
+                method,
+                host,
+                path,
+                body,
+                ..
+            } = request;
+
+            // The lease counts the connection against its address until the
+            // response is in
+            let Established {
+                mut transport,
+                connection,
+                lease: _lease,
+                slot: _slot,
+                admission,
+                timing,
+                keying_material,
+            } = established;
+            let opened = reused.map_or_else(|| self.clock.now(), |reused| reused.opened);
+            let _registration =
+                self.registry
+                    .register(&host, &connection, transport.fd(), Use::Request)?;
+            let _watch = options
+                .on_backpressure
+                .as_ref()
+                .map(|alert| alert.watch(transport.fd(), connection.peer))
+                .transpose()?;
+            let received = match &mut transport {
+                Transport::Ktls(stream) => self.ktls_request(stream, &encoded, body, options).await,
+                Transport::Userspace(tls) => {
+                    self.userspace_request(tls, &encoded, body, options).await
+                }
+            };
+            if let Some(replay) = replay
+                && closed_while_idle(&received)
+            {
+                if self.controls.verbose() {
+                    println!(
+                        "Pooled connection to {} was closed, reconnecting",
+                        connection.peer
+                    );
+                }
+                request = replay;
+                reuse = false;
+                continue;
+            }
+            let Received {
+                raw,
+                rest,
+                unclean_close,
+                complete,
+                ttfb,
+            } = received?;
+            if let Some(log) = &self.wire_log {
+                log.received(&host, &[raw.as_slice(), &rest].concat());
+            }
+            if let Some(cassette) = &self.cassette {
+                cassette.save(&method, &host, &path, &[raw.as_slice(), &rest].concat())?;
+            }
+            let close_policy = unclean_close
+                .then(|| self.judge_close(&host, &raw, options))
+                .transpose()?;
+            let mut response = if method == "HEAD" {
+                self.parse_response_to(&method, &raw)?
+            } else {
+                self.parse_split_response(&raw, rest)?
+            };
+            response.close_policy = close_policy;
+            if let Some(admission) = admission {
+                if response.status >= 500 {
+                    admission.failed();
+                } else {
+                    admission.succeeded();
+                }
+            }
+            response.connection = Some(connection);
+            response.timing = Some(Timing {
+                ttfb,
+                total: started.elapsed(),
+                ..timing
+            });
+
+            let requests = reused.map_or(0, |reused| reused.requests) + 1;
+            let now = self.clock.now();
+            if pooled
+                && complete
+                && response.reusable()
+                && !self.recycle.spent(opened, requests, now)
+                && let Ok(registration) =
+                    self.registry
+                        .register(&host, &connection, transport.fd(), Use::Idle)
+            {
+                self.pool.put(
+                    &host,
+                    Idle {
+                        transport,
+                        connection,
+                        keying_material,
+                        registration,
+                        opened,
+                        requests,
+                        since: now,
+                        server_timeout: response.keep_alive_timeout(),
+                    },
+                    self.nodelay.unwrap_or(false),
+                );
+            }
+            return Ok(response);
+        }
+    }
+
+    /// Connect and handshake with `host`, ready for a request: over kTLS
+    /// where it can be set up, userspace TLS otherwise
+    ///
+    /// With endpoints in `options`, they are tried in order until one
+    /// connects and handshakes, and the connection records which one did.
+    /// With a circuit breaker, fails at once while the host's circuit is open.
+    /// A standby connection to `host` is used instead when one is ready.
+    pub(crate) async fn establish(
+        &self,
+        host: &str,
+        options: &RequestOptions,
+    ) -> Result<Established<'_>, Box<dyn std::error::Error>> {
+        let (established, _) = self.establish_reusing(host, options, false).await?;
+        Ok(established)
+    }
+
+    /// [`establish`](Self::establish), but with `reuse` over an idle
+    /// connection from the [`pool`] when there is one, along with what it
+    /// has been through
+    async fn establish_reusing(
+        &self,
+        host: &str,
+        options: &RequestOptions,
+        reuse: bool,
+    ) -> Result<(Established<'_>, Option<Reused>), Box<dyn std::error::Error>> {
+        if let Some(cassette) = &self.cassette
+            && cassette.is_replay()
+        {
+            return Err(CassetteError::Offline {
+                host: host.to_owned(),
+            }
+            .into());
+        }
+        let slot = self.slot(options).await?;
+        let admission = self.breaker.as_ref().map(|b| b.admit(host)).transpose()?;
+        if reuse
+            && let Some((mut established, reused)) =
+                self.pool
+                    .take(host, options, self.nodelay.unwrap_or(false), &*self.clock)
+        {
+            if self.controls.verbose() {
+                println!("Reusing a connection to {}", established.connection.peer);
+            }
+            established.admission = admission;
+            established.slot = slot;
+            return Ok((established, Some(reused)));
+        }
+        if let Some(mut established) = self.standby.take(host, options, &*self.clock) {
+            if self.controls.verbose() {
+                println!(
+                    "Using a standby connection to {}",
+                    established.connection.peer
+                );
+            }
+            established.admission = admission;
+            established.slot = slot;
+            return Ok((established, None));
+        }
+        match self.establish_any(host, options).await {
+            Ok(mut established) => {
+                established.admission = admission;
+                established.slot = slot;
+                Ok((established, None))
+            }
+            Err(e) => {
+                let cancelled = matches!(e.downcast_ref(), Some(ContextError::Cancelled(_)));
+                if let Some(admission) = admission
+                    && !cancelled
+                {
+                    admission.failed();
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// A connection slot, when the client has a limit, once one is free
+    /// for the options' priority
+    async fn slot(&self, options: &RequestOptions) -> Result<Option<Slot<'_>>, ContextError> {
+        match &self.connection_limit {
+            Some(limit) => {
+                let slot = options
+                    .context
+                    .run("queue", limit.acquire(options.priority))
+                    .await?;
+                Ok(Some(slot))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// [`establish`](Self::establish) through the first endpoint that works
+    async fn establish_any(
+        &self,
+        host: &str,
+        options: &RequestOptions,
+    ) -> Result<Established<'_>, Box<dyn std::error::Error>> {
+        if options.endpoints.is_empty() {
+            return self
+                .establish_via(host, &Endpoint::new(host, 443), options)
+                .await;
+        }
+
+        let mut last_error = None;
+        for (i, endpoint) in options.endpoints.iter().enumerate() {
+            match self.establish_via(host, endpoint, options).await {
+                Ok(mut established) => {
+                    established.connection.endpoint = Some(i);
+                    return Ok(established);
+                }
+                // Out of time for every endpoint, not just this one
+                Err(e) if e.is::<ContextError>() => return Err(e),
+                Err(e) => {
+                    if self.controls.verbose() {
+                        println!("Endpoint {endpoint} failed ({e})");
+                    }
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(EndpointsExhausted {
+            tried: options.endpoints.len(),
+            last: options.endpoints[options.endpoints.len() - 1].clone(),
+            error: last_error.expect("at least one endpoint"),
+        }
+        .into())
+    }
+
+    /// [`establish`](Self::establish) through one endpoint
+    async fn establish_via(
+        &self,
+        host: &str,
+        endpoint: &Endpoint,
+        options: &RequestOptions,
+    ) -> Result<Established<'_>, Box<dyn std::error::Error>> {
+        let ctx = &options.context;
+        let server_name = ServerName::try_from(host.to_owned())?;
+
+        let mut timing = Timing::default();
+        let policy = self.controls.ktls();
+        if policy != KtlsPolicy::Off {
+            let started = Instant::now();
+            let mut lease = self.lease(endpoint, ctx).await?;
+            timing.dns += started.elapsed();
+            let addr = lease.addr;
+            if self.controls.verbose() {
+                println!("Connecting to {addr} via io_uring");
+            }
+
+            // io_uring-based async TCP connect
+            let started = Instant::now();
+            let (stream, mptcp) = ctx.run("connect", self.connect(addr, options)).await??;
+            timing.connect += started.elapsed();
+            let fd = stream.as_raw_fd();
+
+            let phase = markers::phase("handshake");
+            let started = Instant::now();
+            let handshake = handshake::perform_handshake(
+                &stream,
+                self.tls_config.clone(),
+                server_name.clone(),
+                &options.exporters,
+            );
+            let handshake = ctx.run("handshake", handshake).await?;
+            timing.tls_handshake += started.elapsed();
+            drop(phase);
+            match handshake {
+                Ok(result) => {
+                    lease.connected();
+                    let version = ktls::tls_version(result.version);
+
+                    let phase = markers::phase("ktls-setup");
+                    let started = Instant::now();
+                    let setup = ktls::configure_ktls(fd, result.tx, result.rx, version);
+                    timing.ktls_setup += started.elapsed();
+                    drop(phase);
+                    ctx.check("kTLS setup")?;
+                    match setup {
+                        Ok(()) => {
+                            if self.controls.verbose() {
+                                println!("Using kTLS (kernel TLS) + io_uring");
+                            }
+                            return Ok(Established {
+                                transport: Transport::Ktls(stream),
+                                connection: ConnectionInfo {
+                                    peer: addr,
+                                    ktls: true,
+                                    mptcp,
+                                    endpoint: None,
+                                    send_queue: None,
+                                },
+                                lease,
+                                // Taken by `establish`
+                                slot: None,
+                                admission: None,
+                                timing,
+                                keying_material: result.keying_material,
+                            });
+                        }
+                        Err(e) if policy == KtlsPolicy::Require => return Err(e.into()),
+                        Err(e) => {
+                            eprintln!("kTLS setup failed ({e}), using userspace TLS fallback")
+                        }
+                    }
+                }
+                Err(e) if policy == KtlsPolicy::Require => return Err(e.into()),
+                Err(e) => eprintln!("kTLS handshake failed ({e}), using userspace TLS fallback"),
+            }
+        }
+
+        // Fallback path: a new connection, with rustls driven over io_uring
+        // reads and writes
+        let started = Instant::now();
+        let mut lease = self.lease(endpoint, ctx).await?;
+        timing.dns += started.elapsed();
+        let addr = lease.addr;
+        if self.controls.verbose() {
+            println!("Connecting to {addr} for userspace TLS");
+        }
+        let started = Instant::now();
+        let (stream, mptcp) = ctx.run("connect", self.connect(addr, options)).await??;
+        timing.connect += started.elapsed();
+        let conn = ClientConnection::new(self.tls_config.clone(), server_name)?;
+        let mut tls = UringTlsStream::new(stream, conn);
+        let phase = markers::phase("handshake");
+        let started = Instant::now();
+        ctx.run("handshake", tls.handshake()).await??;
+        timing.tls_handshake += started.elapsed();
+        drop(phase);
+        lease.connected();
+        let keying_material = handshake::export(tls.conn(), &options.exporters)?;
+        Ok(Established {
+            transport: Transport::Userspace(Box::new(tls)),
+            connection: ConnectionInfo {
+                peer: addr,
+                ktls: false,
+                mptcp,
+                endpoint: None,
+                send_queue: None,
+            },
+            lease,
+            slot: None,
+            admission: None,
+            timing,
+            keying_material,
+        })
+    }
+
+    /// [`establish`](Self::establish) over a connection made elsewhere
+    ///
+    /// The one socket has to serve whichever TLS ends up being used, so the
+    /// TLS ULP is enabled before the handshake: if the kernel refuses it,
+    /// the handshake can still go ahead in userspace. Once keys are being
+    /// handed to the kernel there is no going back, so a failure then fails
+    /// the connection whatever the kTLS policy.
+    pub(crate) async fn establish_on(
+        &self,
+        stream: std::net::TcpStream,
+        host: &str,
+        options: &RequestOptions,
+    ) -> Result<Established<'_>, Box<dyn std::error::Error>> {
+        let ctx = &options.context;
+        let slot = self.slot(options).await?;
+        let server_name = ServerName::try_from(host.to_owned())?;
+        let peer = stream.peer_addr()?;
+        let stream = TcpStream::from_std(stream);
+        let fd = stream.as_raw_fd();
+        if let Some(class) = options.traffic_class.or(self.traffic_class) {
+            class.apply(fd, &peer)?;
+        }
+        if let Some(nodelay) = options.nodelay.or(self.nodelay) {
+            connect::set_nodelay(fd, nodelay)?;
+        }
+        let mut lease = Lease::unbalanced(peer);
+        let connection = ConnectionInfo {
+            peer,
+            ktls: false,
+            mptcp: connect::is_mptcp(fd),
+            endpoint: None,
+            send_queue: None,
+        };
+
+        // Connecting happened elsewhere, so only the TLS phases are timed
+        let mut timing = Timing::default();
+        let policy = self.controls.ktls();
+        if policy != KtlsPolicy::Off {
+            let started = Instant::now();
+            let ulp = ktls::enable_ulp(fd);
+            timing.ktls_setup += started.elapsed();
+            match ulp {
+                Ok(()) => {
+                    let phase = markers::phase("handshake");
+                    let started = Instant::now();
+                    let handshake = handshake::perform_handshake(
+                        &stream,
+                        self.tls_config.clone(),
+                        server_name,
+                        &options.exporters,
+                    );
+                    let result = ctx.run("handshake", handshake).await??;
+                    timing.tls_handshake += started.elapsed();
+                    drop(phase);
+                    let version = ktls::tls_version(result.version);
+                    let phase = markers::phase("ktls-setup");
+                    let started = Instant::now();
+                    ktls::configure_keys(fd, result.tx, result.rx, version)?;
+                    timing.ktls_setup += started.elapsed();
+                    drop(phase);
+                    lease.connected();
+                    let keying_material = result.keying_material;
+                    if self.controls.verbose() {
+                        println!("Using kTLS (kernel TLS) + io_uring on a supplied connection");
+                    }
+                    return Ok(Established {
+                        transport: Transport::Ktls(stream),
+                        connection: ConnectionInfo {
+                            ktls: true,
+                            ..connection
+                        },
+                        lease,
+                        slot,
+                        admission: None,
+                        timing,
+                        keying_material,
+                    });
+                }
+                Err(e) if policy == KtlsPolicy::Require => return Err(e.into()),
+                Err(e) => eprintln!("kTLS unavailable ({e}), using userspace TLS"),
+            }
+        }
+
+        let conn = ClientConnection::new(self.tls_config.clone(), server_name)?;
+        let mut tls = UringTlsStream::new(stream, conn);
+        let phase = markers::phase("handshake");
+        let started = Instant::now();
+        ctx.run("handshake", tls.handshake()).await??;
+        timing.tls_handshake += started.elapsed();
+        drop(phase);
+        lease.connected();
+        let keying_material = handshake::export(tls.conn(), &options.exporters)?;
+        Ok(Established {
+            transport: Transport::Userspace(Box::new(tls)),
+            connection,
+            lease,
+            slot,
+            admission: None,
+            timing,
+            keying_material,
+        })
+    }
+
+    /// Resolve `endpoint` and choose the address for a new connection to it
+    async fn lease(
+        &self,
+        endpoint: &Endpoint,
+        ctx: &Context,
+    ) -> Result<Lease<'_>, Box<dyn std::error::Error>> {
+        let addrs = self
+            .resolver
+            .resolve(&endpoint.host, endpoint.port, ctx, &*self.clock)
+            .await?;
+        Ok(match &self.balancer {
+            Some(balancer) => balancer.pick(&endpoint.host, &addrs, &*self.rng),
+            None => Lease::unbalanced(addrs[0]),
+        })
+    }
+
+    /// TCP connect, through Fast Open and MPTCP when enabled, with the traffic
+    /// class applied; also returns whether MPTCP was negotiated
+    async fn connect(
+        &self,
+        addr: SocketAddr,
+        options: &RequestOptions,
+    ) -> std::io::Result<(TcpStream, bool)> {
+        let _phase = markers::phase("connect");
+        let mptcp = self.mptcp && connect::mptcp_supported();
+        if self.mptcp && !mptcp && self.controls.verbose() {
+            println!("MPTCP unavailable on this kernel, connecting with TCP");
+        }
+
+        let mut stream = None;
+        if self.fast_open {
+            match connect::connect(addr, true, mptcp).await {
+                Ok(std_stream) => stream = Some(std_stream),
+                Err(e) if connect::fast_open_unsupported(&e) => {
+                    if self.controls.verbose() {
+                        println!("TCP Fast Open unavailable ({e}), connecting normally");
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        if stream.is_none() && mptcp {
+            stream = Some(connect::connect(addr, false, true).await?);
+        }
+        let stream = match stream {
+            Some(std_stream) => TcpStream::from_std(std_stream),
+            None => {
+                stats::uring_submitted();
+                let stream = TcpStream::connect(addr).await;
+                stats::uring_completed();
+                stream?
+            }
+        };
+
+        if let Some(class) = options.traffic_class.or(self.traffic_class) {
+            class.apply(stream.as_raw_fd(), &addr)?;
+        }
+        if let Some(nodelay) = options.nodelay.or(self.nodelay) {
+            connect::set_nodelay(stream.as_raw_fd(), nodelay)?;
+        }
+        let mptcp = mptcp && connect::is_mptcp(stream.as_raw_fd());
+        Ok((stream, mptcp))
+    }
+
+    /// kTLS path: kernel handles encryption, use io_uring for I/O; the body
+    /// is read apart from the head with
+    /// [`with_split_reads`](Self::with_split_reads)
+    async fn ktls_request(
+        &self,
+        stream: &TcpStream,
+        request: &[u8],
+        mut body: Body<'_>,
+        options: &RequestOptions,
+    ) -> Result<Received, Box<dyn std::error::Error>> {
+        let ctx = &options.context;
+        let mut upload = UploadProgress::new(request, &body, options);
+
+        // Send request via io_uring (kernel encrypts)
+        let phase = markers::phase("write");
+        let sent = Instant::now();
+        ctx.run("write", self.write_chunk(stream, request.to_vec()))
+            .await??;
+        upload.sent(request.len());
+
+        while let Some(chunk) = ctx.run("write", body.next_chunk()).await? {
+            let len = chunk.len();
+            ctx.run("write", self.write_chunk(stream, chunk)).await??;
+            upload.sent(len);
+        }
+
+        // Read response via io_uring (kernel decrypts)
+        drop(phase);
+        let _phase = markers::phase("read");
+        let mut download = DownloadProgress::new(options);
+        let mut hints = EarlyHints::new(options);
+        let mut quantum = ReadQuantum::new(self.read_quantum);
+        if let (Some(head_size), Some(ring)) = (self.head_buffer, &self.recv_ring) {
+            let mut head = Vec::new();
+            let mut rest = Vec::new();
+            let mut head_limit = head_size;
+            // Most of the body one read takes; a Content-Length the server
+            // chose doesn't get to size the buffer up front
+            let chunk = 16 * 1024;
+            // Total length once the head is in, see http::expected_len
+            let mut total: Option<Option<usize>> = None;
+            let mut unclean_close = false;
+            let mut complete = false;
+            let mut ttfb = None;
+            loop {
+                let (head_room, body_room) = match total {
+                    None => (head_limit - head.len(), chunk),
+                    Some(None) => (0, chunk),
+                    Some(Some(len)) => match len.saturating_sub(head.len() + rest.len()) {
+                        0 => {
+                            complete = head.len() + rest.len() == len;
+                            break;
+                        }
+                        remaining => (0, remaining.min(chunk)),
+                    },
+                };
+                let read = ring.recv_split(
+                    stream.as_raw_fd(),
+                    &mut head,
+                    head_room,
+                    &mut rest,
+                    body_room,
+                    self.io_timeout,
+                );
+                match ctx.run("read", read).await? {
+                    Ok(0) => break, // EOF
+                    Ok(n) => {
+                        ttfb.get_or_insert_with(|| sent.elapsed());
+                        if total.is_none() {
+                            if http::expected_len(&head).is_none() {
+                                // The head goes on past its buffer, or ends
+                                // just inside the body's
+                                head.append(&mut rest);
+                                if head.len() >= head_limit {
+                                    head_limit = head.len() + head_size;
+                                }
+                            }
+                            total = http::expected_len(&head);
+                        }
+                        download.update_split(&head, head.len() + rest.len());
+                        hints.update(&head);
+                        quantum.consumed(n).await;
+                        // A chunked or bodiless response, complete once it
+                        // ends in a blank line
+                        let tail = if rest.is_empty() { &head } else { &rest };
+                        if total == Some(None) && tail.ends_with(b"\r\n\r\n") {
+                            let response = [head.as_slice(), &rest].concat();
+                            if http::response_complete(&response) {
+                                complete = http::response_len(&response) == Some(response.len());
+                                break;
+                            }
+                        }
+                    }
+                    Err(e) if session::closed_without_notify(&e) && !head.is_empty() => {
+                        unclean_close = true;
+                        break;
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            return Ok(Received {
+                raw: head,
+                rest,
+                unclean_close,
+                complete,
+                ttfb: ttfb.unwrap_or_default(),
+            });
+        }
+
+        let mut response = Vec::new();
+        let mut unclean_close = false;
+        let mut complete = false;
+        let mut ttfb = None;
+        loop {
+            match ctx
+                .run("read", self.read_chunk(stream, &mut response))
+                .await?
+            {
+                Ok(0) => break, // EOF
+                Ok(n) => {
+                    ttfb.get_or_insert_with(|| sent.elapsed());
+                    download.update(&response);
+                    hints.update(&response);
+                    quantum.consumed(n).await;
+                    if http::response_complete(&response) {
+                        complete = http::response_len(&response) == Some(response.len());
+                        break;
+                    }
+                }
+                // kTLS returns EIO when the connection closes without
+                // close_notify, common with "Connection: close"; the
+                // request's ClosePolicy decides on what arrived
+                Err(e) if session::closed_without_notify(&e) && !response.is_empty() => {
+                    unclean_close = true;
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(Received {
+            raw: response,
+            rest: Vec::new(),
+            unclean_close,
+            complete,
+            ttfb: ttfb.unwrap_or_default(),
+        })
+    }
+
+    /// Write all of `data` via io_uring, with a linked timeout if configured
+    pub(crate) async fn write_chunk(
+        &self,
+        stream: &TcpStream,
+        data: Vec<u8>,
+    ) -> std::io::Result<()> {
+        if let (Some(ring), Some(timeout)) = (&self.recv_ring, self.io_timeout) {
+            let guard = fd::WriteGuard::new(fd::borrow(stream));
+            ring.send(stream.as_raw_fd(), data, Some(timeout)).await?;
+            guard.finish();
+            return Ok(());
+        }
+        fd::write_all(stream, data).await
+    }
+
+    /// One io_uring read appended to `out`, through a provided or registered
+    /// buffer if configured
+    pub(crate) async fn read_chunk(
+        &self,
+        stream: &TcpStream,
+        out: &mut Vec<u8>,
+    ) -> std::io::Result<usize> {
+        if let Some(ring) = &self.recv_ring {
+            return ring.recv(stream.as_raw_fd(), out, self.io_timeout).await;
+        }
+
+        stats::uring_submitted();
+        let result = match &self.buffers {
+            Some(pool) => {
+                let (result, buf) = stream.read_fixed(pool.next().await).await;
+                result.inspect(|&n| out.extend_from_slice(&buf[..n]))
+            }
+            None => {
+                let (result, buf) = stream.read(vec![0u8; 8192]).await;
+                result.inspect(|&n| out.extend_from_slice(&buf[..n]))
+            }
+        };
+        stats::uring_completed();
+        result
+    }
+
+    /// Userspace TLS path: rustls encrypts, driven over io_uring reads and
+    /// writes
+    async fn userspace_request(
+        &self,
+        tls: &mut UringTlsStream<ClientConnection>,
+        request: &[u8],
+        mut body: Body<'_>,
+        options: &RequestOptions,
+    ) -> Result<Received, Box<dyn std::error::Error>> {
+        let ctx = &options.context;
+        let mut upload = UploadProgress::new(request, &body, options);
+        let phase = markers::phase("write");
+        let sent = Instant::now();
+        ctx.run("write", tls.write_all(request)).await??;
+        upload.sent(request.len());
+        while let Some(chunk) = ctx.run("write", body.next_chunk()).await? {
+            ctx.run("write", tls.write_all(&chunk)).await??;
+            upload.sent(chunk.len());
+        }
+        drop(phase);
+
+        let _phase = markers::phase("read");
+        let mut download = DownloadProgress::new(options);
+        let mut hints = EarlyHints::new(options);
+        let mut quantum = ReadQuantum::new(self.read_quantum);
+        let mut response = Vec::new();
+        let mut unclean_close = false;
+        let mut complete = false;
+        let mut ttfb = None;
+        let mut buf = vec![0u8; 8192];
+        loop {
+            match ctx.run("read", tls.read(&mut buf)).await? {
+                Ok(0) => break,
+                Ok(n) => {
+                    ttfb.get_or_insert_with(|| sent.elapsed());
+                    response.extend_from_slice(&buf[..n]);
+                    download.update(&response);
+                    hints.update(&response);
+                    quantum.consumed(n).await;
+                    if http::response_complete(&response) {
+                        complete = http::response_len(&response) == Some(response.len());
+                        break;
+                    }
+                }
+                Err(e) if session::closed_without_notify(&e) && !response.is_empty() => {
+                    unclean_close = true;
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(Received {
+            raw: response,
+            rest: Vec::new(),
+            unclean_close,
+            complete,
+            ttfb: ttfb.unwrap_or_default(),
+        })
+    }
+
+    pub async fn get(
+        &self,
+        host: &str,
+        path: &str,
+    ) -> Result<Response, Box<dyn std::error::Error>> {
+        self.request("GET", host, path, Body::Empty, &RequestOptions::default())
+            .await
+    }
+
+    /// GET `path` from `host` over a connection of its own, returning once
+    /// the response head is in, with the body streaming behind it; reading
+    /// holds off while `max_buffered` bytes of it are waiting to be taken,
+    /// see [`Session::send_streaming`]
+    pub async fn get_streaming(
+        &self,
+        host: &str,
+        path: &str,
+        max_buffered: usize,
+    ) -> Result<StreamedResponse, Box<dyn std::error::Error>> {
+        let options = RequestOptions::default();
+        let session = Session::open(self, host, &options).await?;
+        let request = Request::new("GET", host, path);
+        session
+            .send_streaming(request, &options, max_buffered)
+            .await
+    }
+
+    pub async fn post(
+        &self,
+        host: &str,
+        path: &str,
+        body: &str,
+    ) -> Result<Response, Box<dyn std::error::Error>> {
+        self.request(
+            "POST",
+            host,
+            path,
+            Body::Json(body),
+            &RequestOptions::default(),
+        )
+        .await
+    }
+
+    pub async fn put(
+        &self,
+        host: &str,
+        path: &str,
+        body: &str,
+    ) -> Result<Response, Box<dyn std::error::Error>> {
+        self.request(
+            "PUT",
+            host,
+            path,
+            Body::Json(body),
+            &RequestOptions::default(),
+        )
+        .await
+    }
+
+    pub async fn patch(
+        &self,
+        host: &str,
+        path: &str,
+        body: &str,
+    ) -> Result<Response, Box<dyn std::error::Error>> {
+        self.request(
+            "PATCH",
+            host,
+            path,
+            Body::Json(body),
+            &RequestOptions::default(),
+        )
+        .await
+    }
+
+    /// POST a body streamed from `chunks` as another task produces it
+    pub async fn post_stream(
+        &self,
+        host: &str,
+        path: &str,
+        chunks: mpsc::Receiver<Vec<u8>>,
+    ) -> Result<Response, Box<dyn std::error::Error>> {
+        let body = Body::Channel(chunks, Trailers::new());
+        self.request("POST", host, path, body, &RequestOptions::default())
+            .await
+    }
+
+    pub async fn delete(
+        &self,
+        host: &str,
+        path: &str,
+    ) -> Result<Response, Box<dyn std::error::Error>> {
+        self.request(
+            "DELETE",
+            host,
+            path,
+            Body::Empty,
+            &RequestOptions::default(),
+        )
+        .await
+    }
+}
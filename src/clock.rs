@@ -0,0 +1,87 @@
+//! Where the client gets the time from
+//!
+//! Cache freshness and WebSocket heartbeats read the time through a
+//! [`Clock`] rather than straight from the system, so a test can step time
+//! forward with a [`ManualClock`] instead of sleeping, and a service whose
+//! host clock is known to be off (by NTP, say) can run on a
+//! [`SystemClock`] corrected by that offset.
+//!
+//! A clock has two readings: monotonic time for ages and intervals, and
+//! wall time for the dates exchanged with servers. Only wall time is
+//! corrected; skew has no bearing on how long something took.
+
+#[cfg(test)]
+use std::cell::Cell;
+#[cfg(test)]
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime};
+
+pub trait Clock {
+    /// Monotonic time, for ages and intervals
+    fn now(&self) -> Instant;
+    /// Calendar time, for comparing against `Date`, `Expires` and the like
+    fn wall(&self) -> SystemTime;
+}
+
+/// The system's clocks, with wall time optionally shifted
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock {
+    /// Milliseconds added to the system's wall time
+    offset_ms: i64,
+}
+
+impl SystemClock {
+    /// Shift wall time by `offset_ms`, which is positive when the system
+    /// clock is behind, as NTP reports offsets
+    pub fn with_offset(offset_ms: i64) -> Self {
+        Self { offset_ms }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wall(&self) -> SystemTime {
+        let offset = Duration::from_millis(self.offset_ms.unsigned_abs());
+        if self.offset_ms < 0 {
+            SystemTime::now() - offset
+        } else {
+            SystemTime::now() + offset
+        }
+    }
+}
+
+/// A clock that only moves when told to
+///
+/// Clones share the same time, so a test can keep one and hand the other
+/// to the client.
+#[cfg(test)]
+#[derive(Clone, Debug)]
+pub struct ManualClock(Rc<Cell<(Instant, SystemTime)>>);
+
+#[cfg(test)]
+impl ManualClock {
+    /// Stopped with `wall` as its calendar time
+    pub fn at(wall: SystemTime) -> Self {
+        Self(Rc::new(Cell::new((Instant::now(), wall))))
+    }
+
+    /// Move both readings forward by `by`
+    pub fn advance(&self, by: Duration) {
+        let (now, wall) = self.0.get();
+        self.0.set((now + by, wall + by));
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.0.get().0
+    }
+
+    fn wall(&self) -> SystemTime {
+        self.0.get().1
+    }
+}
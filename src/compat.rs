@@ -0,0 +1,261 @@
+//! Poll-based I/O for libraries built on tokio's traits, such as hyper,
+//! and the client as a tower `Service`
+//!
+//! [`PollStream`] implements tokio's `AsyncRead` and `AsyncWrite` over a
+//! connection set up the usual way, kTLS or userspace TLS. Reads and writes
+//! are nonblocking syscalls driven by socket readiness rather than io_uring
+//! operations, since a poll-based caller may drop any operation at any time
+//! and io_uring would still own its buffer.
+//!
+//! With the `hyper` feature, both also implement hyper's own runtime traits,
+//! so hyper's client handshakes over the stream directly and runs its HTTP/2
+//! background tasks on [`UringExecutor`]. Everything stays on the
+//! tokio-uring thread, so nothing needs to be `Send`.
+//!
+//! With the `tower` feature, `&HttpsClient` is a `Service` for
+//! [`Request`](crate::http::Request)s, so tower middleware (retries, rate
+//! and concurrency limits, load balancing) can wrap it. Its futures borrow the client and, like its
+//! errors, aren't `Send`; layers that need either, such as `Buffer` or
+//! `Timeout`, don't apply.
+//!
+//! With the `stream` feature, the receiver of a WebSocket's
+//! [`WsChannels`](crate::websocket::WsChannels) is a `futures_core::Stream`
+//! of its messages, and a [`BodyStream`](crate::streaming::BodyStream) one
+//! of the pieces of its body, for `StreamExt` combinators.
+
+use std::future::Future;
+use std::io::ErrorKind;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, Interest, ReadBuf};
+
+use crate::introspect::Registration;
+use crate::session::Transport;
+
+/// A TLS connection with tokio's `AsyncRead` and `AsyncWrite`
+pub struct PollStream {
+    /// Dropped before the socket closes
+    readiness: AsyncFd<RawFd>,
+    transport: Transport,
+    /// Plaintext read but not yet handed out
+    buffered: Vec<u8>,
+    shutting_down: bool,
+    /// Keeps the connection in the client's debug state while it is open
+    _registration: Registration,
+}
+
+impl PollStream {
+    /// Wrap an established connection, with any bytes already read from it
+    /// and its registration with the client
+    pub fn new(
+        transport: Transport,
+        buffered: Vec<u8>,
+        registration: Registration,
+    ) -> std::io::Result<Self> {
+        let fd = transport.fd().as_raw_fd();
+        Ok(Self {
+            readiness: AsyncFd::with_interest(fd, Interest::READABLE | Interest::WRITABLE)?,
+            transport,
+            buffered,
+            shutting_down: false,
+            _registration: registration,
+        })
+    }
+
+    /// Make sure `buffered` holds plaintext, unless the peer has closed the
+    /// connection
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while self.buffered.is_empty() {
+            let mut guard = ready!(self.readiness.poll_read_ready(cx))?;
+            match guard.try_io(|_| self.transport.try_read(&mut self.buffered)) {
+                Ok(Ok(0)) => break,
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => return Poll::Ready(Err(e)),
+                Err(_would_block) => {}
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Hand out up to `max` buffered bytes through `put`
+    fn take(&mut self, max: usize, put: impl FnOnce(&[u8])) {
+        let n = self.buffered.len().min(max);
+        put(&self.buffered[..n]);
+        self.buffered.drain(..n);
+    }
+
+    /// Run `op` once the socket is writable, until it stops failing with
+    /// `WouldBlock`
+    fn poll_write_with<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        mut op: impl FnMut(&mut Transport) -> std::io::Result<T>,
+    ) -> Poll<std::io::Result<T>> {
+        loop {
+            let mut guard = ready!(self.readiness.poll_write_ready(cx))?;
+            if let Ok(result) = guard.try_io(|_| op(&mut self.transport)) {
+                return Poll::Ready(result);
+            }
+        }
+    }
+}
+
+impl AsyncRead for PollStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_fill(cx))?;
+        this.take(buf.remaining(), |data| buf.put_slice(data));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for PollStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.get_mut()
+            .poll_write_with(cx, |transport| transport.try_write(buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.get_mut().poll_write_with(cx, Transport::try_flush)
+    }
+
+    /// Send `close_notify` where the transport can, then a FIN
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if !this.shutting_down {
+            this.transport.send_close_notify();
+            this.shutting_down = true;
+        }
+        ready!(this.poll_write_with(cx, Transport::try_flush))?;
+        let fd = this.transport.fd().as_raw_fd();
+        if unsafe { libc::shutdown(fd, libc::SHUT_WR) } < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() != ErrorKind::NotConnected {
+                return Poll::Ready(Err(e));
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for PollStream {
+    /// Send `close_notify` unless [`poll_shutdown`](AsyncWrite::poll_shutdown)
+    /// already has, best effort and without waiting
+    fn drop(&mut self) {
+        if !self.shutting_down {
+            self.transport.send_close_notify();
+            let _ = self.transport.try_flush();
+        }
+    }
+}
+
+/// Spawns futures onto the current tokio-uring runtime, as hyper's HTTP/2
+/// client needs for its connection tasks
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UringExecutor;
+
+impl UringExecutor {
+    pub fn execute<F: Future + 'static>(&self, future: F) {
+        tokio_uring::spawn(future);
+    }
+}
+
+#[cfg(feature = "hyper")]
+mod hyper_rt {
+    use super::*;
+
+    impl hyper::rt::Read for PollStream {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            mut buf: hyper::rt::ReadBufCursor<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            let this = self.get_mut();
+            ready!(this.poll_fill(cx))?;
+            this.take(buf.remaining(), |data| buf.put_slice(data));
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl hyper::rt::Write for PollStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            AsyncWrite::poll_write(self, cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            AsyncWrite::poll_flush(self, cx)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            AsyncWrite::poll_shutdown(self, cx)
+        }
+    }
+
+    impl<F: Future + 'static> hyper::rt::Executor<F> for UringExecutor {
+        fn execute(&self, future: F) {
+            UringExecutor::execute(self, future);
+        }
+    }
+}
+
+#[cfg(feature = "stream")]
+mod stream_impl {
+    use super::*;
+    use crate::streaming::BodyStream;
+    use crate::websocket::{Message, MessageReceiver};
+
+    impl futures_core::Stream for MessageReceiver {
+        type Item = Message;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+            self.get_mut().0.poll_recv(cx)
+        }
+    }
+
+    /// Ends after the last piece, or after the error that cut the body short
+    impl futures_core::Stream for BodyStream {
+        type Item = Result<Vec<u8>, Box<dyn std::error::Error>>;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            self.get_mut().poll_chunk(cx).map(Result::transpose)
+        }
+    }
+}
+
+#[cfg(feature = "tower")]
+mod tower_service_impl {
+    use super::*;
+    use crate::http::{Request, Response};
+    use crate::{HttpsClient, RequestOptions};
+
+    impl<'c> tower_service::Service<Request<'c>> for &'c HttpsClient {
+        type Response = Response;
+        type Error = Box<dyn std::error::Error>;
+        type Future = Pin<Box<dyn Future<Output = Result<Response, Self::Error>> + 'c>>;
+
+        /// Always ready: the client holds no per-request capacity of its own
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: Request<'c>) -> Self::Future {
+            let client = *self;
+            Box::pin(async move { client.send(request, &RequestOptions::default()).await })
+        }
+    }
+}
@@ -0,0 +1,387 @@
+//! Settings for the demo, from a config file and the environment
+//!
+//! `--config PATH` reads a TOML file; every setting then can be overridden
+//! by an environment variable named after its key in upper case, so
+//! `io_timeout_ms` in the `[io]` table is also `IO_TIMEOUT_MS`. Only the
+//! part of TOML settings need is understood: tables, `key = value` lines and
+//! comments, with strings, integers and booleans as values.
+//!
+//! ```toml
+//! [tls]
+//! ktls = true
+//! ca_file = "/etc/ssl/internal-ca.pem"   # native roots when unset
+//!
+//! [io]
+//! uring = "require"      # or "fallback" (the default)
+//! io_timeout_ms = 5000   # 0 turns the timeout off
+//! read_quantum = 65536
+//! fast_open = true
+//! mptcp = true
+//! head_buffer = 4096     # read kTLS bodies apart from heads; 0 doesn't
+//!
+//! [pool]
+//! cache_entries = 32
+//! registered_buffers = 64   # 0 reads into plain heap buffers
+//! buffer_size = 16384
+//! max_connection_age_ms = 300000   # 0 keeps connections as long as they last
+//! max_requests = 1000              # per connection; 0 for no limit
+//! idle_connections = 4             # kept per host; 0 closes each after its request
+//! idle_timeout_ms = 30000
+//!
+//! [dns]
+//! max_lookups = 8          # outstanding at once
+//! lookup_timeout_ms = 10000
+//! negative_ttl_ms = 5000   # 0 doesn't remember failed lookups
+//!
+//! [http]
+//! redirects = 5
+//! strict_parsing = true
+//! clock_offset_ms = 0
+//!
+//! [control]
+//! control_socket = "/run/ktls-demo.sock"   # no control socket when unset
+//! ```
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Try kTLS before falling back to userspace TLS
+    pub ktls: bool,
+    /// PEM bundle to trust instead of the platform's roots
+    pub ca_file: Option<PathBuf>,
+    /// Exit rather than fall back when the kernel refuses io_uring
+    pub require_uring: bool,
+    pub io_timeout: Option<Duration>,
+    pub read_quantum: usize,
+    pub fast_open: bool,
+    pub mptcp: bool,
+    /// Head buffer for split kTLS reads; 0 for none
+    pub head_buffer: usize,
+    pub cache_entries: usize,
+    /// Registered buffers for kTLS reads; 0 for none
+    pub registered_buffers: usize,
+    pub buffer_size: usize,
+    /// How long a session's or pooled connection is used before a new one
+    /// takes over
+    pub max_connection_age: Option<Duration>,
+    /// Requests a session's or pooled connection carries before a new one
+    /// takes over
+    pub max_requests: Option<usize>,
+    /// Idle connections kept open per host for later requests
+    pub idle_connections: usize,
+    /// How long a connection may sit idle before it is closed
+    pub idle_timeout: Duration,
+    /// DNS lookups outstanding at once; never zero
+    pub max_lookups: usize,
+    pub lookup_timeout: Duration,
+    /// How long failed lookups are remembered
+    pub negative_ttl: Duration,
+    pub redirects: usize,
+    pub strict_parsing: bool,
+    /// Wall clock correction, as NTP reports it
+    pub clock_offset_ms: i64,
+    /// Where to take runtime commands, see [`control`](ktls_uring_demo::control)
+    pub control_socket: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            ktls: true,
+            ca_file: None,
+            require_uring: false,
+            io_timeout: Some(Duration::from_secs(10)),
+            read_quantum: 64 * 1024,
+            fast_open: true,
+            mptcp: true,
+            head_buffer: 0,
+            cache_entries: 32,
+            registered_buffers: 0,
+            buffer_size: 16 * 1024,
+            max_connection_age: None,
+            max_requests: None,
+            idle_connections: 4,
+            idle_timeout: Duration::from_secs(30),
+            max_lookups: 8,
+            lookup_timeout: Duration::from_secs(10),
+            negative_ttl: Duration::from_secs(5),
+            redirects: 5,
+            strict_parsing: true,
+            clock_offset_ms: 0,
+            control_socket: None,
+        }
+    }
+}
+
+/// Every setting, by table and key
+const KEYS: [(&str, &str); 22] = [
+    ("tls", "ktls"),
+    ("tls", "ca_file"),
+    ("io", "uring"),
+    ("io", "io_timeout_ms"),
+    ("io", "read_quantum"),
+    ("io", "fast_open"),
+    ("io", "mptcp"),
+    ("io", "head_buffer"),
+    ("pool", "cache_entries"),
+    ("pool", "registered_buffers"),
+    ("pool", "buffer_size"),
+    ("pool", "max_connection_age_ms"),
+    ("pool", "max_requests"),
+    ("pool", "idle_connections"),
+    ("pool", "idle_timeout_ms"),
+    ("dns", "max_lookups"),
+    ("dns", "lookup_timeout_ms"),
+    ("dns", "negative_ttl_ms"),
+    ("http", "redirects"),
+    ("http", "strict_parsing"),
+    ("http", "clock_offset_ms"),
+    ("control", "control_socket"),
+];
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    /// A line of the file that isn't a table, setting or comment
+    Syntax {
+        line: usize,
+        message: &'static str,
+    },
+    UnknownKey {
+        line: usize,
+        key: String,
+    },
+    /// A value of the wrong type or out of range, from `source` (a line of
+    /// the file or an environment variable)
+    InvalidValue {
+        source: String,
+        key: String,
+        expected: &'static str,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "Can't read {}: {e}", path.display()),
+            ConfigError::Syntax { line, message } => write!(f, "Line {line}: {message}"),
+            ConfigError::UnknownKey { line, key } => write!(f, "Line {line}: unknown key {key}"),
+            ConfigError::InvalidValue {
+                source,
+                key,
+                expected,
+            } => write!(f, "{source}: {key} must be {expected}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Read(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+}
+
+impl Value {
+    /// An environment variable's text, typed the way it would be unquoted
+    /// in the file
+    fn from_env(text: String) -> Self {
+        match text.as_str() {
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            _ => text.parse().map_or(Value::Str(text), Value::Int),
+        }
+    }
+}
+
+impl Config {
+    /// The defaults, overridden by the file at `path` if given, then by the
+    /// environment
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let mut config = Self::default();
+        if let Some(path) = path {
+            let text =
+                std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_owned(), e))?;
+            for (line, key, value) in parse(&text)? {
+                config.set(key, value, || format!("Line {line}"))?;
+            }
+        }
+        for (_, key) in KEYS {
+            let var = key.to_ascii_uppercase();
+            if let Ok(text) = std::env::var(&var) {
+                config.set(key, Value::from_env(text), || var.clone())?;
+            }
+        }
+        Ok(config)
+    }
+
+    fn set(
+        &mut self,
+        key: &str,
+        value: Value,
+        source: impl Fn() -> String,
+    ) -> Result<(), ConfigError> {
+        let invalid = |expected| ConfigError::InvalidValue {
+            source: source(),
+            key: key.to_owned(),
+            expected,
+        };
+        let boolean = |value: &Value| match value {
+            Value::Bool(b) => Ok(*b),
+            _ => Err(invalid("true or false")),
+        };
+        let size = |value: &Value| match value {
+            Value::Int(n) => usize::try_from(*n).map_err(|_| invalid("a non-negative integer")),
+            _ => Err(invalid("a non-negative integer")),
+        };
+        match key {
+            "ktls" => self.ktls = boolean(&value)?,
+            "ca_file" => match value {
+                Value::Str(path) => self.ca_file = Some(path.into()),
+                _ => return Err(invalid("a path")),
+            },
+            "control_socket" => match value {
+                Value::Str(path) => self.control_socket = Some(path.into()),
+                _ => return Err(invalid("a path")),
+            },
+            "uring" => {
+                self.require_uring = match value {
+                    Value::Str(policy) if policy == "require" => true,
+                    Value::Str(policy) if policy == "fallback" => false,
+                    _ => return Err(invalid("\"require\" or \"fallback\"")),
+                }
+            }
+            "io_timeout_ms" => {
+                let ms = size(&value)? as u64;
+                self.io_timeout = (ms > 0).then(|| Duration::from_millis(ms));
+            }
+            "read_quantum" => self.read_quantum = size(&value)?,
+            "fast_open" => self.fast_open = boolean(&value)?,
+            "mptcp" => self.mptcp = boolean(&value)?,
+            "head_buffer" => self.head_buffer = size(&value)?,
+            "cache_entries" => self.cache_entries = size(&value)?,
+            "registered_buffers" => self.registered_buffers = size(&value)?,
+            "buffer_size" => self.buffer_size = size(&value)?,
+            "max_connection_age_ms" => {
+                let ms = size(&value)? as u64;
+                self.max_connection_age = (ms > 0).then(|| Duration::from_millis(ms));
+            }
+            "max_requests" => self.max_requests = Some(size(&value)?).filter(|&n| n > 0),
+            "idle_connections" => self.idle_connections = size(&value)?,
+            "idle_timeout_ms" => self.idle_timeout = Duration::from_millis(size(&value)? as u64),
+            "max_lookups" => match size(&value)? {
+                0 => return Err(invalid("a positive integer")),
+                n => self.max_lookups = n,
+            },
+            "lookup_timeout_ms" => match size(&value)? {
+                0 => return Err(invalid("a positive integer")),
+                ms => self.lookup_timeout = Duration::from_millis(ms as u64),
+            },
+            "negative_ttl_ms" => self.negative_ttl = Duration::from_millis(size(&value)? as u64),
+            "redirects" => self.redirects = size(&value)?,
+            "strict_parsing" => self.strict_parsing = boolean(&value)?,
+            "clock_offset_ms" => match value {
+                Value::Int(ms) => self.clock_offset_ms = ms,
+                _ => return Err(invalid("an integer")),
+            },
+            _ => unreachable!("{key} is not in KEYS"),
+        }
+        Ok(())
+    }
+}
+
+/// The settings in `text`, with their line numbers, checked against
+/// [`KEYS`]
+fn parse(text: &str) -> Result<Vec<(usize, &'static str, Value)>, ConfigError> {
+    let mut settings = Vec::new();
+    let mut table = "";
+    for (i, line) in text.lines().enumerate() {
+        let line_no = i + 1;
+        let syntax = |message| ConfigError::Syntax {
+            line: line_no,
+            message,
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(rest) = line.strip_prefix('[') {
+            let (name, rest) = rest
+                .split_once(']')
+                .ok_or(syntax("unclosed table header"))?;
+            if !comment_or_empty(rest) {
+                return Err(syntax("text after table header"));
+            }
+            table = name.trim();
+            continue;
+        }
+
+        let (name, rest) = line.split_once('=').ok_or(syntax("expected key = value"))?;
+        let name = name.trim();
+        let (value, rest) =
+            parse_value(rest.trim()).ok_or(syntax("expected a string, integer or boolean"))?;
+        if !comment_or_empty(rest) {
+            return Err(syntax("text after value"));
+        }
+        let Some(&(_, key)) = KEYS.iter().find(|&&(t, k)| t == table && k == name) else {
+            return Err(ConfigError::UnknownKey {
+                line: line_no,
+                key: if table.is_empty() {
+                    name.to_owned()
+                } else {
+                    format!("{table}.{name}")
+                },
+            });
+        };
+        settings.push((line_no, key, value));
+    }
+    Ok(settings)
+}
+
+/// A value at the start of `text`, and what follows it
+fn parse_value(text: &str) -> Option<(Value, &str)> {
+    if let Some(rest) = text.strip_prefix('"') {
+        let mut s = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Some((Value::Str(s), &rest[i + 1..])),
+                '\\' => s.push(match chars.next()?.1 {
+                    'n' => '\n',
+                    't' => '\t',
+                    c @ ('"' | '\\') => c,
+                    _ => return None,
+                }),
+                c => s.push(c),
+            }
+        }
+        return None;
+    }
+    let end = text
+        .find(|c: char| c.is_whitespace() || c == '#')
+        .unwrap_or(text.len());
+    let (token, rest) = text.split_at(end);
+    let value = match token {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        _ => Value::Int(token.replace('_', "").parse().ok()?),
+    };
+    Some((value, rest))
+}
+
+fn comment_or_empty(text: &str) -> bool {
+    let text = text.trim();
+    text.is_empty() || text.starts_with('#')
+}
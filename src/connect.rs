@@ -0,0 +1,296 @@
+//! Client sockets tokio-uring can't create itself: TCP Fast Open and MPTCP
+//!
+//! With `TCP_FASTOPEN_CONNECT`, `connect` returns at once when the kernel
+//! holds a Fast Open cookie for the server, and the SYN is deferred until the
+//! first write, so the ClientHello rides in it and the handshake saves a
+//! round trip. Without a cookie the kernel does a normal handshake and asks
+//! for one; if the server or a middlebox drops data on the SYN, the kernel
+//! retransmits it after the handshake and stops trying Fast Open on that
+//! path.
+//!
+//! An `IPPROTO_MPTCP` socket offers multipath TCP in its SYN and silently
+//! falls back to plain TCP when the server doesn't answer in kind; kTLS
+//! works on top of either. [`is_mptcp`] tells which one was negotiated.
+//!
+//! tokio-uring can only connect sockets it creates itself, so these are
+//! nonblocking `connect(2)` calls completed through the tokio reactor. They
+//! are Linux only; the connection and endpoint types are not.
+
+use std::net::SocketAddr;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+#[cfg(target_os = "linux")]
+use std::sync::OnceLock;
+
+#[cfg(target_os = "linux")]
+use nix::errno::Errno;
+#[cfg(target_os = "linux")]
+use nix::sys::socket::{self, SockaddrStorage, setsockopt, sockopt};
+#[cfg(target_os = "linux")]
+use tokio::io::Interest;
+#[cfg(target_os = "linux")]
+use tokio::io::unix::AsyncFd;
+
+#[cfg(target_os = "linux")]
+use crate::stats;
+
+// From linux/tcp.h (5.16+): 1 while a socket speaks MPTCP, 0 after fallback
+#[cfg(target_os = "linux")]
+const TCP_IS_MPTCP: libc::c_int = 43;
+
+/// How a response's connection was set up
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectionInfo {
+    pub peer: SocketAddr,
+    /// Records were encrypted by the kernel rather than by rustls
+    pub ktls: bool,
+    /// Multipath TCP was negotiated with the server
+    pub mptcp: bool,
+    /// Position in the request's endpoint list of the endpoint that served
+    /// it; `None` without a list
+    pub endpoint: Option<usize>,
+    /// The send queue as of when the connection was asked for it; `None`
+    /// on responses, whose connection is closed by the time they return
+    pub send_queue: Option<SendQueue>,
+}
+
+/// A socket's send queue at one moment, from [`send_queue`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SendQueue {
+    /// Bytes written but not yet acknowledged by the peer (`SIOCOUTQ`)
+    pub queued: usize,
+    /// Of those, bytes not yet sent at all (`SIOCOUTQNSD`)
+    pub unsent: usize,
+    /// The send buffer size (`SO_SNDBUF`); the kernel's bookkeeping counts
+    /// against it too, so somewhat fewer bytes fill it
+    pub capacity: usize,
+    /// The kernel takes no more writes until the peer acknowledges some
+    pub full: bool,
+}
+
+impl SendQueue {
+    /// `queued` as a fraction of `capacity`
+    pub fn occupancy(&self) -> f64 {
+        if self.capacity == 0 {
+            return 0.0;
+        }
+        self.queued as f64 / self.capacity as f64
+    }
+}
+
+/// A host and port to connect to in place of a request's host
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Endpoint {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Endpoint {
+    pub fn new(host: impl Into<String>, port: u16) -> Self {
+        Self {
+            host: host.into(),
+            port,
+        }
+    }
+}
+
+impl std::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// No endpoint in a failover list could be connected to
+#[derive(Debug)]
+pub struct EndpointsExhausted {
+    pub tried: usize,
+    /// The last endpoint tried, which `error` came from
+    pub last: Endpoint,
+    pub error: Box<dyn std::error::Error>,
+}
+
+impl std::fmt::Display for EndpointsExhausted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "All {} endpoints failed, the last ({}) with: {}",
+            self.tried, self.last, self.error
+        )
+    }
+}
+
+impl std::error::Error for EndpointsExhausted {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&*self.error)
+    }
+}
+
+/// Open a connection to `addr` with Fast Open and/or MPTCP
+///
+/// Fast Open fails with `ENOPROTOOPT` on kernels without
+/// `TCP_FASTOPEN_CONNECT` (before 4.11); see [`fast_open_unsupported`].
+/// Check [`mptcp_supported`] before asking for MPTCP.
+#[cfg(target_os = "linux")]
+pub async fn connect(
+    addr: SocketAddr,
+    fast_open: bool,
+    mptcp: bool,
+) -> std::io::Result<std::net::TcpStream> {
+    let domain = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    let protocol = if mptcp { libc::IPPROTO_MPTCP } else { 0 };
+    let fd = open_socket(domain, protocol)?;
+    if fast_open {
+        stats::syscalls(1);
+        setsockopt(&fd, sockopt::TcpFastOpenConnect, &true)?;
+    }
+
+    stats::syscalls(1);
+    let fd = match socket::connect(fd.as_raw_fd(), &SockaddrStorage::from(addr)) {
+        // Fast Open cookie cached: the SYN waits for the first write
+        Ok(()) => fd,
+        Err(Errno::EINPROGRESS) => {
+            let pending = AsyncFd::with_interest(fd, Interest::WRITABLE)?;
+            drop(pending.writable().await?);
+            let fd = pending.into_inner();
+
+            stats::syscalls(1);
+            let err = socket::getsockopt(&fd, sockopt::SocketError)?;
+            if err != 0 {
+                return Err(std::io::Error::from_raw_os_error(err));
+            }
+            fd
+        }
+        Err(e) => return Err(e.into()),
+    };
+    Ok(std::net::TcpStream::from(fd))
+}
+
+#[cfg(target_os = "linux")]
+fn open_socket(domain: libc::c_int, protocol: libc::c_int) -> std::io::Result<OwnedFd> {
+    stats::syscalls(1);
+    let fd = unsafe {
+        libc::socket(
+            domain,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            protocol,
+        )
+    };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Whether `err` means Fast Open isn't available, rather than that the
+/// server couldn't be reached
+#[cfg(target_os = "linux")]
+pub fn fast_open_unsupported(err: &std::io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::ENOPROTOOPT | libc::EOPNOTSUPP)
+    )
+}
+
+/// Whether this kernel can create MPTCP sockets (5.6+ with `net.mptcp.enabled`)
+///
+/// Probed once by opening and closing a socket.
+#[cfg(target_os = "linux")]
+pub fn mptcp_supported() -> bool {
+    static SUPPORTED: OnceLock<bool> = OnceLock::new();
+    *SUPPORTED.get_or_init(|| open_socket(libc::AF_INET, libc::IPPROTO_MPTCP).is_ok())
+}
+
+/// Read the send queue of a connected TCP socket
+#[cfg(target_os = "linux")]
+pub fn send_queue(fd: RawFd) -> std::io::Result<SendQueue> {
+    let ioctl = |request| {
+        let mut value: libc::c_int = 0;
+        if unsafe { libc::ioctl(fd, request, &mut value) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(value as usize)
+    };
+    stats::syscalls(4);
+    // SIOCOUTQ shares its number with TIOCOUTQ
+    let queued = ioctl(libc::TIOCOUTQ)?;
+    let unsent = ioctl(libc::SIOCOUTQNSD as _)?;
+
+    let mut capacity: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_SNDBUF,
+            &mut capacity as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    // Writable once enough of the buffer is free again, as the kernel sees it
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLOUT,
+        revents: 0,
+    };
+    if unsafe { libc::poll(&mut pollfd, 1, 0) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(SendQueue {
+        queued,
+        unsent,
+        capacity: capacity as usize,
+        full: pollfd.revents & libc::POLLOUT == 0,
+    })
+}
+
+/// Turn Nagle's algorithm off (`TCP_NODELAY`), so small writes go out at
+/// once, or back on, so they are coalesced while earlier data is unacked
+#[cfg(target_os = "linux")]
+pub fn set_nodelay(fd: RawFd, enabled: bool) -> std::io::Result<()> {
+    let value = libc::c_int::from(enabled);
+    stats::syscalls(1);
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_NODELAY,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Whether a connected MPTCP socket is still multipath, i.e. the server
+/// accepted MPTCP and the connection did not fall back to plain TCP
+#[cfg(target_os = "linux")]
+pub fn is_mptcp(fd: RawFd) -> bool {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    stats::syscalls(1);
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_TCP,
+            TCP_IS_MPTCP,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    // Plain TCP sockets and pre-5.16 kernels reject the option
+    ret == 0 && value == 1
+}
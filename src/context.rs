@@ -0,0 +1,163 @@
+//! Request context: one deadline and cancellation token for a whole request
+//!
+//! A `Context` travels with a request through DNS, connect, handshake, kTLS
+//! setup, body I/O and retry waits. Each stage races against it and is
+//! dropped when it fires, in-flight io_uring operations and all.
+//!
+//! A proxy derives the contexts of its outgoing requests from the incoming
+//! one with [`Context::child`]: the child never outlives the parent's
+//! deadline and is cancelled along with it. A context may also carry the
+//! distributed trace the request belongs to, which children share.
+
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
+
+use tokio::sync::Notify;
+
+use crate::trace::TraceContext;
+
+#[derive(Debug)]
+pub enum ContextError {
+    /// The deadline passed during the named stage
+    DeadlineExceeded(&'static str),
+    /// The context was cancelled during the named stage
+    Cancelled(&'static str),
+}
+
+impl std::fmt::Display for ContextError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContextError::DeadlineExceeded(stage) => write!(f, "Deadline exceeded during {stage}"),
+            ContextError::Cancelled(stage) => write!(f, "Request cancelled during {stage}"),
+        }
+    }
+}
+
+impl std::error::Error for ContextError {}
+
+#[derive(Default)]
+struct CancelState {
+    cancelled: Cell<bool>,
+    notify: Notify,
+    children: RefCell<Vec<Weak<CancelState>>>,
+}
+
+impl CancelState {
+    fn cancel(&self) {
+        if self.cancelled.replace(true) {
+            return;
+        }
+        self.notify.notify_waiters();
+        for child in self.children.borrow_mut().drain(..) {
+            if let Some(child) = child.upgrade() {
+                child.cancel();
+            }
+        }
+    }
+}
+
+/// Deadline and cancellation shared by every stage of a request
+///
+/// Clones share the same cancellation token.
+#[derive(Clone, Default)]
+pub struct Context {
+    deadline: Option<Instant>,
+    cancel: Rc<CancelState>,
+    /// Sent with every request made under the context, each as a new span
+    trace: Option<TraceContext>,
+}
+
+impl Context {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fail the request once `deadline` has passed
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(self.deadline.map_or(deadline, |d| d.min(deadline)));
+        self
+    }
+
+    /// Make the request part of `trace`, as a child of its current span
+    pub fn with_trace(mut self, trace: TraceContext) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    pub fn trace(&self) -> Option<&TraceContext> {
+        self.trace.as_ref()
+    }
+
+    /// Fail the request `timeout` from now
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.with_deadline(Instant::now() + timeout)
+    }
+
+    /// A context with the same deadline, cancelled when this one is but
+    /// cancellable on its own; narrow it further with [`with_timeout`](Self::with_timeout)
+    pub fn child(&self) -> Self {
+        let child = Rc::new(CancelState::default());
+        if self.cancel.cancelled.get() {
+            child.cancelled.set(true);
+        } else {
+            let mut children = self.cancel.children.borrow_mut();
+            children.retain(|c| c.strong_count() > 0);
+            children.push(Rc::downgrade(&child));
+        }
+        Self {
+            deadline: self.deadline,
+            cancel: child,
+            trace: self.trace.clone(),
+        }
+    }
+
+    /// Cancel every stage still running under this context and its children
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// Time left before the deadline, if there is one
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|d| d.saturating_duration_since(Instant::now()))
+    }
+
+    /// Error out if the context already fired, naming `stage`
+    pub fn check(&self, stage: &'static str) -> Result<(), ContextError> {
+        if self.cancel.cancelled.get() {
+            return Err(ContextError::Cancelled(stage));
+        }
+        if self.remaining() == Some(Duration::ZERO) {
+            return Err(ContextError::DeadlineExceeded(stage));
+        }
+        Ok(())
+    }
+
+    /// Run `stage` to completion unless the deadline passes or the context
+    /// is cancelled first, in which case `stage` is dropped
+    pub async fn run<T>(
+        &self,
+        stage_name: &'static str,
+        stage: impl Future<Output = T>,
+    ) -> Result<T, ContextError> {
+        self.check(stage_name)?;
+
+        let cancelled = self.cancel.notify.notified();
+        tokio::pin!(cancelled);
+        cancelled.as_mut().enable();
+        let deadline = async {
+            match self.deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            output = stage => Ok(output),
+            _ = cancelled => Err(ContextError::Cancelled(stage_name)),
+            _ = deadline => Err(ContextError::DeadlineExceeded(stage_name)),
+        }
+    }
+}
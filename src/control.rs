@@ -0,0 +1,190 @@
+//! Changing a running client's behavior without restarting it
+//!
+//! [`Controls`] holds the settings that may change while the client runs,
+//! shared between the client and whoever changes them. [`serve`] exposes
+//! them on a Unix socket that takes one command per line and answers each
+//! with `ok`, the requested output, or `error: ...`:
+//!
+//! ```text
+//! verbose on|off            print connection progress to stdout
+//! ktls prefer|require|off   kTLS falling back to userspace TLS, kTLS or
+//!                           fail, or userspace TLS only
+//! stats on|off              count syscalls and io_uring operations
+//! dump                      the current settings and counts
+//! connections               the client's open connections
+//! ```
+//!
+//! e.g. `echo 'ktls require' | socat - UNIX-CONNECT:/run/ktls-demo.sock`.
+//! A kTLS policy change applies to connections opened afterwards; open
+//! ones keep going as they were set up.
+
+use std::cell::Cell;
+use std::fmt;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::rc::Rc;
+
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task::JoinHandle;
+
+use crate::introspect::Registry;
+use crate::stats;
+
+/// Whether new connections use kTLS
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum KtlsPolicy {
+    /// Try kTLS, and use userspace TLS where it can't be set up
+    #[default]
+    Prefer,
+    /// Fail connections kTLS can't be set up for
+    Require,
+    /// Userspace TLS only
+    Off,
+}
+
+impl fmt::Display for KtlsPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KtlsPolicy::Prefer => write!(f, "prefer"),
+            KtlsPolicy::Require => write!(f, "require"),
+            KtlsPolicy::Off => write!(f, "off"),
+        }
+    }
+}
+
+/// Settings a running client reads for every new connection
+#[derive(Debug)]
+pub struct Controls {
+    verbose: Cell<bool>,
+    ktls: Cell<KtlsPolicy>,
+}
+
+impl Default for Controls {
+    fn default() -> Self {
+        Self {
+            verbose: Cell::new(true),
+            ktls: Cell::new(KtlsPolicy::Prefer),
+        }
+    }
+}
+
+impl Controls {
+    pub fn verbose(&self) -> bool {
+        self.verbose.get()
+    }
+
+    pub fn set_verbose(&self, verbose: bool) {
+        self.verbose.set(verbose);
+    }
+
+    pub fn ktls(&self) -> KtlsPolicy {
+        self.ktls.get()
+    }
+
+    pub fn set_ktls(&self, policy: KtlsPolicy) {
+        self.ktls.set(policy);
+    }
+
+    /// Carry out one command line, returning the reply
+    fn execute(&self, line: &str, registry: &Registry) -> Result<String, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let on_off = |word: &str| match word {
+            "on" => Ok(true),
+            "off" => Ok(false),
+            _ => Err(format!("expected on or off, got {word:?}")),
+        };
+        match words.as_slice() {
+            ["verbose", setting] => self.set_verbose(on_off(setting)?),
+            ["ktls", "prefer"] => self.set_ktls(KtlsPolicy::Prefer),
+            ["ktls", "require"] => self.set_ktls(KtlsPolicy::Require),
+            ["ktls", "off"] => self.set_ktls(KtlsPolicy::Off),
+            ["ktls", policy] => {
+                return Err(format!("expected prefer, require or off, got {policy:?}"));
+            }
+            ["stats", setting] => stats::set_enabled(on_off(setting)?),
+            ["dump"] => return Ok(self.dump()),
+            ["connections"] => return Ok(registry.snapshot().to_string().trim_end().to_owned()),
+            _ => return Err(format!("unknown command {line:?}")),
+        }
+        Ok("ok".to_owned())
+    }
+
+    fn dump(&self) -> String {
+        let counts = stats::snapshot();
+        format!(
+            "verbose {}\nktls {}\nuring_submissions {}\nuring_completions {}\nsyscalls {}",
+            if self.verbose() { "on" } else { "off" },
+            self.ktls(),
+            counts.uring_submissions,
+            counts.uring_completions,
+            counts.syscalls,
+        )
+    }
+}
+
+/// Take commands for `controls` on a Unix socket at `path` until the
+/// returned task is aborted, reporting the connections in `registry`
+///
+/// A stale socket file left by an earlier run is replaced.
+pub fn serve(
+    path: &Path,
+    controls: Rc<Controls>,
+    registry: Registry,
+) -> std::io::Result<JoinHandle<()>> {
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    Ok(tokio_uring::spawn(async move {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else {
+                continue;
+            };
+            tokio_uring::spawn(session(stream, controls.clone(), registry.clone()));
+        }
+    }))
+}
+
+/// Answer the commands on one control connection until it closes
+async fn session(stream: UnixStream, controls: Rc<Controls>, registry: Registry) {
+    let mut pending = Vec::new();
+    let mut buf = [0u8; 1024];
+    loop {
+        if stream.readable().await.is_err() {
+            return;
+        }
+        match stream.try_read(&mut buf) {
+            Ok(0) => return,
+            Ok(n) => pending.extend_from_slice(&buf[..n]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+            Err(_) => return,
+        }
+        while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            if line.trim().is_empty() {
+                continue;
+            }
+            let reply = match controls.execute(line.trim(), &registry) {
+                Ok(reply) => reply,
+                Err(e) => format!("error: {e}"),
+            };
+            if write_all(&stream, format!("{reply}\n").as_bytes())
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+}
+
+async fn write_all(stream: &UnixStream, mut data: &[u8]) -> std::io::Result<()> {
+    while !data.is_empty() {
+        stream.writable().await?;
+        match stream.try_write(data) {
+            Ok(n) => data = &data[n..],
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,102 @@
+//! HTTP-date parsing (RFC 9110 §5.6.7)
+//!
+//! Accepts the preferred IMF-fixdate form and the two obsolete forms that
+//! recipients must still understand:
+//!
+//! ```text
+//! Sun, 06 Nov 1994 08:49:37 GMT    IMF-fixdate
+//! Sunday, 06-Nov-94 08:49:37 GMT   RFC 850
+//! Sun Nov  6 08:49:37 1994         asctime
+//! ```
+//!
+//! The weekday is not checked against the date, and years past 9999 are
+//! rejected.
+
+use std::time::{Duration, SystemTime};
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+pub fn parse(value: &str) -> Option<SystemTime> {
+    let value = value.trim();
+    let (weekday, rest) = value.split_once([',', ' '])?;
+    let fields: Vec<&str> = rest.split_whitespace().collect();
+
+    let (day, month, year, time) = match fields.as_slice() {
+        // IMF-fixdate: "06 Nov 1994 08:49:37 GMT"
+        [day, month, year, time, "GMT"] if value.as_bytes()[weekday.len()] == b',' => {
+            (day.parse().ok()?, *month, year.parse().ok()?, *time)
+        }
+        // RFC 850: "06-Nov-94 08:49:37 GMT"
+        [date, time, "GMT"] => {
+            let mut parts = date.split('-');
+            let day = parts.next()?.parse().ok()?;
+            let month = parts.next()?;
+            let year: u64 = parts.next()?.parse().ok()?;
+            // A fixed pivot: 70 to 99 are 19xx, the rest 20xx. RFC 9110 instead
+            // counts years more than 50 ahead of the current one as past
+            let year = if year >= 70 { 1900 + year } else { 2000 + year };
+            (day, month, year, *time)
+        }
+        // asctime: "Nov  6 08:49:37 1994"
+        [month, day, time, year] => (day.parse().ok()?, *month, year.parse().ok()?, *time),
+        _ => return None,
+    };
+
+    let month = MONTHS.iter().position(|&m| m == month)? as u64 + 1;
+    let mut hms = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (hour, minute, second) = (hms.next()??, hms.next()??, hms.next()??);
+    if hms.next().is_some() || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let days = days_since_epoch(year, month, day)?;
+    let secs = days
+        .checked_mul(86_400)?
+        .checked_add(hour * 3600 + minute * 60 + second)?;
+    SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+/// Days from 1970-01-01 to the given civil date, for dates on or after it
+fn days_since_epoch(year: u64, month: u64, day: u64) -> Option<u64> {
+    if !(1970..=9999).contains(&year) {
+        return None;
+    }
+    // Shift the year to start in March so the leap day is the last day of it
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y / 400;
+    let year_of_era = y % 400;
+    let day_of_year = (153 * m + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Some(era * 146_097 + day_of_era - 719_468)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_all_three_forms() {
+        let expected = SystemTime::UNIX_EPOCH + Duration::from_secs(784_111_777);
+        for value in [
+            "Sun, 06 Nov 1994 08:49:37 GMT",
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 1994",
+        ] {
+            assert_eq!(parse(value), Some(expected), "{value}");
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_years() {
+        assert_eq!(parse("Sun Nov  6 08:49:37 300000000000"), None);
+        assert_eq!(parse("Sun, 06 Nov 18446744073709551615 08:49:37 GMT"), None);
+        assert_eq!(parse("Fri, 31 Dec 1969 23:59:59 GMT"), None);
+        assert!(parse("Fri, 31 Dec 9999 23:59:59 GMT").is_some());
+    }
+}
@@ -0,0 +1,703 @@
+//! The demo and the subcommands, on the io_uring client
+
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{mpsc, oneshot};
+
+use ktls_uring_demo::backpressure::{Backpressure, BackpressureAlert};
+use ktls_uring_demo::binding::ChannelBinding;
+use ktls_uring_demo::breaker::BreakerPolicy;
+use ktls_uring_demo::buffers::BufferPoolConfig;
+use ktls_uring_demo::cassette::Cassette;
+use ktls_uring_demo::clock::SystemClock;
+use ktls_uring_demo::compat::{PollStream, UringExecutor};
+use ktls_uring_demo::connect::Endpoint;
+use ktls_uring_demo::context::Context;
+use ktls_uring_demo::headers::HeaderMap;
+use ktls_uring_demo::http::{Body, ClosePolicy, Conditional, Request, Trailers, Validators};
+use ktls_uring_demo::limit::Priority;
+use ktls_uring_demo::pool::PoolPolicy;
+use ktls_uring_demo::qos::TrafficClass;
+use ktls_uring_demo::resolver::ResolverPolicy;
+use ktls_uring_demo::retry::{RetryPolicy, Verdict};
+use ktls_uring_demo::rng::SeededRng;
+use ktls_uring_demo::session::RecyclePolicy;
+use ktls_uring_demo::trace::TraceContext;
+use ktls_uring_demo::uring::UringPolicy;
+use ktls_uring_demo::websocket::{FrameEvent, Message, WsChannels, WssClient};
+use ktls_uring_demo::wirelog::WireLog;
+use ktls_uring_demo::{HttpsClient, Progress, RequestOptions, affinity, control, uring};
+
+use crate::config::Config;
+use crate::{audit, bench, chaos, portable_main, postgres, print_response};
+
+/// A plain HTTP/1.1 GET through the poll-based traits, the way hyper drives
+/// a connection; returns the status line
+async fn poll_get(stream: &mut PollStream, host: &str, path: &str) -> std::io::Result<String> {
+    let request = format!("GET {path} HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\n\r\n");
+    let mut sent = 0;
+    while sent < request.len() {
+        sent += std::future::poll_fn(|cx| {
+            Pin::new(&mut *stream).poll_write(cx, &request.as_bytes()[sent..])
+        })
+        .await?;
+    }
+    std::future::poll_fn(|cx| Pin::new(&mut *stream).poll_flush(cx)).await?;
+
+    let mut response = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let mut read = ReadBuf::new(&mut buf);
+        std::future::poll_fn(|cx| Pin::new(&mut *stream).poll_read(cx, &mut read)).await?;
+        if read.filled().is_empty() {
+            break;
+        }
+        response.extend_from_slice(read.filled());
+    }
+    std::future::poll_fn(|cx| Pin::new(&mut *stream).poll_shutdown(cx)).await?;
+    let head = String::from_utf8_lossy(&response);
+    Ok(head.lines().next().unwrap_or_default().to_owned())
+}
+
+const USAGE: &str = "\
+usage: ktls-uring-demo [--config PATH]
+       ktls-uring-demo audit [OPTIONS]
+       ktls-uring-demo bench [OPTIONS]
+       ktls-uring-demo chaos [OPTIONS]
+       ktls-uring-demo pg [OPTIONS]
+
+  --config PATH   read settings from a TOML file; each can be overridden by
+                  an environment variable named after its key in upper case,
+                  e.g. IO_TIMEOUT_MS=5000";
+
+pub fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("audit") {
+        match audit::AuditConfig::from_args(&args[1..]) {
+            Ok(config) => std::process::exit(audit::run(&config)),
+            Err(e) => {
+                eprintln!("{e}\n\n{}", audit::USAGE);
+                std::process::exit(2);
+            }
+        }
+    }
+    if args.first().map(String::as_str) == Some("bench") {
+        let config = match bench::BenchConfig::from_args(&args[1..]) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("{e}\n\n{}", bench::USAGE);
+                std::process::exit(2);
+            }
+        };
+        if !config.pin.is_empty()
+            && let Err(e) = affinity::pin_current_thread(&config.pin)
+        {
+            eprintln!("failed to pin to CPUs {:?}: {e}", config.pin);
+            std::process::exit(1);
+        }
+        // Measuring io_uring without it means nothing, whatever the policy
+        let runtime = uring::runtime().unwrap_or_else(|e| {
+            eprintln!("bench failed: {e}");
+            std::process::exit(1);
+        });
+        runtime.block_on(async {
+            if let Err(e) = bench::run(&config).await {
+                eprintln!("bench failed: {e}");
+                std::process::exit(1);
+            }
+        });
+        return;
+    }
+    if args.first().map(String::as_str) == Some("chaos") {
+        let config = match chaos::ChaosConfig::from_args(&args[1..]) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("{e}\n\n{}", chaos::USAGE);
+                std::process::exit(2);
+            }
+        };
+        let runtime = uring::runtime().unwrap_or_else(|e| {
+            eprintln!("chaos failed: {e}");
+            std::process::exit(1);
+        });
+        let passed = runtime.block_on(async {
+            chaos::run(&config).await.unwrap_or_else(|e| {
+                eprintln!("chaos failed: {e}");
+                std::process::exit(1);
+            })
+        });
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+    if args.first().map(String::as_str) == Some("pg") {
+        let config = match postgres::PgConfig::from_args(&args[1..]) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("{e}\n\n{}", postgres::USAGE);
+                std::process::exit(2);
+            }
+        };
+        let runtime = uring::runtime().unwrap_or_else(|e| {
+            eprintln!("pg failed: {e}");
+            std::process::exit(1);
+        });
+        runtime.block_on(async {
+            if let Err(e) = postgres::run(&config).await {
+                eprintln!("pg failed: {e}");
+                std::process::exit(1);
+            }
+        });
+        return;
+    }
+
+    let config_path = match args.as_slice() {
+        [] => None,
+        [flag, path] if flag == "--config" => Some(PathBuf::from(path)),
+        _ => {
+            eprintln!("{USAGE}");
+            std::process::exit(2);
+        }
+    };
+    let config = Config::load(config_path.as_deref()).unwrap_or_else(|e| {
+        eprintln!("bad configuration: {e}");
+        std::process::exit(2);
+    });
+
+    let policy = if config.require_uring {
+        UringPolicy::Require
+    } else {
+        UringPolicy::Fallback
+    };
+    let runtime = match uring::runtime() {
+        Ok(runtime) => runtime,
+        Err(e) if policy == UringPolicy::Require => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        Err(e) => return portable_main(&e.to_string()),
+    };
+    runtime.block_on(async {
+        println!("=== ktls-uring-demo (with kTLS support) ===\n");
+
+        let mut client = HttpsClient::new()
+            .with_ktls(config.ktls)
+            .with_retry(RetryPolicy {
+                jitter: 0.5,
+                ..Default::default()
+            })
+            // Some APIs report failures in a 200 body; surface those as errors
+            .with_verifier(|r| match r.header("X-Api-Error") {
+                Some("busy") => Verdict::Retry,
+                Some(error) => Verdict::Fail(error.to_owned()),
+                None => Verdict::Accept,
+            })
+            .with_read_quantum(config.read_quantum)
+            .with_fast_open(config.fast_open)
+            .with_mptcp(config.mptcp)
+            // Small API calls go out without waiting on Nagle's algorithm
+            .with_nodelay(true)
+            .with_strict_parsing(config.strict_parsing)
+            // A host clock NTP says is off
+            .with_clock(SystemClock::with_offset(config.clock_offset_ms))
+            .with_redirects(config.redirects)
+            .with_circuit_breaker(BreakerPolicy::default())
+            .with_connection_limit(16)
+            .with_recycling(RecyclePolicy {
+                max_age: config.max_connection_age,
+                max_requests: config.max_requests,
+            })
+            .with_pool(PoolPolicy {
+                max_idle: config.idle_connections,
+                idle_timeout: config.idle_timeout,
+            })
+            .with_resolver(ResolverPolicy {
+                max_concurrent: config.max_lookups,
+                timeout: config.lookup_timeout,
+                negative_ttl: config.negative_ttl,
+            })
+            .with_websocket_redirects(3)
+            // Answer a WebSocket auth challenge with a token from the environment
+            .with_websocket_credentials(|host, challenge| {
+                let token = std::env::var("WS_TOKEN").ok()?;
+                println!(
+                    "--- ws {host} asked for credentials: {:?} ---",
+                    challenge.header("WWW-Authenticate")
+                );
+                Some(format!("Bearer {token}"))
+            });
+        if config.cache_entries > 0 {
+            client = client.with_cache(config.cache_entries);
+        }
+        if let Some(path) = &config.ca_file {
+            client = client
+                .with_ca_file(path)
+                .unwrap_or_else(|e| panic!("failed to load {}: {e}", path.display()));
+        }
+        if config.registered_buffers > 0 {
+            let buffers = BufferPoolConfig {
+                count: config.registered_buffers,
+                size: config.buffer_size,
+                ..Default::default()
+            };
+            client = client
+                .with_buffer_pool(&buffers)
+                .expect("failed to register the buffer pool");
+        }
+        if let Some(timeout) = config.io_timeout {
+            client = client
+                .with_io_timeout(timeout)
+                .expect("failed to set up the io_uring timeout ring");
+        }
+        if config.head_buffer > 0 {
+            client = client
+                .with_split_reads(config.head_buffer)
+                .expect("failed to set up the io_uring read ring");
+        }
+        // e.g. `echo 'verbose off' | socat - UNIX-CONNECT:<path>` mid-run
+        let _control = config.control_socket.as_ref().map(|path| {
+            control::serve(path, client.controls(), client.registry())
+                .unwrap_or_else(|e| panic!("failed to listen on {}: {e}", path.display()))
+        });
+        // WIRE_LOG=<n> dumps the traffic, with the first n bytes of each body
+        let client = match std::env::var("WIRE_LOG") {
+            Ok(bytes) => client.with_wire_log(WireLog {
+                body_bytes: bytes.parse().unwrap_or(0),
+            }),
+            Err(_) => client,
+        };
+        // RNG_SEED=<n> makes WebSocket keys and masks and retry jitter the
+        // same from run to run
+        let client = match std::env::var("RNG_SEED").map(|seed| seed.parse()) {
+            Ok(Ok(seed)) => client.with_rng(SeededRng::new(seed)),
+            _ => client,
+        };
+        // RECORD=<file> saves the responses; REPLAY=<file> serves them back
+        // without a network
+        let client = match (std::env::var("RECORD"), std::env::var("REPLAY")) {
+            (_, Ok(path)) => client.with_cassette(Cassette::replay(path).unwrap()),
+            (Ok(path), _) => client.with_cassette(Cassette::record(path).unwrap()),
+            _ => client,
+        };
+
+        // Handshakes for the requests below done ahead of them, each
+        // request taking a standby connection while the next is set up
+        let warm = Context::new();
+        let requests = async {
+            let r = client.get("httpbin.org", "/get").await.unwrap();
+            print_response("GET", &r);
+
+            let r = client
+                .post("httpbin.org", "/post", r#"{"op":"create"}"#)
+                .await
+                .unwrap();
+            print_response("POST", &r);
+
+            let r = client
+                .put("httpbin.org", "/put", r#"{"op":"replace"}"#)
+                .await
+                .unwrap();
+            print_response("PUT", &r);
+
+            let r = client
+                .patch("httpbin.org", "/patch", r#"{"op":"modify"}"#)
+                .await
+                .unwrap();
+            print_response("PATCH", &r);
+
+            let r = client.delete("httpbin.org", "/delete").await.unwrap();
+            print_response("DELETE", &r);
+            warm.cancel();
+        };
+        tokio::join!(requests, client.keep_warm("httpbin.org", &warm));
+
+        // Body produced concurrently by another task, uploaded chunk by chunk
+        let (tx, rx) = mpsc::channel(4);
+        tokio_uring::spawn(async move {
+            for i in 0..3 {
+                let chunk = format!(r#"{{"part":{i}}}"#).into_bytes();
+                if tx.send(chunk).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let r = client
+            .post_stream("httpbin.org", "/post", rx)
+            .await
+            .unwrap();
+        print_response("POST stream", &r);
+
+        // A trailer the producer only knows once the body is done; TE lets
+        // the server send trailers back
+        let (tx, rx) = mpsc::channel(4);
+        let trailers = Trailers::new();
+        let producer = trailers.clone();
+        tokio_uring::spawn(async move {
+            let mut len = 0;
+            for i in 0..3 {
+                let chunk = format!(r#"{{"part":{i}}}"#).into_bytes();
+                len += chunk.len();
+                if tx.send(chunk).await.is_err() {
+                    return;
+                }
+            }
+            producer.append("X-Body-Length", len.to_string());
+        });
+        let request = Request::new("POST", "httpbin.org", "/post")
+            .with_body(Body::Channel(rx, trailers))
+            .with_te_trailers();
+        match client.send(request, &RequestOptions::default()).await {
+            Ok(r) => {
+                print_response("POST trailers", &r);
+                // Where a gRPC server reports how the call went
+                if let Some(status) = r.trailer("grpc-status") {
+                    println!("--- POST trailers: grpc-status {status} ---\n");
+                }
+            }
+            Err(e) => println!("--- POST trailers: {e} ---\n"),
+        }
+
+        // Download progress reported from the io_uring read loop, marked as
+        // low-priority bulk traffic (DSCP CS1), with Nagle left on
+        let options = RequestOptions {
+            traffic_class: Some(TrafficClass {
+                dscp: Some(8),
+                ..Default::default()
+            }),
+            nodelay: Some(false),
+            // Queued behind everything else once the connection limit is hit
+            priority: Priority::Low,
+            on_download: Some(Box::new(|p: Progress| match p.total {
+                Some(total) => println!("downloaded {}/{total} bytes", p.transferred),
+                None => println!("downloaded {} bytes", p.transferred),
+            })),
+            on_backpressure: Some(BackpressureAlert::new(
+                Duration::from_millis(200),
+                |b: Backpressure| {
+                    println!(
+                        "send buffer to {} full for {:?}: {} bytes queued",
+                        b.peer, b.full_for, b.queue.queued
+                    )
+                },
+            )),
+            // Keep the body should the connection end without close_notify,
+            // but say so
+            close_policy: Some(ClosePolicy::LenientWithWarning),
+            ..Default::default()
+        };
+        let r = client
+            .request("GET", "httpbin.org", "/bytes/20000", Body::Empty, &options)
+            .await
+            .unwrap();
+        print_response("GET progress", &r);
+
+        // Transparently decompressed before text() decodes it
+        let r = client.get("httpbin.org", "/gzip").await.unwrap();
+        print_response("GET gzip", &r);
+
+        // Each hop is its own connection; the response lists them
+        let r = client.get("httpbin.org", "/redirect/2").await.unwrap();
+        print_response("GET redirect", &r);
+
+        // Second request is answered from the response cache (max-age=60)
+        for label in ["GET cache (miss)", "GET cache (hit)"] {
+            let r = client.get("httpbin.org", "/cache/60").await.unwrap();
+            print_response(label, &r);
+        }
+
+        // A health check goes ahead of queued bulk transfers for a connection
+        let options = RequestOptions {
+            priority: Priority::High,
+            ..Default::default()
+        };
+        match client
+            .request("GET", "httpbin.org", "/status/204", Body::Empty, &options)
+            .await
+        {
+            Ok(r) => println!("--- health check: {} ---\n", r.status),
+            Err(e) => println!("--- health check: {e} ---\n"),
+        }
+
+        // Independent requests submitted together and awaited as a batch
+        let batch = vec![
+            Request::new("GET", "httpbin.org", "/uuid"),
+            Request::new("GET", "httpbin.org", "/ip"),
+            Request::new("POST", "httpbin.org", "/anything")
+                .with_body(Body::Json(r#"{"op":"batch"}"#)),
+        ];
+        for (i, result) in client.batch(batch).await.into_iter().enumerate() {
+            match result {
+                Ok(r) => print_response(&format!("batch #{i}"), &r),
+                Err(e) => println!("--- batch #{i} failed: {e} ---\n"),
+            }
+        }
+
+        // One context bounds every stage of a request; /delay/5 outlives it
+        let options = RequestOptions {
+            context: Context::new().with_timeout(Duration::from_secs(2)),
+            ..Default::default()
+        };
+        match client
+            .request("GET", "httpbin.org", "/delay/5", Body::Empty, &options)
+            .await
+        {
+            Ok(r) => print_response("GET deadline", &r),
+            Err(e) => println!("--- GET deadline: {e} ---\n"),
+        }
+
+        // Cancelling a context cancels the requests derived from it
+        let parent = Context::new();
+        let options = RequestOptions {
+            context: parent.child(),
+            ..Default::default()
+        };
+        tokio_uring::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            parent.cancel();
+        });
+        match client
+            .request("GET", "httpbin.org", "/delay/3", Body::Empty, &options)
+            .await
+        {
+            Ok(r) => print_response("GET cancel", &r),
+            Err(e) => println!("--- GET cancel: {e} ---\n"),
+        }
+
+        // Continuing a caller's trace, as a proxy would from its incoming
+        // request's headers; /headers echoes the traceparent sent
+        let mut incoming = HeaderMap::new();
+        incoming.append(
+            "traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        );
+        let trace = match TraceContext::extract(&incoming) {
+            Some(trace) => trace,
+            None => TraceContext::new().unwrap(),
+        };
+        let options = RequestOptions {
+            context: Context::new().with_trace(trace),
+            ..Default::default()
+        };
+        match client
+            .request("GET", "httpbin.org", "/headers", Body::Empty, &options)
+            .await
+        {
+            Ok(r) => print_response("GET traced", &r),
+            Err(e) => println!("--- GET traced: {e} ---\n"),
+        }
+
+        // Endpoints tried in order; nothing listens on the first
+        let options = RequestOptions {
+            endpoints: vec![
+                Endpoint::new("127.0.0.1", 9),
+                Endpoint::new("httpbin.org", 443),
+            ],
+            ..Default::default()
+        };
+        match client
+            .request("GET", "httpbin.org", "/get", Body::Empty, &options)
+            .await
+        {
+            Ok(r) => print_response("GET failover", &r),
+            Err(e) => println!("--- GET failover: {e} ---\n"),
+        }
+
+        // Several requests over one explicitly held connection
+        match client.open_session("httpbin.org").await {
+            Ok(mut session) => {
+                let results = [
+                    ("session GET", session.get("/get").await),
+                    ("session POST", session.post("/post", r#"{"n": 1}"#).await),
+                    ("session PUT", session.put("/put", r#"{"n": 2}"#).await),
+                    (
+                        "session PATCH",
+                        session.patch("/patch", r#"{"n": 3}"#).await,
+                    ),
+                    ("session DELETE", session.delete("/delete").await),
+                ];
+                for (label, result) in results {
+                    match result {
+                        Ok(r) => print_response(label, &r),
+                        Err(e) => println!("--- {label}: {e} ---\n"),
+                    }
+                }
+                if let Some(queue) = session.connection().send_queue {
+                    println!(
+                        "--- session send queue: {} bytes ({} unsent), {:.1}% of {} ---\n",
+                        queue.queued,
+                        queue.unsent,
+                        queue.occupancy() * 100.0,
+                        queue.capacity
+                    );
+                }
+                print!("--- debug state ---\n{}\n", client.debug_state());
+            }
+            Err(e) => println!("--- session: {e} ---\n"),
+        }
+
+        // WebSocket echo, polled with a timeout instead of blocking
+        match client.open_websocket("echo.websocket.org", "/").await {
+            Ok(ws) => {
+                let mut ws = ws.with_frame_hook(|frame: FrameEvent| {
+                    println!(
+                        "--- ws frame {:?}: opcode {:#x}, {} bytes, {:?} since previous ---",
+                        frame.direction, frame.opcode, frame.len, frame.since_previous
+                    )
+                });
+                let greeting = Message::Text("hello over kTLS".to_owned());
+                let result = async {
+                    println!(
+                        "--- ws connected over {} ---",
+                        if ws.connection().ktls {
+                            "kTLS"
+                        } else {
+                            "userspace TLS"
+                        }
+                    );
+                    // Best effort, not waited for; still goes out before the greeting
+                    ws.send_nowait(Message::Text("telemetry: connected".to_owned()))?;
+                    ws.send(greeting.clone()).await?;
+                    // The server may say hello first; read until the echo arrives
+                    while let Some(message) = ws.receive_timeout(Duration::from_secs(5)).await? {
+                        println!("--- ws received: {message:?} ---");
+                        if message == greeting {
+                            break;
+                        }
+                    }
+                    if ws.try_receive()?.is_none() {
+                        println!("--- ws: nothing else pending ---");
+                    }
+                    ws.close(1000, "demo finished").await?;
+                    ws.receive().await
+                }
+                .await;
+                match result {
+                    Ok(message) => println!("--- ws closed: {message:?} ---\n"),
+                    Err(e) => println!("--- ws: {e} ---\n"),
+                }
+            }
+            Err(e) => println!("--- ws: {e} ---\n"),
+        }
+
+        // The same through channels, with a task answering pings and closing
+        match client.open_websocket("echo.websocket.org", "/").await {
+            Ok(ws) => {
+                let WsChannels {
+                    sender,
+                    mut receiver,
+                    task,
+                } = ws
+                    .with_heartbeat(Duration::from_secs(15), 2)
+                    .with_coalescing(16 * 1024)
+                    .into_channels(16);
+                let greeting = Message::Text("hello through a channel".to_owned());
+                if sender.send(greeting.clone()).await.is_ok() {
+                    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+                    while let Ok(Some(message)) =
+                        tokio::time::timeout_at(deadline, receiver.recv()).await
+                    {
+                        println!("--- ws channel received: {message:?} ---");
+                        if message == greeting {
+                            break;
+                        }
+                    }
+                }
+                // Dropping the sender closes the connection
+                drop(sender);
+                match task.await {
+                    Ok(Ok(())) => println!("--- ws channel closed ---\n"),
+                    Ok(Err(e)) => println!("--- ws channel: {e} ---\n"),
+                    Err(e) => println!("--- ws channel task failed: {e} ---\n"),
+                }
+            }
+            Err(e) => println!("--- ws: {e} ---\n"),
+        }
+
+        // The same connection setup through tokio's I/O traits, on a spawned
+        // task, as hyper would use it
+        match client.open_stream("httpbin.org").await {
+            Ok(mut stream) => {
+                let (done, finished) = oneshot::channel();
+                UringExecutor.execute(async move {
+                    let _ = done.send(poll_get(&mut stream, "httpbin.org", "/get").await);
+                });
+                match finished.await {
+                    Ok(Ok(status)) => println!("--- poll stream: {status} ---\n"),
+                    Ok(Err(e)) => println!("--- poll stream: {e} ---\n"),
+                    Err(e) => println!("--- poll stream task failed: {e} ---\n"),
+                }
+            }
+            Err(e) => println!("--- poll stream: {e} ---\n"),
+        }
+
+        // Connections dialed elsewhere (here plainly, standing in for a
+        // custom dialer); the client only does the handshakes on them
+        match std::net::TcpStream::connect(("httpbin.org", 443)) {
+            Ok(stream) => {
+                let request = Request::new("GET", "httpbin.org", "/get");
+                match client
+                    .request_on(stream, request, &RequestOptions::default())
+                    .await
+                {
+                    Ok(r) => print_response("GET on supplied connection", &r),
+                    Err(e) => println!("--- GET on supplied connection: {e} ---\n"),
+                }
+            }
+            Err(e) => println!("--- dial httpbin.org: {e} ---\n"),
+        }
+        match std::net::TcpStream::connect(("echo.websocket.org", 443)) {
+            Ok(stream) => {
+                // tls-exporter channel binding (RFC 9266), derived
+                // before the keys go to the kernel
+                let options = RequestOptions {
+                    exporters: vec![ChannelBinding::exporter()],
+                    ..Default::default()
+                };
+                match WssClient::connect_on(&client, stream, "echo.websocket.org", "/", &options)
+                    .await
+                {
+                    Ok(mut ws) => {
+                        if let Some(binding) = ChannelBinding::from_material(ws.keying_material()) {
+                            let hex: String =
+                                binding.data().iter().map(|b| format!("{b:02x}")).collect();
+                            println!("--- ws channel binding: {hex} ---");
+                            println!("--- SCRAM c= {} ---", binding.scram_attribute(None));
+                        }
+                        let hello = Message::Text("over a supplied connection".to_owned());
+                        let echo = match ws.send(hello).await {
+                            Ok(()) => ws.receive_timeout(Duration::from_secs(5)).await,
+                            Err(e) => Err(e),
+                        };
+                        println!("--- ws on supplied connection: {echo:?} ---\n");
+                    }
+                    Err(e) => println!("--- ws on supplied connection: {e} ---\n"),
+                }
+            }
+            Err(e) => println!("--- dial echo.websocket.org: {e} ---\n"),
+        }
+
+        // Offering a version the server doesn't speak gets the ones it does
+        let legacy = HttpsClient::new().with_websocket_version(8);
+        match legacy.open_websocket("echo.websocket.org", "/").await {
+            Ok(_) => println!("--- ws version 8 accepted ---\n"),
+            Err(e) => println!("--- ws version 8: {e} ---\n"),
+        }
+
+        // Poll with a known ETag: server answers 304 instead of resending the body
+        let validators = Validators {
+            etag: Some("demo".into()),
+            ..Default::default()
+        };
+        match client
+            .get_conditional("httpbin.org", "/etag/demo", &validators)
+            .await
+            .unwrap()
+        {
+            Conditional::Modified(r) => print_response("GET etag (modified)", &r),
+            Conditional::NotModified => println!("--- GET etag: not modified ---\n"),
+        }
+
+        println!("=== done ===");
+    });
+}
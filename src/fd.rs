@@ -0,0 +1,207 @@
+//! Socket ownership across io_uring and direct syscalls
+//!
+//! A connection is opened as a tokio-uring `TcpStream`, which owns the fd
+//! for its whole life. kTLS setup only borrows it, and anything wanting a
+//! std view of the socket does so through [`BorrowedStream`]; the handshake
+//! and the userspace fallback drive rustls over the tokio-uring stream
+//! itself.
+//!
+//! Writes go through [`write_all`], which keeps what the peer receives
+//! whole: each buffer arrives complete, or the stream ends where it broke
+//! off rather than carrying on with the next buffer mid-record.
+
+use std::io::ErrorKind;
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::os::unix::io::{AsRawFd, BorrowedFd, FromRawFd};
+
+use tokio_uring::buf::BoundedBuf;
+use tokio_uring::net::TcpStream;
+
+use crate::stats;
+
+/// Borrow the fd of a tokio-uring stream for as long as the stream lives
+pub fn borrow(stream: &TcpStream) -> BorrowedFd<'_> {
+    // SAFETY: the stream owns the fd and outlives the returned borrow
+    unsafe { BorrowedFd::borrow_raw(stream.as_raw_fd()) }
+}
+
+/// One `recv(2)` that fails with `WouldBlock` rather than waiting for data,
+/// whatever the socket's blocking mode
+pub fn try_recv(fd: BorrowedFd<'_>, buf: &mut [u8]) -> std::io::Result<usize> {
+    stats::syscalls(1);
+    let n = unsafe {
+        libc::recv(
+            fd.as_raw_fd(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            libc::MSG_DONTWAIT,
+        )
+    };
+    if n < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+/// One `send(2)` that fails with `WouldBlock` rather than waiting for room,
+/// whatever the socket's blocking mode
+pub fn try_send(fd: BorrowedFd<'_>, buf: &[u8]) -> std::io::Result<usize> {
+    stats::syscalls(1);
+    let n = unsafe {
+        libc::send(
+            fd.as_raw_fd(),
+            buf.as_ptr() as *const libc::c_void,
+            buf.len(),
+            libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL,
+        )
+    };
+    if n < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+/// Write all of `data` to `stream` through io_uring
+///
+/// Short writes are resumed and interrupted ones (`EINTR`) retried. If the
+/// write fails, or is dropped before it finishes, the sending side of the
+/// socket is shut down, so nothing written later can follow a partial TLS
+/// record or message.
+pub async fn write_all(stream: &TcpStream, data: Vec<u8>) -> std::io::Result<()> {
+    let guard = WriteGuard::new(borrow(stream));
+    let mut buf = data;
+    let mut sent = 0;
+    while sent < buf.len() {
+        stats::uring_submitted();
+        let (result, slice) = stream.write(buf.slice(sent..)).submit().await;
+        stats::uring_completed();
+        buf = slice.into_inner();
+        match result {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(n) => sent += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    guard.finish();
+    Ok(())
+}
+
+/// Shuts down the sending side of a socket when dropped, unless the write
+/// it guards [`finish`](Self::finish)ed
+pub struct WriteGuard<'fd> {
+    fd: BorrowedFd<'fd>,
+    finished: bool,
+}
+
+impl<'fd> WriteGuard<'fd> {
+    pub fn new(fd: BorrowedFd<'fd>) -> Self {
+        Self {
+            fd,
+            finished: false,
+        }
+    }
+
+    pub fn finish(mut self) {
+        self.finished = true;
+    }
+}
+
+impl Drop for WriteGuard<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            stats::syscalls(1);
+            unsafe { libc::shutdown(self.fd.as_raw_fd(), libc::SHUT_WR) };
+        }
+    }
+}
+
+/// A std `TcpStream` view of a socket owned elsewhere, which never closes it
+///
+/// Unlike converting the fd and `mem::forget`ting the stream afterwards, the
+/// fd can't be closed by an early return or a panic in between.
+pub struct BorrowedStream<'fd> {
+    stream: ManuallyDrop<std::net::TcpStream>,
+    _fd: PhantomData<BorrowedFd<'fd>>,
+}
+
+impl<'fd> BorrowedStream<'fd> {
+    pub fn new(fd: BorrowedFd<'fd>) -> Self {
+        Self {
+            // SAFETY: `ManuallyDrop` keeps the stream from closing the fd,
+            // and the borrow keeps the fd open while the stream exists
+            stream: ManuallyDrop::new(unsafe { std::net::TcpStream::from_raw_fd(fd.as_raw_fd()) }),
+            _fd: PhantomData,
+        }
+    }
+}
+
+impl Deref for BorrowedStream<'_> {
+    type Target = std::net::TcpStream;
+
+    fn deref(&self) -> &Self::Target {
+        &self.stream
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::os::unix::io::{AsFd, RawFd};
+
+    fn is_open(fd: RawFd) -> bool {
+        unsafe { libc::fcntl(fd, libc::F_GETFD) >= 0 }
+    }
+
+    fn pair() -> (std::net::TcpStream, std::net::TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn borrowed_stream_leaves_the_fd_open() {
+        let (client, mut server) = pair();
+        {
+            let borrowed = BorrowedStream::new(client.as_fd());
+            (&*borrowed).write_all(b"ping").unwrap();
+        }
+        assert!(is_open(client.as_raw_fd()));
+
+        let mut buf = [0u8; 4];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[test]
+    fn borrowed_stream_survives_a_panic() {
+        let (client, _server) = pair();
+        let result = std::panic::catch_unwind(|| {
+            let _borrowed = BorrowedStream::new(client.as_fd());
+            panic!("handshake failed");
+        });
+        assert!(result.is_err());
+        assert!(is_open(client.as_raw_fd()));
+    }
+
+    #[test]
+    fn unfinished_write_ends_the_stream() {
+        let (client, mut server) = pair();
+        WriteGuard::new(client.as_fd()).finish();
+        (&client).write_all(b"whole").unwrap();
+        {
+            let _guard = WriteGuard::new(client.as_fd());
+            (&client).write_all(b"par").unwrap();
+        }
+        assert!((&client).write_all(b"t").is_err());
+
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"wholepar");
+    }
+}
@@ -1,18 +1,92 @@
-//! TLS handshake driver using rustls unbuffered API
+//! TLS handshake driver for kTLS
 //!
-//! Performs the TLS handshake and extracts secrets for kTLS configuration.
-//! Uses blocking I/O during handshake (acceptable for the small amount of data).
+//! Performs the TLS handshake, as client or server, and extracts secrets
+//! for kTLS configuration.
+//! The handshake's records go through io_uring reads and writes on the
+//! connection's tokio-uring stream, so the runtime goes on with other work,
+//! other connections' handshakes included, while the server answers.
+//!
+//! The handshake runs on a buffered rustls connection: its unbuffered API
+//! has no TLS exporter, and once the secrets go to the kernel there is no
+//! connection left to ask, so any keying material a caller wants is
+//! derived here, between the handshake and the extraction.
 
-use std::io::{Read, Write};
-use std::os::unix::io::{FromRawFd, RawFd};
+use std::io::{ErrorKind, Read};
 use std::sync::Arc;
 
-use rustls::client::UnbufferedClientConnection;
-use rustls::unbuffered::{
-    ConnectionState, EncodeError, EncryptError, InsufficientSizeError, UnbufferedStatus,
+use rustls::client::ClientConnectionData;
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{
+    ClientConfig, ClientConnection, ConnectionCommon, ConnectionTrafficSecrets, ProtocolVersion,
+    ServerConfig, ServerConnection,
 };
-use rustls::{ClientConfig, ConnectionTrafficSecrets, ProtocolVersion};
-use rustls::pki_types::ServerName;
+use tokio_uring::net::TcpStream;
+
+use crate::{fd, stats};
+
+/// Bytes requested from the socket per read; one maximum-size TLS record
+const READ_SIZE: usize = 16 * 1024 + 256;
+
+/// Keying material to derive from the TLS exporter (RFC 5705, RFC 8446
+/// section 7.5), e.g. for channel binding above the kTLS stream
+#[derive(Clone, Debug, PartialEq)]
+pub struct Exporter {
+    /// Registered label, such as `EXPORTER-Channel-Binding`
+    pub label: Vec<u8>,
+    /// `None` and an empty context give different material in TLS 1.2
+    pub context: Option<Vec<u8>>,
+    /// Bytes of material; must not be zero
+    pub len: usize,
+}
+
+/// Material derived from the TLS exporter, looked up by what was asked for,
+/// and the server's certificate, for bindings that hash it instead
+#[derive(Clone, Debug, Default)]
+pub struct KeyingMaterial {
+    exported: Vec<(Exporter, Vec<u8>)>,
+    server_certificate: Option<CertificateDer<'static>>,
+}
+
+impl KeyingMaterial {
+    /// The material derived for `exporter`, if it was asked for
+    pub fn get(&self, exporter: &Exporter) -> Option<&[u8]> {
+        self.exported
+            .iter()
+            .find(|(asked, _)| asked == exporter)
+            .map(|(_, material)| material.as_slice())
+    }
+
+    /// The end-entity certificate the server presented
+    pub fn server_certificate(&self) -> Option<&CertificateDer<'static>> {
+        self.server_certificate.as_ref()
+    }
+}
+
+/// The material for each of `exporters` from a connection whose handshake
+/// is complete
+pub fn export(
+    conn: &ConnectionCommon<ClientConnectionData>,
+    exporters: &[Exporter],
+) -> Result<KeyingMaterial, rustls::Error> {
+    let exported = exporters
+        .iter()
+        .map(|exporter| {
+            let material = conn.export_keying_material(
+                vec![0u8; exporter.len],
+                &exporter.label,
+                exporter.context.as_deref(),
+            )?;
+            Ok((exporter.clone(), material))
+        })
+        .collect::<Result<_, rustls::Error>>()?;
+    Ok(KeyingMaterial {
+        exported,
+        server_certificate: conn
+            .peer_certificates()
+            .and_then(|chain| chain.first())
+            .map(|cert| cert.clone().into_owned()),
+    })
+}
 
 /// Result of a successful TLS handshake
 pub struct HandshakeResult {
@@ -22,15 +96,14 @@ pub struct HandshakeResult {
     pub rx: (u64, ConnectionTrafficSecrets),
     /// Negotiated TLS version
     pub version: ProtocolVersion,
+    /// Derived for the [`Exporter`]s the caller asked for
+    pub keying_material: KeyingMaterial,
 }
 
 #[derive(Debug)]
 pub enum HandshakeError {
     Io(std::io::Error),
     Tls(rustls::Error),
-    Encode(EncodeError),
-    Encrypt(EncryptError),
-    InsufficientSize(InsufficientSizeError),
     ConnectionClosed,
     SecretExtractionFailed,
 }
@@ -40,9 +113,6 @@ impl std::fmt::Display for HandshakeError {
         match self {
             HandshakeError::Io(e) => write!(f, "I/O error during handshake: {e}"),
             HandshakeError::Tls(e) => write!(f, "TLS error during handshake: {e}"),
-            HandshakeError::Encode(e) => write!(f, "Encode error: {e:?}"),
-            HandshakeError::Encrypt(e) => write!(f, "Encrypt error: {e:?}"),
-            HandshakeError::InsufficientSize(e) => write!(f, "Buffer too small: {e:?}"),
             HandshakeError::ConnectionClosed => write!(f, "Connection closed during handshake"),
             HandshakeError::SecretExtractionFailed => write!(f, "Failed to extract TLS secrets"),
         }
@@ -63,155 +133,166 @@ impl From<rustls::Error> for HandshakeError {
     }
 }
 
-impl From<EncodeError> for HandshakeError {
-    fn from(e: EncodeError) -> Self {
-        HandshakeError::Encode(e)
-    }
-}
-
-impl From<EncryptError> for HandshakeError {
-    fn from(e: EncryptError) -> Self {
-        HandshakeError::Encrypt(e)
-    }
-}
-
-impl From<InsufficientSizeError> for HandshakeError {
-    fn from(e: InsufficientSizeError) -> Self {
-        HandshakeError::InsufficientSize(e)
-    }
+/// Perform TLS handshake over `stream` and extract secrets for kTLS,
+/// deriving the keying material for `exporters` on the way
+pub async fn perform_handshake(
+    stream: &TcpStream,
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+    exporters: &[Exporter],
+) -> Result<HandshakeResult, HandshakeError> {
+    let mut conn = ClientConnection::new(config, server_name)?;
+    // Servers speak when spoken to, so nothing follows their last flight
+    drive(stream, &mut conn).await?;
+
+    let version = conn.protocol_version().unwrap_or(ProtocolVersion::TLSv1_3);
+    let keying_material = export(&conn, exporters)?;
+
+    // Extract secrets for kTLS
+    let secrets = conn
+        .dangerous_extract_secrets()
+        .map_err(|_| HandshakeError::SecretExtractionFailed)?;
+
+    Ok(HandshakeResult {
+        tx: secrets.tx,
+        rx: secrets.rx,
+        version,
+        keying_material,
+    })
 }
 
-/// Internal action to defer until after state is dropped
-enum HandshakeAction {
-    NeedData,
+/// Result of a successful handshake with a client
+pub struct ServerHandshakeResult {
+    /// TX secrets: (sequence_number, traffic_secrets)
+    pub tx: (u64, ConnectionTrafficSecrets),
+    /// RX secrets: (sequence_number, traffic_secrets)
+    pub rx: (u64, ConnectionTrafficSecrets),
+    /// Negotiated TLS version
+    pub version: ProtocolVersion,
+    /// The host the client asked for with SNI, if it did
+    pub server_name: Option<String>,
+    /// The protocol agreed through ALPN, if any
+    pub alpn_protocol: Option<Vec<u8>>,
+    /// Application data that arrived with the client's Finished, already
+    /// decrypted; the kernel only sees what comes after it
+    pub received: Vec<u8>,
 }
 
-/// Wrapper for raw FD that doesn't close on drop
-struct BorrowedSocket {
-    fd: RawFd,
+/// Perform the server side of the TLS handshake over `stream` and extract
+/// secrets for kTLS
+///
+/// `config` must have
+/// [`enable_secret_extraction`](rustls::ServerConfig::enable_secret_extraction)
+/// set. TLS 1.3 session tickets it sends go out before the secrets are
+/// extracted, but a kTLS client can't take them in; see
+/// [`KtlsListener`](crate::server::KtlsListener).
+pub async fn accept_handshake(
+    stream: &TcpStream,
+    config: Arc<ServerConfig>,
+) -> Result<ServerHandshakeResult, HandshakeError> {
+    let mut conn = ServerConnection::new(config)?;
+    let received = drive(stream, &mut conn).await?;
+
+    let version = conn.protocol_version().unwrap_or(ProtocolVersion::TLSv1_3);
+    let server_name = conn.server_name().map(str::to_owned);
+    let alpn_protocol = conn.alpn_protocol().map(<[u8]>::to_vec);
+
+    let secrets = conn
+        .dangerous_extract_secrets()
+        .map_err(|_| HandshakeError::SecretExtractionFailed)?;
+
+    Ok(ServerHandshakeResult {
+        tx: secrets.tx,
+        rx: secrets.rx,
+        version,
+        server_name,
+        alpn_protocol,
+        received,
+    })
 }
 
-impl BorrowedSocket {
-    fn new(fd: RawFd) -> Self {
-        Self { fd }
+/// Run `conn`'s handshake over `stream` to completion, returning whatever
+/// application data the peer sent right behind it
+///
+/// Only whole records go to rustls: bytes of a record it hasn't finished
+/// would be lost when the secrets are extracted, and the kernel would pick
+/// up the stream part way through that record. So once the handshake is
+/// done, reading goes on until the last record read is complete.
+async fn drive<Data>(
+    stream: &TcpStream,
+    conn: &mut ConnectionCommon<Data>,
+) -> Result<Vec<u8>, HandshakeError> {
+    let mut buf = vec![0u8; READ_SIZE];
+    let mut pending = Vec::new();
+    while conn.is_handshaking() || !pending.is_empty() {
+        flush(stream, conn).await?;
+        stats::uring_submitted();
+        let (result, read) = stream.read(buf).await;
+        stats::uring_completed();
+        buf = read;
+        match result? {
+            0 => return Err(HandshakeError::ConnectionClosed),
+            n => pending.extend_from_slice(&buf[..n]),
+        }
+        let whole = whole_records(&pending);
+        let mut records = &pending[..whole];
+        while !records.is_empty() {
+            conn.read_tls(&mut records)?;
+            if let Err(e) = conn.process_new_packets() {
+                // Let the peer know why before giving up
+                let _ = flush(stream, conn).await;
+                return Err(e.into());
+            }
+        }
+        pending.drain(..whole);
     }
-
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let mut stream = unsafe { std::net::TcpStream::from_raw_fd(self.fd) };
-        let result = stream.read(buf);
-        std::mem::forget(stream); // Don't close the FD
-        result
+    // Processing the peer's last flight queues our Finished, or for a
+    // server its session tickets
+    flush(stream, conn).await?;
+
+    let mut received = Vec::new();
+    match conn.reader().read_to_end(&mut received) {
+        Err(e) if e.kind() != ErrorKind::WouldBlock => Err(e.into()),
+        _ => Ok(received),
     }
+}
 
-    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
-        let mut stream = unsafe { std::net::TcpStream::from_raw_fd(self.fd) };
-        let result = stream.write_all(buf);
-        std::mem::forget(stream); // Don't close the FD
-        result
+/// How many bytes at the start of `data` make up whole TLS records
+fn whole_records(data: &[u8]) -> usize {
+    let mut end = 0;
+    while let Some(header) = data.get(end..end + 5) {
+        let len = 5 + usize::from(u16::from_be_bytes([header[3], header[4]]));
+        if data.len() < end + len {
+            break;
+        }
+        end += len;
     }
+    end
+}
 
-    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
-        let stream = unsafe { std::net::TcpStream::from_raw_fd(self.fd) };
-        let result = stream.set_nonblocking(nonblocking);
-        std::mem::forget(stream);
-        result
+/// Send everything rustls has queued for the peer
+async fn flush<Data>(stream: &TcpStream, conn: &mut ConnectionCommon<Data>) -> std::io::Result<()> {
+    while conn.wants_write() {
+        let mut records = Vec::new();
+        conn.write_tls(&mut records)?;
+        fd::write_all(stream, records).await?;
     }
+    Ok(())
 }
 
-/// Perform TLS handshake and extract secrets for kTLS
-pub fn perform_handshake(
-    fd: RawFd,
-    config: Arc<ClientConfig>,
-    server_name: ServerName<'static>,
-) -> Result<HandshakeResult, HandshakeError> {
-    let mut socket = BorrowedSocket::new(fd);
-
-    // Ensure blocking mode for handshake
-    socket.set_nonblocking(false)?;
-
-    let mut conn = UnbufferedClientConnection::new(config, server_name)?;
-
-    let mut incoming_tls = vec![0u8; 16384];
-    let mut outgoing_tls = vec![0u8; 16384];
-    let mut incoming_used = 0usize;
-
-    loop {
-        let UnbufferedStatus { discard, state } =
-            conn.process_tls_records(&mut incoming_tls[..incoming_used]);
-
-        // Handle state first, then discard
-        let result = match state? {
-            ConnectionState::EncodeTlsData(mut encoder) => {
-                // Encode handshake data to send
-                let written = encoder.encode(&mut outgoing_tls)?;
-                socket.write_all(&outgoing_tls[..written])?;
-                None
-            }
-
-            ConnectionState::TransmitTlsData(transmit) => {
-                // Data was already sent in EncodeTlsData, mark done
-                transmit.done();
-                None
-            }
-
-            ConnectionState::BlockedHandshake => {
-                // Need more data from peer - handled after discard
-                Some(HandshakeAction::NeedData)
-            }
-
-            ConnectionState::WriteTraffic(_) => {
-                // Handshake complete! Extract secrets for kTLS
-                let version = conn
-                    .protocol_version()
-                    .unwrap_or(ProtocolVersion::TLSv1_3);
-
-                // Extract secrets for kTLS
-                #[allow(deprecated)]
-                let secrets = conn
-                    .dangerous_extract_secrets()
-                    .map_err(|_| HandshakeError::SecretExtractionFailed)?;
-
-                return Ok(HandshakeResult {
-                    tx: secrets.tx,
-                    rx: secrets.rx,
-                    version,
-                });
-            }
-
-            ConnectionState::ReadTraffic(_) => {
-                // Server sent early data - shouldn't happen for client
-                // Continue to process and reach WriteTraffic state
-                None
-            }
-
-            ConnectionState::Closed => {
-                return Err(HandshakeError::ConnectionClosed);
-            }
-
-            ConnectionState::ReadEarlyData(_) => {
-                // Early data state - continue processing
-                None
-            }
-
-            _ => {
-                // Other states - continue processing
-                None
-            }
-        };
-
-        // Discard processed bytes after state is handled
-        if discard > 0 {
-            incoming_tls.copy_within(discard..incoming_used, 0);
-            incoming_used -= discard;
-        }
-
-        // Handle deferred actions
-        if let Some(HandshakeAction::NeedData) = result {
-            let n = socket.read(&mut incoming_tls[incoming_used..])?;
-            if n == 0 {
-                return Err(HandshakeError::ConnectionClosed);
-            }
-            incoming_used += n;
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn whole_records_stops_before_a_partial_record() {
+        let mut data = vec![23, 3, 3, 0, 2, 0xaa, 0xbb];
+        assert_eq!(whole_records(&data), 7);
+        data.extend_from_slice(&[23, 3, 3, 0, 4, 0xcc]);
+        assert_eq!(whole_records(&data), 7);
+        data.extend_from_slice(&[0xdd, 0xee, 0xff]);
+        assert_eq!(whole_records(&data), 16);
+        // Not even a whole header
+        assert_eq!(whole_records(&[22, 3]), 0);
     }
 }
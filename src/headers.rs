@@ -0,0 +1,239 @@
+//! Ordered, case-insensitive header map
+//!
+//! Headers keep the order they were added or received in, and a name may
+//! appear several times (`Set-Cookie`, split `Cache-Control` lines). Names
+//! are compared ASCII case-insensitively but stored as given, so encoding
+//! reproduces them verbatim. Messages carry a handful of headers, so lookups
+//! scan a flat list rather than hashing.
+//!
+//! [`parse_links`] takes `Link` header values apart (RFC 8288).
+
+#[derive(Clone, Debug, Default)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a value, keeping any existing values of the same name
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.entries.push((name.into(), value.into()));
+    }
+
+    /// First value of `name`
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.get_all(name).next()
+    }
+
+    /// Every value of `name`, in order
+    pub fn get_all<'a>(&'a self, name: &str) -> impl Iterator<Item = &'a str> {
+        self.entries
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Drop every value of `name`
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    /// All headers in order, repeated names included
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+}
+
+impl<'a> IntoIterator for &'a HeaderMap {
+    type Item = (&'a str, &'a str);
+    type IntoIter = Box<dyn Iterator<Item = Self::Item> + 'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Box::new(self.iter())
+    }
+}
+
+impl<N: Into<String>, V: Into<String>> Extend<(N, V)> for HeaderMap {
+    fn extend<I: IntoIterator<Item = (N, V)>>(&mut self, iter: I) {
+        for (name, value) in iter {
+            self.append(name, value);
+        }
+    }
+}
+
+/// One link from a `Link` header (RFC 8288 §3)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Link {
+    /// The target, as written between `<` and `>`; may be relative to the
+    /// request's URL
+    pub url: String,
+    /// The `rel` parameter: relation types separated by spaces, such as
+    /// `next` or `preload`; empty when the link has none
+    pub rel: String,
+    /// The other parameters in order, names lowercased and quoted values
+    /// unquoted; a parameter given without a value has an empty one
+    pub params: Vec<(String, String)>,
+}
+
+impl Link {
+    /// Whether `rel` is among the link's relation types, which compare
+    /// case-insensitively
+    pub fn has_rel(&self, rel: &str) -> bool {
+        self.rel
+            .split_ascii_whitespace()
+            .any(|r| r.eq_ignore_ascii_case(rel))
+    }
+
+    /// The first value of parameter `name`
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// The links in a `Link` header value, in order
+///
+/// Commas and semicolons inside the `<...>` target or a quoted parameter
+/// value don't split it. Of several `rel` parameters the first counts, as
+/// RFC 8288 §3.3 requires; a link that doesn't start with `<` is skipped.
+pub fn parse_links(value: &str) -> Vec<Link> {
+    let mut links = Vec::new();
+    let mut rest = value;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c == ',' || c.is_ascii_whitespace());
+        if rest.is_empty() {
+            return links;
+        }
+        let target = rest
+            .strip_prefix('<')
+            .and_then(|after| after.split_once('>'));
+        let Some((url, after)) = target else {
+            // Not a link; skip to the next one
+            rest = skip_element(rest);
+            continue;
+        };
+        rest = after;
+        let mut rel = None;
+        let mut params = Vec::new();
+        while let Some(after) = rest.trim_start().strip_prefix(';') {
+            let (name, value, after) = link_param(after);
+            rest = after;
+            if name == "rel" {
+                rel.get_or_insert(value);
+            } else if !name.is_empty() {
+                params.push((name, value));
+            }
+        }
+        links.push(Link {
+            url: url.trim().to_owned(),
+            rel: rel.unwrap_or_default(),
+            params,
+        });
+        rest = skip_element(rest);
+    }
+}
+
+/// One `; name=value` parameter, the `;` already taken off `input`: its
+/// lowercased name, its value and what follows it
+fn link_param(input: &str) -> (String, String, &str) {
+    let input = input.trim_start();
+    let name_end = input
+        .find(|c: char| matches!(c, '=' | ';' | ',') || c.is_ascii_whitespace())
+        .unwrap_or(input.len());
+    let name = input[..name_end].to_ascii_lowercase();
+    let rest = input[name_end..].trim_start();
+    let Some(rest) = rest.strip_prefix('=') else {
+        return (name, String::new(), rest);
+    };
+    let rest = rest.trim_start();
+    let Some(quoted) = rest.strip_prefix('"') else {
+        let end = rest.find([';', ',']).unwrap_or(rest.len());
+        return (name, rest[..end].trim_end().to_owned(), &rest[end..]);
+    };
+    // A quoted-string, with backslash escapes
+    let mut value = String::new();
+    let mut chars = quoted.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return (name, value, &quoted[i + 1..]),
+            '\\' => value.extend(chars.next().map(|(_, c)| c)),
+            c => value.push(c),
+        }
+    }
+    // Unterminated; take the rest of the value as it is
+    (name, value, "")
+}
+
+/// What follows the comma that ends the list element `input` starts in,
+/// stepping over quoted strings
+fn skip_element(input: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in input.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ',' if !quoted => return &input[i + 1..],
+            _ => {}
+        }
+    }
+    ""
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(url: &str, rel: &str, params: &[(&str, &str)]) -> Link {
+        Link {
+            url: url.to_owned(),
+            rel: rel.to_owned(),
+            params: params
+                .iter()
+                .map(|&(name, value)| (name.to_owned(), value.to_owned()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn links_split_on_commas_outside_quotes_and_targets() {
+        let links = parse_links(
+            r#"<https://example.com/?page=3&a=1,2>; rel="next"; title="Page, three", </a;b>; REL=first; rel=ignored"#,
+        );
+        assert_eq!(
+            links,
+            [
+                link(
+                    "https://example.com/?page=3&a=1,2",
+                    "next",
+                    &[("title", "Page, three")]
+                ),
+                link("/a;b", "first", &[]),
+            ]
+        );
+    }
+
+    #[test]
+    fn links_skip_what_isnt_one_and_unescape_quoted_values() {
+        let links = parse_links(
+            r#"not-a-link; rel="x, y", <https://example.com/>; rel="prev start"; title="say \"hi\""; crossorigin"#,
+        );
+        assert_eq!(
+            links,
+            [link(
+                "https://example.com/",
+                "prev start",
+                &[("title", r#"say "hi""#), ("crossorigin", "")]
+            )]
+        );
+        assert!(links[0].has_rel("START"));
+        assert_eq!(links[0].param("Title"), Some(r#"say "hi""#));
+        assert!(parse_links(" , ").is_empty());
+    }
+}
@@ -0,0 +1,1508 @@
+//! Minimal HTTP/1.1 message types
+//!
+//! `Request` and `Response` are shared by every side that speaks HTTP:
+//! requests are encoded for the wire from structured fields, and responses
+//! are split into status line, headers and body so callers (and the
+//! response cache) can inspect them without string slicing. Response
+//! bodies are de-chunked and decompressed on parse; `text()` then decodes
+//! them according to the Content-Type charset.
+//! Also holds the validator types used for conditional requests.
+//!
+//! Trailer fields travel after a chunked body's last chunk, for values only
+//! known once the body has been produced (checksums, gRPC's `grpc-status`).
+//! Channel bodies send the [`Trailers`] their producer fills in, and parsed
+//! responses keep theirs apart from the headers, in
+//! [`Response::trailers`].
+
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime};
+
+use flate2::Compression;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::GzEncoder;
+use tokio::sync::mpsc;
+
+use crate::connect::ConnectionInfo;
+use crate::date;
+use crate::headers::{HeaderMap, Link, parse_links};
+
+/// Request body source
+pub enum Body<'a> {
+    Empty,
+    Json(&'a str),
+    /// Chunks produced by another task, sent with chunked transfer encoding
+    /// and followed by the trailers.
+    /// A bounded channel applies backpressure: the next chunk is only received
+    /// once the previous one has been written to the socket.
+    Channel(mpsc::Receiver<Vec<u8>>, Trailers),
+    /// Gzip-compressed representation of the given media type
+    Gzip {
+        data: Vec<u8>,
+        content_type: &'static str,
+    },
+    /// Channel body compressed as it streams; each chunk is flushed through
+    /// the compressor so it reaches the server without waiting for the next
+    GzipChannel(mpsc::Receiver<Vec<u8>>, Box<GzEncoder<Vec<u8>>>, Trailers),
+}
+
+/// Trailer fields for a channel body, sent after its last chunk
+///
+/// Clones share the fields, so the task producing the body can keep one
+/// and add fields until it closes the channel; they are read once the
+/// channel is closed.
+#[derive(Clone, Default)]
+pub struct Trailers(Rc<RefCell<HeaderMap>>);
+
+impl Trailers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn append(&self, name: impl Into<String>, value: impl Into<String>) {
+        self.0.borrow_mut().append(name, value);
+    }
+
+    /// The fields as sent, each line CRLF-terminated
+    fn encode(&self) -> String {
+        self.0
+            .borrow()
+            .iter()
+            .map(|(name, value)| format!("{name}: {value}\r\n"))
+            .collect()
+    }
+}
+
+impl<'a> Body<'a> {
+    /// A copy that can be sent again, unless the body is a consumable channel
+    pub fn try_clone(&self) -> Option<Body<'a>> {
+        match self {
+            Body::Empty => Some(Body::Empty),
+            Body::Json(body) => Some(Body::Json(body)),
+            Body::Gzip { data, content_type } => Some(Body::Gzip {
+                data: data.clone(),
+                content_type,
+            }),
+            Body::Channel(..) | Body::GzipChannel(..) => None,
+        }
+    }
+
+    /// Whether [`try_clone`](Self::try_clone) would give a copy
+    pub fn replayable(&self) -> bool {
+        !matches!(self, Body::Channel(..) | Body::GzipChannel(..))
+    }
+
+    /// Bytes sent inline after the request head
+    pub fn inline(&self) -> &[u8] {
+        match self {
+            Body::Json(body) => body.as_bytes(),
+            Body::Gzip { data, .. } => data,
+            Body::Empty | Body::Channel(..) | Body::GzipChannel(..) => &[],
+        }
+    }
+
+    /// Next chunk-encoded frame of a channel body, or the final empty chunk
+    /// and the trailers
+    pub async fn next_chunk(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let data = match self {
+                Body::Channel(rx, _) | Body::GzipChannel(rx, ..) => rx.recv().await,
+                _ => return None,
+            };
+            let Some(data) = data else {
+                return Some(self.finish_chunks());
+            };
+            // An empty chunk would terminate the body early
+            if data.is_empty() {
+                continue;
+            }
+
+            let Body::GzipChannel(_, encoder, _) = self else {
+                return Some(chunk_frame(&data));
+            };
+            // Writing into a Vec can't fail
+            encoder.write_all(&data).expect("gzip into memory");
+            encoder.flush().expect("gzip into memory");
+            let compressed = std::mem::take(encoder.get_mut());
+            if !compressed.is_empty() {
+                return Some(chunk_frame(&compressed));
+            }
+        }
+    }
+
+    /// The compressor's trailer, if any, the final empty chunk and the
+    /// trailer fields
+    fn finish_chunks(&mut self) -> Vec<u8> {
+        let (mut last, trailers) = match std::mem::replace(self, Body::Empty) {
+            Body::GzipChannel(_, encoder, trailers) => (
+                chunk_frame(&encoder.finish().expect("gzip into memory")),
+                trailers,
+            ),
+            Body::Channel(_, trailers) => (Vec::new(), trailers),
+            _ => (Vec::new(), Trailers::new()),
+        };
+        last.extend_from_slice(format!("0\r\n{}\r\n", trailers.encode()).as_bytes());
+        last
+    }
+}
+
+fn chunk_frame(data: &[u8]) -> Vec<u8> {
+    let mut frame = format!("{:x}\r\n", data.len()).into_bytes();
+    frame.extend_from_slice(data);
+    frame.extend_from_slice(b"\r\n");
+    frame
+}
+
+/// An HTTP/1.1 request
+pub struct Request<'a> {
+    pub method: String,
+    /// Sent as the `Host` header
+    pub host: String,
+    pub path: String,
+    /// Headers in send order; `Host` and the body framing headers are
+    /// derived from the other fields
+    pub headers: HeaderMap,
+    pub body: Body<'a>,
+}
+
+impl<'a> Request<'a> {
+    pub fn new(method: &str, host: &str, path: &str) -> Self {
+        Self {
+            method: method.to_owned(),
+            host: host.to_owned(),
+            path: path.to_owned(),
+            headers: HeaderMap::new(),
+            body: Body::Empty,
+        }
+    }
+
+    pub fn with_body(mut self, body: Body<'a>) -> Self {
+        self.body = body;
+        self
+    }
+
+    /// Send `TE: trailers`, telling the server the response may carry
+    /// trailer fields, as gRPC requires
+    ///
+    /// TE only applies to the one connection, so it is named in
+    /// `Connection` too (RFC 9110 §10.1.4).
+    pub fn with_te_trailers(mut self) -> Self {
+        self.headers.append("TE", "trailers");
+        self.headers.append("Connection", "TE");
+        self
+    }
+
+    /// Gzip the body and mark it with `Content-Encoding: gzip`
+    ///
+    /// JSON bodies shorter than `min_size` are sent as they are; channel
+    /// bodies, whose size isn't known up front, are always compressed.
+    pub fn gzip_body(&mut self, min_size: usize) {
+        self.body = match std::mem::replace(&mut self.body, Body::Empty) {
+            Body::Json(json) if json.len() >= min_size => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder
+                    .write_all(json.as_bytes())
+                    .expect("gzip into memory");
+                Body::Gzip {
+                    data: encoder.finish().expect("gzip into memory"),
+                    content_type: "application/json",
+                }
+            }
+            Body::Channel(rx, trailers) => Body::GzipChannel(
+                rx,
+                Box::new(GzEncoder::new(Vec::new(), Compression::fast())),
+                trailers,
+            ),
+            body => {
+                self.body = body;
+                return;
+            }
+        };
+        self.headers.append("Content-Encoding", "gzip");
+    }
+
+    /// A copy for resending, unless the body can only be sent once
+    pub fn try_clone(&self) -> Option<Request<'a>> {
+        Some(Self {
+            method: self.method.clone(),
+            host: self.host.clone(),
+            path: self.path.clone(),
+            headers: self.headers.clone(),
+            body: self.body.try_clone()?,
+        })
+    }
+
+    /// Request line, headers and any inline body, ready to write; a channel
+    /// body's chunks follow separately
+    pub fn encode(&self) -> Vec<u8> {
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\n",
+            self.method, self.path, self.host
+        );
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+
+        match &self.body {
+            Body::Json(body) => {
+                head.push_str(&format!(
+                    "Content-Length: {}\r\n\
+                     Content-Type: application/json\r\n",
+                    body.len()
+                ));
+            }
+            Body::Gzip { data, content_type } => {
+                head.push_str(&format!(
+                    "Content-Length: {}\r\n\
+                     Content-Type: {content_type}\r\n",
+                    data.len()
+                ));
+            }
+            Body::Channel(..) | Body::GzipChannel(..) => {
+                head.push_str("Transfer-Encoding: chunked\r\n")
+            }
+            Body::Empty => {}
+        }
+        head.push_str("\r\n");
+
+        let mut encoded = head.into_bytes();
+        encoded.extend_from_slice(self.body.inline());
+        encoded
+    }
+}
+
+/// A request's head as a server reads it, e.g. a WebSocket upgrade
+#[derive(Clone, Debug)]
+pub struct RequestHead {
+    pub method: String,
+    /// The request target, usually a path with its query
+    pub target: String,
+    pub version: Version,
+    pub headers: HeaderMap,
+}
+
+impl RequestHead {
+    /// The request head `raw` starts with and its length, or `None` while
+    /// it is still incomplete
+    pub fn parse(raw: &[u8]) -> Result<Option<(Self, usize)>, HttpError> {
+        let head = match parse_head(raw) {
+            Ok(head) => head,
+            Err(HttpError::MissingHeaderEnd) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let invalid = || HttpError::InvalidRequestLine(head.start_line.clone());
+        let mut parts = head.start_line.split(' ');
+        let (Some(method), Some(target), Some(version), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        let version = match version {
+            "HTTP/1.0" => Version::Http10,
+            "HTTP/1.1" => Version::Http11,
+            _ => return Err(invalid()),
+        };
+        if method.is_empty() || target.is_empty() {
+            return Err(invalid());
+        }
+        let request = Self {
+            method: method.to_owned(),
+            target: target.to_owned(),
+            version,
+            headers: head.headers,
+        };
+        Ok(Some((request, head.body_start)))
+    }
+}
+
+/// HTTP version a request or response was sent with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Version::Http10 => write!(f, "HTTP/1.0"),
+            Version::Http11 => write!(f, "HTTP/1.1"),
+        }
+    }
+}
+
+/// A parsed HTTP/1.1 response
+#[derive(Clone, Debug)]
+pub struct Response {
+    /// From the status line; an HTTP/1.1 request may be answered in HTTP/1.0
+    pub version: Version,
+    pub status: u16,
+    pub reason: String,
+    /// Headers in the order they were received
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+    /// Fields that followed a chunked body, in the order they were received
+    pub trailers: HeaderMap,
+    /// Set by the client that received the response
+    pub connection: Option<ConnectionInfo>,
+    /// Redirects the client followed to get here, in order
+    pub redirects: Vec<Redirect>,
+    /// The policy that let the response stand when its connection closed
+    /// without `close_notify`; `None` after a clean close
+    pub close_policy: Option<ClosePolicy>,
+    /// Where the time went; set by the client that received the response
+    pub timing: Option<Timing>,
+}
+
+/// What to make of a connection that closes without TLS `close_notify`
+/// after part of a response has arrived
+///
+/// kTLS reports such a close as EIO, rustls as `UnexpectedEof`. Without
+/// the alert nothing shows the server meant to close, so anyone who can
+/// reset the connection can cut the response short. A body framed by
+/// `Content-Length` or chunked encoding still shows whether it is whole;
+/// one that runs until the close doesn't.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClosePolicy {
+    /// Keep the response if its framing can tell it is whole, and fail
+    /// it if it runs until the close
+    Strict,
+    /// Keep whatever arrived
+    Lenient,
+    /// Like `Lenient`, with a warning on stderr
+    LenientWithWarning,
+}
+
+impl ClosePolicy {
+    /// The policy for a response beginning `raw` when the request set
+    /// none: strict when the head frames the body, lenient when only the
+    /// close can end it
+    pub fn default_for(raw: &[u8]) -> Self {
+        if framed(raw) {
+            ClosePolicy::Strict
+        } else {
+            ClosePolicy::Lenient
+        }
+    }
+
+    /// Decide on `raw`, the response so far from `host`, whose connection
+    /// closed without `close_notify`
+    ///
+    /// A framed body that stands is still checked for length by parsing.
+    pub fn apply(self, host: &str, raw: &[u8]) -> Result<(), HttpError> {
+        match self {
+            ClosePolicy::Strict if !framed(raw) => Err(HttpError::UncleanClose),
+            ClosePolicy::Strict | ClosePolicy::Lenient => Ok(()),
+            ClosePolicy::LenientWithWarning => {
+                eprintln!(
+                    "{host} closed the connection without close_notify; the response may be cut short"
+                );
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ClosePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClosePolicy::Strict => write!(f, "strict"),
+            ClosePolicy::Lenient => write!(f, "lenient"),
+            ClosePolicy::LenientWithWarning => write!(f, "lenient with warning"),
+        }
+    }
+}
+
+/// One redirect followed on the way to a response
+#[derive(Clone, Debug)]
+pub struct Redirect {
+    /// URL the redirecting request went to
+    pub from: String,
+    /// URL the `Location` header sent the request on to
+    pub to: String,
+    pub status: u16,
+    /// From sending the request to having the redirect in hand, retries
+    /// included
+    pub elapsed: Duration,
+}
+
+/// Where a request's time went, from monotonic clocks
+///
+/// A phase the request skipped, such as connecting on a session's open
+/// connection or kTLS setup under userspace TLS, stays zero. When kTLS
+/// setup fails and the client reconnects for userspace TLS, each phase
+/// adds up both tries, so `ktls_setup` shows what the failed attempt cost.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Timing {
+    /// Resolving the host
+    pub dns: Duration,
+    /// The TCP (or MPTCP) handshake
+    pub connect: Duration,
+    pub tls_handshake: Duration,
+    /// Handing the session's keys to the kernel
+    pub ktls_setup: Duration,
+    /// From starting to send the request to the first byte of the response
+    pub ttfb: Duration,
+    /// The whole request, parsing the response included
+    pub total: Duration,
+}
+
+impl std::fmt::Display for Timing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "dns {:.1?}, connect {:.1?}, tls {:.1?}, ktls {:.1?}, ttfb {:.1?}, total {:.1?}",
+            self.dns, self.connect, self.tls_handshake, self.ktls_setup, self.ttfb, self.total
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum HttpError {
+    MissingHeaderEnd,
+    InvalidStatusLine(String),
+    InvalidRequestLine(String),
+    InvalidHeader(String),
+    InvalidChunkSize(String),
+    /// Body ended early; for chunked bodies `expected` is the minimum raw length needed
+    IncompleteBody {
+        expected: usize,
+        got: usize,
+    },
+    UnsupportedEncoding(String),
+    Decompress(std::io::Error),
+    UnsupportedCharset(String),
+    InvalidText(std::string::FromUtf8Error),
+    /// Rejected by [`Response::parse_strict`]
+    Protocol(ProtocolViolation),
+    /// The connection closed without `close_notify` during a body only the
+    /// close could end, under [`ClosePolicy::Strict`]
+    UncleanClose,
+}
+
+/// Header constructs a lenient parse lets through but that HTTP/1.1
+/// forbids or that intermediaries disagree on, the raw material of response
+/// splitting and request smuggling
+#[derive(Debug)]
+pub enum ProtocolViolation {
+    /// A header line continued onto the next, which RFC 9112 §5.2 obsoletes
+    ObsFold(String),
+    /// NUL, CR, LF or another control character in the named header's value
+    ControlCharacter(String),
+    /// Content-Length given more than once, with different values
+    ConflictingContentLength(Vec<String>),
+    /// Both Content-Length and Transfer-Encoding, which parties can frame
+    /// differently (RFC 9112 §6.3)
+    ContentLengthWithTransferEncoding,
+}
+
+impl std::fmt::Display for ProtocolViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolViolation::ObsFold(line) => write!(f, "folded header line {line:?}"),
+            ProtocolViolation::ControlCharacter(name) => {
+                write!(f, "control character in {name} header")
+            }
+            ProtocolViolation::ConflictingContentLength(values) => {
+                write!(f, "conflicting Content-Length values {values:?}")
+            }
+            ProtocolViolation::ContentLengthWithTransferEncoding => {
+                write!(f, "both Content-Length and Transfer-Encoding")
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HttpError::MissingHeaderEnd => write!(f, "Response headers not terminated"),
+            HttpError::InvalidStatusLine(l) => write!(f, "Invalid status line: {l:?}"),
+            HttpError::InvalidRequestLine(l) => write!(f, "Invalid request line: {l:?}"),
+            HttpError::InvalidHeader(l) => write!(f, "Invalid header line: {l:?}"),
+            HttpError::InvalidChunkSize(l) => write!(f, "Invalid chunk size line: {l:?}"),
+            HttpError::IncompleteBody { expected, got } => {
+                write!(f, "Incomplete body: expected {expected} bytes, got {got}")
+            }
+            HttpError::UnsupportedEncoding(e) => write!(f, "Unsupported Content-Encoding: {e}"),
+            HttpError::Decompress(e) => write!(f, "Failed to decompress body: {e}"),
+            HttpError::UnsupportedCharset(c) => write!(f, "Unsupported charset: {c}"),
+            HttpError::InvalidText(e) => write!(f, "Body is not valid UTF-8: {e}"),
+            HttpError::Protocol(v) => write!(f, "Protocol violation: {v}"),
+            HttpError::UncleanClose => {
+                write!(
+                    f,
+                    "Connection closed without close_notify before a close-delimited body ended"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+impl Response {
+    /// Parse a complete raw response (headers + body)
+    pub fn parse(raw: &[u8]) -> Result<Self, HttpError> {
+        Self::parse_split(raw, Vec::new(), false)
+    }
+
+    /// Like [`parse`](Self::parse), but failing with
+    /// [`HttpError::Protocol`] on folded header lines, control characters
+    /// in header values, conflicting Content-Length headers, and
+    /// Content-Length alongside Transfer-Encoding
+    pub fn parse_strict(raw: &[u8]) -> Result<Self, HttpError> {
+        Self::parse_split(raw, Vec::new(), true)
+    }
+
+    /// Parse a response read in two parts: `raw`, holding the complete
+    /// head and possibly the start of the body, then `rest`, the remainder
+    /// of the body
+    ///
+    /// `rest` becomes the body without being copied, unless `raw` held
+    /// some of the body too, which is moved in ahead of it.
+    pub fn parse_split(raw: &[u8], rest: Vec<u8>, strict: bool) -> Result<Self, HttpError> {
+        let (mut response, body_start) = Self::parse_head(raw, strict)?;
+        response.body = join(&raw[body_start..], rest);
+        response.check_framing()?;
+        response.decode_content()?;
+        Ok(response)
+    }
+
+    /// Parse just the head `raw` starts with, for a body read on its own:
+    /// the response, with an empty body, and where its body starts in `raw`
+    ///
+    /// The strict checks apply with `strict`, as in
+    /// [`parse_strict`](Self::parse_strict); framing and `Content-Encoding`
+    /// are left to the caller.
+    pub fn parse_head(raw: &[u8], strict: bool) -> Result<(Self, usize), HttpError> {
+        // Interim responses came ahead of this one, and don't make it up
+        let start = final_start(raw);
+        let raw = &raw[start..];
+        if strict {
+            check_head(raw)?;
+        }
+        let Head {
+            start_line: status_line,
+            headers,
+            body_start,
+        } = parse_head(raw)?;
+        let mut parts = status_line.splitn(3, ' ');
+        let invalid = || HttpError::InvalidStatusLine(status_line.clone());
+        let version = match parts.next() {
+            Some("HTTP/1.0") => Version::Http10,
+            Some("HTTP/1.1") => Version::Http11,
+            _ => return Err(invalid()),
+        };
+        let status = parts
+            .next()
+            .and_then(|code| code.parse::<u16>().ok())
+            .ok_or_else(invalid)?;
+        let reason = parts.next().unwrap_or_default().to_owned();
+
+        let response = Self {
+            version,
+            status,
+            reason,
+            headers,
+            body: Vec::new(),
+            trailers: HeaderMap::new(),
+            connection: None,
+            redirects: Vec::new(),
+            close_policy: None,
+            timing: None,
+        };
+        Ok((response, start + body_start))
+    }
+
+    /// Verify the body is as long as its framing says, so a connection that
+    /// died mid-response isn't mistaken for a complete one
+    fn check_framing(&mut self) -> Result<(), HttpError> {
+        // 1xx, 204 and 304 never carry a body (RFC 9112 §6.3)
+        if (100..200).contains(&self.status) || self.status == 204 || self.status == 304 {
+            self.body.clear();
+            return Ok(());
+        }
+
+        let chunked = self
+            .header("Transfer-Encoding")
+            .is_some_and(|te| te.to_ascii_lowercase().contains("chunked"));
+        if chunked {
+            let (body, trailers, _) = decode_chunked(&self.body)?;
+            self.body = body;
+            self.trailers = trailers;
+            return Ok(());
+        }
+
+        if let Some(value) = self.header("Content-Length") {
+            let expected = value
+                .parse::<usize>()
+                .map_err(|_| HttpError::InvalidHeader(format!("Content-Length: {value}")))?;
+            if self.body.len() < expected {
+                return Err(HttpError::IncompleteBody {
+                    expected,
+                    got: self.body.len(),
+                });
+            }
+            self.body.truncate(expected);
+        }
+
+        // Otherwise the body is delimited by connection close
+        Ok(())
+    }
+
+    /// Undo `Content-Encoding` so `body` always holds the representation bytes
+    fn decode_content(&mut self) -> Result<(), HttpError> {
+        let Some(encoding) = self.header("Content-Encoding").map(str::to_ascii_lowercase) else {
+            return Ok(());
+        };
+
+        let mut decoded = Vec::new();
+        match encoding.trim() {
+            "identity" => return Ok(()),
+            "gzip" | "x-gzip" => GzDecoder::new(&self.body[..]).read_to_end(&mut decoded),
+            "deflate" => ZlibDecoder::new(&self.body[..]).read_to_end(&mut decoded),
+            other => return Err(HttpError::UnsupportedEncoding(other.to_owned())),
+        }
+        .map_err(HttpError::Decompress)?;
+
+        // The framing headers described the encoded body, not this one
+        self.headers.remove("Content-Encoding");
+        self.headers.remove("Content-Length");
+        self.body = decoded;
+        Ok(())
+    }
+
+    /// First value of a header, matched case-insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// Whether the connection the response came on may carry another
+    /// request: not once the server has sent `Connection: close`, and over
+    /// HTTP/1.0 only if it sent `Connection: keep-alive` (RFC 9112 §9.3)
+    pub fn reusable(&self) -> bool {
+        let has = |token: &str| {
+            self.headers
+                .get_all("Connection")
+                .flat_map(|value| value.split(','))
+                .any(|t| t.trim().eq_ignore_ascii_case(token))
+        };
+        !has("close") && (self.version != Version::Http10 || has("keep-alive"))
+    }
+
+    /// How long the server keeps the connection open while it is idle, from
+    /// `Keep-Alive: timeout=<seconds>`
+    pub fn keep_alive_timeout(&self) -> Option<Duration> {
+        self.header("Keep-Alive")?.split(',').find_map(|param| {
+            let (name, value) = param.split_once('=')?;
+            if !name.trim().eq_ignore_ascii_case("timeout") {
+                return None;
+            }
+            value.trim().parse().ok().map(Duration::from_secs)
+        })
+    }
+
+    /// Every `Set-Cookie` value, in order
+    ///
+    /// Each one is a cookie of its own. Unlike most headers they aren't
+    /// joined with commas, as cookie attributes such as `Expires` hold
+    /// commas of their own.
+    pub fn set_cookies(&self) -> impl Iterator<Item = &str> {
+        self.headers.get_all("Set-Cookie")
+    }
+
+    /// The links of every `Link` header, in order (RFC 8288)
+    pub fn links(&self) -> Vec<Link> {
+        self.headers.get_all("Link").flat_map(parse_links).collect()
+    }
+
+    /// The first link with relation type `rel`, e.g. `next` for the next
+    /// page of a paginated API
+    pub fn link(&self, rel: &str) -> Option<Link> {
+        self.links().into_iter().find(|link| link.has_rel(rel))
+    }
+
+    /// First value of a trailer field, matched case-insensitively
+    pub fn trailer(&self, name: &str) -> Option<&str> {
+        self.trailers.get(name)
+    }
+
+    /// Wait requested by `Retry-After`, given either as seconds or as an
+    /// HTTP-date; dates are measured from the response's own `Date` so clock
+    /// skew between client and server doesn't stretch or cut the wait
+    pub fn retry_after(&self) -> Option<Duration> {
+        let value = self.header("Retry-After")?.trim();
+        if let Ok(secs) = value.parse::<u64>() {
+            return Some(Duration::from_secs(secs));
+        }
+        let at = date::parse(value)?;
+        let now = self
+            .header("Date")
+            .and_then(date::parse)
+            .unwrap_or_else(SystemTime::now);
+        Some(at.duration_since(now).unwrap_or(Duration::ZERO))
+    }
+
+    /// Raw body bytes, after de-chunking and decompression
+    pub fn bytes(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// `charset` parameter of the Content-Type header, lowercased
+    pub fn charset(&self) -> Option<String> {
+        self.header("Content-Type")?
+            .split(';')
+            .skip(1)
+            .filter_map(|param| param.split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("charset"))
+            .map(|(_, value)| value.trim().trim_matches('"').to_ascii_lowercase())
+    }
+
+    /// Body decoded as text according to the Content-Type charset (UTF-8 if absent)
+    pub fn text(&self) -> Result<String, HttpError> {
+        match self.charset().as_deref() {
+            None | Some("utf-8" | "utf8" | "us-ascii") => {
+                String::from_utf8(self.body.clone()).map_err(HttpError::InvalidText)
+            }
+            // Latin-1 maps each byte directly to the code point of the same value
+            Some("iso-8859-1" | "latin1" | "l1") => {
+                Ok(self.body.iter().map(|&b| b as char).collect())
+            }
+            Some(other) => Err(HttpError::UnsupportedCharset(other.to_owned())),
+        }
+    }
+}
+
+/// `start` followed by `rest`, reusing `rest`'s buffer
+fn join(start: &[u8], mut rest: Vec<u8>) -> Vec<u8> {
+    if rest.is_empty() {
+        return start.to_vec();
+    }
+    rest.splice(0..0, start.iter().copied());
+    rest
+}
+
+/// Total response length implied by a complete head: `Some(None)` when the
+/// length isn't known up front (chunked, close-delimited or malformed),
+/// `None` while the head itself is still incomplete
+///
+/// Follows the same framing rules as [`response_len`].
+pub fn expected_len(raw: &[u8]) -> Option<Option<usize>> {
+    let start = final_start(raw);
+    let head = match parse_head(&raw[start..]) {
+        Ok(head) => head,
+        Err(HttpError::MissingHeaderEnd) => return None,
+        Err(_) => return Some(None),
+    };
+    match body_end(&head) {
+        BodyEnd::At(end) => Some(Some(start + end)),
+        BodyEnd::Chunked | BodyEnd::Close | BodyEnd::Malformed => Some(None),
+    }
+}
+
+/// Whether the head `raw` starts with says where the body ends, so that
+/// the body doesn't run until the connection closes; `false` while the
+/// head is incomplete
+fn framed(raw: &[u8]) -> bool {
+    let Ok(head) = parse_head(&raw[final_start(raw)..]) else {
+        return false;
+    };
+    let status = head
+        .start_line
+        .split(' ')
+        .nth(1)
+        .and_then(|s| s.parse::<u16>().ok());
+    matches!(status, Some(100..=199 | 204 | 304))
+        || head.headers.get("Transfer-Encoding").is_some()
+        || head.headers.get("Content-Length").is_some()
+}
+
+/// Length of the first complete response in `raw`, any interim responses
+/// ahead of it included, or `None` while more of it is still to come
+///
+/// For reading responses off a persistent connection; a body delimited by
+/// connection close is never complete. Malformed framing counts as complete
+/// so that [`Response::parse`] reports it.
+pub fn response_len(raw: &[u8]) -> Option<usize> {
+    let start = final_start(raw);
+    response_len_from(&raw[start..]).map(|len| start + len)
+}
+
+/// [`response_len`] for the response to a `method` request: one to HEAD
+/// ends with its head, whatever that says of the body (RFC 9110 §9.3.2)
+pub fn response_len_to(method: &str, raw: &[u8]) -> Option<usize> {
+    if method == "HEAD" {
+        head_len(raw)
+    } else {
+        response_len(raw)
+    }
+}
+
+/// [`response_len`] for a response with no interim ones ahead of it
+fn response_len_from(raw: &[u8]) -> Option<usize> {
+    let head = match parse_head(raw) {
+        Ok(head) => head,
+        Err(HttpError::MissingHeaderEnd) => return None,
+        Err(_) => return Some(raw.len()),
+    };
+    match body_end(&head) {
+        BodyEnd::At(end) => (raw.len() >= end).then_some(end),
+        BodyEnd::Chunked => match decode_chunked(&raw[head.body_start..]) {
+            Ok((_, _, len)) => Some(head.body_start + len),
+            Err(HttpError::IncompleteBody { .. }) => None,
+            Err(_) => Some(raw.len()),
+        },
+        BodyEnd::Close => None,
+        BodyEnd::Malformed => Some(raw.len()),
+    }
+}
+
+/// Where a response's head says its body ends
+enum BodyEnd {
+    /// At this offset from the start of the response
+    At(usize),
+    /// After its last chunk and trailers
+    Chunked,
+    /// When the connection closes
+    Close,
+    /// Nowhere the head makes out
+    Malformed,
+}
+
+fn body_end(head: &Head) -> BodyEnd {
+    let status = head
+        .start_line
+        .split(' ')
+        .nth(1)
+        .and_then(|s| s.parse::<u16>().ok());
+    let Some(status) = status else {
+        return BodyEnd::Malformed;
+    };
+    if (100..200).contains(&status) || status == 204 || status == 304 {
+        return BodyEnd::At(head.body_start);
+    }
+
+    let chunked = head
+        .headers
+        .get("Transfer-Encoding")
+        .is_some_and(|te| te.to_ascii_lowercase().contains("chunked"));
+    if chunked {
+        return BodyEnd::Chunked;
+    }
+
+    let Some(len) = head.headers.get("Content-Length") else {
+        return BodyEnd::Close;
+    };
+    let end = len
+        .parse::<usize>()
+        .ok()
+        .and_then(|len| head.body_start.checked_add(len));
+    end.map_or(BodyEnd::Malformed, BodyEnd::At)
+}
+
+/// Length of the final response's head in `raw`, any interim responses
+/// ahead of it included, or `None` while it is still incomplete
+pub fn head_len(raw: &[u8]) -> Option<usize> {
+    let start = final_start(raw);
+    let end = raw[start..].windows(4).position(|w| w == b"\r\n\r\n")?;
+    Some(start + end + 4)
+}
+
+/// Whether `raw` holds a whole final response, so a one-shot request can
+/// stop reading without waiting for the server to close the connection
+///
+/// Interim (1xx) responses ahead of it don't count. Chunked bodies are only
+/// decoded once `raw` ends as a whole one must, with the blank line after
+/// its trailers, so checking after every read stays cheap.
+pub fn response_complete(raw: &[u8]) -> bool {
+    let head = match parse_head(&raw[final_start(raw)..]) {
+        Ok(head) => head,
+        Err(HttpError::MissingHeaderEnd) => return false,
+        Err(_) => return true,
+    };
+    let chunked = head
+        .headers
+        .get("Transfer-Encoding")
+        .is_some_and(|te| te.to_ascii_lowercase().contains("chunked"));
+    if chunked && !raw.ends_with(b"\r\n\r\n") {
+        return false;
+    }
+    response_len(raw).is_some()
+}
+
+/// An interim (1xx) response, such as 103 Early Hints, sent ahead of the
+/// final response to a request
+#[derive(Clone, Debug)]
+pub struct Interim {
+    pub status: u16,
+    pub headers: HeaderMap,
+    /// Bytes it took up, head and all; interim responses have no body
+    pub len: usize,
+}
+
+/// Receives the headers of each 103 Early Hints response to a request
+pub type EarlyHintsHook = dyn Fn(&HeaderMap);
+
+/// The interim response `raw` starts with, if a whole one is there
+///
+/// 101 Switching Protocols isn't one: it ends the exchange rather than
+/// coming ahead of its response.
+pub fn interim(raw: &[u8]) -> Option<Interim> {
+    let head = parse_head(raw).ok()?;
+    let status = head
+        .start_line
+        .split(' ')
+        .nth(1)
+        .and_then(|s| s.parse::<u16>().ok())
+        .filter(|status| (100..200).contains(status) && *status != 101)?;
+    Some(Interim {
+        status,
+        headers: head.headers,
+        len: head.body_start,
+    })
+}
+
+/// Where the final response starts in `raw`, past the interim responses
+/// that arrived ahead of it
+fn final_start(raw: &[u8]) -> usize {
+    let mut start = 0;
+    while let Some(interim) = interim(&raw[start..]) {
+        start += interim.len;
+    }
+    start
+}
+
+/// Payload and trailer fields of a chunked body, checking it runs through
+/// its last chunk and trailers, and the number of raw bytes it took up
+fn decode_chunked(body: &[u8]) -> Result<(Vec<u8>, HeaderMap, usize), HttpError> {
+    let incomplete = |expected: usize| HttpError::IncompleteBody {
+        expected,
+        got: body.len(),
+    };
+    let line_end = |from: usize| {
+        body[from..]
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .map(|p| from + p)
+    };
+
+    let mut decoded = Vec::new();
+    let mut pos = 0;
+    loop {
+        // "0\r\n\r\n" is the shortest possible remainder
+        let end = line_end(pos).ok_or_else(|| incomplete(pos + 5))?;
+        let line = String::from_utf8_lossy(&body[pos..end]);
+        let size_str = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size_str, 16)
+            .map_err(|_| HttpError::InvalidChunkSize(line.to_string()))?;
+        pos = end + 2;
+
+        if size == 0 {
+            break;
+        }
+
+        // Chunk data followed by CRLF
+        let chunk_end = pos
+            .checked_add(size)
+            .and_then(|n| n.checked_add(2))
+            .ok_or_else(|| HttpError::InvalidChunkSize(line.to_string()))?;
+        if chunk_end > body.len() {
+            return Err(incomplete(chunk_end));
+        }
+        decoded.extend_from_slice(&body[pos..pos + size]);
+        pos = chunk_end;
+    }
+
+    // Trailer fields, terminated by an empty line
+    let mut trailers = HeaderMap::new();
+    loop {
+        let end = line_end(pos).ok_or_else(|| incomplete(pos + 2))?;
+        if end == pos {
+            return Ok((decoded, trailers, end + 2));
+        }
+        let line = String::from_utf8_lossy(&body[pos..end]);
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| HttpError::InvalidHeader(line.to_string()))?;
+        trailers.append(name.trim(), value.trim());
+        pos = end + 2;
+    }
+}
+
+/// Longest chunk size or trailer line a [`BodyDecoder`] waits out
+const MAX_LINE: usize = 8 * 1024;
+
+/// Takes a response body apart as it arrives, by the framing its head
+/// gives it, for bodies read a piece at a time rather than whole
+///
+/// The payload comes out as sent: chunked encoding is undone, but not
+/// `Content-Encoding`.
+#[derive(Debug)]
+pub struct BodyDecoder {
+    framing: Framing,
+    /// Raw body bytes used up so far
+    consumed: usize,
+    trailers: HeaderMap,
+}
+
+#[derive(Debug)]
+enum Framing {
+    /// `Content-Length`, with the bytes still to come
+    Length(usize),
+    Chunked(ChunkState),
+    /// Runs until the connection closes
+    Close,
+    Done,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum ChunkState {
+    Size,
+    /// Within a chunk, with its bytes still to come
+    Data(usize),
+    /// The CRLF after a chunk's data
+    DataEnd,
+    Trailers,
+}
+
+impl BodyDecoder {
+    /// A decoder for the body of `response`, parsed by
+    /// [`Response::parse_head`]
+    pub fn new(response: &Response) -> Result<Self, HttpError> {
+        let status = response.status;
+        let chunked = response
+            .header("Transfer-Encoding")
+            .is_some_and(|te| te.to_ascii_lowercase().contains("chunked"));
+        let framing = if (100..200).contains(&status) || status == 204 || status == 304 {
+            Framing::Done
+        } else if chunked {
+            Framing::Chunked(ChunkState::Size)
+        } else if let Some(value) = response.header("Content-Length") {
+            let len = value
+                .parse::<usize>()
+                .map_err(|_| HttpError::InvalidHeader(format!("Content-Length: {value}")))?;
+            if len == 0 {
+                Framing::Done
+            } else {
+                Framing::Length(len)
+            }
+        } else {
+            Framing::Close
+        };
+        Ok(Self {
+            framing,
+            consumed: 0,
+            trailers: HeaderMap::new(),
+        })
+    }
+
+    /// Decode as much of `input` as is there, removing what was used up
+    /// from it, and return the payload it held
+    ///
+    /// What's left of `input` is the start of a chunk size or trailer line,
+    /// or, once [`done`](Self::done), whatever followed the body.
+    pub fn decode(&mut self, input: &mut Vec<u8>) -> Result<Vec<u8>, HttpError> {
+        let mut payload = Vec::new();
+        let mut pos = 0;
+        loop {
+            let available = &input[pos..];
+            match &mut self.framing {
+                Framing::Done => break,
+                Framing::Close => {
+                    payload.extend_from_slice(available);
+                    pos = input.len();
+                    break;
+                }
+                Framing::Length(remaining) => {
+                    let take = (*remaining).min(available.len());
+                    payload.extend_from_slice(&available[..take]);
+                    pos += take;
+                    *remaining -= take;
+                    if *remaining == 0 {
+                        self.framing = Framing::Done;
+                    }
+                    break;
+                }
+                Framing::Chunked(ChunkState::Data(remaining)) => {
+                    let take = (*remaining).min(available.len());
+                    if take == 0 {
+                        break;
+                    }
+                    payload.extend_from_slice(&available[..take]);
+                    pos += take;
+                    *remaining -= take;
+                    if *remaining == 0 {
+                        self.framing = Framing::Chunked(ChunkState::DataEnd);
+                    }
+                }
+                Framing::Chunked(ChunkState::DataEnd) => {
+                    if available.len() < 2 {
+                        break;
+                    }
+                    pos += 2;
+                    self.framing = Framing::Chunked(ChunkState::Size);
+                }
+                Framing::Chunked(state) => {
+                    let Some(end) = available.windows(2).position(|w| w == b"\r\n") else {
+                        if available.len() > MAX_LINE {
+                            let line = String::from_utf8_lossy(&available[..MAX_LINE]);
+                            return Err(HttpError::InvalidHeader(line.into_owned()));
+                        }
+                        break;
+                    };
+                    let line = String::from_utf8_lossy(&available[..end]);
+                    pos += end + 2;
+                    if let ChunkState::Size = state {
+                        let size_str = line.split(';').next().unwrap_or_default().trim();
+                        let size = usize::from_str_radix(size_str, 16)
+                            .map_err(|_| HttpError::InvalidChunkSize(line.to_string()))?;
+                        *state = match size {
+                            0 => ChunkState::Trailers,
+                            size => ChunkState::Data(size),
+                        };
+                    } else if line.is_empty() {
+                        self.framing = Framing::Done;
+                    } else {
+                        let (name, value) = line
+                            .split_once(':')
+                            .ok_or_else(|| HttpError::InvalidHeader(line.to_string()))?;
+                        self.trailers.append(name.trim(), value.trim());
+                    }
+                }
+            }
+        }
+        input.drain(..pos);
+        self.consumed += pos;
+        Ok(payload)
+    }
+
+    /// Whether the body has ended, by its framing
+    pub fn done(&self) -> bool {
+        matches!(self.framing, Framing::Done)
+    }
+
+    /// Whether only the connection closing ends the body
+    pub fn close_delimited(&self) -> bool {
+        matches!(self.framing, Framing::Close)
+    }
+
+    /// Account for the connection closing: ends a close-delimited body, and
+    /// fails one the framing says is cut short
+    ///
+    /// As with [`Response::parse`], a chunked body's `expected` length is
+    /// the least it could have run to.
+    pub fn finish(&mut self) -> Result<(), HttpError> {
+        // "0\r\n\r\n" is the shortest end a chunked body can have
+        let still_needed = match self.framing {
+            Framing::Done => return Ok(()),
+            Framing::Close => {
+                self.framing = Framing::Done;
+                return Ok(());
+            }
+            Framing::Length(remaining) => remaining,
+            Framing::Chunked(ChunkState::Size) => 5,
+            Framing::Chunked(ChunkState::Data(remaining)) => remaining.saturating_add(2 + 5),
+            Framing::Chunked(ChunkState::DataEnd) => 2 + 5,
+            Framing::Chunked(ChunkState::Trailers) => 2,
+        };
+        Err(HttpError::IncompleteBody {
+            expected: self.consumed.saturating_add(still_needed),
+            got: self.consumed,
+        })
+    }
+
+    /// Fields that followed a chunked body, once it is done
+    pub fn trailers(&self) -> &HeaderMap {
+        &self.trailers
+    }
+}
+
+/// The strict checks on a raw response head
+fn check_head(raw: &[u8]) -> Result<(), HttpError> {
+    let violation = |v| Err(HttpError::Protocol(v));
+    let head_end = raw
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or(HttpError::MissingHeaderEnd)?;
+
+    let mut content_lengths = Vec::new();
+    let mut transfer_encoding = false;
+    for line in raw[..head_end].split(|&b| b == b'\n').skip(1) {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let text = String::from_utf8_lossy(line);
+        if line.starts_with(b" ") || line.starts_with(b"\t") {
+            return violation(ProtocolViolation::ObsFold(text.into_owned()));
+        }
+        let Some((name, value)) = text.split_once(':') else {
+            // Left for the head parser to report
+            continue;
+        };
+        if value.bytes().any(|b| (b < 0x20 && b != b'\t') || b == 0x7f) {
+            return violation(ProtocolViolation::ControlCharacter(name.trim().to_owned()));
+        }
+        if name.eq_ignore_ascii_case("Content-Length") {
+            content_lengths.extend(value.split(',').map(|v| v.trim().to_owned()));
+        } else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+            transfer_encoding = true;
+        }
+    }
+
+    // Repeats of one value are allowed (RFC 9110 §8.6)
+    if content_lengths.windows(2).any(|pair| pair[0] != pair[1]) {
+        return violation(ProtocolViolation::ConflictingContentLength(content_lengths));
+    }
+    if transfer_encoding && !content_lengths.is_empty() {
+        return violation(ProtocolViolation::ContentLengthWithTransferEncoding);
+    }
+    Ok(())
+}
+
+/// Start line and headers of a raw message
+struct Head {
+    start_line: String,
+    headers: HeaderMap,
+    /// Offset of the body in the raw message
+    body_start: usize,
+}
+
+fn parse_head(raw: &[u8]) -> Result<Head, HttpError> {
+    let head_end = raw
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or(HttpError::MissingHeaderEnd)?;
+    let head = String::from_utf8_lossy(&raw[..head_end]);
+    let mut lines = head.split("\r\n");
+    let start_line = lines.next().unwrap_or_default().to_owned();
+
+    let mut headers = HeaderMap::new();
+    for line in lines {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| HttpError::InvalidHeader(line.to_owned()))?;
+        headers.append(name.trim(), value.trim());
+    }
+    Ok(Head {
+        start_line,
+        headers,
+        body_start: head_end + 4,
+    })
+}
+
+/// Validators sent with a conditional GET
+#[derive(Clone, Debug, Default)]
+pub struct Validators {
+    /// Sent as `If-None-Match`
+    pub etag: Option<String>,
+    /// Sent as `If-Modified-Since` (HTTP-date, passed through verbatim)
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    /// Take the `ETag` and `Last-Modified` of a previous response
+    pub fn from_response(response: &Response) -> Self {
+        Self {
+            etag: response.header("ETag").map(str::to_owned),
+            last_modified: response.header("Last-Modified").map(str::to_owned),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+
+    /// Request headers carrying these validators
+    pub fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(etag) = &self.etag {
+            headers.append("If-None-Match", etag);
+        }
+        if let Some(date) = &self.last_modified {
+            headers.append("If-Modified-Since", date);
+        }
+        headers
+    }
+}
+
+/// Result of a conditional GET
+#[derive(Debug)]
+pub enum Conditional {
+    /// The resource changed (or the server ignored the validators)
+    Modified(Box<Response>),
+    /// The server answered 304; the caller's copy is still current
+    NotModified,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fmt::Write;
+    use std::path::Path;
+
+    /// `raw` parsed leniently and strictly, with its framed length, as the
+    /// golden files record it
+    fn render(raw: &[u8]) -> String {
+        let mut out = String::new();
+        for (mode, result) in [
+            ("lenient", Response::parse(raw)),
+            ("strict", Response::parse_strict(raw)),
+        ] {
+            writeln!(out, "[{mode}]").unwrap();
+            match result {
+                Ok(r) => {
+                    writeln!(out, "{} {} {:?}", r.version, r.status, r.reason).unwrap();
+                    for (name, value) in &r.headers {
+                        writeln!(out, "header {name}: {value:?}").unwrap();
+                    }
+                    for (name, value) in &r.trailers {
+                        writeln!(out, "trailer {name}: {value:?}").unwrap();
+                    }
+                    let body = String::from_utf8_lossy(&r.body);
+                    writeln!(out, "body {} bytes {body:?}", r.body.len()).unwrap();
+                }
+                Err(e) => writeln!(out, "error {e}").unwrap(),
+            }
+        }
+        writeln!(out, "[framing]").unwrap();
+        let mut start = 0;
+        while let Some(interim) = interim(&raw[start..]) {
+            let links: Vec<_> = interim.headers.get_all("Link").collect();
+            writeln!(out, "interim {} links {links:?}", interim.status).unwrap();
+            start += interim.len;
+        }
+        writeln!(out, "response_len {:?}", response_len(raw)).unwrap();
+        writeln!(out, "response_complete {}", response_complete(raw)).unwrap();
+        if let Ok(r) = Response::parse(raw) {
+            writeln!(out, "reusable {}", r.reusable()).unwrap();
+            writeln!(out, "keep_alive_timeout {:?}", r.keep_alive_timeout()).unwrap();
+        }
+        writeln!(out, "close_policy {}", ClosePolicy::default_for(raw)).unwrap();
+        writeln!(out, "streamed {}", streamed(raw)).unwrap();
+        out
+    }
+
+    /// `raw`'s body fed to a [`BodyDecoder`] a byte at a time, then the
+    /// connection closing
+    fn streamed(raw: &[u8]) -> String {
+        let Some(head_len) = head_len(raw) else {
+            return "no head".to_owned();
+        };
+        let decoder = Response::parse_head(raw, false).and_then(|(r, _)| BodyDecoder::new(&r));
+        let mut decoder = match decoder {
+            Ok(decoder) => decoder,
+            Err(e) => return format!("error {e}"),
+        };
+        let mut input = Vec::new();
+        let mut payload = Vec::new();
+        for &byte in &raw[head_len..] {
+            input.push(byte);
+            match decoder.decode(&mut input) {
+                Ok(decoded) => payload.extend(decoded),
+                Err(e) => return format!("error {e}"),
+            }
+        }
+        let end = match (decoder.done(), decoder.finish()) {
+            (true, _) => "done".to_owned(),
+            (false, Ok(())) => "done at close".to_owned(),
+            (false, Err(e)) => format!("error {e}"),
+        };
+        format!(
+            "{} bytes, {} trailers, {} left over, {end}",
+            payload.len(),
+            decoder.trailers().iter().count(),
+            input.len()
+        )
+    }
+
+    #[test]
+    fn rejects_chunk_sizes_past_the_address_space() {
+        let raw = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nffffffffffffffff\r\nabc";
+        assert!(matches!(
+            Response::parse(raw),
+            Err(HttpError::InvalidChunkSize(size)) if size == "ffffffffffffffff"
+        ));
+        assert_eq!(response_len(raw), Some(raw.len()));
+    }
+
+    #[test]
+    fn head_responses_end_with_their_head() {
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Length: 1000\r\n\r\n";
+        assert_eq!(response_len(raw), None);
+        assert_eq!(response_len_to("HEAD", raw), Some(raw.len()));
+        assert_eq!(response_len_to("GET", raw), None);
+    }
+
+    #[test]
+    fn expected_len_follows_the_framing_rules() {
+        let not_modified = b"HTTP/1.1 304 Not Modified\r\nContent-Length: 1000\r\n\r\n";
+        assert_eq!(expected_len(not_modified), Some(Some(not_modified.len())));
+        assert_eq!(response_len(not_modified), Some(not_modified.len()));
+
+        let chunked =
+            b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(expected_len(chunked), Some(None));
+
+        let huge = b"HTTP/1.1 200 OK\r\nContent-Length: 18446744073709551615\r\n\r\n";
+        assert_eq!(expected_len(huge), Some(None));
+        assert_eq!(expected_len(b"HTTP/1.1 200 OK\r\n"), None);
+    }
+
+    #[test]
+    fn set_cookies_stay_apart_and_links_span_every_link_header() {
+        let raw = b"HTTP/1.1 200 OK\r\n\
+            Set-Cookie: id=1; Expires=Wed, 21 Oct 2026 07:28:00 GMT\r\n\
+            Link: <https://example.com/2>; rel=next, <https://example.com/1>; rel=first\r\n\
+            Set-Cookie: theme=dark\r\n\
+            Link: <https://example.com/9>; rel=\"last\"; title=\"a, b\"\r\n\
+            Content-Length: 0\r\n\r\n";
+        let r = Response::parse(raw).unwrap();
+        assert_eq!(
+            r.set_cookies().collect::<Vec<_>>(),
+            ["id=1; Expires=Wed, 21 Oct 2026 07:28:00 GMT", "theme=dark"]
+        );
+        let urls: Vec<_> = r.links().into_iter().map(|link| link.url).collect();
+        assert_eq!(
+            urls,
+            [
+                "https://example.com/2",
+                "https://example.com/1",
+                "https://example.com/9"
+            ]
+        );
+        let last = r.link("last").unwrap();
+        assert_eq!(last.param("title"), Some("a, b"));
+        assert!(r.link("prev").is_none());
+    }
+
+    /// Every `testdata/http/*.http` response against its `.golden`;
+    /// `UPDATE_GOLDEN=1` rewrites the golden files instead
+    #[test]
+    fn conformance_vectors() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/http");
+        let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+        let mut vectors: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "http"))
+            .collect();
+        vectors.sort();
+        assert!(!vectors.is_empty(), "no vectors in {}", dir.display());
+
+        let mut mismatched = Vec::new();
+        for vector in &vectors {
+            let rendered = render(&std::fs::read(vector).unwrap());
+            let golden = vector.with_extension("golden");
+            if update {
+                std::fs::write(&golden, &rendered).unwrap();
+            } else if std::fs::read_to_string(&golden).ok().as_deref() != Some(&rendered) {
+                eprintln!("--- {} ---\n{rendered}", golden.display());
+                mismatched.push(golden);
+            }
+        }
+        assert!(
+            mismatched.is_empty(),
+            "output differs from {mismatched:?}; rerun with UPDATE_GOLDEN=1 if intended"
+        );
+    }
+}
@@ -0,0 +1,257 @@
+//! Snapshots of the client's open connections, for debug and health
+//! endpoints
+//!
+//! Every connection the client establishes is entered in its [`Registry`]
+//! until the request, session or WebSocket using it lets go.
+//! [`HttpsClient::debug_state`](crate::HttpsClient) reads each one's socket
+//! at the time of the call, so byte counts and idle times come from the
+//! kernel's TCP statistics and cost nothing while no one is looking. They
+//! count TLS records, not plaintext.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::connect::{self, ConnectionInfo, SendQueue};
+use crate::stats;
+
+/// What a connection is being used for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Use {
+    /// A request, for as long as it uses the connection
+    Request,
+    Session,
+    WebSocket,
+    /// Handed to a caller doing its own HTTP
+    Stream,
+    /// Handshaken and waiting for a request, see [`crate::standby`]
+    Standby,
+    /// Kept open between requests, see [`crate::pool`]
+    Idle,
+}
+
+impl fmt::Display for Use {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Use::Request => write!(f, "request"),
+            Use::Session => write!(f, "session"),
+            Use::WebSocket => write!(f, "websocket"),
+            Use::Stream => write!(f, "stream"),
+            Use::Standby => write!(f, "standby"),
+            Use::Idle => write!(f, "idle"),
+        }
+    }
+}
+
+/// One connection as it was at the time of the snapshot
+#[derive(Clone, Debug)]
+pub struct ConnectionState {
+    pub host: String,
+    pub peer: SocketAddr,
+    pub ktls: bool,
+    pub mptcp: bool,
+    pub used_for: Use,
+    /// Since the connection was established
+    pub age: Duration,
+    /// Since data last went either way; `None` if the kernel didn't say
+    pub idle: Option<Duration>,
+    /// Acknowledged by the peer
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Data the kernel holds for the peer, not yet sent or acknowledged
+    pub send_queue: Option<SendQueue>,
+    /// Received data the client hasn't read yet
+    pub unread: usize,
+}
+
+/// The client's connections, oldest first
+#[derive(Clone, Debug, Default)]
+pub struct DebugState {
+    pub connections: Vec<ConnectionState>,
+}
+
+impl fmt::Display for DebugState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} connections", self.connections.len())?;
+        for c in &self.connections {
+            write!(
+                f,
+                "{} {} {} {} {}, age {:.1?}, sent {} received {}",
+                c.used_for,
+                c.host,
+                c.peer,
+                if c.ktls { "kTLS" } else { "userspace TLS" },
+                if c.mptcp { "MPTCP" } else { "TCP" },
+                c.age,
+                c.bytes_sent,
+                c.bytes_received,
+            )?;
+            if let Some(idle) = c.idle {
+                write!(f, ", idle {idle:.1?}")?;
+            }
+            if let Some(queue) = c.send_queue {
+                write!(f, ", {} queued", queue.queued)?;
+            }
+            writeln!(f, ", {} unread", c.unread)?;
+        }
+        Ok(())
+    }
+}
+
+struct Entry {
+    host: String,
+    connection: ConnectionInfo,
+    /// A duplicate, so a snapshot never reads a socket that was closed and
+    /// its number reused
+    fd: OwnedFd,
+    used_for: Use,
+    opened: Instant,
+}
+
+#[derive(Default)]
+struct Entries {
+    next_id: u64,
+    by_id: BTreeMap<u64, Entry>,
+}
+
+/// The connections a client has open
+#[derive(Clone, Default)]
+pub struct Registry(Rc<RefCell<Entries>>);
+
+impl Registry {
+    /// Enter a connection until the returned registration is dropped
+    pub fn register(
+        &self,
+        host: &str,
+        connection: &ConnectionInfo,
+        fd: BorrowedFd<'_>,
+        used_for: Use,
+    ) -> std::io::Result<Registration> {
+        let fd = fd.try_clone_to_owned()?;
+        let mut entries = self.0.borrow_mut();
+        let id = entries.next_id;
+        entries.next_id += 1;
+        entries.by_id.insert(
+            id,
+            Entry {
+                host: host.to_owned(),
+                connection: *connection,
+                fd,
+                used_for,
+                opened: Instant::now(),
+            },
+        );
+        Ok(Registration {
+            registry: self.clone(),
+            id,
+        })
+    }
+
+    pub fn snapshot(&self) -> DebugState {
+        let entries = self.0.borrow();
+        let connections = entries
+            .by_id
+            .values()
+            .map(|entry| {
+                let fd = entry.fd.as_raw_fd();
+                let traffic = traffic(fd);
+                ConnectionState {
+                    host: entry.host.clone(),
+                    peer: entry.connection.peer,
+                    ktls: entry.connection.ktls,
+                    mptcp: entry.connection.mptcp,
+                    used_for: entry.used_for,
+                    age: entry.opened.elapsed(),
+                    idle: traffic.as_ref().map(|t| t.idle),
+                    bytes_sent: traffic.as_ref().map_or(0, |t| t.bytes_acked),
+                    bytes_received: traffic.as_ref().map_or(0, |t| t.bytes_received),
+                    send_queue: connect::send_queue(fd).ok(),
+                    unread: unread(fd),
+                }
+            })
+            .collect();
+        DebugState { connections }
+    }
+}
+
+/// A connection's entry in a [`Registry`]; removes it when dropped
+pub struct Registration {
+    registry: Registry,
+    id: u64,
+}
+
+impl Registration {
+    /// Record that the connection has been put to another use, e.g. a
+    /// session upgraded to a WebSocket
+    pub fn set_use(&self, used_for: Use) {
+        if let Some(entry) = self.registry.0.borrow_mut().by_id.get_mut(&self.id) {
+            entry.used_for = used_for;
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.0.borrow_mut().by_id.remove(&self.id);
+    }
+}
+
+/// The parts of `TCP_INFO` a snapshot reports
+struct Traffic {
+    bytes_acked: u64,
+    bytes_received: u64,
+    idle: Duration,
+}
+
+/// The kernel's `struct tcp_info` as far as the byte counters (Linux 4.1),
+/// which libc's stops short of
+#[repr(C)]
+struct TcpInfo {
+    base: libc::tcp_info,
+    pacing_rate: u64,
+    max_pacing_rate: u64,
+    bytes_acked: u64,
+    bytes_received: u64,
+}
+
+fn traffic(fd: RawFd) -> Option<Traffic> {
+    let mut info: TcpInfo = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<TcpInfo>() as libc::socklen_t;
+    stats::syscalls(1);
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut TcpInfo as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return None;
+    }
+    // Older kernels fill in less, leaving the counters zero
+    let idle_ms = info
+        .base
+        .tcpi_last_data_sent
+        .min(info.base.tcpi_last_data_recv);
+    Some(Traffic {
+        bytes_acked: info.bytes_acked,
+        bytes_received: info.bytes_received,
+        idle: Duration::from_millis(idle_ms.into()),
+    })
+}
+
+/// Bytes waiting in the socket's receive queue
+fn unread(fd: RawFd) -> usize {
+    let mut value: libc::c_int = 0;
+    stats::syscalls(1);
+    if unsafe { libc::ioctl(fd, libc::FIONREAD, &mut value) } < 0 {
+        return 0;
+    }
+    value as usize
+}
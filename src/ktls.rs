@@ -11,6 +11,11 @@ const TCP_ULP: libc::c_int = 31;
 const SOL_TLS: libc::c_int = 282;
 const TLS_TX: libc::c_int = 1;
 const TLS_RX: libc::c_int = 2;
+const TLS_SET_RECORD_TYPE: libc::c_int = 1;
+
+// TLS record content type and the close_notify alert (level, description)
+const CONTENT_TYPE_ALERT: u8 = 21;
+const CLOSE_NOTIFY: [u8; 2] = [1, 0];
 
 // TLS versions
 const TLS_1_2_VERSION: u16 = 0x0303;
@@ -93,7 +98,17 @@ pub fn configure_ktls(
     rx: (u64, ConnectionTrafficSecrets),
     version: u16,
 ) -> Result<(), KtlsError> {
-    // Step 1: Enable TLS ULP (Upper Layer Protocol)
+    enable_ulp(fd)?;
+    configure_keys(fd, tx, rx, version)
+}
+
+/// Step 1: Enable TLS ULP (Upper Layer Protocol)
+///
+/// Until keys are configured the socket carries data unchanged, so this may
+/// be done ahead of the handshake, to learn whether kTLS is available while
+/// the connection can still go on in userspace TLS.
+pub fn enable_ulp(fd: RawFd) -> Result<(), KtlsError> {
+    crate::stats::syscalls(1);
     let ulp_name = b"tls\0";
     let ret = unsafe {
         libc::setsockopt(
@@ -107,12 +122,24 @@ pub fn configure_ktls(
     if ret < 0 {
         return Err(KtlsError::UlpSetupFailed(std::io::Error::last_os_error()));
     }
+    Ok(())
+}
 
+/// Steps 2 and 3: hand the session's keys to the kernel, once
+/// [`enable_ulp`] has succeeded
+pub fn configure_keys(
+    fd: RawFd,
+    tx: (u64, ConnectionTrafficSecrets),
+    rx: (u64, ConnectionTrafficSecrets),
+    version: u16,
+) -> Result<(), KtlsError> {
     // Step 2: Configure TX (transmit/encrypt) direction
+    crate::stats::syscalls(1);
     configure_direction(fd, TLS_TX, tx.0, &tx.1, version)
         .map_err(KtlsError::TxSetupFailed)?;
 
     // Step 3: Configure RX (receive/decrypt) direction
+    crate::stats::syscalls(1);
     configure_direction(fd, TLS_RX, rx.0, &rx.1, version)
         .map_err(KtlsError::RxSetupFailed)?;
 
@@ -233,3 +260,38 @@ fn configure_direction(
 
     Ok(())
 }
+
+/// Send a `close_notify` alert on a connection whose keys the kernel holds
+///
+/// The kernel sends data as application data records unless a control
+/// message sets another record type, as this does for the alert. It is sent
+/// without waiting: two bytes go out unless the send buffer is full, and a
+/// connection that is closing anyway is no worse off if they don't.
+pub fn send_close_notify(fd: RawFd) -> std::io::Result<()> {
+    let mut alert = CLOSE_NOTIFY;
+    let mut iov = libc::iovec {
+        iov_base: alert.as_mut_ptr() as *mut libc::c_void,
+        iov_len: alert.len(),
+    };
+    let space = unsafe { libc::CMSG_SPACE(1) } as usize;
+    let mut control = vec![0u8; space];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = SOL_TLS;
+        (*cmsg).cmsg_type = TLS_SET_RECORD_TYPE;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(1) as _;
+        *libc::CMSG_DATA(cmsg) = CONTENT_TYPE_ALERT;
+    }
+
+    crate::stats::syscalls(1);
+    let ret = unsafe { libc::sendmsg(fd, &msg, libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,78 @@
+//! An HTTPS and WebSocket client that hands TLS to the kernel (kTLS) and
+//! does its I/O through io_uring
+//!
+//! [`HttpsClient`] is the entry point: build one, then send requests, open
+//! [`Session`](session::Session)s for several requests over one connection,
+//! or upgrade to a [`WssClient`](websocket::WssClient). Everything runs on
+//! the current thread's tokio-uring runtime, so call it from inside
+//! `tokio_uring::start` or [`uring::runtime`]. The handshake itself
+//! ([`handshake`]) and setting up kTLS on a socket ([`ktls`]) can also be
+//! used on their own.
+
+#[cfg(target_os = "linux")]
+pub mod affinity;
+#[cfg(target_os = "linux")]
+pub mod backpressure;
+pub mod balance;
+#[cfg(target_os = "linux")]
+pub mod binding;
+pub mod breaker;
+#[cfg(target_os = "linux")]
+pub mod buffers;
+#[cfg(target_os = "linux")]
+pub mod bufring;
+pub mod cache;
+pub mod cassette;
+#[cfg(target_os = "linux")]
+mod client;
+pub mod clock;
+#[cfg(target_os = "linux")]
+pub mod compat;
+pub mod connect;
+pub mod context;
+#[cfg(target_os = "linux")]
+pub mod control;
+pub mod date;
+#[cfg(target_os = "linux")]
+pub mod fd;
+#[cfg(target_os = "linux")]
+pub mod handshake;
+pub mod headers;
+pub mod http;
+#[cfg(target_os = "linux")]
+pub mod introspect;
+#[cfg(target_os = "linux")]
+pub mod ktls;
+#[cfg(target_os = "linux")]
+pub mod limit;
+pub mod markers;
+#[cfg(target_os = "linux")]
+pub mod pool;
+pub mod portable;
+#[cfg(target_os = "linux")]
+pub mod qos;
+#[cfg(target_os = "linux")]
+pub mod resolver;
+pub mod retry;
+pub mod rng;
+#[cfg(target_os = "linux")]
+pub mod server;
+#[cfg(target_os = "linux")]
+pub mod session;
+#[cfg(target_os = "linux")]
+pub mod standby;
+pub mod stats;
+#[cfg(target_os = "linux")]
+pub mod streaming;
+#[cfg(target_os = "linux")]
+pub mod tls;
+pub mod trace;
+#[cfg(target_os = "linux")]
+pub mod uring;
+#[cfg(target_os = "linux")]
+pub mod websocket;
+pub mod wirelog;
+pub mod wsproto;
+
+#[cfg(target_os = "linux")]
+pub use client::{HttpsClient, Progress, RequestOptions};
@@ -0,0 +1,148 @@
+//! Capping the client's connections, handed out by priority
+//!
+//! With a limit set, every request, session and WebSocket upgrade takes a
+//! slot before it connects and gives it back once it is done with the
+//! connection; an upgraded WebSocket no longer counts. When all the slots
+//! are taken, new ones queue, and a freed slot goes to the longest-waiting
+//! request of the highest [`Priority`] queued. Health checks and
+//! control-plane calls marked [`Priority::High`] then get the next
+//! connection even while a queue of bulk transfers is waiting, though they
+//! still wait for one to finish: nothing already connected is interrupted.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+use tokio::sync::oneshot;
+
+/// Where a request queues for a connection slot
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Bulk transfers that can wait
+    Low,
+    #[default]
+    Normal,
+    /// Health checks, control-plane calls
+    High,
+}
+
+struct State {
+    in_use: usize,
+    /// Waiting requests by priority, lowest first, each in arrival order
+    waiting: [VecDeque<oneshot::Sender<()>>; 3],
+}
+
+/// A client's connection slots
+pub struct ConnectionLimit {
+    max: usize,
+    state: RefCell<State>,
+}
+
+impl ConnectionLimit {
+    /// `max` connections at once; must not be zero
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            state: RefCell::new(State {
+                in_use: 0,
+                waiting: Default::default(),
+            }),
+        }
+    }
+
+    /// A slot, once one is free and no request of higher priority, or of
+    /// the same and queued earlier, is waiting for it
+    pub async fn acquire(&self, priority: Priority) -> Slot<'_> {
+        let rx = {
+            let mut state = self.state.borrow_mut();
+            if state.in_use < self.max {
+                state.in_use += 1;
+                return Slot { limit: self };
+            }
+            let (tx, rx) = oneshot::channel();
+            state.waiting[priority as usize].push_back(tx);
+            rx
+        };
+        let mut queued = Queued {
+            limit: self,
+            rx: Some(rx),
+        };
+        let rx = queued.rx.as_mut().expect("set above");
+        rx.await.expect("senders are only dropped by sending");
+        queued.rx = None;
+        Slot { limit: self }
+    }
+
+    /// Pass a slot on to the next in line, or free it
+    fn release(&self) {
+        let mut state = self.state.borrow_mut();
+        for queue in state.waiting.iter_mut().rev() {
+            while let Some(tx) = queue.pop_front() {
+                // Gone if its request gave up while queued
+                if tx.send(()).is_ok() {
+                    return;
+                }
+            }
+        }
+        state.in_use -= 1;
+    }
+}
+
+/// A connection slot, given back when dropped
+pub struct Slot<'a> {
+    limit: &'a ConnectionLimit,
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.limit.release();
+    }
+}
+
+/// A place in the queue; a request that gives up after being handed a
+/// slot, but before taking it, passes the slot on
+struct Queued<'a> {
+    limit: &'a ConnectionLimit,
+    rx: Option<oneshot::Receiver<()>>,
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        if let Some(mut rx) = self.rx.take() {
+            rx.close();
+            if rx.try_recv().is_ok() {
+                self.limit.release();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn freed_slots_go_to_the_highest_priority() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let limit = ConnectionLimit::new(1);
+            let first = limit.acquire(Priority::Normal).await;
+            let order = RefCell::new(Vec::new());
+            let take = |priority| {
+                let (limit, order) = (&limit, &order);
+                async move {
+                    let _slot = limit.acquire(priority).await;
+                    order.borrow_mut().push(priority);
+                }
+            };
+            let release = async {
+                tokio::task::yield_now().await;
+                drop(first);
+            };
+            tokio::join!(take(Priority::Low), take(Priority::High), release);
+            assert_eq!(*order.borrow(), [Priority::High, Priority::Low]);
+            assert_eq!(limit.state.borrow().in_use, 0);
+        });
+    }
+}
@@ -0,0 +1,69 @@
+//! Phase markers for profiling with perf
+//!
+//! Once [`enable`]d, the connect, handshake, kTLS setup, write and read
+//! phases of each request write a begin and an end line to ftrace's
+//! `trace_marker`, e.g. `ktls-uring-demo: handshake begin`. perf records
+//! them as `ftrace:print` events alongside its samples:
+//!
+//! ```text
+//! perf record -g -e ftrace:print -e cpu-clock -- ktls-uring-demo bench --perf-markers
+//! perf script
+//! ```
+//!
+//! so a flamegraph or timeline can be split by phase. Markers cost one
+//! `write(2)` each and are off by default.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::OnceLock;
+
+/// tracefs mounts here on current kernels, under debugfs on older ones
+const TRACE_MARKER_PATHS: [&str; 2] = [
+    "/sys/kernel/tracing/trace_marker",
+    "/sys/kernel/debug/tracing/trace_marker",
+];
+
+static TRACE_MARKER: OnceLock<File> = OnceLock::new();
+
+/// Start writing phase markers; fails if `trace_marker` can't be opened,
+/// usually for lack of root or a mounted tracefs
+pub fn enable() -> std::io::Result<()> {
+    if TRACE_MARKER.get().is_some() {
+        return Ok(());
+    }
+    let mut last_error = None;
+    for path in TRACE_MARKER_PATHS {
+        match OpenOptions::new().write(true).open(path) {
+            Ok(file) => {
+                let _ = TRACE_MARKER.set(file);
+                return Ok(());
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.expect("at least one path"))
+}
+
+/// Mark the start of `name`; its end is marked when the guard drops
+pub fn phase(name: &'static str) -> Phase {
+    mark(name, "begin");
+    Phase(name)
+}
+
+/// A phase in progress
+pub struct Phase(&'static str);
+
+impl Drop for Phase {
+    fn drop(&mut self) {
+        mark(self.0, "end");
+    }
+}
+
+/// One write per marker, since each write is one event; left out of the
+/// syscall counts so markers don't skew benchmark results
+fn mark(name: &str, edge: &str) {
+    if let Some(mut file) = TRACE_MARKER.get() {
+        let line = format!("ktls-uring-demo: {name} {edge}");
+        let _ = file.write_all(line.as_bytes());
+    }
+}
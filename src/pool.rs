@@ -0,0 +1,345 @@
+//! Keep-alive: connections kept open between requests to the same host
+//!
+//! Once a request's response is in, [`HttpsClient`](crate::HttpsClient)
+//! leaves its connection here if the response was read to the end of its
+//! framing, allows the connection to be reused (see
+//! [`Response::reusable`](crate::http::Response::reusable)), and the
+//! client's [`RecyclePolicy`](crate::session::RecyclePolicy) isn't done
+//! with it. The next request to that host takes it instead of connecting,
+//! handshaking and setting up kTLS again.
+//!
+//! An idle connection is closed after [`PoolPolicy::idle_timeout`], or a
+//! second before the server's own `Keep-Alive: timeout` if that is sooner,
+//! and dropped when the server is found to have hung up on it. Dropping the
+//! client closes them all. A server can
+//! still close one just as a request goes out on it; a request that gets
+//! nothing back over a reused connection is sent again over a new one.
+//!
+//! Only requests whose body can be sent twice take a connection, and only
+//! those that ask nothing of the connection itself (no endpoints, exporters
+//! or traffic class of their own) use the pool at all. `HEAD` requests
+//! don't either, as their responses don't carry the body their framing
+//! announces. Idle connections are left out of the load balancer's counts
+//! and the connection limit until a request takes them.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+
+use crate::RequestOptions;
+use crate::balance::Lease;
+use crate::clock::Clock;
+use crate::connect::{self, ConnectionInfo};
+use crate::handshake::KeyingMaterial;
+use crate::http::Timing;
+use crate::introspect::Registration;
+use crate::session::{Established, Transport};
+use crate::standby::hung_up;
+
+/// How long before the server's `Keep-Alive: timeout` a connection is given
+/// up on, so a request isn't sent just as the server closes it
+const SERVER_TIMEOUT_MARGIN: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug)]
+pub struct PoolPolicy {
+    /// Idle connections kept per host; 0 closes every connection after its
+    /// request, with `Connection: close`
+    pub max_idle: usize,
+    /// How long a connection may sit idle before it is closed
+    pub idle_timeout: Duration,
+}
+
+impl Default for PoolPolicy {
+    fn default() -> Self {
+        Self {
+            max_idle: 4,
+            idle_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// A connection between requests
+pub struct Idle {
+    pub transport: Transport,
+    pub connection: ConnectionInfo,
+    pub keying_material: KeyingMaterial,
+    /// Its entry in the client's registry, until a request takes it
+    pub registration: Registration,
+    /// When the connection was established, by the client's clock
+    pub opened: Instant,
+    /// Requests it has carried
+    pub requests: usize,
+    /// When its last response was read
+    pub since: Instant,
+    /// How long the server keeps it open while idle, if it said
+    pub server_timeout: Option<Duration>,
+}
+
+/// What a reused connection has been through, for the recycle policy
+#[derive(Clone, Copy, Debug)]
+pub struct Reused {
+    pub opened: Instant,
+    pub requests: usize,
+}
+
+/// A client's idle connections
+pub struct Pool {
+    policy: PoolPolicy,
+    hosts: RefCell<HashMap<String, VecDeque<Idle>>>,
+}
+
+impl Pool {
+    pub fn new(policy: PoolPolicy) -> Self {
+        Self {
+            policy,
+            hosts: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub fn policy(&self) -> &PoolPolicy {
+        &self.policy
+    }
+
+    /// An idle connection to `host` for a request with `options`, if there
+    /// is one still open; the most recently used, so the others can time out
+    ///
+    /// TCP_NODELAY is set as the request asks, or else to `nodelay`, the
+    /// client's default.
+    pub fn take<'c>(
+        &self,
+        host: &str,
+        options: &RequestOptions,
+        nodelay: bool,
+        clock: &dyn Clock,
+    ) -> Option<(Established<'c>, Reused)> {
+        let idle = {
+            let mut hosts = self.hosts.borrow_mut();
+            let idle = hosts.get_mut(host)?;
+            self.prune(idle, clock);
+            idle.pop_back()?
+        };
+        let Idle {
+            transport,
+            connection,
+            keying_material,
+            opened,
+            requests,
+            ..
+        } = idle;
+        let nodelay = options.nodelay.unwrap_or(nodelay);
+        connect::set_nodelay(transport.fd().as_raw_fd(), nodelay).ok()?;
+        let mut lease = Lease::unbalanced(connection.peer);
+        lease.connected();
+        let established = Established {
+            transport,
+            connection,
+            lease,
+            slot: None,
+            admission: None,
+            // Nothing to wait for but the request
+            timing: Timing::default(),
+            keying_material,
+        };
+        Some((established, Reused { opened, requests }))
+    }
+
+    /// Keep `idle` for the next request to `host`, closing the one idle
+    /// longest if that makes more than the policy allows
+    ///
+    /// TCP_NODELAY goes back to `nodelay`, the client's default, undoing
+    /// whatever the last request set; a connection that can't be reset is
+    /// closed instead.
+    pub fn put(&self, host: &str, idle: Idle, nodelay: bool) {
+        if connect::set_nodelay(idle.transport.fd().as_raw_fd(), nodelay).is_err() {
+            close(idle);
+            return;
+        }
+        let mut hosts = self.hosts.borrow_mut();
+        let kept = hosts.entry(host.to_owned()).or_default();
+        kept.push_back(idle);
+        while kept.len() > self.policy.max_idle {
+            if let Some(oldest) = kept.pop_front() {
+                close(oldest);
+            }
+        }
+    }
+
+    /// Close what has been idle too long, and drop what the server closed
+    fn prune(&self, idle: &mut VecDeque<Idle>, clock: &dyn Clock) {
+        let now = clock.now();
+        for conn in std::mem::take(idle) {
+            if hung_up(conn.transport.fd()) {
+                continue;
+            }
+            let limit = conn
+                .server_timeout
+                .map_or(self.policy.idle_timeout, |timeout| {
+                    timeout
+                        .saturating_sub(SERVER_TIMEOUT_MARGIN)
+                        .min(self.policy.idle_timeout)
+                });
+            if now.saturating_duration_since(conn.since) >= limit {
+                close(conn);
+            } else {
+                idle.push_back(conn);
+            }
+        }
+    }
+}
+
+impl Drop for Pool {
+    /// Close the idle connections along with the client
+    fn drop(&mut self) {
+        for (_, idle) in self.hosts.get_mut().drain() {
+            idle.into_iter().for_each(close);
+        }
+    }
+}
+
+/// Close an idle connection with `close_notify`, best effort
+fn close(mut idle: Idle) {
+    idle.transport.send_close_notify();
+    let _ = idle.transport.try_flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::time::SystemTime;
+
+    use nix::sys::socket::{getsockopt, sockopt};
+
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::introspect::{Registry, Use};
+
+    /// An idle connection to `listener` that has carried `requests`, with
+    /// the server's end of it
+    fn idle(listener: &TcpListener, clock: &ManualClock, requests: usize) -> (Idle, TcpStream) {
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, peer) = listener.accept().unwrap();
+        let transport = Transport::Ktls(tokio_uring::net::TcpStream::from_std(client));
+        let connection = ConnectionInfo {
+            peer,
+            ktls: false,
+            mptcp: false,
+            endpoint: None,
+            send_queue: None,
+        };
+        let registration = Registry::default()
+            .register("test", &connection, transport.fd(), Use::Idle)
+            .unwrap();
+        let idle = Idle {
+            transport,
+            connection,
+            keying_material: KeyingMaterial::default(),
+            registration,
+            opened: clock.now(),
+            requests,
+            since: clock.now(),
+            server_timeout: None,
+        };
+        (idle, server)
+    }
+
+    fn nodelay(transport: &Transport) -> bool {
+        getsockopt(&transport.fd(), sockopt::TcpNoDelay).unwrap()
+    }
+
+    #[test]
+    fn a_request_nodelay_is_undone_on_return() {
+        tokio_uring::start(async {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let clock = ManualClock::at(SystemTime::UNIX_EPOCH);
+            let pool = Pool::new(PoolPolicy::default());
+            let (conn, _server) = idle(&listener, &clock, 1);
+            pool.put("test", conn, false);
+
+            let options = RequestOptions {
+                nodelay: Some(true),
+                ..RequestOptions::default()
+            };
+            let (established, _) = pool.take("test", &options, false, &clock).unwrap();
+            assert!(nodelay(&established.transport));
+
+            let (mut conn, _server) = idle(&listener, &clock, 2);
+            conn.transport = established.transport;
+            pool.put("test", conn, false);
+            assert!(!nodelay(&pool.hosts.borrow()["test"][0].transport));
+
+            // Without a request's own, the client's default applies
+            let defaults = RequestOptions::default();
+            let (established, _) = pool.take("test", &defaults, true, &clock).unwrap();
+            assert!(nodelay(&established.transport));
+        });
+    }
+
+    #[test]
+    fn takes_the_most_recent_and_keeps_at_most_max_idle() {
+        tokio_uring::start(async {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let clock = ManualClock::at(SystemTime::UNIX_EPOCH);
+            let pool = Pool::new(PoolPolicy {
+                max_idle: 2,
+                ..PoolPolicy::default()
+            });
+            let mut servers = Vec::new();
+            for requests in 1..=3 {
+                let (conn, server) = idle(&listener, &clock, requests);
+                pool.put("test", conn, false);
+                servers.push(server);
+            }
+
+            let options = RequestOptions::default();
+            let taken = || {
+                let (_, reused) = pool.take("test", &options, false, &clock)?;
+                Some(reused.requests)
+            };
+            assert_eq!(taken(), Some(3));
+            assert_eq!(taken(), Some(2));
+            assert_eq!(taken(), None);
+        });
+    }
+
+    #[test]
+    fn closes_connections_idle_too_long() {
+        tokio_uring::start(async {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let clock = ManualClock::at(SystemTime::UNIX_EPOCH);
+            let pool = Pool::new(PoolPolicy::default());
+            let options = RequestOptions::default();
+
+            let (conn, _server) = idle(&listener, &clock, 1);
+            pool.put("test", conn, false);
+            clock.advance(pool.policy().idle_timeout);
+            assert!(pool.take("test", &options, false, &clock).is_none());
+
+            // A second short of the server's own timeout is too long
+            let (mut conn, _server) = idle(&listener, &clock, 1);
+            conn.server_timeout = Some(Duration::from_secs(5));
+            pool.put("test", conn, false);
+            clock.advance(Duration::from_secs(4));
+            assert!(pool.take("test", &options, false, &clock).is_none());
+
+            let (conn, _server) = idle(&listener, &clock, 1);
+            pool.put("test", conn, false);
+            clock.advance(Duration::from_secs(4));
+            assert!(pool.take("test", &options, false, &clock).is_some());
+        });
+    }
+
+    #[test]
+    fn drops_connections_the_server_closed() {
+        tokio_uring::start(async {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let clock = ManualClock::at(SystemTime::UNIX_EPOCH);
+            let pool = Pool::new(PoolPolicy::default());
+            let (conn, server) = idle(&listener, &clock, 1);
+            pool.put("test", conn, false);
+            drop(server);
+            let options = RequestOptions::default();
+            assert!(pool.take("test", &options, false, &clock).is_none());
+        });
+    }
+}
@@ -0,0 +1,207 @@
+//! Userspace-only transport for where io_uring and kTLS aren't available
+//!
+//! io_uring, kTLS and the socket options the client relies on are Linux
+//! only, and so is everything built on them. Elsewhere the crate still
+//! builds its protocol layers (HTTP encoding and parsing, the response
+//! cache, WebSocket framing and so on) and carries requests over this
+//! transport instead: rustls on a plain tokio socket, one connection per
+//! request. It exists so those layers can be developed and tested on any
+//! workstation, not to be fast. On Linux it also stands in when the kernel
+//! refuses io_uring; see [`uring`](crate::uring).
+
+use std::io::{ErrorKind, Read, Write};
+use std::sync::Arc;
+use std::time::Instant;
+
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection};
+use tokio::net::TcpStream;
+
+use crate::headers::HeaderMap;
+use crate::http::{ClosePolicy, Request, Response, Timing};
+
+/// Bytes requested from the socket per read; one maximum-size TLS record
+const READ_SIZE: usize = 16 * 1024 + 256;
+
+/// A rustls client connection over a tokio `TcpStream`
+pub struct TokioTlsStream {
+    stream: TcpStream,
+    conn: ClientConnection,
+}
+
+/// Socket writes that fail with `WouldBlock` instead of waiting
+struct NonBlocking<'s>(&'s TcpStream);
+
+impl Write for NonBlocking<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.try_write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl TokioTlsStream {
+    /// Connect to `host` on `port` and complete the handshake
+    pub async fn connect(
+        config: Arc<ClientConfig>,
+        host: &str,
+        port: u16,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let server_name = ServerName::try_from(host.to_owned())?;
+        let stream = TcpStream::connect((host, port)).await?;
+        let conn = ClientConnection::new(config, server_name)?;
+        let mut tls = Self { stream, conn };
+        while tls.conn.is_handshaking() {
+            tls.flush().await?;
+            if tls.conn.is_handshaking() && tls.fill().await? == 0 {
+                return Err(std::io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "connection closed during handshake",
+                )
+                .into());
+            }
+        }
+        tls.flush().await?;
+        Ok(tls)
+    }
+
+    /// Encrypt and send all of `data`
+    pub async fn write_all(&mut self, mut data: &[u8]) -> std::io::Result<()> {
+        while !data.is_empty() {
+            let n = self.conn.writer().write(data)?;
+            data = &data[n..];
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Read plaintext into `buf`
+    ///
+    /// Returns 0 once the peer has sent `close_notify`, and `UnexpectedEof`
+    /// if it closed the connection without one.
+    pub async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            match self.conn.reader().read(buf) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    self.fill().await?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Send everything rustls has queued for the peer
+    async fn flush(&mut self) -> std::io::Result<()> {
+        while self.conn.wants_write() {
+            self.stream.writable().await?;
+            match self.conn.write_tls(&mut NonBlocking(&self.stream)) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                result => {
+                    result?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Read one batch of records from the peer into rustls and process them;
+    /// returns 0 once the peer has closed the connection
+    async fn fill(&mut self) -> std::io::Result<usize> {
+        let mut buf = vec![0u8; READ_SIZE];
+        let n = loop {
+            self.stream.readable().await?;
+            match self.stream.try_read(&mut buf) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                result => break result?,
+            }
+        };
+
+        let mut records = &buf[..n];
+        loop {
+            self.conn.read_tls(&mut records)?;
+            let processed = self
+                .conn
+                .process_new_packets()
+                .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e));
+            if processed.is_err() {
+                // Let the peer know why before giving up
+                let _ = self.flush().await;
+            }
+            processed?;
+            if records.is_empty() {
+                return Ok(n);
+            }
+        }
+    }
+}
+
+/// TLS settings trusting the platform's root certificates
+pub fn client_config() -> Arc<ClientConfig> {
+    let mut root_store = rustls::RootCertStore::empty();
+    for cert in rustls_native_certs::load_native_certs().expect("failed to load native certs") {
+        let _ = root_store.add(cert);
+    }
+    Arc::new(
+        ClientConfig::builder()
+            .with_root_certificates(root_store)
+            .with_no_client_auth(),
+    )
+}
+
+/// Send `request` over a new connection to its host on port 443 and read
+/// the response up to the close, judging a close without close_notify by
+/// the default [`ClosePolicy`]
+pub async fn send(
+    config: &Arc<ClientConfig>,
+    mut request: Request<'_>,
+) -> Result<Response, Box<dyn std::error::Error>> {
+    let started = Instant::now();
+    let mut headers = HeaderMap::new();
+    headers.append("User-Agent", "ktls-uring-demo/0.1");
+    headers.append("Accept-Encoding", "gzip, deflate");
+    headers.extend(&request.headers);
+    headers.append("Connection", "close");
+    request.headers = headers;
+
+    let mut tls = TokioTlsStream::connect(config.clone(), &request.host, 443).await?;
+    let sent = Instant::now();
+    tls.write_all(&request.encode()).await?;
+    let mut body = request.body;
+    while let Some(chunk) = body.next_chunk().await {
+        tls.write_all(&chunk).await?;
+    }
+
+    let mut response = Vec::new();
+    let mut close_policy = None;
+    let mut ttfb = None;
+    let mut buf = vec![0u8; 8192];
+    loop {
+        match tls.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(n) => {
+                ttfb.get_or_insert_with(|| sent.elapsed());
+                response.extend_from_slice(&buf[..n]);
+            }
+            // Servers often close without close_notify after Connection: close
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof && !response.is_empty() => {
+                let policy = ClosePolicy::default_for(&response);
+                policy.apply(&request.host, &response)?;
+                close_policy = Some(policy);
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let mut response = Response::parse(&response)?;
+    response.close_policy = close_policy;
+    // Tokio resolves, connects and handshakes in one go, so those phases
+    // only show in the total
+    response.timing = Some(Timing {
+        ttfb: ttfb.unwrap_or_default(),
+        total: started.elapsed(),
+        ..Timing::default()
+    });
+    Ok(response)
+}
@@ -0,0 +1,586 @@
+//! `pg` subcommand: a PostgreSQL query over kTLS
+//!
+//! Asks the server for TLS with an SSLRequest, as `sslmode=require` does,
+//! hands the socket to the client for the handshake and kTLS setup, then
+//! speaks the frontend/backend protocol (version 3.0) over the resulting
+//! transport: startup, authentication, one simple query, and Terminate.
+//! The certificate is always verified, so in libpq's terms this is closer
+//! to `verify-full`.
+//!
+//! Passwords go through SCRAM-SHA-256, bound to the channel with
+//! `tls-server-end-point` when the server offers SCRAM-SHA-256-PLUS, or
+//! in the clear (inside TLS) if the server asks for that. MD5 isn't
+//! supported. Passwords aren't SASLprep-normalized, which only matters for
+//! non-ASCII ones.
+
+use std::io::{Read, Write};
+use std::path::Path;
+
+use aws_lc_rs::{digest, hmac, pbkdf2};
+
+use ktls_uring_demo::binding::ChannelBinding;
+use ktls_uring_demo::rng;
+use ktls_uring_demo::session::{Session, Transport};
+use ktls_uring_demo::wsproto::{base64, unbase64};
+use ktls_uring_demo::{HttpsClient, RequestOptions};
+
+pub const USAGE: &str = "\
+usage: ktls-uring-demo pg --user USER [--host HOST] [--port PORT]
+                          [--database NAME] [--ca-file PATH]
+                          [--channel-binding POLICY] [--query SQL]
+
+  --host HOST               server to connect to (default: localhost)
+  --port PORT               server port (default: 5432)
+  --user USER               role to log in as
+  --database NAME           database to connect to (default: the role's name)
+  --ca-file PATH            trust this PEM bundle instead of the platform's roots
+  --channel-binding POLICY  require, prefer or disable, as libpq's option
+                            (default: prefer)
+  --query SQL               simple query to run (default: SELECT version())
+
+The password is read from PGPASSWORD.";
+
+/// Protocol version 3.0
+const PROTOCOL_VERSION: u32 = 196_608;
+/// The code an SSLRequest carries in place of a protocol version
+const SSL_REQUEST: u32 = 80_877_103;
+
+/// Whether to insist on SCRAM bound to the channel, as libpq's
+/// `channel_binding`
+#[derive(Clone, Copy, PartialEq)]
+enum BindingPolicy {
+    Require,
+    Prefer,
+    Disable,
+}
+
+pub struct PgConfig {
+    host: String,
+    port: u16,
+    user: String,
+    database: Option<String>,
+    password: Option<String>,
+    ca_file: Option<String>,
+    channel_binding: BindingPolicy,
+    query: String,
+}
+
+impl PgConfig {
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let mut config = Self {
+            host: "localhost".to_owned(),
+            port: 5432,
+            user: String::new(),
+            database: None,
+            password: std::env::var("PGPASSWORD").ok(),
+            ca_file: None,
+            channel_binding: BindingPolicy::Prefer,
+            query: "SELECT version()".to_owned(),
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .cloned()
+                    .ok_or_else(|| format!("{arg} needs a value"))
+            };
+            match arg.as_str() {
+                "--host" => config.host = value()?,
+                "--port" => {
+                    let port = value()?;
+                    config.port = port
+                        .parse()
+                        .map_err(|_| format!("{arg} expects a port, got {port:?}"))?;
+                }
+                "--user" => config.user = value()?,
+                "--database" => config.database = Some(value()?),
+                "--ca-file" => config.ca_file = Some(value()?),
+                "--channel-binding" => {
+                    config.channel_binding = match value()?.as_str() {
+                        "require" => BindingPolicy::Require,
+                        "prefer" => BindingPolicy::Prefer,
+                        "disable" => BindingPolicy::Disable,
+                        other => return Err(format!("unknown channel binding policy {other:?}")),
+                    };
+                }
+                "--query" => config.query = value()?,
+                _ => return Err(format!("unknown option {arg:?}")),
+            }
+        }
+        if config.user.is_empty() {
+            return Err("--user is required".to_owned());
+        }
+        Ok(config)
+    }
+}
+
+/// An ErrorResponse from the server, or something the client won't go on
+/// with
+#[derive(Debug)]
+pub struct PgError(String);
+
+impl std::fmt::Display for PgError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PostgreSQL: {}", self.0)
+    }
+}
+
+impl std::error::Error for PgError {}
+
+fn fail<T>(what: impl Into<String>) -> Result<T, Box<dyn std::error::Error>> {
+    Err(PgError(what.into()).into())
+}
+
+/// Connect, authenticate, run the query and print its results
+pub async fn run(config: &PgConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = HttpsClient::new().with_verbose(false);
+    if let Some(path) = &config.ca_file {
+        client = client.with_ca_file(Path::new(path))?;
+    }
+
+    // PostgreSQL negotiates TLS in the clear before the handshake
+    let mut stream = std::net::TcpStream::connect((config.host.as_str(), config.port))?;
+    let mut request = 8u32.to_be_bytes().to_vec();
+    request.extend_from_slice(&SSL_REQUEST.to_be_bytes());
+    stream.write_all(&request)?;
+    let mut answer = [0u8];
+    stream.read_exact(&mut answer)?;
+    if answer[0] != b'S' {
+        return fail("server refused TLS");
+    }
+
+    let session =
+        Session::open_on(&client, stream, &config.host, &RequestOptions::default()).await?;
+    let ktls = session.connection().ktls;
+    let binding = ChannelBinding::server_end_point(session.keying_material());
+    let (transport, buffered, _registration) = session.into_transport();
+    println!(
+        "--- connected to {}:{} over {} ---",
+        config.host,
+        config.port,
+        if ktls { "kTLS" } else { "userspace TLS" }
+    );
+
+    let mut conn = Connection {
+        transport,
+        buffered,
+    };
+    let mut startup = PROTOCOL_VERSION.to_be_bytes().to_vec();
+    for (key, value) in [
+        ("user", config.user.as_str()),
+        (
+            "database",
+            config.database.as_deref().unwrap_or(&config.user),
+        ),
+        ("application_name", "ktls-uring-demo"),
+    ] {
+        push_cstr(&mut startup, key);
+        push_cstr(&mut startup, value);
+    }
+    startup.push(0);
+    conn.send(None, &startup).await?;
+
+    let bound = authenticate(&mut conn, config, binding, &client).await?;
+    println!(
+        "--- authenticated{} ---",
+        if bound { ", channel bound" } else { "" }
+    );
+
+    // Parameters and the cancellation key, up to ReadyForQuery
+    loop {
+        let (tag, body) = conn.receive().await?;
+        match tag {
+            b'S' => {
+                let mut fields = Fields(&body);
+                if let (Some("server_version"), Some(version)) = (fields.cstr(), fields.cstr()) {
+                    println!("--- server version {version} ---");
+                }
+            }
+            b'Z' => break,
+            _ => {}
+        }
+    }
+
+    let mut query = Vec::new();
+    push_cstr(&mut query, &config.query);
+    conn.send(Some(b'Q'), &query).await?;
+    loop {
+        let (tag, body) = conn.receive().await?;
+        let mut fields = Fields(&body);
+        match tag {
+            // RowDescription: the columns' names, past their type details
+            b'T' => {
+                let count = fields.u16().unwrap_or(0);
+                let names: Vec<_> = (0..count)
+                    .map_while(|_| {
+                        let name = fields.cstr()?;
+                        fields.skip(18)?;
+                        Some(name)
+                    })
+                    .collect();
+                println!("{}", names.join("\t"));
+            }
+            b'D' => {
+                let count = fields.u16().unwrap_or(0);
+                let values: Vec<_> = (0..count)
+                    .map_while(|_| match fields.u32()? {
+                        u32::MAX => Some("NULL".into()),
+                        len => fields.bytes(len as usize).map(String::from_utf8_lossy),
+                    })
+                    .collect();
+                println!("{}", values.join("\t"));
+            }
+            b'C' => println!("--- {} ---", fields.cstr().unwrap_or_default()),
+            b'I' => println!("--- empty query ---"),
+            b'N' => println!("--- notice: {} ---", notice_message(&body)),
+            b'Z' => break,
+            _ => {}
+        }
+    }
+
+    conn.send(Some(b'X'), &[]).await
+}
+
+/// Answer the server's authentication requests up to AuthenticationOk;
+/// whether SCRAM bound itself to the channel
+async fn authenticate(
+    conn: &mut Connection,
+    config: &PgConfig,
+    binding: Option<ChannelBinding>,
+    client: &HttpsClient,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let password = || match &config.password {
+        Some(password) => Ok(password.as_str()),
+        None => fail("server asked for a password; set PGPASSWORD"),
+    };
+    let mut scram = None;
+    loop {
+        let (tag, body) = conn.receive().await?;
+        if tag != b'R' {
+            return fail(format!(
+                "expected authentication, got message {:?}",
+                tag as char
+            ));
+        }
+        let mut fields = Fields(&body);
+        match fields.u32() {
+            Some(0) => {
+                let bound = scram.is_some_and(|scram: Scram| scram.bound);
+                if config.channel_binding == BindingPolicy::Require && !bound {
+                    return fail(
+                        "channel binding required, but the server authenticated without it",
+                    );
+                }
+                return Ok(bound);
+            }
+            // Cleartext password
+            Some(3) => {
+                if config.channel_binding == BindingPolicy::Require {
+                    return fail("channel binding required, but the server asked for a password");
+                }
+                let mut message = Vec::new();
+                push_cstr(&mut message, password()?);
+                conn.send(Some(b'p'), &message).await?;
+            }
+            Some(5) => return fail("MD5 password authentication isn't supported"),
+            // SASL, with the mechanisms the server offers
+            Some(10) => {
+                let mechanisms: Vec<_> =
+                    std::iter::from_fn(|| fields.cstr().filter(|m| !m.is_empty())).collect();
+                let plus = mechanisms.contains(&"SCRAM-SHA-256-PLUS");
+                let (mechanism, binding) = match (config.channel_binding, plus, binding.clone()) {
+                    (BindingPolicy::Disable, ..) => ("SCRAM-SHA-256", Binding::Unsupported),
+                    (_, true, Some(binding)) => ("SCRAM-SHA-256-PLUS", Binding::Bound(binding)),
+                    (BindingPolicy::Require, ..) => {
+                        return fail("channel binding required, but the server doesn't offer it");
+                    }
+                    // We could have bound, had the server offered it
+                    (_, _, Some(_)) => ("SCRAM-SHA-256", Binding::Unoffered),
+                    (_, _, None) => ("SCRAM-SHA-256", Binding::Unsupported),
+                };
+                if !mechanisms.contains(&mechanism) {
+                    return fail(format!("no supported SASL mechanism in {mechanisms:?}"));
+                }
+                let nonce = base64(&rng::bytes::<18>(client.rng())?);
+                let started = Scram::start(binding, &nonce);
+                let mut message = Vec::new();
+                push_cstr(&mut message, mechanism);
+                message.extend_from_slice(&(started.first.len() as u32).to_be_bytes());
+                message.extend_from_slice(started.first.as_bytes());
+                conn.send(Some(b'p'), &message).await?;
+                scram = Some(started);
+            }
+            // SASLContinue: the server-first-message
+            Some(11) => {
+                let Some(scram) = &mut scram else {
+                    return fail("SASL continuation without SASL");
+                };
+                let server_first = std::str::from_utf8(fields.rest())?;
+                let client_final = scram.finish(password()?, server_first)?;
+                conn.send(Some(b'p'), client_final.as_bytes()).await?;
+            }
+            // SASLFinal: the server-final-message
+            Some(12) => {
+                let Some(scram) = &scram else {
+                    return fail("SASL outcome without SASL");
+                };
+                scram.verify(std::str::from_utf8(fields.rest())?)?;
+            }
+            code => return fail(format!("unsupported authentication request {code:?}")),
+        }
+    }
+}
+
+/// The transport and what has been read from it past the last message
+struct Connection {
+    transport: Transport,
+    buffered: Vec<u8>,
+}
+
+impl Connection {
+    /// Send a message: its type byte, if it has one, its length, and `body`
+    async fn send(
+        &mut self,
+        tag: Option<u8>,
+        body: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut message = Vec::with_capacity(5 + body.len());
+        message.extend(tag);
+        message.extend_from_slice(&(4 + body.len() as u32).to_be_bytes());
+        message.extend_from_slice(body);
+        Ok(self.transport.write(message).await?)
+    }
+
+    /// The next message's type byte and body; an ErrorResponse fails
+    async fn receive(&mut self) -> Result<(u8, Vec<u8>), Box<dyn std::error::Error>> {
+        loop {
+            if let Some(&[tag, a, b, c, d]) = self.buffered.get(..5) {
+                let len = u32::from_be_bytes([a, b, c, d]) as usize;
+                if len < 4 {
+                    return fail(format!("message {:?} with length {len}", tag as char));
+                }
+                if self.buffered.len() > len {
+                    let body = self.buffered[5..1 + len].to_vec();
+                    self.buffered.drain(..1 + len);
+                    if tag == b'E' {
+                        return fail(notice_message(&body));
+                    }
+                    return Ok((tag, body));
+                }
+            }
+            if self.transport.read(&mut self.buffered).await? == 0 {
+                return fail("server closed the connection");
+            }
+        }
+    }
+}
+
+/// An ErrorResponse's or NoticeResponse's severity, SQLSTATE and message
+fn notice_message(body: &[u8]) -> String {
+    let mut fields = Fields(body);
+    let (mut severity, mut code, mut message) = ("", "", "");
+    while let Some(&kind) = fields.bytes(1).and_then(<[u8]>::first)
+        && kind != 0
+    {
+        let value = fields.cstr().unwrap_or_default();
+        match kind {
+            b'S' => severity = value,
+            b'C' => code = value,
+            b'M' => message = value,
+            _ => {}
+        }
+    }
+    format!("{severity} {code}: {message}")
+}
+
+fn push_cstr(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(s.as_bytes());
+    out.push(0);
+}
+
+/// Reads a message body's fields front to back
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        let (field, rest) = self.0.split_at_checked(n)?;
+        self.0 = rest;
+        Some(field)
+    }
+
+    fn skip(&mut self, n: usize) -> Option<()> {
+        self.bytes(n).map(|_| ())
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// A NUL-terminated string; `None` if it isn't one or isn't UTF-8
+    fn cstr(&mut self) -> Option<&'a str> {
+        let end = self.0.iter().position(|&b| b == 0)?;
+        let s = std::str::from_utf8(&self.0[..end]).ok()?;
+        self.0 = &self.0[end + 1..];
+        Some(s)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.0)
+    }
+}
+
+/// What the client tells the server about channel binding, in the GS2
+/// header
+enum Binding {
+    /// `n`: the client can't bind
+    Unsupported,
+    /// `y`: the client could bind, but the server didn't offer to
+    Unoffered,
+    /// `p`: bound to this
+    Bound(ChannelBinding),
+}
+
+/// A SCRAM-SHA-256 exchange, client side (RFC 5802, RFC 7677)
+struct Scram {
+    /// GS2 header and channel binding data, for the `c=` attribute
+    binding: String,
+    bound: bool,
+    nonce: String,
+    /// The client-first-message
+    first: String,
+    /// The client-first-message past the GS2 header
+    first_bare: String,
+    /// The server signature to expect, once the proof has been sent
+    server_signature: Option<String>,
+}
+
+impl Scram {
+    /// Start with `nonce`, leaving the user name to the connection's, as
+    /// PostgreSQL does
+    fn start(binding: Binding, nonce: &str) -> Self {
+        Self::start_as("", binding, nonce)
+    }
+
+    fn start_as(user: &str, binding: Binding, nonce: &str) -> Self {
+        let (gs2, bound) = match binding {
+            Binding::Unsupported => ("n,,".to_owned(), None),
+            Binding::Unoffered => ("y,,".to_owned(), None),
+            Binding::Bound(binding) => (binding.gs2_header(None), Some(binding)),
+        };
+        let first_bare = format!("n={user},r={nonce}");
+        let binding = match &bound {
+            Some(binding) => binding.scram_attribute(None),
+            None => base64(gs2.as_bytes()),
+        };
+        Self {
+            binding,
+            bound: bound.is_some(),
+            nonce: nonce.to_owned(),
+            first: format!("{gs2}{first_bare}"),
+            first_bare,
+            server_signature: None,
+        }
+    }
+
+    /// The client-final-message, with the proof of `password`, for the
+    /// `server_first` message
+    fn finish(
+        &mut self,
+        password: &str,
+        server_first: &str,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let (mut nonce, mut salt, mut iterations) = (None, None, None);
+        for attribute in server_first.split(',') {
+            match attribute.split_once('=') {
+                Some(("r", value)) => nonce = Some(value),
+                Some(("s", value)) => salt = unbase64(value),
+                Some(("i", value)) => iterations = value.parse().ok(),
+                _ => {}
+            }
+        }
+        let (Some(nonce), Some(salt), Some(iterations)) = (nonce, salt, iterations) else {
+            return fail("malformed SCRAM server-first-message");
+        };
+        if !nonce.starts_with(&self.nonce) {
+            return fail("SCRAM server nonce doesn't extend the client's");
+        }
+
+        let mut salted = [0u8; 32];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            iterations,
+            &salt,
+            password.as_bytes(),
+            &mut salted,
+        );
+        let salted = hmac::Key::new(hmac::HMAC_SHA256, &salted);
+        let client_key = hmac::sign(&salted, b"Client Key");
+        let stored_key = digest::digest(&digest::SHA256, client_key.as_ref());
+
+        let without_proof = format!("c={},r={nonce}", self.binding);
+        let auth_message = format!("{},{server_first},{without_proof}", self.first_bare);
+        let client_signature = hmac::sign(
+            &hmac::Key::new(hmac::HMAC_SHA256, stored_key.as_ref()),
+            auth_message.as_bytes(),
+        );
+        let proof: Vec<u8> = client_key
+            .as_ref()
+            .iter()
+            .zip(client_signature.as_ref())
+            .map(|(k, s)| k ^ s)
+            .collect();
+
+        let server_key = hmac::sign(&salted, b"Server Key");
+        let server_signature = hmac::sign(
+            &hmac::Key::new(hmac::HMAC_SHA256, server_key.as_ref()),
+            auth_message.as_bytes(),
+        );
+        self.server_signature = Some(base64(server_signature.as_ref()));
+        Ok(format!("{without_proof},p={}", base64(&proof)))
+    }
+
+    /// Check the server-final-message proves the server knew the password
+    fn verify(&self, server_final: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(error) = server_final.strip_prefix("e=") {
+            return fail(format!("SCRAM failed: {error}"));
+        }
+        match (server_final.strip_prefix("v="), &self.server_signature) {
+            (Some(got), Some(expected)) if got == expected => Ok(()),
+            _ => fail("SCRAM server signature doesn't match"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scram_sha_256_exchange() {
+        // RFC 7677 section 3
+        let mut scram = Scram::start_as("user", Binding::Unsupported, "rOprNGfwEbeRWgbNEkqO");
+        assert_eq!(scram.first, "n,,n=user,r=rOprNGfwEbeRWgbNEkqO");
+        let client_final = scram
+            .finish(
+                "pencil",
+                "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                 s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096",
+            )
+            .unwrap();
+        assert_eq!(
+            client_final,
+            "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+             p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+        );
+        scram
+            .verify("v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=")
+            .unwrap();
+        assert!(scram.verify("v=AAAA").is_err());
+    }
+}
@@ -0,0 +1,70 @@
+//! Traffic classification for outgoing connections
+//!
+//! Marks a connection's packets with a DSCP code point (`IP_TOS` on IPv4,
+//! `IPV6_TCLASS` on IPv6) for network QoS, and optionally sets the local
+//! queueing priority (`SO_PRIORITY`). tokio-uring connects in the same call
+//! that creates the socket, so the marks apply from the TLS handshake on;
+//! the SYN itself goes out unmarked.
+
+use std::net::SocketAddr;
+use std::os::unix::io::RawFd;
+
+use crate::stats;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TrafficClass {
+    /// Differentiated Services code point, 0..=63 (e.g. 46 for EF, 8 for CS1)
+    pub dscp: Option<u8>,
+    /// `SO_PRIORITY` for the local qdisc; values above 6 need `CAP_NET_ADMIN`
+    pub priority: Option<u32>,
+}
+
+impl TrafficClass {
+    pub fn apply(&self, fd: RawFd, addr: &SocketAddr) -> std::io::Result<()> {
+        if let Some(dscp) = self.dscp {
+            if dscp > 63 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("DSCP {dscp} out of range"),
+                ));
+            }
+            // DSCP occupies the upper six bits; the ECN bits stay with the kernel
+            let tos = libc::c_int::from(dscp) << 2;
+            match addr {
+                SocketAddr::V4(_) => setsockopt(fd, libc::IPPROTO_IP, libc::IP_TOS, tos)?,
+                SocketAddr::V6(_) => setsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos)?,
+            }
+        }
+        if let Some(priority) = self.priority {
+            setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_PRIORITY,
+                priority as libc::c_int,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn setsockopt(
+    fd: RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> std::io::Result<()> {
+    stats::syscalls(1);
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,201 @@
+//! Host name lookups off the runtime thread, bounded, with failures
+//! remembered
+//!
+//! `getaddrinfo` blocks, so each lookup runs on one of tokio's blocking
+//! threads while the runtime thread gets on with other requests. At most
+//! [`ResolverPolicy::max_concurrent`] lookups are outstanding at once and
+//! the rest queue for a slot. A lookup keeps its slot until the resolver
+//! answers, even once the request that wanted it has timed out or been
+//! cancelled, so a resolver that has stopped answering isn't piled onto.
+//!
+//! A lookup that fails, because the name doesn't resolve or because it
+//! took longer than [`ResolverPolicy::timeout`], is remembered for
+//! [`ResolverPolicy::negative_ttl`]. Lookups of the same host in that time
+//! fail at once with the same error instead of going back to the resolver,
+//! which keeps a misconfigured host that the retry layer keeps asking for
+//! from flooding it.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::Semaphore;
+
+use crate::clock::Clock;
+use crate::context::Context;
+
+#[derive(Clone, Debug)]
+pub struct ResolverPolicy {
+    /// Lookups outstanding at once; must not be zero
+    pub max_concurrent: usize,
+    /// How long a lookup may take before it counts as failed
+    pub timeout: Duration,
+    /// How long a failed lookup is remembered; zero remembers none
+    pub negative_ttl: Duration,
+}
+
+impl Default for ResolverPolicy {
+    fn default() -> Self {
+        Self {
+            max_concurrent: 8,
+            timeout: Duration::from_secs(10),
+            negative_ttl: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Why a lookup failed
+#[derive(Clone, Debug)]
+pub enum LookupError {
+    /// The resolver's error, or no addresses
+    NotFound(String),
+    /// No answer within the policy's timeout
+    TimedOut(Duration),
+}
+
+/// A host's lookup failed, now or recently enough to be remembered
+#[derive(Clone, Debug)]
+pub struct LookupFailed {
+    pub host: String,
+    pub error: LookupError,
+    /// Remembered from an earlier lookup rather than just asked
+    pub cached: bool,
+}
+
+impl std::fmt::Display for LookupFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "DNS lookup for {} ", self.host)?;
+        match &self.error {
+            LookupError::NotFound(reason) => write!(f, "failed: {reason}")?,
+            LookupError::TimedOut(after) => {
+                write!(f, "timed out after {:.1}s", after.as_secs_f64())?
+            }
+        }
+        if self.cached {
+            write!(f, " (remembered)")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for LookupFailed {}
+
+/// Lookups for one client
+pub struct Resolver {
+    policy: ResolverPolicy,
+    slots: Arc<Semaphore>,
+    /// Failed hosts, with when to forget them
+    failures: RefCell<HashMap<String, (Instant, LookupError)>>,
+}
+
+impl Resolver {
+    pub fn new(policy: ResolverPolicy) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(policy.max_concurrent)),
+            policy,
+            failures: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Every address `host` resolves to on `port`, in the resolver's order
+    pub async fn resolve(
+        &self,
+        host: &str,
+        port: u16,
+        ctx: &Context,
+        clock: &dyn Clock,
+    ) -> Result<Vec<SocketAddr>, Box<dyn std::error::Error>> {
+        ctx.check("DNS lookup")?;
+        if let Some(failed) = self.remembered(host, clock) {
+            return Err(failed.into());
+        }
+
+        let slot = ctx
+            .run("DNS lookup", self.slots.clone().acquire_owned())
+            .await?
+            .expect("the semaphore is never closed");
+        let name = host.to_owned();
+        let lookup = tokio::task::spawn_blocking(move || {
+            let _slot = slot;
+            (name.as_str(), port)
+                .to_socket_addrs()
+                .map(Iterator::collect::<Vec<_>>)
+        });
+        let error = match ctx
+            .run(
+                "DNS lookup",
+                tokio::time::timeout(self.policy.timeout, lookup),
+            )
+            .await?
+        {
+            Ok(lookup) => match lookup.map_err(std::io::Error::other)? {
+                Ok(addrs) if !addrs.is_empty() => return Ok(addrs),
+                Ok(_) => LookupError::NotFound("no addresses".to_owned()),
+                Err(e) => LookupError::NotFound(e.to_string()),
+            },
+            Err(_) => LookupError::TimedOut(self.policy.timeout),
+        };
+        Err(self.remember(host, error, clock).into())
+    }
+
+    /// The failure remembered for `host`, unless it has expired
+    fn remembered(&self, host: &str, clock: &dyn Clock) -> Option<LookupFailed> {
+        let mut failures = self.failures.borrow_mut();
+        let (until, error) = failures.get(host)?;
+        if clock.now() >= *until {
+            failures.remove(host);
+            return None;
+        }
+        Some(LookupFailed {
+            host: host.to_owned(),
+            error: error.clone(),
+            cached: true,
+        })
+    }
+
+    fn remember(&self, host: &str, error: LookupError, clock: &dyn Clock) -> LookupFailed {
+        if !self.policy.negative_ttl.is_zero() {
+            let now = clock.now();
+            let mut failures = self.failures.borrow_mut();
+            failures.retain(|_, (until, _)| *until > now);
+            failures.insert(
+                host.to_owned(),
+                (now + self.policy.negative_ttl, error.clone()),
+            );
+        }
+        LookupFailed {
+            host: host.to_owned(),
+            error,
+            cached: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn failures_are_remembered_for_the_ttl() {
+        let clock = ManualClock::at(SystemTime::UNIX_EPOCH);
+        let resolver = Resolver::new(ResolverPolicy {
+            negative_ttl: Duration::from_secs(5),
+            ..Default::default()
+        });
+        let error = LookupError::NotFound("Name or service not known".to_owned());
+        assert!(!resolver.remember("typo.example", error, &clock).cached);
+
+        clock.advance(Duration::from_secs(4));
+        let failed = resolver.remembered("typo.example", &clock).unwrap();
+        assert!(failed.cached);
+        assert!(resolver.remembered("example.com", &clock).is_none());
+
+        clock.advance(Duration::from_secs(1));
+        assert!(resolver.remembered("typo.example", &clock).is_none());
+    }
+}
@@ -0,0 +1,103 @@
+//! Retry of throttled requests, and response verification
+//!
+//! `429 Too Many Requests` and `503 Service Unavailable` are retried after
+//! the interval the server asked for in `Retry-After`, or with exponential
+//! backoff when it didn't say. A verifier can additionally judge each
+//! response by API-specific rules (say, a 200 whose body reports an error)
+//! and ask for a retry or turn it into an error. Only requests whose body
+//! can be sent again are retried; a channel body has been consumed by the
+//! first attempt.
+
+use std::time::Duration;
+
+use crate::http::Response;
+use crate::rng::{self, Rng};
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Attempts in total, the first one included
+    pub max_attempts: u32,
+    /// Backoff before the first retry when there is no `Retry-After`;
+    /// doubles with every further attempt
+    pub base_delay: Duration,
+    /// Longest wait worth retrying after; a server asking for more gets its
+    /// response returned as is
+    pub max_delay: Duration,
+    /// Up to this fraction of each backoff, 0 to 1, is taken off at random
+    /// so that clients throttled together don't retry together; 0 waits the
+    /// backoff out exactly. `Retry-After` is always honored as given.
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            jitter: 0.0,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait before retrying after `response` to attempt number `attempt`
+    /// (starting at 1), or `None` if it shouldn't be retried; jitter is
+    /// drawn from `rng`
+    pub fn delay(&self, response: &Response, attempt: u32, rng: &dyn Rng) -> Option<Duration> {
+        if !matches!(response.status, 429 | 503) {
+            return None;
+        }
+        match response.retry_after() {
+            Some(delay) if attempt < self.max_attempts => {
+                (delay <= self.max_delay).then_some(delay)
+            }
+            Some(_) => None,
+            None => self.backoff(attempt, rng),
+        }
+    }
+
+    /// Exponential backoff after attempt number `attempt`, less jitter
+    /// drawn from `rng`, or `None` once the attempts are used up
+    pub fn backoff(&self, attempt: u32, rng: &dyn Rng) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        let delay = self
+            .base_delay
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(self.max_delay);
+        // Falls back to the full backoff if the RNG fails
+        let jitter = self.jitter.clamp(0.0, 1.0) * rng::fraction(rng).unwrap_or(0.0);
+        Some(delay.mul_f64(1.0 - jitter))
+    }
+}
+
+/// Response verifier installed on a client
+pub type Verifier = dyn Fn(&Response) -> Verdict;
+
+/// What a response verifier makes of a response
+#[derive(Debug)]
+pub enum Verdict {
+    /// No objection; the response still gets the usual throttling retries
+    Accept,
+    /// Send the request again after the retry policy's backoff
+    Retry,
+    /// Turn the response into a [`Rejected`] error
+    Fail(String),
+}
+
+/// A response refused by the verifier
+#[derive(Debug)]
+pub struct Rejected {
+    pub status: u16,
+    pub reason: String,
+}
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Response rejected ({}): {}", self.status, self.reason)
+    }
+}
+
+impl std::error::Error for Rejected {}
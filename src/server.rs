@@ -0,0 +1,127 @@
+//! kTLS server: accept connections and hand their TLS to the kernel
+//!
+//! [`KtlsListener`] is the server counterpart of the client's connection
+//! setup. It accepts TCP connections through io_uring, runs the rustls
+//! server handshake over io_uring reads and writes
+//! ([`accept_handshake`](crate::handshake::accept_handshake)), and
+//! configures `TLS_TX` and `TLS_RX` on the accepted socket with the
+//! extracted secrets. What comes back is a plain tokio-uring `TcpStream`
+//! that the kernel encrypts and decrypts, so an HTTPS or WSS server built
+//! on it takes the same io_uring and kTLS path the client does.
+//!
+//! Accepting and handshaking are separate steps: [`KtlsListener::accept`]
+//! returns an [`Incoming`] connection at once, and its
+//! [`handshake`](Incoming::handshake) can run on a task of its own, so a
+//! slow client doesn't hold up the next one. Bound the handshake with a
+//! timeout; nothing here does.
+//!
+//! There is no userspace fallback: where the kernel has no TLS support, a
+//! connection fails its handshake with
+//! [`KtlsError::UlpSetupFailed`](crate::ktls::KtlsError::UlpSetupFailed).
+
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+
+use rustls::ServerConfig;
+use tokio_uring::net::{TcpListener, TcpStream};
+
+use crate::handshake;
+use crate::ktls;
+use crate::session::Transport;
+use crate::stats;
+
+/// Accepts TLS connections and sets kTLS up on each one
+pub struct KtlsListener {
+    listener: TcpListener,
+    config: Arc<ServerConfig>,
+}
+
+impl KtlsListener {
+    /// Listen on `addr`, handshaking with `config`
+    ///
+    /// Secret extraction is turned on in `config`, and TLS 1.3 session
+    /// tickets off: they would go out after the handshake, and a kTLS
+    /// client would read them as records it can't decrypt into data.
+    pub fn bind(addr: SocketAddr, mut config: ServerConfig) -> std::io::Result<Self> {
+        config.enable_secret_extraction = true;
+        config.send_tls13_tickets = 0;
+        Ok(Self {
+            listener: TcpListener::bind(addr)?,
+            config: Arc::new(config),
+        })
+    }
+
+    /// The address the listener is bound to, e.g. to learn the port after
+    /// binding port 0
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Wait for the next connection; its handshake is yet to run
+    pub async fn accept(&self) -> std::io::Result<Incoming> {
+        stats::uring_submitted();
+        let accepted = self.listener.accept().await;
+        stats::uring_completed();
+        let (stream, peer) = accepted?;
+        Ok(Incoming {
+            stream,
+            peer,
+            config: self.config.clone(),
+        })
+    }
+}
+
+/// An accepted connection that hasn't handshaken yet
+pub struct Incoming {
+    stream: TcpStream,
+    peer: SocketAddr,
+    config: Arc<ServerConfig>,
+}
+
+impl Incoming {
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Run the server handshake and hand the connection's keys to the
+    /// kernel
+    pub async fn handshake(self) -> Result<KtlsStream, Box<dyn std::error::Error>> {
+        let fd = self.stream.as_raw_fd();
+        // Before the handshake, so a kernel without kTLS fails fast
+        ktls::enable_ulp(fd)?;
+        let result = handshake::accept_handshake(&self.stream, self.config).await?;
+        let version = ktls::tls_version(result.version);
+        ktls::configure_keys(fd, result.tx, result.rx, version)?;
+        Ok(KtlsStream {
+            stream: self.stream,
+            buffered: result.received,
+            peer: self.peer,
+            server_name: result.server_name,
+            alpn_protocol: result.alpn_protocol,
+        })
+    }
+}
+
+/// A server-side connection encrypted by the kernel
+pub struct KtlsStream {
+    /// Reads and writes carry plaintext from here on
+    pub stream: TcpStream,
+    /// Data the client sent along with its last handshake flight, already
+    /// decrypted; it comes before anything read from `stream`
+    pub buffered: Vec<u8>,
+    pub peer: SocketAddr,
+    /// The host the client asked for with SNI, if it did
+    pub server_name: Option<String>,
+    /// The protocol agreed through ALPN, if any
+    pub alpn_protocol: Option<Vec<u8>>,
+}
+
+impl KtlsStream {
+    /// The connection as a [`Transport`], with the data already read from
+    /// it, as [`Session::into_transport`](crate::session::Session::into_transport)
+    /// hands over a client connection
+    pub fn into_transport(self) -> (Transport, Vec<u8>) {
+        (Transport::Ktls(self.stream), self.buffered)
+    }
+}
@@ -0,0 +1,884 @@
+//! Explicit connection control: connect once, send many requests
+//!
+//! [`HttpsClient::open_session`] connects and handshakes once; the returned
+//! [`Session`] then sends requests over that connection one after another,
+//! reading each response by its framing rather than to EOF. Nothing
+//! reconnects behind the caller's back: once the server has closed the
+//! connection, further requests fail with [`SessionClosed`] and it is up to
+//! the caller to open a new session. A response with `Connection: close`
+//! closes the session as it is read, and one that leaves the connection idle
+//! past the server's `Keep-Alive: timeout` is taken as closed, so the next
+//! request fails up front rather than being written to a connection the
+//! server has dropped.
+//!
+//! The one exception is the client's [`RecyclePolicy`]. A connection that
+//! has carried as many requests, or been open as long, as the policy allows
+//! is closed with `close_notify` once its response is in, and the session's
+//! next request goes over a new one. Load balancers then get to spread
+//! long-lived sessions over their backends, and no connection's keys
+//! encrypt more records than the policy allows for: a kTLS connection
+//! can't rekey, so its keys last as long as it does. Sessions opened over a
+//! caller's connection can't reconnect, and fail with [`SessionClosed`]
+//! instead.
+//!
+//! [`Session::pipeline`] writes several requests before reading any of
+//! their responses, packed into as few writes, and under kTLS TLS records,
+//! as the client's [`with_write_coalescing`](HttpsClient::with_write_coalescing)
+//! allows.
+//!
+//! [`Session::send_streaming`] hands the connection over to a response
+//! body read as it arrives, for bodies too large, or too slow, to wait for
+//! whole.
+//!
+//! [`Session::close`] ends the connection with `close_notify`, and so does
+//! dropping a session between requests, so the server doesn't hold it open
+//! waiting for more.
+//!
+//! Sessions skip the response cache, retries and the verifier, which all
+//! assume they are free to issue requests of their own.
+
+use std::io::ErrorKind;
+use std::os::unix::io::{AsRawFd, BorrowedFd, RawFd};
+use std::time::{Duration, Instant};
+
+use rustls::ClientConnection;
+use tokio::task::JoinHandle;
+use tokio_uring::net::TcpStream;
+
+use crate::backpressure::Watch;
+use crate::balance::Lease;
+use crate::breaker::Admission;
+use crate::client::EarlyHints;
+use crate::connect::{self, ConnectionInfo};
+use crate::context::Context;
+use crate::handshake::KeyingMaterial;
+use crate::headers::HeaderMap;
+use crate::http::{self, Body, BodyDecoder, Request, Response, Timing};
+use crate::introspect::{Registration, Use};
+use crate::limit::Slot;
+use crate::streaming::{BodyStream, StreamedResponse};
+use crate::tls::UringTlsStream;
+use crate::{HttpsClient, RequestOptions, fd, ktls, stats};
+
+/// Only [`Session::into_transport`] takes a session's connection, and the
+/// session with it
+const TAKEN: &str = "session used after into_transport";
+
+/// The server closed a session's connection
+#[derive(Debug)]
+pub struct SessionClosed;
+
+impl std::fmt::Display for SessionClosed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Session connection was closed by the server")
+    }
+}
+
+impl std::error::Error for SessionClosed {}
+
+/// When a session's connection is retired for a new one
+#[derive(Clone, Debug, Default)]
+pub struct RecyclePolicy {
+    /// How long a connection carries requests for; unset, as long as it
+    /// stays open
+    pub max_age: Option<Duration>,
+    /// How many requests a connection carries; unset, as many as are sent
+    pub max_requests: Option<usize>,
+}
+
+impl RecyclePolicy {
+    /// Whether a connection opened at `opened` that has carried `requests`
+    /// requests should carry no more at `now`
+    pub fn spent(&self, opened: Instant, requests: usize, now: Instant) -> bool {
+        self.max_requests.is_some_and(|max| requests >= max)
+            || self.max_age.is_some_and(|max| now >= opened + max)
+    }
+}
+
+/// An established connection, encrypted by the kernel or by rustls
+pub enum Transport {
+    Ktls(TcpStream),
+    Userspace(Box<UringTlsStream<ClientConnection>>),
+}
+
+impl Transport {
+    pub fn fd(&self) -> BorrowedFd<'_> {
+        match self {
+            Transport::Ktls(stream) => fd::borrow(stream),
+            Transport::Userspace(tls) => tls.fd(),
+        }
+    }
+
+    /// Append the next data from the peer to `out`; 0 once it has closed
+    /// the connection
+    pub async fn read(&mut self, out: &mut Vec<u8>) -> std::io::Result<usize> {
+        eof_on_close(self.read_raw(out).await)
+    }
+
+    /// Like [`read`](Self::read), but failing as the transport does on a
+    /// close without close_notify, see [`closed_without_notify`]
+    pub async fn read_raw(&mut self, out: &mut Vec<u8>) -> std::io::Result<usize> {
+        match self {
+            Transport::Ktls(stream) => {
+                stats::uring_submitted();
+                let (result, buf) = stream.read(vec![0u8; 8192]).await;
+                stats::uring_completed();
+                result.inspect(|&n| out.extend_from_slice(&buf[..n]))
+            }
+            Transport::Userspace(tls) => {
+                let mut buf = vec![0u8; 8192];
+                let result = tls.read(&mut buf).await;
+                result.inspect(|&n| out.extend_from_slice(&buf[..n]))
+            }
+        }
+    }
+
+    /// Like [`read`](Self::read), but failing with `WouldBlock` rather than
+    /// waiting when there is nothing to read yet
+    pub fn try_read(&mut self, out: &mut Vec<u8>) -> std::io::Result<usize> {
+        let mut buf = vec![0u8; 8192];
+        let result = match self {
+            Transport::Ktls(stream) => fd::try_recv(fd::borrow(stream), &mut buf),
+            Transport::Userspace(tls) => tls.try_read(&mut buf),
+        };
+        eof_on_close(result.inspect(|&n| out.extend_from_slice(&buf[..n])))
+    }
+
+    /// Send what the socket takes of `data` without waiting; `WouldBlock`
+    /// if it has no room
+    pub fn try_write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Transport::Ktls(stream) => fd::try_send(fd::borrow(stream), data),
+            Transport::Userspace(tls) => tls.try_write(data),
+        }
+    }
+
+    /// Finish sending anything [`try_write`](Self::try_write) accepted;
+    /// `WouldBlock` until the socket has taken it all
+    pub fn try_flush(&mut self) -> std::io::Result<()> {
+        match self {
+            // The kernel owns anything a kTLS send accepted
+            Transport::Ktls(_) => Ok(()),
+            Transport::Userspace(tls) => tls.try_flush(),
+        }
+    }
+
+    /// Send a `close_notify` alert, or for userspace TLS queue it for the
+    /// next flush
+    ///
+    /// Over kTLS the alert goes out at once if the send buffer has room for
+    /// it, and is skipped otherwise; the peer still sees the TCP FIN.
+    pub fn send_close_notify(&mut self) {
+        match self {
+            Transport::Ktls(stream) => {
+                let _ = ktls::send_close_notify(stream.as_raw_fd());
+            }
+            Transport::Userspace(tls) => tls.send_close_notify(),
+        }
+    }
+
+    /// Start sending `data` without waiting for it to go out
+    ///
+    /// Over kTLS the write is submitted from a task of `reaper`'s, after
+    /// any it already has in flight. Userspace TLS can't share its stream
+    /// with a task, so `data` is encrypted and sent as far as the socket
+    /// takes it now, and the rest goes out ahead of the next write.
+    pub fn send_nowait(&mut self, data: Vec<u8>, reaper: &mut Reaper) -> std::io::Result<()> {
+        match self {
+            Transport::Ktls(stream) => reaper.submit(fd::borrow(stream), data),
+            Transport::Userspace(tls) => tls.queue(&data),
+        }
+    }
+
+    pub async fn write(&mut self, data: Vec<u8>) -> std::io::Result<()> {
+        match self {
+            Transport::Ktls(stream) => fd::write_all(stream, data).await,
+            Transport::Userspace(tls) => tls.write_all(&data).await,
+        }
+    }
+}
+
+/// Completes [`Transport::send_nowait`] writes in the background, in the
+/// order they were submitted
+///
+/// Each write goes to a duplicate of the socket's fd, so the socket stays
+/// open until it completes even if the connection is dropped first.
+#[derive(Default)]
+pub struct Reaper {
+    /// The latest write's task; each one waits for the one before it
+    last: Option<JoinHandle<std::io::Result<()>>>,
+}
+
+impl Reaper {
+    fn submit(&mut self, fd: BorrowedFd<'_>, data: Vec<u8>) -> std::io::Result<()> {
+        let stream = TcpStream::from_std(fd.try_clone_to_owned()?.into());
+        let previous = self.last.take();
+        self.last = Some(tokio_uring::spawn(async move {
+            if let Some(previous) = previous {
+                // A failed write leaves the stream mid-message
+                previous.await.map_err(std::io::Error::other)??;
+            }
+            fd::write_all(&stream, data).await
+        }));
+        Ok(())
+    }
+
+    /// Whether every write submitted so far has completed
+    pub fn idle(&self) -> bool {
+        self.last.as_ref().is_none_or(JoinHandle::is_finished)
+    }
+
+    /// Wait for the writes in flight; the first of them to fail, if any did
+    pub async fn settle(&mut self) -> std::io::Result<()> {
+        match self.last.take() {
+            Some(last) => last.await.map_err(std::io::Error::other)?,
+            None => Ok(()),
+        }
+    }
+}
+
+/// A connection fresh from its handshake, with the lease on its address
+pub struct Established<'c> {
+    pub transport: Transport,
+    pub connection: ConnectionInfo,
+    pub lease: Lease<'c>,
+    /// Counts the connection against the client's connection limit, if it
+    /// has one
+    pub slot: Option<Slot<'c>>,
+    /// Passage through the host's circuit, when the client has a breaker;
+    /// the response decides whether it succeeded
+    pub admission: Option<Admission<'c>>,
+    /// How long getting here took, phase by phase
+    pub timing: Timing,
+    /// From the TLS exporter, for the options' exporters
+    pub keying_material: KeyingMaterial,
+}
+
+/// Whether `e` is how the transport reports a close without close_notify:
+/// EIO from kTLS, UnexpectedEof from rustls
+pub fn closed_without_notify(e: &std::io::Error) -> bool {
+    e.kind() == ErrorKind::UnexpectedEof || e.raw_os_error() == Some(libc::EIO)
+}
+
+/// Report a close as a 0-byte read, with or without close_notify
+fn eof_on_close(result: std::io::Result<usize>) -> std::io::Result<usize> {
+    match result {
+        Err(e) if closed_without_notify(&e) => Ok(0),
+        result => result,
+    }
+}
+
+/// `TCP_NODELAY` changed for one request on a session, and changed back
+/// once it is dropped, whether the request went through or not
+struct NoDelayOverride {
+    fd: RawFd,
+    restore: bool,
+}
+
+impl NoDelayOverride {
+    fn set(fd: RawFd, nodelay: bool, restore: bool) -> std::io::Result<Self> {
+        connect::set_nodelay(fd, nodelay)?;
+        Ok(Self { fd, restore })
+    }
+}
+
+impl Drop for NoDelayOverride {
+    fn drop(&mut self) {
+        // The session is closed anyway if the socket refuses
+        let _ = connect::set_nodelay(self.fd, self.restore);
+    }
+}
+
+/// One connection to one host, reused for every request sent through it
+pub struct Session<'c> {
+    client: &'c HttpsClient,
+    host: String,
+    /// Until [`into_transport`](Self::into_transport) takes it
+    transport: Option<Transport>,
+    connection: ConnectionInfo,
+    /// Bytes received past the end of the previous response
+    buffered: Vec<u8>,
+    /// Set once the connection can't carry another request, and while one
+    /// is in flight
+    closed: bool,
+    /// Closed by the session itself, under the client's recycle policy
+    retired: bool,
+    /// Whether the session connected itself, and so can again
+    reconnects: bool,
+    /// When the connection was established, by the client's clock
+    opened: Instant,
+    /// Requests sent over the connection so far
+    requests: usize,
+    /// When the last response was read, by the client's clock
+    last_used: Instant,
+    /// How long the server said it keeps the connection open while idle
+    idle_limit: Option<Duration>,
+    /// Counts the connection against its address while the session lasts
+    _lease: Lease<'c>,
+    /// And against the client's connection limit
+    _slot: Option<Slot<'c>>,
+    /// Watches the send buffer, when the options asked for alerts
+    _backpressure: Option<Watch>,
+    /// The connection's entry in the client's [`debug_state`](HttpsClient::debug_state)
+    registration: Option<Registration>,
+    /// Exported when the session was opened
+    keying_material: KeyingMaterial,
+    /// `TCP_NODELAY` as opened, which requests overriding it put back
+    nodelay: bool,
+}
+
+impl<'c> Session<'c> {
+    /// Connect to `host`, over kTLS where it can be set up and userspace TLS
+    /// otherwise
+    pub async fn open(
+        client: &'c HttpsClient,
+        host: &str,
+        options: &RequestOptions,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let established = client.establish(host, options).await?;
+        Self::start(client, host, established, options, true)
+    }
+
+    /// Like [`open`](Self::open), but over `stream`, already connected to
+    /// `host`'s server
+    pub async fn open_on(
+        client: &'c HttpsClient,
+        stream: std::net::TcpStream,
+        host: &str,
+        options: &RequestOptions,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let established = client.establish_on(stream, host, options).await?;
+        Self::start(client, host, established, options, false)
+    }
+
+    fn start(
+        client: &'c HttpsClient,
+        host: &str,
+        established: Established<'c>,
+        options: &RequestOptions,
+        reconnects: bool,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let Established {
+            transport,
+            connection,
+            lease,
+            slot,
+            admission,
+            // The session's responses time their own exchanges only
+            timing: _,
+            keying_material,
+        } = established;
+        // The handshake went through; responses on the session don't count
+        if let Some(admission) = admission {
+            admission.succeeded();
+        }
+        let backpressure = options
+            .on_backpressure
+            .as_ref()
+            .map(|alert| alert.watch(transport.fd(), connection.peer))
+            .transpose()?;
+        let registration =
+            client
+                .registry
+                .register(host, &connection, transport.fd(), Use::Session)?;
+        Ok(Self {
+            client,
+            host: host.to_owned(),
+            transport: Some(transport),
+            connection,
+            buffered: Vec::new(),
+            closed: false,
+            retired: false,
+            reconnects,
+            opened: client.clock.now(),
+            requests: 0,
+            last_used: client.clock.now(),
+            idle_limit: None,
+            _lease: lease,
+            _slot: slot,
+            _backpressure: backpressure,
+            registration: Some(registration),
+            keying_material,
+            nodelay: options.nodelay.or(client.nodelay).unwrap_or(false),
+        })
+    }
+
+    /// Send `request` over the session's connection and read its response
+    ///
+    /// The request goes to the session's host whatever its own says. A
+    /// request that fails part way leaves the session closed, since the
+    /// connection may hold half a message.
+    ///
+    /// When the connection was retired under the client's
+    /// [`RecyclePolicy`], the request goes over a new one, set up with
+    /// `options`.
+    pub async fn send(
+        &mut self,
+        mut request: Request<'_>,
+        options: &RequestOptions,
+    ) -> Result<Response, Box<dyn std::error::Error>> {
+        self.ready(options).await?;
+        self.closed = true;
+        self.requests += 1;
+        let started = Instant::now();
+        self.prepare(&mut request, options)?;
+
+        let _nodelay = match options.nodelay {
+            Some(nodelay) if nodelay != self.nodelay => Some(NoDelayOverride::set(
+                self.transport().fd().as_raw_fd(),
+                nodelay,
+                self.nodelay,
+            )?),
+            _ => None,
+        };
+
+        let sent = self.transmit(&mut request, &options.context).await?;
+        let (response, eof) = self
+            .receive(&request.method, options, started, sent)
+            .await?;
+        self.finish(&response, eof);
+        Ok(response)
+    }
+
+    /// Send `request` and return its response as soon as the head is in,
+    /// with the body to follow through a [`BodyStream`]
+    ///
+    /// The connection goes to the stream's reader, which holds off reading
+    /// once `max_buffered` bytes of the body are waiting to be taken (see
+    /// [`crate::streaming`]), and closes it once the body has ended; the
+    /// session is used up. Early hints are reported as for
+    /// [`send`](Self::send). The body comes as the server sent it: chunked
+    /// encoding is undone, `Content-Encoding` isn't.
+    pub async fn send_streaming(
+        mut self,
+        mut request: Request<'_>,
+        options: &RequestOptions,
+        max_buffered: usize,
+    ) -> Result<StreamedResponse, Box<dyn std::error::Error>> {
+        self.ready(options).await?;
+        self.closed = true;
+        self.requests += 1;
+        self.prepare(&mut request, options)?;
+        let ctx = &options.context;
+        self.transmit(&mut request, ctx).await?;
+
+        let mut hints = EarlyHints::new(options);
+        let len = loop {
+            hints.update(&self.buffered);
+            if let Some(len) = http::head_len(&self.buffered) {
+                break len;
+            }
+            if ctx.run("read", self.read()).await?? == 0 {
+                return Err(SessionClosed.into());
+            }
+        };
+        if let Some(log) = &self.client.wire_log {
+            log.received(&self.host, &self.buffered[..len]);
+        }
+        let (mut head, body_start) =
+            Response::parse_head(&self.buffered[..len], self.client.strict_parsing)?;
+        let decoder = BodyDecoder::new(&head)?;
+        head.connection = Some(self.connection);
+        self.buffered.drain(..body_start);
+
+        let (transport, buffered, registration) = self.into_transport();
+        registration.set_use(Use::Request);
+        let body = BodyStream::spawn(transport, buffered, decoder, registration, max_buffered);
+        Ok(StreamedResponse { head, body })
+    }
+
+    /// Write `request`, then its body as it comes; when the write started
+    async fn transmit(
+        &mut self,
+        request: &mut Request<'_>,
+        ctx: &Context,
+    ) -> Result<Instant, Box<dyn std::error::Error>> {
+        let encoded = request.encode();
+        if let Some(log) = &self.client.wire_log {
+            log.sent(&self.host, &encoded);
+        }
+        let sent = Instant::now();
+        self.write(ctx, encoded).await?;
+        while let Some(chunk) = ctx.run("write", request.body.next_chunk()).await? {
+            self.write(ctx, chunk).await?;
+        }
+        Ok(sent)
+    }
+
+    /// Send `requests` back to back without waiting for their responses,
+    /// then read the responses in order (HTTP/1.1 pipelining)
+    ///
+    /// With the client's [`with_write_coalescing`](HttpsClient::with_write_coalescing)
+    /// the requests are packed into as few writes as it allows, except
+    /// those whose options set
+    /// [`no_coalescing`](RequestOptions::no_coalescing), which go out on
+    /// their own as soon as they are ready. Streamed bodies are read to the
+    /// end before their request is written. Each request's own context and
+    /// close policy apply to it; `nodelay` overrides don't, as the writes
+    /// are packed already.
+    ///
+    /// Pipeline idempotent requests only: once a response closes the
+    /// connection or a read fails, the requests after it fail with
+    /// [`SessionClosed`] or that error, though the server may have acted on
+    /// them. The recycle policy is checked before the pipeline, not between
+    /// its requests.
+    pub async fn pipeline(
+        &mut self,
+        requests: Vec<(Request<'_>, RequestOptions)>,
+    ) -> Vec<Result<Response, Box<dyn std::error::Error>>> {
+        let count = requests.len();
+        let mut results = Vec::with_capacity(count);
+        if let Err(e) = self.pipeline_into(requests, &mut results).await {
+            results.push(Err(e));
+        }
+        while results.len() < count {
+            results.push(Err(SessionClosed.into()));
+        }
+        results
+    }
+
+    /// [`pipeline`](Self::pipeline), pushing responses onto `results` until
+    /// one can't be had
+    async fn pipeline_into(
+        &mut self,
+        mut requests: Vec<(Request<'_>, RequestOptions)>,
+        results: &mut Vec<Result<Response, Box<dyn std::error::Error>>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some((_, first)) = requests.first() else {
+            return Ok(());
+        };
+        self.ready(first).await?;
+        self.closed = true;
+        self.requests += requests.len();
+        let started = Instant::now();
+
+        let limit = self.client.coalesce_writes;
+        let mut pending = Vec::new();
+        // When each request's write started, for its time to first byte
+        let mut sent = Vec::with_capacity(requests.len());
+        for (request, options) in &mut requests {
+            self.prepare(request, options)?;
+            let ctx = &options.context;
+            let mut encoded = request.encode();
+            while let Some(chunk) = ctx.run("write", request.body.next_chunk()).await? {
+                encoded.extend(chunk);
+            }
+            if let Some(log) = &self.client.wire_log {
+                log.sent(&self.host, &encoded);
+            }
+            let alone = options.no_coalescing || limit == 0;
+            if !pending.is_empty() && (alone || pending.len() + encoded.len() > limit) {
+                self.write(ctx, std::mem::take(&mut pending)).await?;
+            }
+            sent.push(Instant::now());
+            pending.extend(encoded);
+            if alone {
+                self.write(ctx, std::mem::take(&mut pending)).await?;
+            }
+        }
+        if let Some((_, last)) = requests.last()
+            && !pending.is_empty()
+        {
+            self.write(&last.context, pending).await?;
+        }
+
+        let last = requests.len() - 1;
+        for (i, ((request, options), sent)) in requests.iter().zip(sent).enumerate() {
+            let (response, eof) = self
+                .receive(&request.method, options, started, sent)
+                .await?;
+            let done = i == last || eof || !response.reusable();
+            if done {
+                self.finish(&response, eof);
+            }
+            results.push(Ok(response));
+            if done {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the connection ready for the next request: retire it if it is
+    /// spent, and reconnect with `options` if it was retired; fails with
+    /// [`SessionClosed`] if the connection is closed for good
+    async fn ready(&mut self, options: &RequestOptions) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.closed && self.idled_out() {
+            self.closed = true;
+        }
+        if !self.closed && self.spent() {
+            self.retire();
+        }
+        if self.retired && self.reconnects {
+            self.reconnect(options).await?;
+        }
+        if self.closed {
+            return Err(SessionClosed.into());
+        }
+        Ok(())
+    }
+
+    /// Add the session's headers to `request` and address it to the
+    /// session's host
+    fn prepare(
+        &self,
+        request: &mut Request<'_>,
+        options: &RequestOptions,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut headers = HeaderMap::new();
+        headers.append("User-Agent", "ktls-uring-demo/0.1");
+        headers.append("Accept-Encoding", "gzip, deflate");
+        headers.extend(&request.headers);
+        request.headers = headers;
+        request.host.clone_from(&self.host);
+        if let Some(trace) = options.context.trace() {
+            trace.child()?.inject(&mut request.headers);
+        }
+        if let Some(min_size) = self.client.compress_requests {
+            request.gzip_body(min_size);
+        }
+        Ok(())
+    }
+
+    /// Read the next response off the connection, for a `method` request
+    /// whose write started at `sent`; with whether the server closed the
+    /// connection to end it
+    async fn receive(
+        &mut self,
+        method: &str,
+        options: &RequestOptions,
+        started: Instant,
+        sent: Instant,
+    ) -> Result<(Response, bool), Box<dyn std::error::Error>> {
+        let ctx = &options.context;
+        let mut hints = EarlyHints::new(options);
+        let mut eof = false;
+        let mut unclean_close = false;
+        let mut ttfb = None;
+        let len = loop {
+            hints.update(&self.buffered);
+            if let Some(len) = http::response_len_to(method, &self.buffered) {
+                break len;
+            }
+            let read = match ctx.run("read", self.read()).await? {
+                Err(e) if closed_without_notify(&e) => {
+                    unclean_close = true;
+                    Ok(0)
+                }
+                read => read,
+            };
+            let n = read?;
+            if n > 0 {
+                ttfb.get_or_insert_with(|| sent.elapsed());
+            }
+            if n == 0 {
+                if self.buffered.is_empty() {
+                    return Err(SessionClosed.into());
+                }
+                // Delimited by the close, or cut short; parsing tells which
+                eof = true;
+                break self.buffered.len();
+            }
+        };
+
+        let raw: Vec<u8> = self.buffered.drain(..len).collect();
+        if let Some(log) = &self.client.wire_log {
+            log.received(&self.host, &raw);
+        }
+        let close_policy = unclean_close
+            .then(|| self.client.judge_close(&self.host, &raw, options))
+            .transpose()?;
+        let mut response = self.client.parse_response_to(method, &raw)?;
+        response.connection = Some(self.connection);
+        response.close_policy = close_policy;
+        response.timing = Some(Timing {
+            // Already buffered when the request went out
+            ttfb: ttfb.unwrap_or_else(|| sent.elapsed()),
+            total: started.elapsed(),
+            ..Timing::default()
+        });
+        self.last_used = self.client.clock.now();
+        self.idle_limit = response.keep_alive_timeout();
+        Ok((response, eof))
+    }
+
+    /// Settle the connection after the last response in flight: closed if
+    /// the server closed it or is about to, retired if it is spent
+    fn finish(&mut self, response: &Response, eof: bool) {
+        self.closed = eof;
+        if !response.reusable() {
+            // The server is closing after this response; say goodbye first
+            self.close();
+        } else if !self.closed && self.spent() {
+            self.retire();
+        }
+    }
+
+    /// Whether the connection has sat idle for about as long as the server
+    /// keeps idle connections, leaving a second's margin for the request to
+    /// arrive in
+    fn idled_out(&self) -> bool {
+        self.idle_limit.is_some_and(|limit| {
+            let idle = self
+                .client
+                .clock
+                .now()
+                .saturating_duration_since(self.last_used);
+            idle + Duration::from_secs(1) >= limit
+        })
+    }
+
+    /// Whether the connection has had its turn under the recycle policy
+    fn spent(&self) -> bool {
+        self.client
+            .recycle
+            .spent(self.opened, self.requests, self.client.clock.now())
+    }
+
+    /// Close the connection with `close_notify`, as dropping the session
+    /// does
+    ///
+    /// Best effort, as the kTLS alert is, and never waits. Does nothing once
+    /// the session is closed, whether by an earlier call, the server or a
+    /// request that failed part way.
+    pub fn close(&mut self) {
+        if self.closed {
+            return;
+        }
+        self.closed = true;
+        let transport = self.transport();
+        transport.send_close_notify();
+        let _ = transport.try_flush();
+    }
+
+    /// Close the connection with `close_notify`, for a new one to take over
+    fn retire(&mut self) {
+        self.close();
+        self.retired = true;
+    }
+
+    /// Carry on over a new connection to the session's host
+    async fn reconnect(
+        &mut self,
+        options: &RequestOptions,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self.client.controls.verbose() {
+            println!(
+                "Recycling the session's connection to {} after {} requests",
+                self.connection.peer, self.requests
+            );
+        }
+        let established = self.client.establish(&self.host, options).await?;
+        *self = Self::start(self.client, &self.host, established, options, true)?;
+        Ok(())
+    }
+
+    pub async fn get(&mut self, path: &str) -> Result<Response, Box<dyn std::error::Error>> {
+        self.send_simple("GET", path, Body::Empty).await
+    }
+
+    pub async fn post(
+        &mut self,
+        path: &str,
+        body: &str,
+    ) -> Result<Response, Box<dyn std::error::Error>> {
+        self.send_simple("POST", path, Body::Json(body)).await
+    }
+
+    pub async fn put(
+        &mut self,
+        path: &str,
+        body: &str,
+    ) -> Result<Response, Box<dyn std::error::Error>> {
+        self.send_simple("PUT", path, Body::Json(body)).await
+    }
+
+    pub async fn patch(
+        &mut self,
+        path: &str,
+        body: &str,
+    ) -> Result<Response, Box<dyn std::error::Error>> {
+        self.send_simple("PATCH", path, Body::Json(body)).await
+    }
+
+    pub async fn delete(&mut self, path: &str) -> Result<Response, Box<dyn std::error::Error>> {
+        self.send_simple("DELETE", path, Body::Empty).await
+    }
+
+    async fn send_simple(
+        &mut self,
+        method: &str,
+        path: &str,
+        body: Body<'_>,
+    ) -> Result<Response, Box<dyn std::error::Error>> {
+        let request = Request::new(method, &self.host, path).with_body(body);
+        self.send(request, &RequestOptions::default()).await
+    }
+
+    async fn write(
+        &mut self,
+        ctx: &Context,
+        data: Vec<u8>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let client = self.client;
+        match self.transport() {
+            Transport::Ktls(stream) => ctx.run("write", client.write_chunk(stream, data)).await??,
+            transport => ctx.run("write", transport.write(data)).await??,
+        }
+        Ok(())
+    }
+
+    /// Read more of the connection into `buffered`; 0 once the server has
+    /// closed it cleanly, see [`closed_without_notify`] for otherwise
+    async fn read(&mut self) -> std::io::Result<usize> {
+        let transport = self.transport.as_mut().expect(TAKEN);
+        match transport {
+            Transport::Ktls(stream) => self.client.read_chunk(stream, &mut self.buffered).await,
+            transport => transport.read_raw(&mut self.buffered).await,
+        }
+    }
+
+    /// How the session's connection was set up, and its send queue now
+    pub fn connection(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            send_queue: self
+                .transport
+                .as_ref()
+                .and_then(|transport| connect::send_queue(transport.fd().as_raw_fd()).ok()),
+            ..self.connection
+        }
+    }
+
+    /// Material from the TLS exporter, for the
+    /// [`Exporter`](crate::handshake::Exporter)s in the options the session
+    /// was opened with
+    pub fn keying_material(&self) -> &KeyingMaterial {
+        &self.keying_material
+    }
+
+    /// Take over the connection, with any bytes already read past the last
+    /// response, e.g. after a protocol upgrade, and its registration with
+    /// the client
+    pub fn into_transport(mut self) -> (Transport, Vec<u8>, Registration) {
+        // Nothing left for the drop to close
+        self.closed = true;
+        let transport = self.transport.take().expect(TAKEN);
+        let registration = self.registration.take().expect(TAKEN);
+        (transport, std::mem::take(&mut self.buffered), registration)
+    }
+
+    /// The connection, which only [`into_transport`](Self::into_transport)
+    /// takes
+    fn transport(&mut self) -> &mut Transport {
+        self.transport.as_mut().expect(TAKEN)
+    }
+}
+
+impl Drop for Session<'_> {
+    /// Close the connection with `close_notify` if it is between requests,
+    /// so the server isn't left waiting on it; one dropped mid-request gets
+    /// none, as the truncation would then look deliberate
+    fn drop(&mut self) {
+        self.close();
+    }
+}
@@ -0,0 +1,195 @@
+//! Warm standby: connections handshaken ahead of the requests that need them
+//!
+//! [`HttpsClient::keep_warm`](crate::HttpsClient::keep_warm) keeps
+//! [`StandbyPolicy::ready`] connections to a host connected, handshaken and
+//! idle. A request to that host takes one of them instead of connecting, so
+//! neither the connect nor the handshake is part of its latency, and
+//! `keep_warm` connects a replacement. Servers close connections that sit
+//! idle for long, so each one is replaced once it reaches
+//! [`StandbyPolicy::max_age`], and any the server has hung up on are
+//! dropped when next looked at.
+//!
+//! Only requests that ask nothing of the connection itself take one: not
+//! those with endpoints, exporters or a traffic class of their own. Until a
+//! request takes it, a standby connection is left out of the load
+//! balancer's counts and the connection limit.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::os::unix::io::{AsRawFd, BorrowedFd};
+use std::time::{Duration, Instant};
+
+use tokio::sync::Notify;
+
+use crate::balance::Lease;
+use crate::clock::Clock;
+use crate::connect::{self, ConnectionInfo};
+use crate::handshake::KeyingMaterial;
+use crate::http::Timing;
+use crate::introspect::Registration;
+use crate::session::{Established, Transport};
+use crate::{RequestOptions, stats};
+
+#[derive(Clone, Debug)]
+pub struct StandbyPolicy {
+    /// Connections to keep ready per host
+    pub ready: usize,
+    /// How long a connection is kept before it is replaced; below the
+    /// servers' idle timeouts, which for a connection that hasn't sent a
+    /// request yet can be as short as the time allowed to send one
+    pub max_age: Duration,
+}
+
+impl Default for StandbyPolicy {
+    fn default() -> Self {
+        Self {
+            ready: 2,
+            max_age: Duration::from_secs(15),
+        }
+    }
+}
+
+/// A connection waiting for a request
+pub struct Warm {
+    pub transport: Transport,
+    pub connection: ConnectionInfo,
+    /// Only the server's certificate, since no exporters were asked for
+    pub keying_material: KeyingMaterial,
+    /// Its entry in the client's registry, until a request takes it
+    pub registration: Registration,
+    pub opened: Instant,
+}
+
+/// A client's standby connections
+pub struct Standby {
+    policy: StandbyPolicy,
+    hosts: RefCell<HashMap<String, VecDeque<Warm>>>,
+    /// Woken when a connection is taken, so it can be replaced
+    taken: Notify,
+}
+
+impl Standby {
+    pub fn new(policy: StandbyPolicy) -> Self {
+        Self {
+            policy,
+            hosts: RefCell::new(HashMap::new()),
+            taken: Notify::new(),
+        }
+    }
+
+    pub fn policy(&self) -> &StandbyPolicy {
+        &self.policy
+    }
+
+    /// A connection to `host` for a request with `options`, if one is ready
+    /// and the request can have it; the oldest, as it expires soonest
+    pub fn take<'c>(
+        &self,
+        host: &str,
+        options: &RequestOptions,
+        clock: &dyn Clock,
+    ) -> Option<Established<'c>> {
+        if !options.endpoints.is_empty()
+            || !options.exporters.is_empty()
+            || options.traffic_class.is_some()
+        {
+            return None;
+        }
+        let warm = {
+            let mut hosts = self.hosts.borrow_mut();
+            let ready = hosts.get_mut(host)?;
+            self.prune(ready, clock);
+            ready.pop_front()?
+        };
+        self.taken.notify_waiters();
+
+        let Warm {
+            transport,
+            connection,
+            keying_material,
+            ..
+        } = warm;
+        if let Some(nodelay) = options.nodelay {
+            // A connection that refuses is no use to the request either
+            connect::set_nodelay(transport.fd().as_raw_fd(), nodelay).ok()?;
+        }
+        let mut lease = Lease::unbalanced(connection.peer);
+        lease.connected();
+        Some(Established {
+            transport,
+            connection,
+            lease,
+            slot: None,
+            admission: None,
+            // Nothing left to wait for but the request
+            timing: Timing::default(),
+            keying_material,
+        })
+    }
+
+    /// Put `warm` on standby for `host`
+    pub fn add(&self, host: &str, warm: Warm) {
+        self.hosts
+            .borrow_mut()
+            .entry(host.to_owned())
+            .or_default()
+            .push_back(warm);
+    }
+
+    /// How many more connections `host` needs, after dropping any that
+    /// expired or were closed, and when the oldest left expires
+    pub fn shortfall(&self, host: &str, clock: &dyn Clock) -> (usize, Option<Instant>) {
+        let mut hosts = self.hosts.borrow_mut();
+        let ready = hosts.entry(host.to_owned()).or_default();
+        self.prune(ready, clock);
+        let expires = ready.front().map(|warm| warm.opened + self.policy.max_age);
+        (self.policy.ready.saturating_sub(ready.len()), expires)
+    }
+
+    /// Wait until a connection is taken, to any host
+    pub async fn taken(&self) {
+        self.taken.notified().await
+    }
+
+    /// Drop what is too old to use or already closed
+    fn prune(&self, ready: &mut VecDeque<Warm>, clock: &dyn Clock) {
+        let now = clock.now();
+        ready.retain(|warm| {
+            now < warm.opened + self.policy.max_age && !hung_up(warm.transport.fd())
+        });
+    }
+}
+
+/// Whether the peer has closed `fd`'s connection, or it failed
+pub fn hung_up(fd: BorrowedFd<'_>) -> bool {
+    let mut poll = libc::pollfd {
+        fd: fd.as_raw_fd(),
+        events: libc::POLLRDHUP,
+        revents: 0,
+    };
+    stats::syscalls(1);
+    // Looks without reading, so TLS records waiting are left alone
+    if unsafe { libc::poll(&mut poll, 1, 0) } < 0 {
+        return true;
+    }
+    poll.revents & (libc::POLLRDHUP | libc::POLLHUP | libc::POLLERR) != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::io::AsFd;
+
+    use super::*;
+
+    #[test]
+    fn notices_the_peer_hanging_up() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        assert!(!hung_up(client.as_fd()));
+
+        drop(server);
+        assert!(hung_up(client.as_fd()));
+    }
+}
@@ -0,0 +1,103 @@
+//! Syscall accounting without strace
+//!
+//! Counts the io_uring operations and direct syscalls issued by the client
+//! on the current thread, so benchmarks can show how much of the transfer
+//! the kTLS + io_uring path keeps off the syscall boundary. Counting is off
+//! until [`set_enabled`] is called.
+//!
+//! Operations are counted where the client issues them: a uring `write_all`
+//! that the kernel completes in several short writes counts once, and
+//! resolver syscalls made by `getaddrinfo` are not counted at all.
+
+use std::cell::Cell;
+use std::io::{Read, Write};
+use std::ops::Sub;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SyscallCounts {
+    /// Operations submitted to io_uring (connect, read, write)
+    pub uring_submissions: u64,
+    /// Completions reaped for those operations; fewer when some were
+    /// dropped or timed out before the kernel finished them
+    pub uring_completions: u64,
+    /// Syscalls made directly (blocking socket I/O, setsockopt, dup, ...)
+    pub syscalls: u64,
+}
+
+impl Sub for SyscallCounts {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        Self {
+            uring_submissions: self.uring_submissions - rhs.uring_submissions,
+            uring_completions: self.uring_completions - rhs.uring_completions,
+            syscalls: self.syscalls - rhs.syscalls,
+        }
+    }
+}
+
+thread_local! {
+    static ENABLED: Cell<bool> = const { Cell::new(false) };
+    static COUNTS: Cell<SyscallCounts> = const {
+        Cell::new(SyscallCounts {
+            uring_submissions: 0,
+            uring_completions: 0,
+            syscalls: 0,
+        })
+    };
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.with(|e| e.set(enabled));
+}
+
+/// Counters accumulated on this thread so far
+pub fn snapshot() -> SyscallCounts {
+    COUNTS.with(Cell::get)
+}
+
+fn bump(f: impl FnOnce(&mut SyscallCounts)) {
+    if ENABLED.with(Cell::get) {
+        COUNTS.with(|c| {
+            let mut counts = c.get();
+            f(&mut counts);
+            c.set(counts);
+        });
+    }
+}
+
+/// Record one operation submitted to io_uring
+pub fn uring_submitted() {
+    bump(|c| c.uring_submissions += 1);
+}
+
+/// Record the completion of one, once its result is in
+pub fn uring_completed() {
+    bump(|c| c.uring_completions += 1);
+}
+
+/// Record `n` direct syscalls
+pub fn syscalls(n: u64) {
+    bump(|c| c.syscalls += n);
+}
+
+/// Socket wrapper that counts each read/write call reaching the kernel
+pub struct Counted<S>(pub S);
+
+impl<S: Read> Read for Counted<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        syscalls(1);
+        self.0.read(buf)
+    }
+}
+
+impl<S: Write> Write for Counted<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        syscalls(1);
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
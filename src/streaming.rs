@@ -0,0 +1,308 @@
+//! Response bodies read as they arrive, with bounded buffering
+//!
+//! [`Session::send_streaming`](crate::session::Session::send_streaming)
+//! returns a response as soon as its head is in, and a task of its own
+//! reads the body off the connection into a [`BodyStream`]. The task stops
+//! submitting reads once `max_buffered` bytes of payload are waiting for
+//! the consumer, and starts again once the consumer has taken enough of
+//! them. A slow consumer leaves the data in the socket's receive buffer
+//! instead, the TCP window closes, and the server is held back to match,
+//! rather than the body piling up in memory.
+//!
+//! The limit is checked between reads, so the buffer can go over it by
+//! one read's worth.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::headers::HeaderMap;
+use crate::http::{BodyDecoder, Response};
+use crate::introspect::Registration;
+use crate::session::{Transport, closed_without_notify};
+
+/// A response whose body is still arriving
+pub struct StreamedResponse {
+    /// Status line and headers; its `body` and `trailers` stay empty
+    pub head: Response,
+    pub body: BodyStream,
+}
+
+/// The body of a [`StreamedResponse`], taken a piece at a time
+///
+/// Dropping it before the body ends stops the reader and drops the
+/// connection, without `close_notify`, as the truncation isn't the
+/// server's doing.
+pub struct BodyStream {
+    shared: Rc<Shared>,
+    reader: JoinHandle<()>,
+}
+
+/// A piece of the body, `None` past its end
+type NextChunk = Result<Option<Vec<u8>>, Box<dyn Error>>;
+
+/// What the reader and the consumer share
+struct Shared {
+    state: RefCell<State>,
+    /// Wakes the reader once the consumer has taken some of the buffer
+    drained: Notify,
+}
+
+struct State {
+    chunks: VecDeque<Vec<u8>>,
+    /// Payload bytes in `chunks`
+    buffered: usize,
+    limit: usize,
+    /// The reader is waiting for the consumer to catch up
+    paused: bool,
+    /// How many times it has had to
+    pauses: u64,
+    /// How the body ended, once it has; an error is only reported once
+    end: Option<Result<(), Box<dyn Error>>>,
+    trailers: HeaderMap,
+    /// Woken once a chunk or the end arrives
+    consumer: Option<Waker>,
+}
+
+impl BodyStream {
+    /// Read the body after `input`, the start of it already read, off
+    /// `transport`, holding off once `max_buffered` bytes are waiting
+    pub(crate) fn spawn(
+        transport: Transport,
+        input: Vec<u8>,
+        decoder: BodyDecoder,
+        registration: Registration,
+        max_buffered: usize,
+    ) -> Self {
+        let shared = Rc::new(Shared {
+            state: RefCell::new(State {
+                chunks: VecDeque::new(),
+                buffered: 0,
+                limit: max_buffered.max(1),
+                paused: false,
+                pauses: 0,
+                end: None,
+                trailers: HeaderMap::new(),
+                consumer: None,
+            }),
+            drained: Notify::new(),
+        });
+        let reader = tokio_uring::spawn(read(
+            transport,
+            input,
+            decoder,
+            shared.clone(),
+            registration,
+        ));
+        Self { shared, reader }
+    }
+
+    /// The next piece of the body, in the sizes it was read in; `None`
+    /// once all of it has been taken
+    pub async fn chunk(&mut self) -> NextChunk {
+        std::future::poll_fn(|cx| self.poll_chunk(cx)).await
+    }
+
+    /// [`chunk`](Self::chunk) for poll-based callers, waking `cx` once
+    /// there is more to take
+    pub(crate) fn poll_chunk(&mut self, cx: &mut Context<'_>) -> Poll<NextChunk> {
+        let mut state = self.shared.state.borrow_mut();
+        if let Some(chunk) = state.chunks.pop_front() {
+            state.buffered -= chunk.len();
+            if state.buffered < state.limit {
+                self.shared.drained.notify_one();
+            }
+            return Poll::Ready(Ok(Some(chunk)));
+        }
+        match state.end.replace(Ok(())) {
+            Some(Ok(())) => Poll::Ready(Ok(None)),
+            Some(Err(e)) => Poll::Ready(Err(e)),
+            None => {
+                state.end = None;
+                state.consumer = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    /// The rest of the body, in one buffer
+    pub async fn collect(mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut body = Vec::new();
+        while let Some(chunk) = self.chunk().await? {
+            body.extend(chunk);
+        }
+        Ok(body)
+    }
+
+    /// Payload bytes read but not yet taken
+    pub fn buffered(&self) -> usize {
+        self.shared.state.borrow().buffered
+    }
+
+    /// Whether the reader is holding off until some of the buffer is taken
+    pub fn paused(&self) -> bool {
+        self.shared.state.borrow().paused
+    }
+
+    /// How many times the reader has held off so far
+    pub fn pauses(&self) -> u64 {
+        self.shared.state.borrow().pauses
+    }
+
+    /// Fields that followed a chunked body; empty until the body has ended
+    pub fn trailers(&self) -> HeaderMap {
+        self.shared.state.borrow().trailers.clone()
+    }
+}
+
+impl Drop for BodyStream {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+impl Shared {
+    fn push(&self, payload: Vec<u8>) {
+        if payload.is_empty() {
+            return;
+        }
+        let mut state = self.state.borrow_mut();
+        state.buffered += payload.len();
+        state.chunks.push_back(payload);
+        drop(state);
+        self.wake_consumer();
+    }
+
+    fn wake_consumer(&self) {
+        let consumer = self.state.borrow_mut().consumer.take();
+        if let Some(consumer) = consumer {
+            consumer.wake();
+        }
+    }
+
+    /// Wait until the buffer is under the limit
+    async fn room(&self) {
+        loop {
+            {
+                let mut state = self.state.borrow_mut();
+                if state.buffered < state.limit {
+                    state.paused = false;
+                    return;
+                }
+                if !state.paused {
+                    state.paused = true;
+                    state.pauses += 1;
+                }
+            }
+            self.drained.notified().await;
+        }
+    }
+}
+
+/// The reader's task: the body into `shared` until it ends, then the
+/// connection closed
+async fn read(
+    mut transport: Transport,
+    mut input: Vec<u8>,
+    mut decoder: BodyDecoder,
+    shared: Rc<Shared>,
+    // Keeps the connection in the client's debug state while it is read
+    _registration: Registration,
+) {
+    let result = pump(&mut transport, &mut input, &mut decoder, &shared).await;
+    if let Ok(true) = result {
+        // The server would keep the connection open for more
+        transport.send_close_notify();
+        let _ = transport.try_flush();
+    }
+    let mut state = shared.state.borrow_mut();
+    state.trailers = decoder.trailers().clone();
+    state.end = Some(result.map(|_| ()));
+    drop(state);
+    shared.wake_consumer();
+}
+
+/// Decode and hand over the body until its end, reading only while there
+/// is room for more; whether the connection is still open after it
+async fn pump(
+    transport: &mut Transport,
+    input: &mut Vec<u8>,
+    decoder: &mut BodyDecoder,
+    shared: &Shared,
+) -> Result<bool, Box<dyn Error>> {
+    loop {
+        shared.push(decoder.decode(input)?);
+        if decoder.done() {
+            return Ok(true);
+        }
+        shared.room().await;
+        match transport.read_raw(input).await {
+            Ok(0) => break,
+            // Stands for a close-delimited body, as under the default close
+            // policy; the decoder fails any other
+            Err(e) if closed_without_notify(&e) => break,
+            Err(e) => return Err(e.into()),
+            Ok(_) => {}
+        }
+    }
+    decoder.finish()?;
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    use crate::connect::ConnectionInfo;
+    use crate::fd;
+    use crate::introspect::{Registry, Use};
+
+    #[test]
+    fn reading_holds_off_until_the_consumer_catches_up() {
+        const LEN: usize = 256 * 1024;
+        const LIMIT: usize = 16 * 1024;
+        tokio_uring::start(async {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (mut server, peer) = listener.accept().unwrap();
+            let writer = std::thread::spawn(move || server.write_all(&[7; LEN]));
+
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {LEN}\r\n\r\n");
+            let (head, _) = Response::parse_head(head.as_bytes(), false).unwrap();
+            let decoder = BodyDecoder::new(&head).unwrap();
+            let stream = tokio_uring::net::TcpStream::from_std(client);
+            let connection = ConnectionInfo {
+                peer,
+                ktls: false,
+                mptcp: false,
+                endpoint: None,
+                send_queue: None,
+            };
+            let registration = Registry::default()
+                .register("test", &connection, fd::borrow(&stream), Use::Request)
+                .unwrap();
+            let transport = Transport::Ktls(stream);
+            let body = BodyStream::spawn(transport, Vec::new(), decoder, registration, LIMIT);
+
+            // Left alone, the reader fills the buffer and stops there
+            while !body.paused() {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            assert!(body.buffered() >= LIMIT);
+            assert!(body.buffered() < LIMIT + 8192);
+
+            let all = body.collect().await.unwrap();
+            assert_eq!(all, [7; LEN]);
+            writer.join().unwrap().unwrap();
+        });
+    }
+}
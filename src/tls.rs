@@ -0,0 +1,222 @@
+//! Userspace TLS over io_uring
+//!
+//! [`UringTlsStream`] drives a rustls connection, client or server, with
+//! owned-buffer reads and writes on a tokio-uring `TcpStream`. It is the path
+//! for connections that can't use kTLS, whether because setup failed or
+//! because the kernel or cipher suite doesn't support it, and it never
+//! blocks the runtime thread.
+
+use std::io::{ErrorKind, Read, Write};
+use std::ops::DerefMut;
+use std::os::unix::io::BorrowedFd;
+
+use rustls::ConnectionCommon;
+use tokio_uring::net::TcpStream;
+
+use crate::{fd, stats};
+
+/// Bytes requested from the socket per read; one maximum-size TLS record
+const READ_SIZE: usize = 16 * 1024 + 256;
+
+/// rustls' default limit on data buffered for sending
+const SEND_BUFFER_LIMIT: usize = 64 * 1024;
+
+/// Socket writes that fail with `WouldBlock` instead of waiting
+struct NonBlocking<'fd>(BorrowedFd<'fd>);
+
+impl Write for NonBlocking<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        fd::try_send(self.0, buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A rustls `ClientConnection` or `ServerConnection` over a tokio-uring stream
+pub struct UringTlsStream<C> {
+    stream: TcpStream,
+    conn: C,
+    /// Socket read buffer, handed to io_uring and back on every read
+    read_buf: Vec<u8>,
+}
+
+impl<C, D> UringTlsStream<C>
+where
+    C: DerefMut<Target = ConnectionCommon<D>>,
+{
+    pub fn new(stream: TcpStream, conn: C) -> Self {
+        Self {
+            stream,
+            conn,
+            read_buf: Vec::new(),
+        }
+    }
+
+    pub fn fd(&self) -> BorrowedFd<'_> {
+        fd::borrow(&self.stream)
+    }
+
+    pub fn conn(&self) -> &C {
+        &self.conn
+    }
+
+    /// Run the handshake to completion
+    ///
+    /// Optional: reads and writes complete the handshake first if needed.
+    pub async fn handshake(&mut self) -> std::io::Result<()> {
+        while self.conn.is_handshaking() {
+            self.flush().await?;
+            if self.conn.is_handshaking() && self.fill().await? == 0 {
+                return Err(std::io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "connection closed during handshake",
+                ));
+            }
+        }
+        // Processing the peer's last flight can queue our own Finished
+        self.flush().await
+    }
+
+    /// Encrypt and send all of `data`, flushing whenever rustls' send buffer fills
+    pub async fn write_all(&mut self, mut data: &[u8]) -> std::io::Result<()> {
+        self.handshake().await?;
+        while !data.is_empty() {
+            let n = self.conn.writer().write(data)?;
+            data = &data[n..];
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Read plaintext into `buf`
+    ///
+    /// Returns 0 once the peer has sent `close_notify`, and `UnexpectedEof`
+    /// if it closed the connection without one.
+    pub async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.handshake().await?;
+        loop {
+            match self.conn.reader().read(buf) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    self.fill().await?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Send everything rustls has queued for the peer
+    async fn flush(&mut self) -> std::io::Result<()> {
+        while self.conn.wants_write() {
+            let mut records = Vec::new();
+            self.conn.write_tls(&mut records)?;
+            fd::write_all(&self.stream, records).await?;
+        }
+        Ok(())
+    }
+
+    /// Read one batch of records from the peer into rustls and process them;
+    /// returns 0 once the peer has closed the connection
+    async fn fill(&mut self) -> std::io::Result<usize> {
+        // Empty if a previous read was dropped while io_uring held the buffer
+        let mut buf = std::mem::take(&mut self.read_buf);
+        if buf.is_empty() {
+            buf = vec![0u8; READ_SIZE];
+        }
+        stats::uring_submitted();
+        let (result, buf) = self.stream.read(buf).await;
+        stats::uring_completed();
+        let n = result?;
+
+        let processed = self.ingest(&buf[..n]);
+        if processed.is_err() {
+            // Let the peer know why before giving up
+            let _ = self.flush().await;
+        }
+        self.read_buf = buf;
+        processed.map(|()| n)
+    }
+
+    /// Read plaintext into `buf` without waiting on the socket
+    ///
+    /// Fails with `WouldBlock` when rustls has no plaintext buffered and the
+    /// socket has nothing to read. Meant for use after the handshake; alerts
+    /// it provokes go out with the next write.
+    pub fn try_read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            match self.conn.reader().read(buf) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+            let mut records = std::mem::take(&mut self.read_buf);
+            if records.is_empty() {
+                records = vec![0u8; READ_SIZE];
+            }
+            let processed =
+                fd::try_recv(self.fd(), &mut records).and_then(|n| self.ingest(&records[..n]));
+            self.read_buf = records;
+            processed?;
+        }
+    }
+
+    /// Encrypt what rustls will take of `buf` and send as much as the socket
+    /// accepts without waiting
+    ///
+    /// Fails with `WouldBlock`, taking nothing, while records from earlier
+    /// writes are still waiting for room; [`try_flush`](Self::try_flush)
+    /// sends the rest. Meant for use after the handshake.
+    pub fn try_write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.try_flush()?;
+        let n = self.conn.writer().write(buf)?;
+        match self.try_flush() {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(n),
+            result => result.map(|()| n),
+        }
+    }
+
+    /// Send everything rustls has queued without waiting; `WouldBlock` if
+    /// the socket can't take it all yet
+    pub fn try_flush(&mut self) -> std::io::Result<()> {
+        let mut socket = NonBlocking(fd::borrow(&self.stream));
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut socket)?;
+        }
+        Ok(())
+    }
+
+    /// Encrypt all of `buf` and send as much as the socket accepts without
+    /// waiting; the rest goes out ahead of the next write
+    ///
+    /// Unlike [`try_write`](Self::try_write) this never takes part of `buf`,
+    /// going past rustls' send buffer limit if it has to.
+    pub fn queue(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.conn.set_buffer_limit(None);
+        let queued = self.conn.writer().write_all(buf);
+        self.conn.set_buffer_limit(Some(SEND_BUFFER_LIMIT));
+        queued?;
+        match self.try_flush() {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Queue a `close_notify` alert for the next flush
+    pub fn send_close_notify(&mut self) {
+        self.conn.send_close_notify();
+    }
+
+    /// Hand records read from the socket to rustls; an empty slice tells it
+    /// about the EOF
+    fn ingest(&mut self, mut records: &[u8]) -> std::io::Result<()> {
+        loop {
+            self.conn.read_tls(&mut records)?;
+            self.conn
+                .process_new_packets()
+                .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+            if records.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! W3C Trace Context: carrying a distributed trace across requests
+//!
+//! A [`TraceContext`] names a trace and the span within it that work is
+//! being done for. Attached to a request's [`Context`](crate::context::Context),
+//! it makes the client send `traceparent` and `tracestate` headers, each
+//! request as a span of its own under the context's, so the service on the
+//! other end joins the same trace. A proxy takes the trace from its incoming
+//! request with [`TraceContext::extract`] and hands it on the same way.
+//!
+//! Only version `00` of `traceparent` is generated; later versions are
+//! read as far as the fields `00` defines, as the spec asks.
+
+use crate::headers::HeaderMap;
+use crate::wsproto::random;
+
+/// The `sampled` bit of `trace-flags`
+const SAMPLED: u8 = 0x01;
+
+/// Entries `tracestate` may carry (W3C Trace Context §3.3.1.1)
+const MAX_STATE_ENTRIES: usize = 32;
+
+/// A position in a distributed trace
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    /// The current span, which requests sent under this context are
+    /// children of
+    pub span_id: [u8; 8],
+    /// `trace-flags`; bit 0 is set when the caller records the trace
+    pub flags: u8,
+    /// Vendor entries from `tracestate`, passed on as they came
+    pub state: Option<String>,
+}
+
+impl TraceContext {
+    /// The root span of a new, sampled trace
+    pub fn new() -> std::io::Result<Self> {
+        Ok(Self {
+            trace_id: nonzero_random()?,
+            span_id: nonzero_random()?,
+            flags: SAMPLED,
+            state: None,
+        })
+    }
+
+    /// A new span in the same trace, with this one as its parent
+    pub fn child(&self) -> std::io::Result<Self> {
+        Ok(Self {
+            span_id: nonzero_random()?,
+            ..self.clone()
+        })
+    }
+
+    /// Parse a `traceparent` value, with the `tracestate` that came with
+    /// it if any; `None` if `traceparent` is malformed
+    pub fn parse(traceparent: &str, tracestate: Option<&str>) -> Option<Self> {
+        let fields: Vec<&str> = traceparent.trim().split('-').collect();
+        let [version, trace_id, span_id, flags, rest @ ..] = fields.as_slice() else {
+            return None;
+        };
+        let version = hex::<1>(version)?[0];
+        // Version 00 has exactly four fields; later ones may add more
+        if version == 0xff || (version == 0 && !rest.is_empty()) {
+            return None;
+        }
+        let trace_id = hex::<16>(trace_id)?;
+        let span_id = hex::<8>(span_id)?;
+        if trace_id == [0; 16] || span_id == [0; 8] {
+            return None;
+        }
+        Some(Self {
+            trace_id,
+            span_id,
+            flags: hex::<1>(flags)?[0],
+            state: tracestate.and_then(clean_state),
+        })
+    }
+
+    /// The trace an incoming request is part of, from its headers
+    ///
+    /// More than one `traceparent` makes the trace ambiguous, so it is
+    /// ignored; several `tracestate` headers are one list split up.
+    pub fn extract(headers: &HeaderMap) -> Option<Self> {
+        let mut parents = headers.get_all("traceparent");
+        let traceparent = parents.next()?;
+        if parents.next().is_some() {
+            return None;
+        }
+        let state: Vec<&str> = headers.get_all("tracestate").collect();
+        let state = state.join(",");
+        Self::parse(traceparent, Some(&state))
+    }
+
+    /// Set `traceparent`, and `tracestate` when there is any, in `headers`,
+    /// replacing what they held
+    pub fn inject(&self, headers: &mut HeaderMap) {
+        headers.remove("traceparent");
+        headers.remove("tracestate");
+        headers.append("traceparent", self.traceparent());
+        if let Some(state) = &self.state {
+            headers.append("tracestate", state.as_str());
+        }
+    }
+
+    /// The `traceparent` header value
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            to_hex(&self.trace_id),
+            to_hex(&self.span_id),
+            self.flags
+        )
+    }
+}
+
+/// Random bytes, never all zero, which would make an invalid ID
+fn nonzero_random<const N: usize>() -> std::io::Result<[u8; N]> {
+    loop {
+        let bytes = random::<N>()?;
+        if bytes != [0; N] {
+            return Ok(bytes);
+        }
+    }
+}
+
+/// Exactly `N` bytes of lowercase hex
+fn hex<const N: usize>(text: &str) -> Option<[u8; N]> {
+    if text.len() != N * 2 || !text.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return None;
+    }
+    let mut bytes = [0u8; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// `tracestate` without empty list members, cut to the entries the spec
+/// allows; `None` if nothing is left
+fn clean_state(state: &str) -> Option<String> {
+    let entries: Vec<&str> = state
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .take(MAX_STATE_ENTRIES)
+        .collect();
+    (!entries.is_empty()).then(|| entries.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn round_trips_traceparent() {
+        let trace =
+            TraceContext::parse(PARENT, Some("rojo=00f067aa0ba902b7, ,congo=t61rcWkgMzE")).unwrap();
+        assert_eq!(trace.traceparent(), PARENT);
+        assert_eq!(trace.flags, SAMPLED);
+        assert_eq!(
+            trace.state.as_deref(),
+            Some("rojo=00f067aa0ba902b7,congo=t61rcWkgMzE")
+        );
+
+        let child = trace.child().unwrap();
+        assert_eq!(child.trace_id, trace.trace_id);
+        assert_ne!(child.span_id, trace.span_id);
+    }
+
+    #[test]
+    fn rejects_malformed_traceparent() {
+        for bad in [
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        ] {
+            assert_eq!(TraceContext::parse(bad, None), None, "{bad}");
+        }
+        // A later version may add fields after the ones 00 defines
+        let future = "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra";
+        assert!(TraceContext::parse(future, None).is_some());
+    }
+
+    #[test]
+    fn extracts_from_headers() {
+        let mut headers = HeaderMap::new();
+        headers.append("Traceparent", PARENT);
+        headers.append("tracestate", "a=1");
+        headers.append("tracestate", "b=2");
+        let trace = TraceContext::extract(&headers).unwrap();
+        assert_eq!(trace.state.as_deref(), Some("a=1,b=2"));
+
+        headers.append("traceparent", PARENT);
+        assert_eq!(TraceContext::extract(&headers), None);
+    }
+}
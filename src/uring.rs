@@ -0,0 +1,73 @@
+//! Starting the io_uring runtime where the kernel may refuse it
+//!
+//! `tokio_uring::start` panics if the ring can't be created, which happens
+//! well short of a broken kernel: `kernel.io_uring_disabled` (Linux 6.6+)
+//! turns io_uring off system-wide or limits it to one group, container
+//! runtimes' default seccomp profiles deny `io_uring_setup`, and small
+//! `RLIMIT_MEMLOCK`s fail it on older kernels. [`runtime`] creates the ring
+//! itself and says which of these it ran into, and [`UringPolicy`] decides
+//! whether the program then carries on without io_uring or stops.
+
+use std::fmt;
+
+/// What to do when io_uring can't be used
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum UringPolicy {
+    /// Run on the userspace-only transport instead
+    #[default]
+    Fallback,
+    /// Fail with [`UringUnavailable`]
+    Require,
+}
+
+#[derive(Debug)]
+pub enum UringUnavailable {
+    /// `kernel.io_uring_disabled` is 2
+    Disabled,
+    /// `kernel.io_uring_disabled` is 1 and the process isn't in
+    /// `kernel.io_uring_group`
+    Restricted,
+    /// The kernel has no io_uring, or a seccomp filter claims so
+    Unsupported,
+    /// Ring setup failed otherwise: `EPERM` from seccomp or an LSM,
+    /// `ENOMEM` from `RLIMIT_MEMLOCK`, ...
+    Setup(std::io::Error),
+}
+
+impl fmt::Display for UringUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UringUnavailable::Disabled => {
+                write!(f, "io_uring is disabled (kernel.io_uring_disabled = 2)")
+            }
+            UringUnavailable::Restricted => write!(
+                f,
+                "io_uring is limited to kernel.io_uring_group, which this process isn't in"
+            ),
+            UringUnavailable::Unsupported => write!(f, "io_uring is not supported by this kernel"),
+            UringUnavailable::Setup(e) => write!(f, "io_uring setup failed: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for UringUnavailable {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            UringUnavailable::Setup(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Create the tokio-uring runtime, or say why the kernel won't allow it
+pub fn runtime() -> Result<tokio_uring::Runtime, UringUnavailable> {
+    tokio_uring::Runtime::new(&tokio_uring::builder()).map_err(|e| {
+        let sysctl = std::fs::read_to_string("/proc/sys/kernel/io_uring_disabled");
+        match (e.raw_os_error(), sysctl.as_deref().map(str::trim)) {
+            (Some(libc::EPERM), Ok("2")) => UringUnavailable::Disabled,
+            (Some(libc::EPERM), Ok("1")) => UringUnavailable::Restricted,
+            (Some(libc::ENOSYS), _) => UringUnavailable::Unsupported,
+            _ => UringUnavailable::Setup(e),
+        }
+    })
+}
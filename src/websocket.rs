@@ -0,0 +1,1069 @@
+//! WebSocket client (RFC 6455) over kTLS, and its server end
+//!
+//! [`WssClient::connect`] sets up a connection the way a [`Session`] does,
+//! over kTLS where the kernel allows it and userspace TLS otherwise, and
+//! upgrades it. From then on frames go straight to the socket: with kTLS
+//! the kernel encrypts each one and io_uring carries it.
+//!
+//! [`WssClient::accept`] is the other end: it answers the upgrade request
+//! on a connection from a [`KtlsListener`](crate::server::KtlsListener),
+//! and the connection then sends unmasked frames and insists on masked
+//! ones, as RFC 6455 §5.1 has a server do. Everything else works the same
+//! on both ends.
+//!
+//! Fragmented messages are put back together by a [`FrameAssembler`] and
+//! handed over whole, up to [`WssClient::with_max_message_size`]. Control
+//! frames are handed to the caller like any other message, so answering
+//! pings is up to it, unless [`WssClient::with_auto_pong`] has them
+//! answered as they are read, or the connection is handed to a task of its
+//! own with [`WssClient::into_channels`].
+//!
+//! A NAT or load balancer that silently drops an idle connection leaves the
+//! socket looking healthy until the next write. [`WssClient::with_heartbeat`]
+//! pings the server on a schedule while waiting for messages and fails with
+//! [`WsError::Unresponsive`] once too many pings go unanswered.
+//!
+//! Once either side has sent a Close frame, what can no longer be sent or
+//! received fails with [`WsError::Closed`], carrying the status code and
+//! reason of that first Close and which side sent it. A client dropped
+//! before it has sent one sends a Close with [`GOING_AWAY`] on its way out,
+//! then `close_notify`, if the socket takes them without waiting.
+
+use std::io::ErrorKind;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use tokio::io::Interest;
+use tokio::io::unix::AsyncFd;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::backpressure::Watch;
+use crate::clock::{Clock, SystemClock};
+use crate::connect::{self, ConnectionInfo};
+use crate::handshake::KeyingMaterial;
+use crate::http::{Request, RequestHead, Response};
+use crate::introspect::{Registration, Use};
+use crate::rng::{self, Rng, SystemRng};
+use crate::server::KtlsStream;
+use crate::session::{Reaper, Session, Transport};
+use crate::wsproto::{
+    ABNORMAL_CLOSURE, GOING_AWAY, OP_CLOSE, OP_CONTINUATION, accept_key, base64, check_upgrade,
+    close_status, decode_client_frame, decode_frame, encode_frame, encode_server_frame,
+    redirect_target, supported_versions, upgrade_rejection, upgrade_response,
+};
+use crate::{HttpsClient, RequestOptions};
+
+pub use crate::wsproto::{Frame, Message, Side, WsError};
+
+/// Bytes a client's upgrade request head may take up
+const MAX_UPGRADE_HEAD: usize = 16 * 1024;
+/// Payload bytes a received message may add up to, unless the connection
+/// was given another limit
+const MAX_MESSAGE: usize = 16 * 1024 * 1024;
+
+/// Supplies an `Authorization` header value for a host that answered the
+/// upgrade with 401, given that response; `None` gives up
+pub type Credentials = dyn Fn(&str, &Response) -> Option<String>;
+
+/// A WebSocket driven by its own task, from [`WssClient::into_channels`]
+pub struct WsChannels {
+    pub sender: mpsc::Sender<Message>,
+    pub receiver: MessageReceiver,
+    /// Finishes when the connection does, with the error that ended it, if any
+    pub task: JoinHandle<Result<(), WsError>>,
+}
+
+/// Messages from the server, as the task behind [`WsChannels`] receives them
+///
+/// With the `stream` feature, also a `futures_core::Stream` of them.
+pub struct MessageReceiver(pub(crate) mpsc::Receiver<Message>);
+
+impl MessageReceiver {
+    /// The next message; `None` once the task has ended and every message
+    /// it received has been taken
+    pub async fn recv(&mut self) -> Option<Message> {
+        self.0.recv().await
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// One frame on the wire, as reported to [`WssClient::with_frame_hook`]
+#[derive(Clone, Copy, Debug)]
+pub struct FrameEvent {
+    pub direction: Direction,
+    /// RFC 6455 opcode, e.g. 0x1 for text or 0x9 for ping
+    pub opcode: u8,
+    /// Payload bytes, excluding the frame header
+    pub len: usize,
+    /// Time since the previous frame in the same direction; `None` for the
+    /// first one
+    pub since_previous: Option<Duration>,
+}
+
+/// Feeds the frame hook, timing each frame against the last one each way
+#[derive(Default)]
+struct FrameTrace {
+    hook: Option<Box<dyn Fn(FrameEvent)>>,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+}
+
+impl FrameTrace {
+    fn record(&mut self, direction: Direction, opcode: u8, len: usize, now: Instant) {
+        let Some(hook) = &self.hook else {
+            return;
+        };
+        let last = match direction {
+            Direction::Sent => &mut self.last_sent,
+            Direction::Received => &mut self.last_received,
+        };
+        hook(FrameEvent {
+            direction,
+            opcode,
+            len,
+            since_previous: last.replace(now).map(|last| now - last),
+        });
+    }
+}
+
+/// How far the closing handshake has got, from [`WssClient::state`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseState {
+    Open,
+    /// One side has sent a Close frame and the other hasn't answered yet
+    Closing,
+    /// Both sides have, or the connection dropped
+    Closed,
+}
+
+/// The closing handshake, once either side has started it
+struct Closing {
+    /// Status code of the first Close frame, reported from then on
+    code: u16,
+    reason: String,
+    initiated_by: Side,
+    /// A Close was sent, or can't be any more
+    sent: bool,
+    /// The server's Close arrived, or can't any more
+    received: bool,
+}
+
+/// Puts fragmented messages back together (RFC 6455 §5.4)
+///
+/// Frames go in as they are decoded; a message comes out once its last
+/// frame is in. Control frames may arrive between the fragments of a
+/// message and come straight back out.
+pub struct FrameAssembler {
+    max_size: usize,
+    /// Opcode of the message being put together and its payload so far
+    partial: Option<(u8, Vec<u8>)>,
+}
+
+impl FrameAssembler {
+    /// Fail messages whose payload adds up to more than `max_size` bytes
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            partial: None,
+        }
+    }
+
+    /// Take in `frame`, returning the message it completes, if any
+    pub fn push(&mut self, frame: Frame) -> Result<Option<Message>, WsError> {
+        if frame.opcode >= OP_CLOSE {
+            return Message::from_parts(frame.opcode, frame.payload).map(Some);
+        }
+        let (opcode, mut payload) = match (self.partial.take(), frame.opcode) {
+            (None, OP_CONTINUATION) => {
+                return Err(WsError::Protocol("continuation frame without a message"));
+            }
+            (None, opcode) => (opcode, Vec::new()),
+            (Some((opcode, payload)), OP_CONTINUATION) => (opcode, payload),
+            (Some(_), _) => {
+                return Err(WsError::Protocol("new message before the last one ended"));
+            }
+        };
+        if payload.len() + frame.payload.len() > self.max_size {
+            return Err(WsError::Protocol("message too large"));
+        }
+        payload.extend_from_slice(&frame.payload);
+        if !frame.fin {
+            self.partial = Some((opcode, payload));
+            return Ok(None);
+        }
+        Message::from_parts(opcode, payload).map(Some)
+    }
+}
+
+/// Client-initiated pings, from [`WssClient::with_heartbeat`]
+struct Heartbeat {
+    interval: Duration,
+    max_missed: u32,
+    next_ping: Instant,
+    /// Pings sent since the last one was answered
+    unanswered: u32,
+    /// Pings sent so far; each carries its number, so a pong shows whether
+    /// it answers one
+    sent: u64,
+}
+
+impl Heartbeat {
+    /// Whether a pong carrying `payload` answers the last ping sent
+    fn answered_by(&self, payload: &[u8]) -> bool {
+        self.unanswered > 0 && payload == self.sent.to_be_bytes()
+    }
+}
+
+/// A connection upgraded to the WebSocket protocol: the client end, from
+/// [`connect`](Self::connect), or the server end, from
+/// [`accept`](Self::accept)
+pub struct WssClient {
+    /// Socket readiness for [`receive_timeout`](Self::receive_timeout),
+    /// registered on first use; dropped before the socket closes
+    readiness: Option<AsyncFd<RawFd>>,
+    /// This end: clients mask their frames, servers don't
+    side: Side,
+    transport: Transport,
+    connection: ConnectionInfo,
+    /// Bytes received but not yet decoded into messages
+    buffered: Vec<u8>,
+    assembler: FrameAssembler,
+    closing: Option<Closing>,
+    heartbeat: Option<Heartbeat>,
+    /// Answer pings, and drop pongs that answer heartbeat pings, rather
+    /// than hand them over
+    auto_pong: bool,
+    trace: FrameTrace,
+    /// Payload bytes the channel task packs into one write; 0 sends each
+    /// message on its own
+    coalesce: usize,
+    /// Writes from [`send_nowait`](Self::send_nowait) still in flight
+    reaper: Reaper,
+    /// Set while a write is awaited, which dropping may cut short
+    writing: bool,
+    /// Watches the send buffer, when the options asked for alerts
+    _backpressure: Option<Watch>,
+    /// The client's clock, for heartbeats and frame timing
+    clock: Rc<dyn Clock>,
+    /// The client's RNG, for frame masks
+    rng: Rc<dyn Rng>,
+    /// Keeps the connection in the client's debug state while it is open;
+    /// server ends have no client
+    _registration: Option<Registration>,
+    /// Exported when the connection was set up
+    keying_material: KeyingMaterial,
+}
+
+impl WssClient {
+    /// Connect to `host` and upgrade `path` to a WebSocket
+    ///
+    /// Redirects are followed, each over a new connection, as far as the
+    /// client's [`with_websocket_redirects`](HttpsClient::with_websocket_redirects)
+    /// allows. A 401 is retried once with credentials from its
+    /// [`with_websocket_credentials`](HttpsClient::with_websocket_credentials)
+    /// callback, if it has one.
+    pub async fn connect(
+        client: &HttpsClient,
+        host: &str,
+        path: &str,
+        options: &RequestOptions,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::connect_with(client, None, host, path, options).await
+    }
+
+    /// Upgrade `path` to a WebSocket over `stream`, already connected to
+    /// `host`'s server, e.g. by a custom dialer or a transparent proxy
+    ///
+    /// Only the TLS and WebSocket handshakes happen here. Redirects and
+    /// 401s would need a connection of the client's own, so they fail the
+    /// upgrade like any other status.
+    pub async fn connect_on(
+        client: &HttpsClient,
+        stream: std::net::TcpStream,
+        host: &str,
+        path: &str,
+        options: &RequestOptions,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::connect_with(client, Some(stream), host, path, options).await
+    }
+
+    /// Take a WebSocket upgrade on `stream`, a connection accepted by a
+    /// [`KtlsListener`](crate::server::KtlsListener): read the client's
+    /// upgrade request, check it, and answer 101
+    ///
+    /// A request that isn't a valid upgrade, or whose head runs past
+    /// 16 KiB, is answered with 400, or 426 for a `Sec-WebSocket-Version`
+    /// other than 13, and fails with the reason. The request comes back
+    /// with the connection, for routing on its target. Frames from the
+    /// client must be masked; the server's aren't.
+    pub async fn accept(stream: KtlsStream) -> Result<(Self, RequestHead), WsError> {
+        let connection = ConnectionInfo {
+            peer: stream.peer,
+            ktls: true,
+            mptcp: connect::is_mptcp(stream.stream.as_raw_fd()),
+            endpoint: None,
+            send_queue: None,
+        };
+        let (mut transport, mut buffered) = stream.into_transport();
+        let parsed = loop {
+            match RequestHead::parse(&buffered) {
+                Ok(Some(parsed)) => break Ok(parsed),
+                Ok(None) if buffered.len() < MAX_UPGRADE_HEAD => {
+                    if transport.read(&mut buffered).await? == 0 {
+                        return Err(WsError::InvalidUpgrade("connection closed mid-request"));
+                    }
+                }
+                Ok(None) => break Err(WsError::InvalidUpgrade("request head too large")),
+                Err(_) => break Err(WsError::InvalidUpgrade("malformed request head")),
+            }
+        };
+        let checked =
+            parsed.and_then(|(request, len)| Ok((check_upgrade(&request)?, request, len)));
+        let (request, len) = match checked {
+            Ok((key, request, len)) => {
+                transport.write(upgrade_response(&key)).await?;
+                (request, len)
+            }
+            Err(e) => {
+                transport.write(upgrade_rejection(&e)).await?;
+                return Err(e);
+            }
+        };
+        // Anything after the head is the client's first frames
+        buffered.drain(..len);
+        let ws = Self {
+            readiness: None,
+            side: Side::Server,
+            transport,
+            connection,
+            buffered,
+            assembler: FrameAssembler::new(MAX_MESSAGE),
+            closing: None,
+            heartbeat: None,
+            auto_pong: false,
+            trace: FrameTrace::default(),
+            coalesce: 0,
+            reaper: Reaper::default(),
+            writing: false,
+            _backpressure: None,
+            clock: Rc::new(SystemClock::default()),
+            rng: Rc::new(SystemRng),
+            _registration: None,
+            keying_material: KeyingMaterial::default(),
+        };
+        Ok((ws, request))
+    }
+
+    async fn connect_with(
+        client: &HttpsClient,
+        stream: Option<std::net::TcpStream>,
+        host: &str,
+        path: &str,
+        options: &RequestOptions,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let supplied = stream.is_some();
+        let mut stream = stream;
+        let (mut host, mut path) = (host.to_owned(), path.to_owned());
+        let mut redirects = 0;
+        let mut authorization = None;
+        let (session, response, key) = loop {
+            let mut session = match stream.take() {
+                Some(stream) => Session::open_on(client, stream, &host, options).await?,
+                None => Session::open(client, &host, options).await?,
+            };
+
+            let key = base64(&rng::bytes::<16>(&*client.rng)?);
+            let mut request = Request::new("GET", &host, &path);
+            request.headers.append("Upgrade", "websocket");
+            request.headers.append("Connection", "Upgrade");
+            request.headers.append("Sec-WebSocket-Key", key.as_str());
+            request.headers.append(
+                "Sec-WebSocket-Version",
+                client.websocket_version.to_string(),
+            );
+            if let Some(authorization) = &authorization {
+                request.headers.append("Authorization", authorization);
+            }
+            let response = session.send(request, options).await?;
+
+            match response.status {
+                301 | 302 | 303 | 307 | 308
+                    if !supplied && redirects < client.websocket_redirects =>
+                {
+                    let Some(target) = response
+                        .header("Location")
+                        .and_then(|location| redirect_target(location, &host))
+                    else {
+                        break (session, response, key);
+                    };
+                    if client.controls.verbose() {
+                        println!("WebSocket upgrade redirected to {}{}", target.0, target.1);
+                    }
+                    // Credentials were for the old location
+                    authorization = None;
+                    (host, path) = target;
+                    redirects += 1;
+                }
+                401 if !supplied && authorization.is_none() => {
+                    let credentials = client.websocket_credentials.as_deref();
+                    match credentials.and_then(|credentials| credentials(&host, &response)) {
+                        Some(value) => authorization = Some(value),
+                        None => break (session, response, key),
+                    }
+                }
+                _ => break (session, response, key),
+            }
+        };
+
+        // RFC 6455 §4.4: 426 with the versions the server supports
+        if response.status == 426
+            && let Some(supported) = supported_versions(&response)
+        {
+            return Err(WsError::UnsupportedVersion {
+                offered: client.websocket_version,
+                supported,
+            }
+            .into());
+        }
+        if response.status != 101 {
+            return Err(WsError::Upgrade {
+                status: response.status,
+                reason: response.reason,
+            }
+            .into());
+        }
+        if response.header("Sec-WebSocket-Accept") != Some(accept_key(&key).as_str()) {
+            return Err(WsError::InvalidAccept.into());
+        }
+
+        let connection = session.connection();
+        let keying_material = session.keying_material().clone();
+        let (transport, buffered, registration) = session.into_transport();
+        registration.set_use(Use::WebSocket);
+        let backpressure = options
+            .on_backpressure
+            .as_ref()
+            .map(|alert| alert.watch(transport.fd(), connection.peer))
+            .transpose()?;
+        Ok(Self {
+            readiness: None,
+            side: Side::Client,
+            transport,
+            connection,
+            buffered,
+            assembler: FrameAssembler::new(MAX_MESSAGE),
+            closing: None,
+            heartbeat: None,
+            auto_pong: false,
+            trace: FrameTrace::default(),
+            coalesce: 0,
+            reaper: Reaper::default(),
+            writing: false,
+            _backpressure: backpressure,
+            clock: client.clock.clone(),
+            rng: client.rng.clone(),
+            _registration: Some(registration),
+            keying_material,
+        })
+    }
+
+    /// Have the task behind [`into_channels`](Self::into_channels) send
+    /// messages already queued on its channel together, in one write of up
+    /// to about `max_bytes` of payload, instead of one write each
+    ///
+    /// Meant for publishers of many small messages, where the per-write
+    /// cost dominates. Off by default.
+    pub fn with_coalescing(mut self, max_bytes: usize) -> Self {
+        self.coalesce = max_bytes;
+        self
+    }
+
+    /// Fail with [`WsError::Protocol`] on a message whose payload adds up
+    /// to more than `max_bytes`, however many frames it comes in; 16 MiB
+    /// by default
+    pub fn with_max_message_size(mut self, max_bytes: usize) -> Self {
+        self.assembler.max_size = max_bytes;
+        self
+    }
+
+    /// Call `hook` for every frame sent or received, control frames included
+    ///
+    /// Sent frames are reported once written, received ones once decoded,
+    /// each fragment of a message on its own.
+    /// The hook runs on the connection's task, so it should be quick.
+    pub fn with_frame_hook(mut self, hook: impl Fn(FrameEvent) + 'static) -> Self {
+        self.trace.hook = Some(Box::new(hook));
+        self
+    }
+
+    /// Ping the server every `interval` spent waiting for messages, and
+    /// fail with [`WsError::Unresponsive`] once `max_missed` pings in a row
+    /// have gone unanswered
+    ///
+    /// Pings go out from [`receive`](Self::receive),
+    /// [`receive_timeout`](Self::receive_timeout) and the task behind
+    /// [`into_channels`](Self::into_channels). Only a pong carrying the
+    /// last ping's payload counts as an answer, not one the server sends
+    /// unasked.
+    pub fn with_heartbeat(mut self, interval: Duration, max_missed: u32) -> Self {
+        self.heartbeat = Some(Heartbeat {
+            interval,
+            max_missed: max_missed.max(1),
+            next_ping: self.clock.now() + interval,
+            unanswered: 0,
+            sent: 0,
+        });
+        self
+    }
+
+    /// Answer every ping with a pong carrying its payload, as soon as it
+    /// is read, instead of handing it over
+    ///
+    /// Pongs that answer [`with_heartbeat`](Self::with_heartbeat) pings
+    /// are taken in too; any other pong, which a peer may send unasked as
+    /// a keepalive of its own, is still handed over. Close and data
+    /// messages are unaffected. Off by default; the task behind
+    /// [`into_channels`](Self::into_channels) answers pings either way.
+    pub fn with_auto_pong(mut self, enabled: bool) -> Self {
+        self.auto_pong = enabled;
+        self
+    }
+
+    /// Material from the TLS exporter, as for
+    /// [`Session::keying_material`]
+    pub fn keying_material(&self) -> &KeyingMaterial {
+        &self.keying_material
+    }
+
+    /// How the underlying connection was set up, and its send queue now
+    pub fn connection(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            send_queue: connect::send_queue(self.transport.fd().as_raw_fd()).ok(),
+            ..self.connection
+        }
+    }
+
+    /// Where the closing handshake stands
+    pub fn state(&self) -> CloseState {
+        match &self.closing {
+            None => CloseState::Open,
+            Some(closing) if closing.sent && closing.received => CloseState::Closed,
+            Some(_) => CloseState::Closing,
+        }
+    }
+
+    /// Send one message as a single frame
+    ///
+    /// After sending [`Message::Close`], keep receiving until the server's
+    /// Close arrives; nothing more can be sent. Once the server's Close has
+    /// arrived, only a Close can be.
+    pub async fn send(&mut self, message: Message) -> Result<(), WsError> {
+        self.send_batch(vec![message]).await
+    }
+
+    /// Close the connection with `code` and `reason`, or answer the
+    /// server's Close with them
+    ///
+    /// Does nothing once a Close has been sent, so it can be called again
+    /// safely. Keep receiving afterwards until the server's Close arrives.
+    pub async fn close(&mut self, code: u16, reason: &str) -> Result<(), WsError> {
+        if self.sent_close() {
+            return Ok(());
+        }
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        self.send(Message::Close(payload)).await
+    }
+
+    /// Write `bytes` to the connection as they are, in one write
+    ///
+    /// Nothing is framed or checked, so this can send what
+    /// [`send`](Self::send) refuses to: malformed frames for testing how a
+    /// server copes, as the `chaos` subcommand does. The frames aren't
+    /// traced, and a Close among them doesn't end sending.
+    pub async fn send_raw(&mut self, bytes: Vec<u8>) -> Result<(), WsError> {
+        self.reaper.settle().await?;
+        self.writing = true;
+        self.transport.write(bytes).await?;
+        self.writing = false;
+        Ok(())
+    }
+
+    /// Send `messages` in order, their frames packed into a single write
+    ///
+    /// Many small messages cost one io_uring write, and under kTLS as few
+    /// TLS records as their total size allows, instead of one each. Nothing
+    /// is sent if any of them can't be, e.g. a message after a Close.
+    pub async fn send_batch(&mut self, messages: Vec<Message>) -> Result<(), WsError> {
+        let mut frames = Vec::new();
+        let mut done = self.sent_close();
+        let received = self.closing.as_ref().is_some_and(|c| c.received);
+        for message in &messages {
+            let is_close = matches!(message, Message::Close(_));
+            if done || (received && !is_close) {
+                return Err(self.closed());
+            }
+            if message.opcode() >= OP_CLOSE && message.payload().len() > 125 {
+                return Err(WsError::Protocol(
+                    "control frame payloads are limited to 125 bytes",
+                ));
+            }
+            frames.extend(self.encode(message)?);
+            done = is_close;
+        }
+        if frames.is_empty() {
+            return Ok(());
+        }
+        self.reaper.settle().await?;
+        self.writing = true;
+        self.transport.write(frames).await?;
+        self.writing = false;
+        for message in &messages {
+            let len = message.payload().len();
+            self.trace
+                .record(Direction::Sent, message.opcode(), len, self.clock.now());
+            if let Message::Close(payload) = message {
+                self.record_close(self.side, payload);
+            }
+        }
+        Ok(())
+    }
+
+    /// Best-effort send that returns without waiting for the write, for
+    /// telemetry and the like that can't afford to wait
+    ///
+    /// The frame goes out ahead of anything sent after it. A failed write
+    /// is only reported by the next [`send`](Self::send) or
+    /// [`send_batch`](Self::send_batch), as the connection is then broken.
+    pub fn send_nowait(&mut self, message: Message) -> Result<(), WsError> {
+        let received = self.closing.as_ref().is_some_and(|c| c.received);
+        if self.sent_close() || (received && !matches!(message, Message::Close(_))) {
+            return Err(self.closed());
+        }
+        if message.opcode() >= OP_CLOSE && message.payload().len() > 125 {
+            return Err(WsError::Protocol(
+                "control frame payloads are limited to 125 bytes",
+            ));
+        }
+        let frame = self.encode(&message)?;
+        self.transport.send_nowait(frame, &mut self.reaper)?;
+        let len = message.payload().len();
+        self.trace
+            .record(Direction::Sent, message.opcode(), len, self.clock.now());
+        if let Message::Close(payload) = &message {
+            self.record_close(self.side, payload);
+        }
+        Ok(())
+    }
+
+    /// Wait for the next message
+    ///
+    /// Not cancellation safe: dropping the future while a read is in flight
+    /// can lose data. To bound the wait, use
+    /// [`receive_timeout`](Self::receive_timeout).
+    pub async fn receive(&mut self) -> Result<Message, WsError> {
+        if self.heartbeat.is_some() {
+            // Waits on readiness, so pings can go out in between
+            loop {
+                if let Some(message) = self.try_receive()? {
+                    return Ok(message);
+                }
+                self.beat().await?;
+                self.wait().await?;
+            }
+        }
+        loop {
+            if let Some(message) = self.decode()? {
+                return Ok(message);
+            }
+            if self.transport.read(&mut self.buffered).await? == 0 {
+                return Err(self.dropped());
+            }
+        }
+    }
+
+    /// Wait at most `timeout` for the next message; `None` if none arrived
+    ///
+    /// The wait is on socket readiness and reads only happen once data is
+    /// there, so a timed-out call leaves nothing in flight and the next
+    /// call picks up exactly where it left off.
+    pub async fn receive_timeout(&mut self, timeout: Duration) -> Result<Option<Message>, WsError> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            if let Some(message) = self.try_receive()? {
+                return Ok(Some(message));
+            }
+            self.beat().await?;
+            if tokio::time::timeout_at(deadline, self.wait())
+                .await
+                .is_err()
+            {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// The next message if one has already arrived in full, without waiting
+    pub fn try_receive(&mut self) -> Result<Option<Message>, WsError> {
+        loop {
+            if let Some(message) = self.decode()? {
+                return Ok(Some(message));
+            }
+            match self.transport.try_read(&mut self.buffered) {
+                Ok(0) => return Err(self.dropped()),
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Hand the connection to a task of its own, talking to it through channels
+    ///
+    /// Messages sent on the returned sender go out in order, batched if
+    /// [`with_coalescing`](Self::with_coalescing) was set; received text
+    /// and binary messages arrive on the receiver, which holds up to
+    /// `capacity` of them before the task stops reading. The task answers
+    /// pings, and closes the connection once the sender is dropped, the
+    /// server closes it, or the receiver is dropped. With a heartbeat set,
+    /// the task also sends the pings and ends with
+    /// [`WsError::Unresponsive`] if the server stops answering.
+    pub fn into_channels(self, capacity: usize) -> WsChannels {
+        let (sender, outgoing) = mpsc::channel(capacity);
+        let (incoming, receiver) = mpsc::channel(capacity);
+        let task = tokio_uring::spawn(self.drive(outgoing, incoming));
+        WsChannels {
+            sender,
+            receiver: MessageReceiver(receiver),
+            task,
+        }
+    }
+
+    async fn drive(
+        mut self,
+        mut outgoing: mpsc::Receiver<Message>,
+        incoming: mpsc::Sender<Message>,
+    ) -> Result<(), WsError> {
+        const NORMAL_CLOSURE: [u8; 2] = 1000u16.to_be_bytes();
+        loop {
+            while let Some(message) = self.try_receive()? {
+                match message {
+                    Message::Ping(payload) if !self.sent_close() => {
+                        self.send(Message::Pong(payload)).await?
+                    }
+                    Message::Ping(_) | Message::Pong(_) => {}
+                    // Echo the status code back, as RFC 6455 §5.5.1 suggests
+                    Message::Close(payload) => {
+                        if !self.sent_close() {
+                            let code = payload.get(..2).unwrap_or(&NORMAL_CLOSURE).to_vec();
+                            self.send(Message::Close(code)).await?;
+                        }
+                        return Ok(());
+                    }
+                    message => {
+                        if incoming.send(message).await.is_err() && !self.sent_close() {
+                            self.send(Message::Close(NORMAL_CLOSURE.to_vec())).await?;
+                        }
+                    }
+                }
+            }
+            self.beat().await?;
+
+            tokio::select! {
+                ready = self.wait() => ready?,
+                message = outgoing.recv(), if !self.sent_close() => {
+                    let message = message.unwrap_or(Message::Close(NORMAL_CLOSURE.to_vec()));
+                    let mut batch_len = message.payload().len();
+                    let mut batch = vec![message];
+                    // Pick up whatever else is already queued, up to the limit
+                    while batch_len < self.coalesce
+                        && !matches!(batch.last(), Some(Message::Close(_)))
+                        && let Ok(message) = outgoing.try_recv()
+                    {
+                        batch_len += message.payload().len();
+                        batch.push(message);
+                    }
+                    self.send_batch(batch).await?;
+                }
+            }
+        }
+    }
+
+    /// Send a heartbeat ping if one is due, or give up on the server if it
+    /// has missed too many
+    ///
+    /// Kept apart from [`wait`](Self::wait) so a timeout around the wait
+    /// never cuts a ping short.
+    async fn beat(&mut self) -> Result<(), WsError> {
+        let Some(heartbeat) = &mut self.heartbeat else {
+            return Ok(());
+        };
+        let now = self.clock.now();
+        if now < heartbeat.next_ping || self.closing.as_ref().is_some_and(|c| c.sent) {
+            return Ok(());
+        }
+        if heartbeat.unanswered >= heartbeat.max_missed {
+            return Err(WsError::Unresponsive {
+                missed: heartbeat.unanswered,
+            });
+        }
+        heartbeat.unanswered += 1;
+        heartbeat.sent += 1;
+        heartbeat.next_ping = now + heartbeat.interval;
+        let payload = heartbeat.sent.to_be_bytes().to_vec();
+        self.send(Message::Ping(payload)).await
+    }
+
+    /// Wait until the socket has something to read or a heartbeat ping is
+    /// due
+    async fn wait(&mut self) -> Result<(), WsError> {
+        let Some(next_ping) = self.heartbeat.as_ref().map(|h| h.next_ping) else {
+            return self.readable().await;
+        };
+        let due = next_ping.saturating_duration_since(self.clock.now());
+        tokio::select! {
+            ready = self.readable() => ready,
+            () = tokio::time::sleep(due) => Ok(()),
+        }
+    }
+
+    /// Wait until the socket has something to read
+    async fn readable(&mut self) -> Result<(), WsError> {
+        let readiness = match &self.readiness {
+            Some(readiness) => readiness,
+            None => {
+                let fd = self.transport.fd().as_raw_fd();
+                self.readiness
+                    .insert(AsyncFd::with_interest(fd, Interest::READABLE)?)
+            }
+        };
+        readiness.readable().await?.clear_ready();
+        Ok(())
+    }
+
+    /// Whether a Close has been sent, or can't be any more
+    fn sent_close(&self) -> bool {
+        self.closing.as_ref().is_some_and(|c| c.sent)
+    }
+
+    /// Note a Close frame sent by `by`, the first of which starts the
+    /// closing handshake
+    fn record_close(&mut self, by: Side, payload: &[u8]) {
+        let closing = self.closing.get_or_insert_with(|| {
+            let (code, reason) = close_status(payload);
+            Closing {
+                code,
+                reason,
+                initiated_by: by,
+                sent: false,
+                received: false,
+            }
+        });
+        if by == self.side {
+            closing.sent = true;
+        } else {
+            closing.received = true;
+        }
+    }
+
+    /// Note the connection dropping, with or without a closing handshake,
+    /// and report it
+    fn dropped(&mut self) -> WsError {
+        let closing = self.closing.get_or_insert_with(|| Closing {
+            code: ABNORMAL_CLOSURE,
+            reason: String::new(),
+            initiated_by: self.side.peer(),
+            sent: false,
+            received: false,
+        });
+        closing.sent = true;
+        closing.received = true;
+        self.closed()
+    }
+
+    /// What sends and receives fail with once they can't go on
+    fn closed(&self) -> WsError {
+        match &self.closing {
+            Some(closing) => WsError::Closed {
+                code: closing.code,
+                reason: closing.reason.clone(),
+                initiated_by: closing.initiated_by,
+            },
+            None => WsError::Closed {
+                code: ABNORMAL_CLOSURE,
+                reason: String::new(),
+                initiated_by: self.side.peer(),
+            },
+        }
+    }
+
+    /// `message` in a frame as this end sends them: masked from a client,
+    /// as is from a server
+    fn encode(&self, message: &Message) -> Result<Vec<u8>, WsError> {
+        Ok(match self.side {
+            Side::Client => encode_frame(message, rng::bytes(&*self.rng)?),
+            Side::Server => encode_server_frame(message),
+        })
+    }
+
+    /// Take the first complete message off `buffered`, frame by frame,
+    /// answering pings and dropping heartbeat pongs on the way with
+    /// [`with_auto_pong`](Self::with_auto_pong)
+    fn decode(&mut self) -> Result<Option<Message>, WsError> {
+        loop {
+            if self.closing.as_ref().is_some_and(|c| c.received) {
+                return Err(self.closed());
+            }
+            let Some(message) = self.decode_message()? else {
+                return Ok(None);
+            };
+            let mut solicited = false;
+            if let Message::Close(payload) = &message {
+                self.record_close(self.side.peer(), payload);
+            }
+            if let (Message::Pong(payload), Some(heartbeat)) = (&message, &mut self.heartbeat)
+                && heartbeat.answered_by(payload)
+            {
+                solicited = true;
+                heartbeat.unanswered = 0;
+            }
+            match message {
+                Message::Ping(payload) if self.auto_pong => {
+                    if !self.sent_close() {
+                        self.send_nowait(Message::Pong(payload))?;
+                    }
+                }
+                Message::Pong(_) if self.auto_pong && solicited => {}
+                message => return Ok(Some(message)),
+            }
+        }
+    }
+
+    /// Take the first complete message off `buffered`, whatever it is
+    fn decode_message(&mut self) -> Result<Option<Message>, WsError> {
+        loop {
+            let decoded = match self.side {
+                Side::Client => decode_frame(&self.buffered)?,
+                Side::Server => decode_client_frame(&self.buffered)?,
+            };
+            let Some((frame, len)) = decoded else {
+                return Ok(None);
+            };
+            self.buffered.drain(..len);
+            let (opcode, len) = (frame.opcode, frame.payload.len());
+            self.trace
+                .record(Direction::Received, opcode, len, self.clock.now());
+            if let Some(message) = self.assembler.push(frame)? {
+                return Ok(Some(message));
+            }
+        }
+    }
+}
+
+impl Drop for WssClient {
+    /// Send a Close with [`GOING_AWAY`] and then `close_notify`, unless a
+    /// Close has been sent already or the connection dropped
+    ///
+    /// Best effort and without waiting: nothing is sent while a write is
+    /// cut short or still in flight, as the frame would land in the middle
+    /// of it, and over kTLS `close_notify` only follows a frame the socket
+    /// took whole.
+    fn drop(&mut self) {
+        if self.sent_close() || self.writing || !self.reaper.idle() {
+            return;
+        }
+        let Ok(frame) = self.encode(&Message::Close(GOING_AWAY.to_be_bytes().to_vec())) else {
+            return;
+        };
+        let sent = match &mut self.transport {
+            Transport::Ktls(_) => self
+                .transport
+                .try_write(&frame)
+                .is_ok_and(|n| n == frame.len()),
+            Transport::Userspace(tls) => tls.queue(&frame).is_ok(),
+        };
+        if sent {
+            self.transport.send_close_notify();
+            let _ = self.transport.try_flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wsproto::{OP_BINARY, OP_PING, OP_TEXT};
+
+    fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Frame {
+        Frame {
+            fin,
+            opcode,
+            payload: payload.to_vec(),
+        }
+    }
+
+    #[test]
+    fn assembles_fragments_around_control_frames() {
+        let mut assembler = FrameAssembler::new(MAX_MESSAGE);
+        assert_eq!(assembler.push(frame(false, OP_TEXT, b"Hel")).unwrap(), None);
+        assert_eq!(
+            assembler.push(frame(true, OP_PING, b"?")).unwrap(),
+            Some(Message::Ping(b"?".to_vec()))
+        );
+        assert_eq!(
+            assembler
+                .push(frame(false, OP_CONTINUATION, b"lo, "))
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            assembler
+                .push(frame(true, OP_CONTINUATION, b"world"))
+                .unwrap(),
+            Some(Message::Text("Hello, world".to_owned()))
+        );
+        // Unfragmented messages pass straight through
+        assert_eq!(
+            assembler.push(frame(true, OP_BINARY, b"\x00")).unwrap(),
+            Some(Message::Binary(vec![0]))
+        );
+    }
+
+    #[test]
+    fn only_the_last_pings_payload_answers_the_heartbeat() {
+        let mut heartbeat = Heartbeat {
+            interval: Duration::from_secs(1),
+            max_missed: 3,
+            next_ping: Instant::now(),
+            unanswered: 0,
+            sent: 0,
+        };
+        // Nothing to answer yet, whatever the payload
+        assert!(!heartbeat.answered_by(&0u64.to_be_bytes()));
+
+        heartbeat.unanswered = 2;
+        heartbeat.sent = 2;
+        assert!(heartbeat.answered_by(&2u64.to_be_bytes()));
+        assert!(!heartbeat.answered_by(&1u64.to_be_bytes()));
+        assert!(!heartbeat.answered_by(b""));
+    }
+
+    #[test]
+    fn rejects_misordered_and_oversized_fragments() {
+        let mut assembler = FrameAssembler::new(MAX_MESSAGE);
+        assert!(assembler.push(frame(true, OP_CONTINUATION, b"x")).is_err());
+        assembler.push(frame(false, OP_TEXT, b"a")).unwrap();
+        assert!(assembler.push(frame(true, OP_BINARY, b"b")).is_err());
+
+        let mut assembler = FrameAssembler::new(4);
+        assembler.push(frame(false, OP_BINARY, b"abc")).unwrap();
+        assert!(assembler.push(frame(true, OP_CONTINUATION, b"de")).is_err());
+    }
+}
@@ -0,0 +1,104 @@
+//! Debug dump of the HTTP traffic on each connection
+//!
+//! With kTLS the plaintext never exists in userspace outside this process,
+//! and tcpdump only sees ciphertext, so interop problems are hard to look
+//! at from outside. The wire log prints the bytes each request puts on the
+//! connection and each response takes off it to stderr, before any
+//! decoding: `>` lines were sent, `<` lines received. Credential headers
+//! have their values replaced, and bodies are left out unless
+//! [`WireLog::body_bytes`] asks for a hexdump of their start.
+//!
+//! Chunks of a channel body are sent after the head and don't appear.
+
+use std::fmt::Write;
+
+/// Headers whose values never reach the log
+const REDACTED: [&str; 4] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+];
+
+#[derive(Clone, Debug, Default)]
+pub struct WireLog {
+    /// Hexdump up to this many bytes of each body; 0 logs heads only
+    pub body_bytes: usize,
+}
+
+impl WireLog {
+    /// Log a request as written to `host`
+    pub fn sent(&self, host: &str, raw: &[u8]) {
+        self.dump('>', host, raw);
+    }
+
+    /// Log a response as read from `host`
+    pub fn received(&self, host: &str, raw: &[u8]) {
+        self.dump('<', host, raw);
+    }
+
+    fn dump(&self, marker: char, host: &str, raw: &[u8]) {
+        let (head, body) = match raw.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(end) => (&raw[..end], &raw[end + 4..]),
+            None => (raw, &[][..]),
+        };
+
+        let mut out = format!("{marker} [{host}]\n");
+        for line in String::from_utf8_lossy(head).split("\r\n") {
+            match line.split_once(':') {
+                Some((name, _))
+                    if REDACTED.contains(&name.trim().to_ascii_lowercase().as_str()) =>
+                {
+                    let _ = writeln!(out, "{marker} {name}: [redacted]");
+                }
+                _ => {
+                    let _ = writeln!(out, "{marker} {line}");
+                }
+            }
+        }
+        if !body.is_empty() {
+            let _ = writeln!(out, "{marker}");
+            if self.body_bytes == 0 {
+                let _ = writeln!(out, "{marker} ({} body bytes)", body.len());
+            } else {
+                hexdump(&mut out, marker, &body[..body.len().min(self.body_bytes)]);
+                if body.len() > self.body_bytes {
+                    let _ = writeln!(
+                        out,
+                        "{marker} ... {} more bytes",
+                        body.len() - self.body_bytes
+                    );
+                }
+            }
+        }
+        // One write, so concurrent requests' dumps don't interleave
+        eprint!("{out}");
+    }
+}
+
+/// `data` as offset, hex and ASCII columns, 16 bytes a line
+fn hexdump(out: &mut String, marker: char, data: &[u8]) {
+    for (i, row) in data.chunks(16).enumerate() {
+        let _ = write!(out, "{marker} {:08x}  ", i * 16);
+        for col in 0..16 {
+            match row.get(col) {
+                Some(byte) => {
+                    let _ = write!(out, "{byte:02x} ");
+                }
+                None => out.push_str("   "),
+            }
+            if col == 7 {
+                out.push(' ');
+            }
+        }
+        out.push_str(" |");
+        out.extend(row.iter().map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        }));
+        out.push_str("|\n");
+    }
+}
@@ -0,0 +1,576 @@
+//! WebSocket protocol pieces that don't depend on the connection
+//!
+//! Frame encoding and decoding (RFC 6455 §5) and the header logic of the
+//! upgrade handshake, for both ends of a connection, kept apart from
+//! [`WssClient`](crate::websocket::WssClient) and its io_uring transport so
+//! they build and can be tested anywhere.
+
+use aws_lc_rs::digest;
+
+use crate::http::{RequestHead, Response, Version};
+
+/// Appended to the key to derive `Sec-WebSocket-Accept` (RFC 6455 §1.3)
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// The only `Sec-WebSocket-Version` a server end accepts
+const SUPPORTED_VERSION: u8 = 13;
+/// Largest frame payload accepted from the peer
+const MAX_PAYLOAD: u64 = 16 * 1024 * 1024;
+
+pub const OP_CONTINUATION: u8 = 0x0;
+pub const OP_TEXT: u8 = 0x1;
+pub const OP_BINARY: u8 = 0x2;
+pub const OP_CLOSE: u8 = 0x8;
+pub const OP_PING: u8 = 0x9;
+pub const OP_PONG: u8 = 0xA;
+
+/// Sent by a client going away, e.g. one dropped without closing first
+pub const GOING_AWAY: u16 = 1001;
+/// Reported for a Close frame without a status code; never sent (RFC 6455
+/// §7.4.1)
+pub const NO_STATUS: u16 = 1005;
+/// Reported for a connection that dropped without a Close frame; never sent
+pub const ABNORMAL_CLOSURE: u16 = 1006;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// Raw close payload: a big-endian status code and UTF-8 reason, or empty
+    Close(Vec<u8>),
+}
+
+impl Message {
+    pub fn opcode(&self) -> u8 {
+        match self {
+            Message::Text(_) => OP_TEXT,
+            Message::Binary(_) => OP_BINARY,
+            Message::Ping(_) => OP_PING,
+            Message::Pong(_) => OP_PONG,
+            Message::Close(_) => OP_CLOSE,
+        }
+    }
+
+    /// The message a whole `payload` sent with `opcode` makes up
+    pub fn from_parts(opcode: u8, payload: Vec<u8>) -> Result<Message, WsError> {
+        Ok(match opcode {
+            OP_TEXT => Message::Text(
+                String::from_utf8(payload)
+                    .map_err(|_| WsError::Protocol("text message is not UTF-8"))?,
+            ),
+            OP_BINARY => Message::Binary(payload),
+            OP_CLOSE => Message::Close(payload),
+            OP_PING => Message::Ping(payload),
+            OP_PONG => Message::Pong(payload),
+            _ => return Err(WsError::Protocol("unknown opcode")),
+        })
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        match self {
+            Message::Text(text) => text.as_bytes(),
+            Message::Binary(data)
+            | Message::Ping(data)
+            | Message::Pong(data)
+            | Message::Close(data) => data,
+        }
+    }
+}
+
+/// A frame as it came off the wire, unmasked; a message, or a fragment of
+/// one (RFC 6455 §5.4)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    /// Set on a message's last frame
+    pub fin: bool,
+    /// [`OP_CONTINUATION`] for every fragment after the first
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+/// One end of a WebSocket connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+impl Side {
+    /// The other end
+    pub fn peer(self) -> Side {
+        match self {
+            Side::Client => Side::Server,
+            Side::Server => Side::Client,
+        }
+    }
+}
+
+impl std::fmt::Display for Side {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Side::Client => write!(f, "client"),
+            Side::Server => write!(f, "server"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WsError {
+    Io(std::io::Error),
+    /// The server answered the upgrade request with something other than 101
+    Upgrade {
+        status: u16,
+        reason: String,
+    },
+    /// The server doesn't speak the offered `Sec-WebSocket-Version`; it
+    /// listed the ones it does, if any
+    UnsupportedVersion {
+        offered: u8,
+        supported: Vec<u8>,
+    },
+    /// `Sec-WebSocket-Accept` doesn't match the key that was sent
+    InvalidAccept,
+    /// A client's upgrade request isn't one RFC 6455 §4.2.1 lets a server
+    /// accept
+    InvalidUpgrade(&'static str),
+    /// A frame broke RFC 6455 or uses something this client doesn't support
+    Protocol(&'static str),
+    /// A Close frame was already sent (for sends) or received (for
+    /// receives), or the connection was dropped
+    Closed {
+        /// From the first Close frame: [`NO_STATUS`] if it had none,
+        /// [`ABNORMAL_CLOSURE`] if the connection dropped before one came
+        code: u16,
+        /// That frame's reason, often empty
+        reason: String,
+        /// Who sent that frame, or dropped the connection
+        initiated_by: Side,
+    },
+    /// The server let `missed` heartbeat pings in a row go unanswered
+    Unresponsive {
+        missed: u32,
+    },
+}
+
+impl std::fmt::Display for WsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WsError::Io(e) => write!(f, "WebSocket I/O error: {e}"),
+            WsError::Upgrade { status, reason } => {
+                write!(f, "WebSocket upgrade rejected: {status} {reason}")
+            }
+            WsError::UnsupportedVersion { offered, supported } => {
+                write!(f, "Server doesn't support WebSocket version {offered}")?;
+                if !supported.is_empty() {
+                    let supported: Vec<_> = supported.iter().map(u8::to_string).collect();
+                    write!(f, ", only {}", supported.join(", "))?;
+                }
+                Ok(())
+            }
+            WsError::InvalidAccept => write!(f, "Server sent the wrong Sec-WebSocket-Accept"),
+            WsError::InvalidUpgrade(e) => write!(f, "Invalid WebSocket upgrade request: {e}"),
+            WsError::Protocol(e) => write!(f, "WebSocket protocol error: {e}"),
+            WsError::Closed {
+                code,
+                reason,
+                initiated_by,
+            } => {
+                write!(
+                    f,
+                    "WebSocket connection was closed by the {initiated_by} ({code}"
+                )?;
+                if !reason.is_empty() {
+                    write!(f, " {reason}")?;
+                }
+                write!(f, ")")
+            }
+            WsError::Unresponsive { missed } => {
+                write!(
+                    f,
+                    "WebSocket server missed {missed} pongs, assuming the connection is dead"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for WsError {}
+
+impl From<std::io::Error> for WsError {
+    fn from(e: std::io::Error) -> Self {
+        WsError::Io(e)
+    }
+}
+
+/// Status code and reason of a Close frame's payload
+pub fn close_status(payload: &[u8]) -> (u16, String) {
+    match payload {
+        [high, low, reason @ ..] => (
+            u16::from_be_bytes([*high, *low]),
+            String::from_utf8_lossy(reason).into_owned(),
+        ),
+        _ => (NO_STATUS, String::new()),
+    }
+}
+
+/// A masked frame carrying all of `message`, as clients must send
+pub fn encode_frame(message: &Message, mask: [u8; 4]) -> Vec<u8> {
+    encode(message, Some(mask))
+}
+
+/// An unmasked frame carrying all of `message`, as servers must send
+pub fn encode_server_frame(message: &Message) -> Vec<u8> {
+    encode(message, None)
+}
+
+fn encode(message: &Message, mask: Option<[u8; 4]>) -> Vec<u8> {
+    let payload = message.payload();
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    frame.push(0x80 | message.opcode());
+    let masked = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        len @ 0..=125 => frame.push(masked | len as u8),
+        len @ 126..=0xffff => {
+            frame.push(masked | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(masked | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    match mask {
+        Some(mask) => {
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+        }
+        None => frame.extend_from_slice(payload),
+    }
+    frame
+}
+
+/// The first frame from the server in `buf` and its length on the wire,
+/// once it has arrived in full
+pub fn decode_frame(buf: &[u8]) -> Result<Option<(Frame, usize)>, WsError> {
+    decode(buf, Side::Server)
+}
+
+/// [`decode_frame`] for frames from a client, which must be masked
+pub fn decode_client_frame(buf: &[u8]) -> Result<Option<(Frame, usize)>, WsError> {
+    decode(buf, Side::Client)
+}
+
+fn decode(buf: &[u8], sender: Side) -> Result<Option<(Frame, usize)>, WsError> {
+    let [first, second, ..] = *buf else {
+        return Ok(None);
+    };
+    let fin = first & 0x80 != 0;
+    let opcode = first & 0x0f;
+    if first & 0x70 != 0 {
+        return Err(WsError::Protocol(
+            "reserved bits set without a negotiated extension",
+        ));
+    }
+    let masked = second & 0x80 != 0;
+    match sender {
+        Side::Server if masked => {
+            return Err(WsError::Protocol("server frames must not be masked"));
+        }
+        Side::Client if !masked => return Err(WsError::Protocol("client frames must be masked")),
+        _ => {}
+    }
+
+    let (len, mut header) = match second & 0x7f {
+        126 => match buf.get(2..4) {
+            Some(len) => (u64::from(u16::from_be_bytes([len[0], len[1]])), 4),
+            None => return Ok(None),
+        },
+        127 => match buf.get(2..10) {
+            Some(len) => (u64::from_be_bytes(len.try_into().unwrap()), 10),
+            None => return Ok(None),
+        },
+        len => (u64::from(len), 2),
+    };
+    if len > MAX_PAYLOAD {
+        return Err(WsError::Protocol("frame payload too large"));
+    }
+    let mask = if masked {
+        let Some(mask) = buf.get(header..header + 4) else {
+            return Ok(None);
+        };
+        header += 4;
+        Some([mask[0], mask[1], mask[2], mask[3]])
+    } else {
+        None
+    };
+    let end = header + len as usize;
+    let Some(payload) = buf.get(header..end) else {
+        return Ok(None);
+    };
+
+    if !matches!(
+        opcode,
+        OP_CONTINUATION | OP_TEXT | OP_BINARY | OP_CLOSE | OP_PING | OP_PONG
+    ) {
+        return Err(WsError::Protocol("unknown opcode"));
+    }
+    if opcode >= OP_CLOSE && (!fin || len > 125) {
+        return Err(WsError::Protocol(
+            "control frames must be single frames of at most 125 bytes",
+        ));
+    }
+
+    let payload = match mask {
+        Some(mask) => payload
+            .iter()
+            .zip(mask.iter().cycle())
+            .map(|(b, m)| b ^ m)
+            .collect(),
+        None => payload.to_vec(),
+    };
+    let frame = Frame {
+        fin,
+        opcode,
+        payload,
+    };
+    Ok(Some((frame, end)))
+}
+
+/// Host and path a `Location` header sends the upgrade (or a plain
+/// request) to: an absolute `wss://` or `https://` URL, or a path on `host`
+///
+/// Redirects to plain `ws://` or `http://`, or to another port, aren't
+/// followed.
+pub fn redirect_target(location: &str, host: &str) -> Option<(String, String)> {
+    if location.starts_with('/') && !location.starts_with("//") {
+        return Some((host.to_owned(), location.to_owned()));
+    }
+    let rest = location
+        .strip_prefix("wss://")
+        .or_else(|| location.strip_prefix("https://"))?;
+    let (authority, path) = match rest.find(['/', '?']) {
+        Some(i) if rest.as_bytes()[i] == b'/' => (&rest[..i], rest[i..].to_owned()),
+        Some(i) => (&rest[..i], format!("/{}", &rest[i..])),
+        None => (rest, "/".to_owned()),
+    };
+    let host = authority.strip_suffix(":443").unwrap_or(authority);
+    if host.is_empty() || host.contains([':', '@']) {
+        return None;
+    }
+    Some((host.to_owned(), path))
+}
+
+/// Versions listed in a version-negotiation response, which may spread
+/// them over several `Sec-WebSocket-Version` headers; `None` if it has none
+pub fn supported_versions(response: &Response) -> Option<Vec<u8>> {
+    let mut values = response.headers.get_all("Sec-WebSocket-Version").peekable();
+    values.peek()?;
+    Some(
+        values
+            .flat_map(|value| value.split(','))
+            .filter_map(|version| version.trim().parse().ok())
+            .collect(),
+    )
+}
+
+/// `Sec-WebSocket-Accept` value the server must answer `key` with
+pub fn accept_key(key: &str) -> String {
+    let hash = digest::digest(
+        &digest::SHA1_FOR_LEGACY_USE_ONLY,
+        format!("{key}{ACCEPT_GUID}").as_bytes(),
+    );
+    base64(hash.as_ref())
+}
+
+/// The `Sec-WebSocket-Key` of a client's upgrade request, once the
+/// request checks out as RFC 6455 §4.2.1 requires
+pub fn check_upgrade(request: &RequestHead) -> Result<String, WsError> {
+    let has_token = |name, token: &str| {
+        request
+            .headers
+            .get_all(name)
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    };
+    if request.method != "GET" || request.version != Version::Http11 {
+        return Err(WsError::InvalidUpgrade("not an HTTP/1.1 GET"));
+    }
+    if request.headers.get("Host").is_none() {
+        return Err(WsError::InvalidUpgrade("no Host header"));
+    }
+    if !has_token("Upgrade", "websocket") || !has_token("Connection", "Upgrade") {
+        return Err(WsError::InvalidUpgrade(
+            "not asking to upgrade to websocket",
+        ));
+    }
+    let version = request
+        .headers
+        .get("Sec-WebSocket-Version")
+        .and_then(|version| version.trim().parse().ok());
+    if version != Some(SUPPORTED_VERSION) {
+        return Err(WsError::UnsupportedVersion {
+            offered: version.unwrap_or(0),
+            supported: vec![SUPPORTED_VERSION],
+        });
+    }
+    // Any 16 random bytes, base64-encoded
+    let key = request.headers.get("Sec-WebSocket-Key").unwrap_or_default();
+    if unbase64(key).is_none_or(|nonce| nonce.len() != 16) {
+        return Err(WsError::InvalidUpgrade("invalid Sec-WebSocket-Key"));
+    }
+    Ok(key.to_owned())
+}
+
+/// The 101 response accepting an upgrade request sent with `key`
+pub fn upgrade_response(key: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    )
+    .into_bytes()
+}
+
+/// The response refusing an upgrade request that failed `check_upgrade`
+/// with `error`: 426 listing the version spoken, for a version this end
+/// doesn't speak (RFC 6455 §4.4), 400 otherwise
+pub fn upgrade_rejection(error: &WsError) -> Vec<u8> {
+    match error {
+        WsError::UnsupportedVersion { .. } => format!(
+            "HTTP/1.1 426 Upgrade Required\r\n\
+             Sec-WebSocket-Version: {SUPPORTED_VERSION}\r\n\
+             Content-Length: 0\r\n\r\n"
+        ),
+        _ => "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n".to_owned(),
+    }
+    .into_bytes()
+}
+
+pub fn random<const N: usize>() -> std::io::Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    aws_lc_rs::rand::fill(&mut bytes).map_err(|_| std::io::Error::other("system RNG failed"))?;
+    Ok(bytes)
+}
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Standard padded base64, for the handshake keys
+pub fn base64(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from(bytes[0]) << 16 | u32::from(bytes[1]) << 8 | u32::from(bytes[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 63] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// The bytes standard padded base64 `text` encodes; `None` if it isn't that
+pub fn unbase64(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(4) {
+        return None;
+    }
+    let groups = text.len() / 4;
+    let mut out = Vec::with_capacity(groups * 3);
+    for (i, chunk) in text.as_bytes().chunks(4).enumerate() {
+        // Only the last group pads, and by at most two
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && i + 1 < groups) {
+            return None;
+        }
+        let mut n = 0u32;
+        for &c in &chunk[..4 - padding] {
+            let value = ALPHABET.iter().position(|&a| a == c)?;
+            n = n << 6 | value as u32;
+        }
+        n <<= 6 * padding;
+        out.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upgrade_request(extra: &str) -> RequestHead {
+        let raw = format!(
+            "GET /chat HTTP/1.1\r\n\
+             Host: example.com\r\n\
+             Upgrade: websocket\r\n\
+             Connection: keep-alive, Upgrade\r\n\
+             {extra}\r\n"
+        );
+        RequestHead::parse(raw.as_bytes()).unwrap().unwrap().0
+    }
+
+    #[test]
+    fn accepts_the_rfc_sample_upgrade() {
+        let request = upgrade_request(
+            "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n",
+        );
+        let key = check_upgrade(&request).unwrap();
+        let response = String::from_utf8(upgrade_response(&key)).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 "));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    }
+
+    #[test]
+    fn rejects_bad_upgrades() {
+        let short_key =
+            upgrade_request("Sec-WebSocket-Key: c2hvcnQ=\r\nSec-WebSocket-Version: 13\r\n");
+        assert!(matches!(
+            check_upgrade(&short_key),
+            Err(WsError::InvalidUpgrade(_))
+        ));
+
+        let old = upgrade_request(
+            "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 8\r\n",
+        );
+        let error = check_upgrade(&old).unwrap_err();
+        assert!(matches!(
+            error,
+            WsError::UnsupportedVersion { offered: 8, .. }
+        ));
+        let response = String::from_utf8(upgrade_rejection(&error)).unwrap();
+        assert!(response.starts_with("HTTP/1.1 426 "));
+        assert!(response.contains("Sec-WebSocket-Version: 13\r\n"));
+    }
+
+    #[test]
+    fn each_end_decodes_the_others_frames() {
+        let message = Message::Text("hello".to_owned());
+        let frame = Frame {
+            fin: true,
+            opcode: OP_TEXT,
+            payload: b"hello".to_vec(),
+        };
+        let from_server = encode_server_frame(&message);
+        assert_eq!(from_server, b"\x81\x05hello");
+        assert_eq!(
+            decode_frame(&from_server).unwrap(),
+            Some((frame.clone(), 7))
+        );
+        assert!(decode_client_frame(&from_server).is_err());
+
+        let from_client = encode_frame(&message, [1, 2, 3, 4]);
+        assert_eq!(
+            decode_client_frame(&from_client).unwrap(),
+            Some((frame, from_client.len()))
+        );
+        assert!(decode_frame(&from_client).is_err());
+    }
+}